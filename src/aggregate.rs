@@ -0,0 +1,362 @@
+//! Spread of the maxima over repeated runs of the same firmware. A single run hides
+//! memory behavior which depends on timing, like interrupts nesting at different points.
+//!
+//! The runs can be merged into the statistics of all of them, each maximum keeps the run
+//! it was measured in.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    mem_monitoring::RamSnapshotRecorder,
+    statistics::{RamStatistics, StatisticsEngine},
+};
+
+/// Minimum, maximum and standard deviation of one value over all runs.
+#[derive(Debug, Serialize)]
+pub struct Spread {
+    pub min: u32,
+    pub max: u32,
+    pub mean: f32,
+    pub std_dev: f32,
+}
+
+impl Spread {
+    /// `values` must not be empty.
+    fn of(values: &[u32]) -> Self {
+        let mean = values.iter().map(|v| *v as f32).sum::<f32>() / values.len() as f32;
+        let variance = values
+            .iter()
+            .map(|v| (*v as f32 - mean).powi(2))
+            .sum::<f32>()
+            / values.len() as f32;
+
+        Self {
+            min: values.iter().copied().min().unwrap_or(0),
+            max: values.iter().copied().max().unwrap_or(0),
+            mean,
+            std_dev: variance.sqrt(),
+        }
+    }
+
+    /// Difference between the largest and the smallest value relative to the mean.
+    pub fn relative_range(&self) -> f32 {
+        if self.mean == 0.0 {
+            return 0.0;
+        }
+        (self.max - self.min) as f32 / self.mean
+    }
+}
+
+impl std::fmt::Display for Spread {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} to {} bytes, mean {:.0}, std dev {:.1} ({:.1}% range)",
+            self.min,
+            self.max,
+            self.mean,
+            self.std_dev,
+            self.relative_range() * 100.0
+        )
+    }
+}
+
+pub struct Aggregate {
+    pub runs: usize,
+    pub max_stack_ptr_off: Spread,
+    /// Only over the runs which monitored the heap.
+    pub max_heap_usage: Option<Spread>,
+}
+
+impl Aggregate {
+    /// `recordings` must not be empty.
+    pub fn new(recordings: &[RamSnapshotRecorder]) -> Self {
+        let statistics = recordings
+            .iter()
+            .map(|r| StatisticsEngine::new(r).calculate())
+            .collect::<Vec<_>>();
+        let max_stack = statistics
+            .iter()
+            .map(|s| s.max_stack_ptr_off)
+            .collect::<Vec<_>>();
+        let max_heap = statistics
+            .iter()
+            .filter_map(|s| s.max_heap_usage())
+            .collect::<Vec<_>>();
+
+        Self {
+            runs: recordings.len(),
+            max_stack_ptr_off: Spread::of(&max_stack),
+            max_heap_usage: if max_heap.is_empty() {
+                None
+            } else {
+                Some(Spread::of(&max_heap))
+            },
+        }
+    }
+
+    /// Prints the spreads and flags the ones wider than `tolerance` as non-deterministic.
+    pub fn print(&self, tolerance: f32) {
+        println!("{} runs", self.runs);
+        let print_spread = |name: &str, spread: &Spread| {
+            println!("{}: {}", name, spread);
+            if spread.relative_range() > tolerance {
+                println!(
+                    "  non-deterministic: {} varies by {} bytes between runs",
+                    name,
+                    spread.max - spread.min
+                );
+            }
+        };
+        print_spread("max stack ptr offset", &self.max_stack_ptr_off);
+        if let Some(heap) = self.max_heap_usage.as_ref() {
+            print_spread("max heap usage", heap);
+        }
+    }
+}
+
+/// Where a merged run comes from and its own maxima.
+#[derive(Debug, Serialize)]
+pub struct RunProvenance {
+    pub record_file: String,
+    pub timestamp: DateTime<Utc>,
+    pub device_id: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub records: usize,
+    pub max_stack_ptr_off: u32,
+    pub median_stack_ptr_off: u32,
+    pub max_heap_usage: Option<u32>,
+}
+
+impl RunProvenance {
+    fn new(record_file: &str, recorder: &RamSnapshotRecorder, statistics: &RamStatistics) -> Self {
+        let session = recorder.session();
+        Self {
+            record_file: String::from(record_file),
+            timestamp: session.timestamp,
+            device_id: session.device_id.to_owned(),
+            labels: session.labels.to_owned(),
+            records: recorder.timeline().count() + recorder.evicted_records(),
+            max_stack_ptr_off: statistics.max_stack_ptr_off,
+            median_stack_ptr_off: statistics.median_stack_ptr_off(),
+            max_heap_usage: statistics.max_heap_usage(),
+        }
+    }
+}
+
+/// Maxima of a function over all runs, `run` is the index of the run with the deepest
+/// stack in it.
+#[derive(Debug, Serialize)]
+pub struct MergedFunction {
+    pub function: String,
+    pub records: usize,
+    pub max_stack_ptr_off: u32,
+    pub max_used_bytes: u32,
+    pub run: usize,
+}
+
+/// Statistics of several runs of the same firmware as one, runs are referred to by
+/// their index in `runs`.
+#[derive(Debug, Serialize)]
+pub struct MergedStatistics {
+    pub firmware_hash: String,
+    pub runs: Vec<RunProvenance>,
+    pub records: usize,
+    pub max_stack_ptr_off: u32,
+    pub max_stack_run: usize,
+    /// Median over the records of all runs, not the median of the run medians.
+    pub median_stack_ptr_off: u32,
+    /// Distribution of the max stack ptr offset of the single runs.
+    pub max_stack_spread: Spread,
+    pub max_heap_usage: Option<u32>,
+    pub max_heap_run: Option<usize>,
+    pub max_heap_spread: Option<Spread>,
+    pub functions: Vec<MergedFunction>,
+}
+
+impl MergedStatistics {
+    /// `recordings` must not be empty, `record_files` are their paths.
+    pub fn new(record_files: &[&str], recordings: &[RamSnapshotRecorder]) -> Self {
+        let statistics = recordings
+            .iter()
+            .map(|r| StatisticsEngine::new(r).calculate())
+            .collect::<Vec<_>>();
+        let runs = record_files
+            .iter()
+            .zip(recordings.iter().zip(statistics.iter()))
+            .map(|(path, (recorder, statistics))| RunProvenance::new(path, recorder, statistics))
+            .collect::<Vec<_>>();
+
+        let (max_stack_run, max_stack_ptr_off) = runs
+            .iter()
+            .map(|r| r.max_stack_ptr_off)
+            .enumerate()
+            .max_by_key(|(_, max)| *max)
+            .unwrap_or_default();
+        let max_heap = runs
+            .iter()
+            .enumerate()
+            .filter_map(|(run, r)| r.max_heap_usage.map(|max| (run, max)))
+            .collect::<Vec<_>>();
+        let heap_values = max_heap.iter().map(|(_, max)| *max).collect::<Vec<_>>();
+
+        let mut stack_ptrs_off = recordings
+            .iter()
+            .flat_map(|r| r.timeline())
+            .filter(|s| s.sp_anomaly.is_none())
+            .map(|s| s.stack_ptr_offset)
+            .collect::<Vec<_>>();
+        stack_ptrs_off.sort_unstable();
+
+        let mut functions: HashMap<String, MergedFunction> = HashMap::new();
+        for (run, recorder) in recordings.iter().enumerate() {
+            for function in recorder.function_statistics() {
+                let merged = functions
+                    .entry(function.function.to_owned())
+                    .or_insert_with(|| MergedFunction {
+                        function: function.function.to_owned(),
+                        records: 0,
+                        max_stack_ptr_off: function.max_stack_ptr_off,
+                        max_used_bytes: 0,
+                        run,
+                    });
+                merged.records += function.records;
+                merged.max_used_bytes = merged.max_used_bytes.max(function.max_used_bytes);
+                if function.max_stack_ptr_off > merged.max_stack_ptr_off {
+                    merged.max_stack_ptr_off = function.max_stack_ptr_off;
+                    merged.run = run;
+                }
+            }
+        }
+        let mut functions = functions.into_values().collect::<Vec<_>>();
+        functions.sort_by(|a, b| {
+            b.max_stack_ptr_off
+                .cmp(&a.max_stack_ptr_off)
+                .then_with(|| a.function.cmp(&b.function))
+        });
+
+        Self {
+            firmware_hash: recordings[0].session().firmware_hash.to_owned(),
+            records: runs.iter().map(|r| r.records).sum(),
+            max_stack_ptr_off,
+            max_stack_run,
+            median_stack_ptr_off: stack_ptrs_off
+                .get(stack_ptrs_off.len() / 2)
+                .copied()
+                .unwrap_or(0),
+            max_stack_spread: Spread::of(
+                &runs.iter().map(|r| r.max_stack_ptr_off).collect::<Vec<_>>(),
+            ),
+            max_heap_usage: heap_values.iter().copied().max(),
+            max_heap_run: max_heap
+                .iter()
+                .max_by_key(|(_, max)| *max)
+                .map(|(run, _)| *run),
+            max_heap_spread: if heap_values.is_empty() {
+                None
+            } else {
+                Some(Spread::of(&heap_values))
+            },
+            functions,
+            runs,
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "merged {} runs with {} records",
+            self.runs.len(),
+            self.records
+        );
+        for (index, run) in self.runs.iter().enumerate() {
+            println!(
+                "  run {}: {} ({}{}), {} records, max stack {} bytes",
+                index,
+                run.record_file,
+                run.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                run.device_id
+                    .as_ref()
+                    .map(|id| format!(", device {}", id))
+                    .unwrap_or_default(),
+                run.records,
+                run.max_stack_ptr_off
+            );
+        }
+        println!(
+            "max stack ptr offset: {} bytes in run {} ({})",
+            self.max_stack_ptr_off, self.max_stack_run, self.runs[self.max_stack_run].record_file
+        );
+        println!(
+            "median stack ptr offset over all records: {} bytes",
+            self.median_stack_ptr_off
+        );
+        if let (Some(max), Some(run)) = (self.max_heap_usage, self.max_heap_run) {
+            println!(
+                "max heap usage: {} bytes in run {} ({})",
+                max, run, self.runs[run].record_file
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        import::{to_recording, FunctionStack},
+        session::SessionInfo,
+    };
+
+    fn run(functions: &[(&str, u32)]) -> RamSnapshotRecorder {
+        let functions = functions
+            .iter()
+            .map(|(function, bytes)| FunctionStack {
+                function: String::from(*function),
+                bytes: *bytes,
+            })
+            .collect::<Vec<_>>();
+        to_recording(
+            SessionInfo::new(b"firmware", String::from("test")),
+            &functions,
+            1024,
+        )
+    }
+
+    #[test]
+    fn merged_maxima_keep_their_run() {
+        let recordings = [
+            run(&[("main", 64), ("isr", 200)]),
+            run(&[("main", 96), ("isr", 120), ("idle", 8)]),
+        ];
+        let merged = MergedStatistics::new(&["a.json", "b.json"], &recordings);
+
+        assert_eq!(merged.records, 5);
+        assert_eq!((merged.max_stack_ptr_off, merged.max_stack_run), (200, 0));
+        assert_eq!(merged.median_stack_ptr_off, 96);
+        assert_eq!(
+            (merged.max_stack_spread.min, merged.max_stack_spread.max),
+            (120, 200)
+        );
+        assert_eq!(merged.max_stack_spread.mean, 160.0);
+        assert!(merged.max_heap_usage.is_none() && merged.max_heap_spread.is_none());
+        assert_eq!(
+            merged
+                .runs
+                .iter()
+                .map(|r| (r.record_file.as_str(), r.max_stack_ptr_off))
+                .collect::<Vec<_>>(),
+            [("a.json", 200), ("b.json", 120)]
+        );
+        assert_eq!(
+            merged
+                .functions
+                .iter()
+                .map(|f| (f.function.as_str(), f.records, f.max_stack_ptr_off, f.run))
+                .collect::<Vec<_>>(),
+            [("isr", 2, 200, 0), ("main", 2, 96, 1), ("idle", 1, 8, 1)]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Sampling intervals which alias with the period of the main loop. An interval close to
+//! a multiple of the loop period samples the same few points of the loop over and over
+//! and misses the stack peaks in between.
+//!
+//! The period is timed with the cycle counter between hits of the loop head, the core
+//! clock is counted while the core runs freely.
+
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use probe_rs::MemoryInterface;
+
+use crate::{
+    cpu,
+    findings::{Category, Finding, Severity},
+    mem_monitoring::RamSnapshotRecorder,
+    registers::DWTCtrl,
+    DynError,
+};
+
+/// Loop iterations the period is the median of.
+const LAPS: usize = 8;
+/// Longest loop iteration waited for.
+const LAP_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time the cycles of the running core are counted to get its clock.
+const CLOCK_WINDOW: Duration = Duration::from_millis(200);
+/// Samples falling on at most this many points of the loop alias.
+const MAX_PHASES: u32 = 4;
+/// Distance in loop periods from such a multiple which still aliases.
+const TOLERANCE: f64 = 0.05;
+/// Samples with the same pc as the previous sample from which on the recording hints
+/// at aliasing, checked without a cycle counter.
+const SAME_PC_SHARE: f32 = 0.8;
+const MIN_SAMPLES: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoopPeriod {
+    pub cycles: u32,
+    pub duration: Duration,
+}
+
+impl Display for LoopPeriod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({} cycles)", self.duration, self.cycles)
+    }
+}
+
+/// Period of the loop around `loop_head`, none without a cycle counter or if the loop
+/// head is not hit regularly. Runs the firmware for a few iterations and leaves the
+/// core halted.
+pub fn measure_loop_period(cpu: &mut cpu::CPU, loop_head: u32) -> DynError<Option<LoopPeriod>> {
+    if !cpu.has_cycle_counter() {
+        return Ok(None);
+    }
+    let hits = match cpu.cycles_at_hits(loop_head, LAPS + 1, LAP_TIMEOUT)? {
+        Some(hits) => hits,
+        None => return Ok(None),
+    };
+    let mut laps = hits
+        .windows(2)
+        .map(|w| w[1].wrapping_sub(w[0]))
+        .collect::<Vec<_>>();
+    laps.sort_unstable();
+    let cycles = laps[laps.len() / 2];
+
+    cpu.run()?;
+    let read_cycles = |cpu: &mut cpu::CPU| {
+        cpu.access_core(|core| core.read_word_32(DWTCtrl::CYCCNT_ADDRESS))
+            .map(|cycles| (cycles, Instant::now()))
+    };
+    let (start_cycles, start) = read_cycles(cpu)?;
+    std::thread::sleep(CLOCK_WINDOW);
+    let (end_cycles, end) = read_cycles(cpu)?;
+    cpu.halt()?;
+    let clock_hz = end_cycles.wrapping_sub(start_cycles) as f64 / (end - start).as_secs_f64();
+    if clock_hz <= 0.0 {
+        return Ok(None);
+    }
+
+    Ok(Some(LoopPeriod {
+        cycles,
+        duration: Duration::from_secs_f64(cycles as f64 / clock_hz),
+    }))
+}
+
+/// Sampling interval which falls on only a few points of the loop.
+#[derive(Debug, Clone)]
+pub struct Aliasing {
+    pub interval: Duration,
+    pub period: LoopPeriod,
+    /// Points of the loop the samples fall on.
+    pub phases: u32,
+    /// Closest interval in whole milliseconds which spreads the samples over the loop.
+    pub suggestion: Option<Duration>,
+}
+
+impl Display for Aliasing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sampling interval {:?} aliases with the main loop period {}, the samples fall on only {} point(s) of the loop and miss the stack peaks in between",
+            self.interval, self.period, self.phases
+        )?;
+        if let Some(suggestion) = self.suggestion {
+            write!(f, ", use --interval {}", suggestion.as_millis())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of loop points samples `running` apart fall on, if that are few.
+fn phases(running: Duration, period: Duration) -> Option<u32> {
+    let ratio = running.as_secs_f64() / period.as_secs_f64();
+    (1..=MAX_PHASES).find(|q| {
+        let laps = ratio * *q as f64;
+        laps.round() >= 1.0 && (laps - laps.round()).abs() < TOLERANCE
+    })
+}
+
+/// The core stands still while halted, between two samples the loop advances by the
+/// interval minus the halt of a sample.
+pub fn check(interval: Duration, sample_cost: Duration, period: LoopPeriod) -> Option<Aliasing> {
+    let running = |interval: Duration| interval.saturating_sub(sample_cost);
+    let phases = phases(running(interval), period.duration)?;
+    let interval_ms = interval.as_millis() as u64;
+    let suggestion = (1..=interval_ms)
+        .flat_map(|step| [interval_ms + step, interval_ms - step])
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis)
+        .find(|candidate| self::phases(running(*candidate), period.duration).is_none());
+
+    Some(Aliasing {
+        interval,
+        period,
+        phases,
+        suggestion,
+    })
+}
+
+/// Without the loop period aliasing shows as samples which keep landing on the same pc.
+pub fn repeated_pc_finding(recorder: &RamSnapshotRecorder) -> Option<Finding> {
+    let pcs = recorder.timeline().map(|s| s.instr_ptr).collect::<Vec<_>>();
+    if pcs.len() < MIN_SAMPLES {
+        return None;
+    }
+    let repeated = pcs.windows(2).filter(|w| w[0] == w[1]).count();
+    let share = repeated as f32 / (pcs.len() - 1) as f32;
+    if share < SAME_PC_SHARE {
+        return None;
+    }
+
+    Some(
+        Finding::new(
+            Severity::Info,
+            Category::Sampling,
+            format!(
+                "{:.0}% of the samples landed on the pc of the previous sample, the sampling interval may alias with the main loop period (or the firmware sleeps there), try a slightly different --interval",
+                share * 100.0
+            ),
+        )
+        .with_addresses(vec![pcs[pcs.len() - 1]]),
+    )
+}
@@ -0,0 +1,381 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_hex::{SerHex, StrictPfx};
+
+use crate::{
+    asm_parsing::AsmFile,
+    cpu, elf,
+    findings::{Category, Finding, Severity},
+    interrupt,
+    source::SourceResolver,
+    DynError,
+};
+
+/// Allocator entry points of a firmware.
+struct AllocatorSymbols {
+    alloc: &'static str,
+    dealloc: &'static str,
+    realloc: &'static str,
+    /// Argument holding the new size of a reallocation, after the old pointer.
+    realloc_size_arg: usize,
+}
+
+/// Allocators of rust (`__rust_realloc(ptr, old_size, align, new_size)`) and c firmwares.
+const ALLOCATORS: &[AllocatorSymbols] = &[
+    AllocatorSymbols {
+        alloc: "__rust_alloc",
+        dealloc: "__rust_dealloc",
+        realloc: "__rust_realloc",
+        realloc_size_arg: 3,
+    },
+    AllocatorSymbols {
+        alloc: "malloc",
+        dealloc: "free",
+        realloc: "realloc",
+        realloc_size_arg: 1,
+    },
+];
+/// Live allocations of one call site at the end of the run reported as possible leak.
+const LEAK_MIN_LIVE_ALLOCATIONS: usize = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Allocation {
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub ptr: u32,
+    pub size: u32,
+    /// Return address of the allocator call.
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub call_site: u32,
+}
+
+/// Aggregated allocations of one call site.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CallSiteStatistics {
+    pub allocation_count: usize,
+    pub total_bytes: u32,
+    pub live_bytes: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AllocationTracker {
+    live: HashMap<u32, Allocation>,
+    call_sites: HashMap<u32, CallSiteStatistics>,
+    allocation_count: usize,
+    free_count: usize,
+    #[serde(default)]
+    realloc_count: usize,
+    live_bytes: u32,
+    peak_live_bytes: u32,
+    live_bytes_course: Vec<u32>,
+}
+
+impl AllocationTracker {
+    fn insert(&mut self, allocation: Allocation) {
+        self.live_bytes += allocation.size;
+        self.peak_live_bytes = self.peak_live_bytes.max(self.live_bytes);
+
+        let call_site = self.call_sites.entry(allocation.call_site).or_default();
+        call_site.allocation_count += 1;
+        call_site.total_bytes += allocation.size;
+        call_site.live_bytes += allocation.size;
+
+        self.live.insert(allocation.ptr, allocation);
+    }
+
+    fn remove(&mut self, ptr: u32) -> Option<Allocation> {
+        let allocation = self.live.remove(&ptr)?;
+        self.live_bytes -= allocation.size;
+        if let Some(call_site) = self.call_sites.get_mut(&allocation.call_site) {
+            call_site.live_bytes -= allocation.size;
+        }
+
+        Some(allocation)
+    }
+
+    fn alloc(&mut self, allocation: Allocation) {
+        // allocation failed
+        if allocation.ptr == 0 {
+            return;
+        }
+        self.allocation_count += 1;
+        self.insert(allocation);
+        self.live_bytes_course.push(self.live_bytes);
+    }
+
+    fn free(&mut self, ptr: u32) {
+        if self.remove(ptr).is_some() {
+            self.free_count += 1;
+            self.live_bytes_course.push(self.live_bytes);
+        }
+    }
+
+    /// The block at `old_ptr` moved to or was resized in place to `allocation`, a
+    /// failed reallocation keeps the old block and one to size 0 frees it.
+    fn realloc(&mut self, old_ptr: u32, allocation: Allocation) {
+        if allocation.size == 0 {
+            self.free(old_ptr);
+            return;
+        }
+        if allocation.ptr == 0 {
+            return;
+        }
+        self.realloc_count += 1;
+        self.remove(old_ptr);
+        self.insert(allocation);
+        self.live_bytes_course.push(self.live_bytes);
+    }
+
+    /// Call sites with several allocations still live at the end of the run.
+    pub fn leak_findings(&self) -> Vec<Finding> {
+        let mut live_counts: HashMap<u32, usize> = HashMap::new();
+        for allocation in self.live.values() {
+            *live_counts.entry(allocation.call_site).or_default() += 1;
+        }
+
+        let mut findings = live_counts
+            .into_iter()
+            .filter(|(_, count)| *count >= LEAK_MIN_LIVE_ALLOCATIONS)
+            .collect::<Vec<_>>();
+        findings.sort_by_key(|finding| Reverse(finding.1));
+        findings
+            .into_iter()
+            .map(|(call_site, count)| {
+                Finding::new(
+                    Severity::Warning,
+                    Category::Leak,
+                    format!(
+                        "{} allocations from 0x{:08x} ({} bytes) were not freed, possible leak",
+                        count, call_site, self.call_sites[&call_site].live_bytes
+                    ),
+                )
+                .with_addresses(vec![call_site])
+            })
+            .collect()
+    }
+
+    /// Allocations which were not freed until the end of the run.
+    pub fn live_allocations(&self) -> impl Iterator<Item = &Allocation> {
+        self.live.values()
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "allocations: {}, reallocations: {}, frees: {}, live bytes: {}, peak live bytes: {}",
+            self.allocation_count,
+            self.realloc_count,
+            self.free_count,
+            self.live_bytes,
+            self.peak_live_bytes
+        );
+        for allocation in self.live_allocations() {
+            println!(
+                "not freed: {} bytes at 0x{:08x} allocated from 0x{:08x}",
+                allocation.size, allocation.ptr, allocation.call_site
+            );
+        }
+    }
+
+    /// Prints the call sites sorted by live bytes, resolved to function and source line.
+    pub fn print_call_site_report(
+        &self,
+        asm_file: Option<&AsmFile>,
+        resolver: Option<&SourceResolver>,
+    ) {
+        let mut call_sites = self.call_sites.iter().collect::<Vec<_>>();
+        call_sites.sort_by(|(_, a), (_, b)| {
+            b.live_bytes
+                .cmp(&a.live_bytes)
+                .then(b.total_bytes.cmp(&a.total_bytes))
+        });
+
+        println!("heap usage by allocation site:");
+        for (addr, statistics) in call_sites {
+            let function = asm_file
+                .and_then(|a| a.get_function_based_on_addr(addr))
+                .map(|f| f.name)
+                .unwrap_or_else(|| String::from("<unknown>"));
+            // the return address may already belong to the next source line
+            let location = resolver
+                .and_then(|r| r.locate(addr - 1))
+                .map(|l| format!("{}:{}", l.file, l.line))
+                .unwrap_or_default();
+            println!(
+                "  0x{:08x} {} {}: live bytes: {}, allocations: {}, total bytes: {}",
+                addr,
+                function,
+                location,
+                statistics.live_bytes,
+                statistics.allocation_count,
+                statistics.total_bytes
+            );
+        }
+    }
+}
+
+/// Entry addresses of the allocator functions found in the obj file.
+struct AllocatorFunctions {
+    alloc: u32,
+    dealloc: u32,
+    realloc: Option<u32>,
+    realloc_size_arg: usize,
+}
+
+impl AllocatorFunctions {
+    fn find(obj_file: &object::File) -> Option<Self> {
+        ALLOCATORS.iter().find_map(|symbols| {
+            Some(Self {
+                alloc: elf::find_symbol(obj_file, symbols.alloc)? & !1,
+                dealloc: elf::find_symbol(obj_file, symbols.dealloc)? & !1,
+                realloc: elf::find_symbol(obj_file, symbols.realloc).map(|addr| addr & !1),
+                realloc_size_arg: symbols.realloc_size_arg,
+            })
+        })
+    }
+
+    fn entries(&self) -> Vec<u32> {
+        let mut entries = vec![self.alloc, self.dealloc];
+        entries.extend(self.realloc);
+        entries
+    }
+}
+
+enum Call {
+    Alloc { size: u32 },
+    Realloc { old_ptr: u32, size: u32 },
+}
+
+/// Allocator call which did not return yet. Interrupts may allocate while it runs, so
+/// several calls can be pending, their return is told apart by the stack pointer.
+struct PendingCall {
+    call: Call,
+    return_addr: u32,
+    stack_ptr: u32,
+}
+
+/// Tracks all allocations by halting at the allocator functions of the firmware
+/// until `duration` elapsed. The return of an allocator call is caught by a breakpoint
+/// at its return address, set only while the call is pending. All breakpoints are
+/// removed again, also if tracking fails.
+pub fn track_allocations(
+    cpu: &mut cpu::CPU,
+    obj_file: &object::File,
+    duration: Duration,
+) -> DynError<AllocationTracker> {
+    let functions =
+        AllocatorFunctions::find(obj_file).ok_or("no allocator functions found in obj file")?;
+
+    let mut tracker = AllocationTracker::default();
+    let mut pending = Vec::new();
+    let tracked = track(cpu, &functions, duration, &mut tracker, &mut pending);
+
+    let mut breakpoints = functions.entries();
+    breakpoints.extend(pending.iter().map(|p| p.return_addr));
+    breakpoints.sort_unstable();
+    breakpoints.dedup();
+    let cleared = cpu.access_core(|core| {
+        for addr in breakpoints.iter() {
+            core.clear_hw_breakpoint(*addr)?;
+        }
+
+        Ok(())
+    });
+    tracked?;
+    cleared?;
+
+    Ok(tracker)
+}
+
+fn track(
+    cpu: &mut cpu::CPU,
+    functions: &AllocatorFunctions,
+    duration: Duration,
+    tracker: &mut AllocationTracker,
+    pending: &mut Vec<PendingCall>,
+) -> DynError<()> {
+    cpu.access_core(|core| {
+        for addr in functions.entries() {
+            core.set_hw_breakpoint(addr)?;
+        }
+
+        Ok(())
+    })?;
+    cpu.run()?;
+
+    let start = Instant::now();
+    while Instant::now() - start < duration && !interrupt::interrupted() {
+        if !cpu.wait_for_halt(Duration::from_millis(100))? {
+            continue;
+        }
+
+        let (pc, stack_ptr, args, result, return_addr) = cpu.access_core(|core| {
+            let registers = core.registers();
+            let mut args = [0u32; 4];
+            for (index, arg) in args.iter_mut().enumerate() {
+                *arg = core.read_core_reg(registers.argument_register(index))?;
+            }
+            Ok((
+                core.read_core_reg(registers.program_counter())?,
+                core.read_core_reg(registers.stack_pointer())?,
+                args,
+                core.read_core_reg(registers.result_register(0))?,
+                core.read_core_reg(registers.return_address())? & !1,
+            ))
+        })?;
+
+        let call = if pc == functions.alloc {
+            Some(Call::Alloc { size: args[0] })
+        } else if Some(pc) == functions.realloc {
+            Some(Call::Realloc {
+                old_ptr: args[0],
+                size: args[functions.realloc_size_arg],
+            })
+        } else {
+            None
+        };
+
+        if let Some(call) = call {
+            // a nested call of the same site shares the breakpoint
+            if !pending.iter().any(|p| p.return_addr == return_addr) {
+                cpu.access_core(|core| core.set_hw_breakpoint(return_addr))?;
+            }
+            pending.push(PendingCall {
+                call,
+                return_addr,
+                stack_ptr,
+            });
+            cpu.step_over_breakpoint(pc)?;
+        } else if pc == functions.dealloc {
+            tracker.free(args[0]);
+            cpu.step_over_breakpoint(pc)?;
+        } else if let Some(index) = pending
+            .iter()
+            .rposition(|p| p.return_addr == pc && p.stack_ptr == stack_ptr)
+        {
+            let returned = pending.remove(index);
+            match pending.iter().any(|p| p.return_addr == pc) {
+                true => cpu.step_over_breakpoint(pc)?,
+                false => cpu.access_core(|core| core.clear_hw_breakpoint(pc))?,
+            }
+            let allocation = |size| Allocation {
+                ptr: result,
+                size,
+                call_site: pc,
+            };
+            match returned.call {
+                Call::Alloc { size } => tracker.alloc(allocation(size)),
+                Call::Realloc { old_ptr, size } => tracker.realloc(old_ptr, allocation(size)),
+            }
+        } else if pending.iter().any(|p| p.return_addr == pc) {
+            // the return address of a pending call reached on another path
+            cpu.step_over_breakpoint(pc)?;
+        }
+        cpu.run()?;
+    }
+
+    Ok(())
+}
@@ -1,7 +1,11 @@
+use capstone::{arch, prelude::*};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
 use regex::Regex;
-use std::{fs::File, io::BufRead, num::ParseIntError, ops::Range, path::Path, time::Instant};
+use std::{fs::File, io::BufRead, num::ParseIntError, ops::Range, path::Path};
 use thiserror::Error;
 
+use crate::demangle::{demangle, Language};
+
 #[derive(Error, Debug)]
 pub enum AsmError {
     #[error("could not open asm file")]
@@ -10,6 +14,14 @@ pub enum AsmError {
     LineParseError { line: usize, source: std::io::Error },
     #[error("failed parsing addr {0}")]
     AddrParseError(String, ParseIntError),
+    #[error("failed reading code from obj file")]
+    ObjError(#[from] object::Error),
+    #[error("failed disassembling obj file")]
+    DisassemblyError(capstone::Error),
+    #[error("asm file does not match obj file, no function starts at entry point 0x{0:08x}")]
+    EntryPointMismatch(u32),
+    #[error("disassembling {0:?} obj files is not supported, pass an objdump file instead")]
+    UnsupportedArchitecture(object::Architecture),
 }
 
 #[derive(Debug, Clone)]
@@ -18,24 +30,282 @@ pub enum Instruction {
     Branch { dest: String },
 }
 
+impl Instruction {
+    /// Bytes the instruction reserves on the stack, for objdump and capstone syntax.
+    pub fn stack_growth(&self) -> u32 {
+        let text = match self {
+            Instruction::Any(text) => text,
+            Instruction::Branch { .. } => return 0,
+        };
+        // drop objdump comments like `; 0x200`
+        let text = text.split(';').next().unwrap_or("");
+        let mut parts = text.split('\t').map(str::trim);
+        let mnemonic = match parts.find(|p| STACK_MNEMONICS.contains(&strip_width_suffix(p))) {
+            Some(mnemonic) => strip_width_suffix(mnemonic),
+            None => return 0,
+        };
+        let operands = parts.next().unwrap_or("");
+
+        match mnemonic {
+            "push" | "vpush" => register_list_size(operands),
+            // risc-v prologue `addi sp,sp,-32`
+            "addi" => operands
+                .strip_prefix("sp,sp,-")
+                .and_then(parse_immediate)
+                .unwrap_or(0),
+            "stmdb" | "stmfd" if operands.starts_with("sp!") => register_list_size(operands),
+            "sub" | "subw" if operands.starts_with("sp,") => operands
+                .rsplit('#')
+                .next()
+                .and_then(parse_immediate)
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Whether the instruction calls a function through a register.
+    pub fn is_indirect_call(&self) -> bool {
+        match self {
+            Instruction::Any(text) => {
+                let mut parts = text.split('\t').map(str::trim);
+                match parts.find(|p| *p == "blx" || *p == "blx.n" || *p == "jalr") {
+                    // `jalr zero,..` is an indirect jump, objdump prints it as `jr`
+                    Some("jalr") => !parts.next().unwrap_or("").starts_with("zero,"),
+                    Some(_) => !parts.next().unwrap_or("").starts_with('#'),
+                    None => false,
+                }
+            }
+            Instruction::Branch { .. } => false,
+        }
+    }
+
+    /// Target of a local branch like `b.n 8000123 <main+0x12>` or `bne #0x8000123`, calls
+    /// have no target.
+    pub fn branch_target(&self) -> Option<u32> {
+        let text = match self {
+            Instruction::Any(text) => text,
+            Instruction::Branch { .. } => return None,
+        };
+        let mut parts = text.split('\t').map(str::trim);
+        parts.find(|p| BRANCH_MNEMONICS.contains(&strip_width_suffix(p)))?;
+        let target = parts.next()?.split('<').next()?.rsplit(',').next()?.trim();
+        let target = target.trim_start_matches('#').trim_start_matches("0x");
+
+        u32::from_str_radix(target, 16).ok()
+    }
+}
+
+/// Mnemonics of local branches of arm and risc-v.
+const BRANCH_MNEMONICS: &[&str] = &[
+    "b", "beq", "bne", "bcs", "bhs", "bcc", "blo", "bmi", "bpl", "bvs", "bvc", "bhi", "bls", "bge",
+    "blt", "bgt", "ble", "cbz", "cbnz", "j", "bltu", "bgeu", "beqz", "bnez",
+];
+
+/// Functions holding the main loop of arduino, cortex-m-rt and C firmwares, in the order
+/// they are looked for. cortex-m-rt's `main` only calls `__cortex_m_rt_main`.
+const MAIN_LOOP_FUNCTIONS: &[&str] = &["loop", "__cortex_m_rt_main", "main"];
+
+/// Mnemonics which reserve stack memory.
+const STACK_MNEMONICS: &[&str] = &["push", "vpush", "stmdb", "stmfd", "sub", "subw", "addi"];
+
+fn strip_width_suffix(mnemonic: &str) -> &str {
+    mnemonic.trim_end_matches(".w").trim_end_matches(".n")
+}
+
+fn parse_immediate(text: &str) -> Option<u32> {
+    let text = text.trim();
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Size of a register list like `{r4, r5, r7, lr}` or `{d8-d15}`.
+fn register_list_size(operands: &str) -> u32 {
+    let list = match (operands.find('{'), operands.find('}')) {
+        (Some(start), Some(end)) if start < end => &operands[start + 1..end],
+        _ => return 0,
+    };
+
+    list.split(',')
+        .map(str::trim)
+        .map(|register| {
+            let register_size = if register.starts_with('d') { 8 } else { 4 };
+            let count = match register.split_once('-') {
+                Some((first, last)) => {
+                    let number = |r: &str| r.trim_start_matches(char::is_alphabetic).parse::<u32>();
+                    match (number(first), number(last)) {
+                        (Ok(first), Ok(last)) if first <= last => last - first + 1,
+                        _ => 1,
+                    }
+                }
+                None => 1,
+            };
+            count * register_size
+        })
+        .sum()
+}
+
 #[derive(Debug, Clone)]
 pub struct Function {
+    /// Demangled for display, different monomorphizations of rust functions may share it.
     pub name: String,
+    /// Link name of the function, unique and the name branch destinations refer to.
+    pub symbol: String,
     pub range: Range<u32>,
     pub instructions: Vec<(u32, Instruction)>,
 }
 
+impl Function {
+    /// Stack reserved by all pushes and sp adjustments of the function. Conservative
+    /// if different paths of the function reserve different amounts.
+    pub fn frame_size(&self) -> u32 {
+        self.instructions
+            .iter()
+            .map(|(_, instr)| instr.stack_growth())
+            .sum()
+    }
+
+    /// Head of the outermost loop, the target of the backward branch spanning the most
+    /// instructions.
+    pub fn outermost_loop_head(&self) -> Option<u32> {
+        self.instructions
+            .iter()
+            .filter_map(|(addr, instr)| Some((*addr, instr.branch_target()?)))
+            .filter(|(addr, target)| self.range.start <= *target && target <= addr)
+            .max_by_key(|(addr, target)| addr - target)
+            .map(|(_, target)| target)
+    }
+}
+
 #[derive(Debug)]
 pub struct AsmFile {
     functions: Vec<Function>,
 }
 
 impl AsmFile {
+    /// Parses the objdump file at `path` if given, else disassembles `obj_file`, names
+    /// are demangled for `language`.
+    pub fn load(
+        obj_file: &object::File,
+        path: Option<&Path>,
+        language: Language,
+    ) -> Result<Self, AsmError> {
+        let mut asm_file = match path {
+            Some(path) => {
+                let asm_file = AsmFile::from_file(path)?;
+                asm_file.validate_against_elf(obj_file)?;
+                asm_file
+            }
+            None => AsmFile::from_elf(obj_file)?,
+        };
+        asm_file.demangle_names(language);
+
+        Ok(asm_file)
+    }
+
     pub fn from_file(path: &Path) -> Result<Self, AsmError> {
         let file = load_file(path)?;
         parse_asm_file(&file)
     }
 
+    /// Disassembles all function symbols of the obj file.
+    pub fn from_elf(obj_file: &object::File) -> Result<Self, AsmError> {
+        if obj_file.architecture() != object::Architecture::Arm {
+            return Err(AsmError::UnsupportedArchitecture(obj_file.architecture()));
+        }
+
+        let cs = Capstone::new()
+            .arm()
+            .mode(arch::arm::ArchMode::Thumb)
+            .extra_mode(std::iter::once(arch::arm::ArchExtraMode::MClass))
+            .build()
+            .map_err(AsmError::DisassemblyError)?;
+
+        let mut symbols = obj_file
+            .symbols()
+            .filter(|s| s.kind() == SymbolKind::Text && s.size() > 0)
+            .filter_map(|s| {
+                Some((
+                    s.name().ok()?,
+                    s.address() as u32 & !1,
+                    s.size() as u32,
+                    s.section_index()?,
+                ))
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|(_, addr, _, _)| *addr);
+        symbols.dedup_by_key(|(_, addr, _, _)| *addr);
+
+        let mut functions = Vec::new();
+        for (name, addr, size, section_index) in symbols.iter() {
+            let section = obj_file.section_by_index(*section_index)?;
+            let code = match section.data_range(*addr as u64, *size as u64)? {
+                Some(code) => code,
+                None => continue,
+            };
+
+            let disassembly = cs
+                .disasm_all(code, *addr as u64)
+                .map_err(AsmError::DisassemblyError)?;
+            let instructions = disassembly
+                .iter()
+                .map(|i| {
+                    let mnemonic = i.mnemonic().unwrap_or("");
+                    let op_str = i.op_str().unwrap_or("");
+                    let dest = match mnemonic {
+                        "bl" | "blx" => u32::from_str_radix(op_str.trim_start_matches("#0x"), 16)
+                            .ok()
+                            .and_then(|dest| symbols.iter().find(|(_, a, _, _)| *a == dest)),
+                        _ => None,
+                    };
+                    let instruction = match dest {
+                        Some((dest, _, _, _)) => Instruction::Branch {
+                            dest: String::from(*dest),
+                        },
+                        None => Instruction::Any(format!("{}\t{}", mnemonic, op_str)),
+                    };
+                    (i.address() as u32, instruction)
+                })
+                .collect::<Vec<_>>();
+
+            functions.push(Function {
+                name: String::from(*name),
+                symbol: String::from(*name),
+                range: *addr..*addr + *size,
+                instructions,
+            });
+        }
+
+        Ok(Self { functions })
+    }
+
+    /// Checks that the asm file was generated from the given obj file.
+    pub fn validate_against_elf(&self, obj_file: &object::File) -> Result<(), AsmError> {
+        let entry = obj_file.entry() as u32 & !1;
+        let entry_symbol = obj_file
+            .symbols()
+            .find(|s| s.kind() == SymbolKind::Text && s.address() as u32 & !1 == entry)
+            .and_then(|s| s.name().ok().map(String::from));
+
+        match self.functions.iter().find(|f| f.range.start == entry) {
+            Some(f) if entry_symbol.map(|n| n == f.symbol).unwrap_or(true) => Ok(()),
+            _ => Err(AsmError::EntryPointMismatch(entry)),
+        }
+    }
+
+    /// Replaces the mangled function names by their demangled form, the symbols and
+    /// branch destinations keep the link names.
+    pub fn demangle_names(&mut self, language: Language) {
+        for function in self.functions.iter_mut() {
+            function.name = demangle(&function.symbol, language).into_owned();
+        }
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
     pub fn get_function_based_on_addr(&self, addr: &u32) -> Option<Function> {
         self.functions
             .iter()
@@ -43,36 +313,27 @@ impl AsmFile {
             .map(|f| f.to_owned())
     }
 
-    pub fn get_subfunctions_of_function(&self, function: &str) -> Option<Vec<Function>> {
-        let mut functions = Vec::<Function>::new();
-        let function = self.functions.iter().find(|f| f.name.eq(function));
-        let function = match function {
-            None => return None,
-            Some(f) => f,
-        };
-        for (addr, instr) in function.instructions.iter() {
-            match instr {
-                Instruction::Branch { dest } => {
-                    if let None = functions.iter().find(|f| f.name.eq(dest.as_str())) {
-                        functions.push(
-                            self.functions
-                                .iter()
-                                .find(|f| f.name.eq(dest.as_str()))
-                                .unwrap()
-                                .to_owned(),
-                        );
-                    }
-                }
-                _ => (),
-            }
-        }
+    pub fn get_function_by_name(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|f| f.name == name)
+    }
 
-        Some(functions)
+    /// Function and head of the main loop, found by the outermost loop of the first
+    /// main loop function which has one. Demangled names match by their last segment.
+    pub fn main_loop(&self) -> Option<(&str, u32)> {
+        MAIN_LOOP_FUNCTIONS.iter().find_map(|candidate| {
+            self.functions
+                .iter()
+                .filter(|f| {
+                    let name = f.name.trim_end_matches("()");
+                    name == *candidate || name.ends_with(&format!("::{}", candidate))
+                })
+                .find_map(|f| Some((f.name.as_str(), f.outermost_loop_head()?)))
+        })
     }
 }
 
 fn load_file(path: &Path) -> Result<File, AsmError> {
-    std::fs::File::open(path).map_err(|e| AsmError::FailedOpeningAsmFile(e))
+    std::fs::File::open(path).map_err(AsmError::FailedOpeningAsmFile)
 }
 
 struct FunctionHeader {
@@ -91,7 +352,13 @@ impl FunctionHeader {
     }
     fn complete(self) -> Function {
         Function {
-            range: self.start_addr..self.instructions.last().unwrap().0 + 1,
+            symbol: self.name.to_owned(),
+            range: self.start_addr
+                ..self
+                    .instructions
+                    .last()
+                    .map(|(addr, _)| addr + 1)
+                    .unwrap_or(self.start_addr),
             name: self.name,
             instructions: self.instructions,
         }
@@ -107,6 +374,9 @@ fn parse_asm_file(file: &File) -> Result<AsmFile, AsmError> {
     let function_heading = Regex::new(r"(?P<addr>[\d\w]+) <(?P<func_name>[\s\S]+)>:").unwrap();
     let instruction_line = Regex::new(r" (?P<addr>[\d\w]+):	(?P<instr_line>[\s\S]*)").unwrap();
     let instruction_bl = Regex::new(r"[\s\S]+	bl[\s\S]+<(?P<func_name>[\s\S]+)>").unwrap();
+    // risc-v calls link through ra, `jal ra,<dest>` or `auipc ra` followed by `jalr`
+    let instruction_jal =
+        Regex::new(r"[\s\S]+	jalr?	(ra,)?[\s\S]*<(?P<func_name>[\s\S]+)>").unwrap();
 
     let mut actual_function: Option<FunctionHeader> = None;
     for (index, l) in buf_reader.enumerate() {
@@ -131,7 +401,10 @@ fn parse_asm_file(file: &File) -> Result<AsmFile, AsmError> {
             let instr_addr = &captures["addr"];
             let instr_addr = u32::from_str_radix(instr_addr, 16)
                 .map_err(|e| AsmError::AddrParseError(String::from(instr_addr), e))?;
-            let instruction = if let Some(captures) = instruction_bl.captures(instr_line) {
+            let instruction = if let Some(captures) = instruction_bl
+                .captures(instr_line)
+                .or_else(|| instruction_jal.captures(instr_line))
+            {
                 let dest_func = &captures["func_name"];
                 Instruction::Branch {
                     dest: String::from(dest_func),
@@ -0,0 +1,266 @@
+//! Binary search over a sequence of firmware builds for the first build whose max stack
+//! ptr offset exceeds a threshold, to find the change which grew the stack on hardware.
+//!
+//! Each build is measured by a `measure` run of this executable, so it takes the same
+//! options and leaves a recording of every measured build behind.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+};
+
+use thiserror::Error;
+
+use crate::{session, statistics::StatisticsEngine, DynError};
+
+#[derive(Error, Debug)]
+pub enum BisectError {
+    #[error("bisecting needs at least two builds, found {0}")]
+    TooFewBuilds(usize),
+    #[error("invalid revision range {0}, expected `<GOOD>..<BAD>`")]
+    InvalidRange(String),
+    #[error("the first build {build} already exceeds the threshold with {bytes} bytes")]
+    FirstExceeds { build: String, bytes: u32 },
+    #[error("the last build {build} stays within the threshold with {bytes} bytes")]
+    LastWithin { build: String, bytes: u32 },
+    #[error("command `{0}` failed with {1}")]
+    CommandFailed(String, ExitStatus),
+    #[error("measuring {0} wrote no records")]
+    NoRecords(String),
+}
+
+/// A firmware file, or a revision built by the build command.
+#[derive(Debug, Clone)]
+pub enum Build {
+    Artifact(PathBuf),
+    Revision(String),
+}
+
+impl Display for Build {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Build::Artifact(path) => write!(f, "{}", path.display()),
+            Build::Revision(rev) => write!(f, "{}", rev),
+        }
+    }
+}
+
+/// Files in `dir` in the order of their names, builds named by build number or date
+/// sort chronologically.
+pub fn builds_in(dir: &Path) -> DynError<Vec<Build>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|p| p.is_file());
+    paths.sort();
+
+    Ok(paths.into_iter().map(Build::Artifact).collect())
+}
+
+/// `GOOD` followed by the commits of `GOOD..BAD` from oldest to newest.
+pub fn revisions(range: &str) -> DynError<Vec<Build>> {
+    let (good, _) = range
+        .split_once("..")
+        .filter(|(good, bad)| !good.is_empty() && !bad.is_empty())
+        .ok_or_else(|| BisectError::InvalidRange(String::from(range)))?;
+    let output = Command::new("git")
+        .args(["rev-list", "--reverse", "--first-parent", range])
+        .output()?;
+    if !output.status.success() {
+        return Err(
+            BisectError::CommandFailed(format!("git rev-list {}", range), output.status).into(),
+        );
+    }
+
+    Ok(std::iter::once(String::from(good))
+        .chain(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(String::from),
+        )
+        .map(Build::Revision)
+        .collect())
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+pub struct Bisector {
+    /// Shell command building a revision, `{rev}` is replaced with the revision.
+    pub build_command: Option<String>,
+    /// Firmware the build command produces.
+    pub artifact: PathBuf,
+    /// Options passed on to `measure`.
+    pub measure_args: Vec<String>,
+    /// Directory the recordings of the measured builds are written to.
+    pub record_dir: PathBuf,
+}
+
+impl Bisector {
+    fn firmware(&self, build: &Build) -> DynError<PathBuf> {
+        match build {
+            Build::Artifact(path) => Ok(path.to_owned()),
+            Build::Revision(rev) => {
+                if let Some(command) = self.build_command.as_ref() {
+                    let command = command.replace("{rev}", rev);
+                    let status = shell(&command).status()?;
+                    if !status.success() {
+                        return Err(BisectError::CommandFailed(command, status).into());
+                    }
+                }
+                Ok(self.artifact.to_owned())
+            }
+        }
+    }
+
+    /// Max stack ptr offset of a `measure` run of the build. Failed runs like exceeded
+    /// budgets still count as long as they recorded something.
+    pub fn measure(&self, index: usize, build: &Build) -> DynError<u32> {
+        let firmware = self.firmware(build)?;
+        std::fs::create_dir_all(&self.record_dir)?;
+        let record_path = self.record_dir.join(format!("build-{:03}.json", index));
+        if record_path.exists() {
+            std::fs::remove_file(&record_path)?;
+        }
+        Command::new(std::env::current_exe()?)
+            .arg("measure")
+            .arg("-f")
+            .arg(&firmware)
+            .arg("--output")
+            .arg(&record_path)
+            .args(["--force", "--no-history", "--non-interactive"])
+            .args(&self.measure_args)
+            .status()?;
+        if !record_path.exists() {
+            return Err(BisectError::NoRecords(build.to_string()).into());
+        }
+        let recorder = session::load_recording(&record_path)?;
+        if recorder.is_empty() {
+            return Err(BisectError::NoRecords(build.to_string()).into());
+        }
+
+        Ok(StatisticsEngine::new(&recorder)
+            .calculate()
+            .max_stack_ptr_off)
+    }
+
+    /// Index of the first build above `threshold` bytes, see [`first_exceeding`].
+    pub fn run(&self, builds: &[Build], threshold: u32) -> DynError<usize> {
+        first_exceeding(builds, threshold, |index| {
+            let bytes = self.measure(index, &builds[index])?;
+            println!(
+                "[{}/{}] {}: max stack ptr offset {} bytes, {}",
+                index + 1,
+                builds.len(),
+                builds[index],
+                bytes,
+                if bytes > threshold { "bad" } else { "good" }
+            );
+            Ok(bytes)
+        })
+    }
+}
+
+/// Index of the first build whose `measure`d bytes are above `threshold`, assuming the
+/// builds stay above it once they exceeded it. The first and the last build are
+/// measured first to check that they enclose the regression.
+fn first_exceeding(
+    builds: &[Build],
+    threshold: u32,
+    mut measure: impl FnMut(usize) -> DynError<u32>,
+) -> DynError<usize> {
+    if builds.len() < 2 {
+        return Err(BisectError::TooFewBuilds(builds.len()).into());
+    }
+
+    let (mut good, mut bad) = (0, builds.len() - 1);
+    let bytes = measure(good)?;
+    if bytes > threshold {
+        return Err(BisectError::FirstExceeds {
+            build: builds[good].to_string(),
+            bytes,
+        }
+        .into());
+    }
+    let bytes = measure(bad)?;
+    if bytes <= threshold {
+        return Err(BisectError::LastWithin {
+            build: builds[bad].to_string(),
+            bytes,
+        }
+        .into());
+    }
+    while bad - good > 1 {
+        let mid = good + (bad - good) / 2;
+        if measure(mid)? > threshold {
+            bad = mid;
+        } else {
+            good = mid;
+        }
+    }
+
+    Ok(bad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revisions(count: usize) -> Vec<Build> {
+        (0..count).map(|i| Build::Revision(i.to_string())).collect()
+    }
+
+    #[test]
+    fn finds_the_first_build_above_the_threshold() {
+        let bytes = [100, 100, 100, 104, 120, 150, 150, 160];
+        for threshold in [100, 110, 150] {
+            let mut measured = Vec::new();
+            let first = first_exceeding(&revisions(bytes.len()), threshold, |index| {
+                measured.push(index);
+                Ok(bytes[index])
+            })
+            .unwrap();
+
+            assert_eq!(
+                first,
+                bytes.iter().position(|b| *b > threshold).unwrap(),
+                "threshold {}",
+                threshold
+            );
+            // first and last plus the binary search
+            assert!(measured.len() <= 2 + 3, "measured {:?}", measured);
+        }
+    }
+
+    #[test]
+    fn builds_not_enclosing_the_regression_are_rejected() {
+        let builds = revisions(4);
+        let error = first_exceeding(&builds, 100, |_| Ok(120)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(BisectError::FirstExceeds { bytes: 120, .. })
+        ));
+
+        let error = first_exceeding(&builds, 100, |_| Ok(80)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(BisectError::LastWithin { bytes: 80, .. })
+        ));
+
+        let error = first_exceeding(&builds[..1], 100, |_| Ok(80)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(BisectError::TooFewBuilds(1))
+        ));
+    }
+}
@@ -0,0 +1,113 @@
+//! Stack budgets per function, checked against the maxima of a run.
+//!
+//! Every line of a budget file has the form `pattern bytes`, the pattern is a function
+//! name where `*` matches any characters, e.g. `app::net::* 1024`. The first matching
+//! line applies to a function, `#` starts a comment.
+
+use std::{io::BufRead, num::ParseIntError, path::Path};
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::mem_monitoring::FunctionStatistics;
+
+#[derive(Error, Debug)]
+pub enum BudgetError {
+    #[error("could not read budget file")]
+    FailedReadingFile(#[from] std::io::Error),
+    #[error("malformed line {line} in budget file: {content}")]
+    MalformedLine { line: usize, content: String },
+    #[error("failed parsing budget in line {line}")]
+    SizeParseError { line: usize, source: ParseIntError },
+}
+
+struct FunctionBudget {
+    pattern: String,
+    regex: Regex,
+    bytes: u32,
+}
+
+/// Maximum stack ptr offset of a function above its budget.
+#[derive(Debug)]
+pub struct FunctionBudgetViolation {
+    pub function: String,
+    pub pattern: String,
+    pub limit: u32,
+    pub measured: u32,
+}
+
+impl std::fmt::Display for FunctionBudgetViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stack in {} {} bytes exceeds {} bytes of {}",
+            self.function, self.measured, self.limit, self.pattern
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct FunctionBudgets {
+    budgets: Vec<FunctionBudget>,
+}
+
+impl FunctionBudgets {
+    pub fn from_file(path: &Path) -> Result<Self, BudgetError> {
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut budgets = Self::default();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line_number = index + 1;
+            let content = line.split('#').next().unwrap().trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            let (pattern, bytes) = content.rsplit_once(char::is_whitespace).ok_or_else(|| {
+                BudgetError::MalformedLine {
+                    line: line_number,
+                    content: line.to_owned(),
+                }
+            })?;
+            let pattern = pattern.trim();
+            let regex = format!(
+                "^{}$",
+                pattern
+                    .split('*')
+                    .map(regex::escape)
+                    .collect::<Vec<_>>()
+                    .join(".*")
+            );
+            budgets.budgets.push(FunctionBudget {
+                pattern: String::from(pattern),
+                regex: Regex::new(&regex).unwrap(),
+                bytes: bytes.parse().map_err(|e| BudgetError::SizeParseError {
+                    line: line_number,
+                    source: e,
+                })?,
+            });
+        }
+
+        Ok(budgets)
+    }
+
+    /// Functions whose maximum stack ptr offset exceeds the budget of their first
+    /// matching pattern.
+    pub fn check(&self, functions: &[FunctionStatistics]) -> Vec<FunctionBudgetViolation> {
+        functions
+            .iter()
+            .filter_map(|function| {
+                let budget = self
+                    .budgets
+                    .iter()
+                    .find(|b| b.regex.is_match(&function.function))?;
+                (function.max_stack_ptr_off > budget.bytes).then(|| FunctionBudgetViolation {
+                    function: function.function.to_owned(),
+                    pattern: budget.pattern.to_owned(),
+                    limit: budget.bytes,
+                    measured: function.max_stack_ptr_off,
+                })
+            })
+            .collect()
+    }
+}
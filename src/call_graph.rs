@@ -0,0 +1,271 @@
+//! Static worst case stack depth from the call graph of the asm file.
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use crate::{
+    asm_parsing::{AsmFile, Instruction},
+    stack_usage::FrameSizes,
+};
+
+/// Deepest call chain starting at an entry point.
+#[derive(Debug, Clone)]
+pub struct StackDepth {
+    pub entry: String,
+    pub bytes: u32,
+    pub path: Vec<String>,
+    /// The chain contains recursion, `bytes` covers only one iteration.
+    pub recursive: bool,
+    /// Functions on the chain call through registers, these callees are not included.
+    pub indirect_calls: bool,
+    /// Functions on the chain have frames which grow at runtime.
+    pub dynamic_frames: bool,
+}
+
+impl StackDepth {
+    pub fn is_lower_bound(&self) -> bool {
+        self.recursive || self.indirect_calls || self.dynamic_frames
+    }
+}
+
+struct Node {
+    frame_size: u32,
+    callees: Vec<usize>,
+    indirect_calls: bool,
+    dynamic_frame: bool,
+}
+
+enum Visit {
+    InProgress,
+    Done(Depth),
+}
+
+#[derive(Clone)]
+struct Depth {
+    bytes: u32,
+    path: Vec<usize>,
+    recursive: bool,
+    indirect_calls: bool,
+    dynamic_frames: bool,
+}
+
+pub struct CallGraph<'a> {
+    asm_file: &'a AsmFile,
+    nodes: Vec<Node>,
+}
+
+impl<'a> CallGraph<'a> {
+    /// Uses the compiler reported frame sizes where available, else the frame sizes
+    /// derived from the disassembly. Functions are told apart by their symbol, the
+    /// demangled names of monomorphizations collide.
+    pub fn new(asm_file: &'a AsmFile, frame_sizes: &FrameSizes) -> Self {
+        let functions = asm_file.functions();
+        let indices = functions
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.symbol.as_str(), i))
+            .collect::<HashMap<_, _>>();
+
+        let nodes = functions
+            .iter()
+            .map(|f| {
+                let mut callees = f
+                    .instructions
+                    .iter()
+                    .filter_map(|(_, instr)| match instr {
+                        Instruction::Branch { dest } => indices.get(dest.as_str()).copied(),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                callees.sort_unstable();
+                callees.dedup();
+
+                // su files name functions like their demangled form
+                let compiler_frame = frame_sizes.get(&f.name);
+                Node {
+                    frame_size: compiler_frame
+                        .map(|s| s.bytes)
+                        .unwrap_or_else(|| f.frame_size()),
+                    callees,
+                    indirect_calls: f.instructions.iter().any(|(_, i)| i.is_indirect_call()),
+                    dynamic_frame: compiler_frame.map(|s| s.dynamic).unwrap_or(false),
+                }
+            })
+            .collect();
+
+        Self { asm_file, nodes }
+    }
+
+    /// Functions no other function calls directly, like the reset and interrupt handlers.
+    fn entry_points(&self) -> Vec<usize> {
+        let mut called = vec![false; self.nodes.len()];
+        for node in self.nodes.iter() {
+            for callee in node.callees.iter() {
+                called[*callee] = true;
+            }
+        }
+
+        (0..self.nodes.len()).filter(|i| !called[*i]).collect()
+    }
+
+    /// Worst case stack depth per entry point, deepest first.
+    pub fn worst_case_stack(&self) -> Vec<StackDepth> {
+        let mut visits = HashMap::new();
+        let mut depths = self
+            .entry_points()
+            .into_iter()
+            .map(|entry| {
+                let depth = self.depth(entry, &mut visits);
+                self.to_stack_depth(entry, depth)
+            })
+            .collect::<Vec<_>>();
+        depths.sort_by_key(|depth| Reverse(depth.bytes));
+
+        depths
+    }
+
+    /// Worst case stack depth of the chains starting at the function at `addr`.
+    pub fn worst_case_stack_at(&self, addr: u32) -> Option<StackDepth> {
+        let entry = self
+            .asm_file
+            .functions()
+            .iter()
+            .position(|f| f.range.start == addr)?;
+        let depth = self.depth(entry, &mut HashMap::new());
+
+        Some(self.to_stack_depth(entry, depth))
+    }
+
+    /// Symbol of the function containing `addr`.
+    pub fn symbol_at(&self, addr: u32) -> Option<&'a str> {
+        self.asm_file
+            .functions()
+            .iter()
+            .find(|f| f.range.contains(&addr))
+            .map(|f| f.symbol.as_str())
+    }
+
+    /// Name, start address and frame size of every function.
+    pub fn frames(&self) -> impl Iterator<Item = (&'a str, u32, u32)> + '_ {
+        self.asm_file
+            .functions()
+            .iter()
+            .zip(self.nodes.iter())
+            .map(|(f, node)| (f.name.as_str(), f.range.start, node.frame_size))
+    }
+
+    /// Deepest stack any call chain from an entry point reaches within each function,
+    /// including its own frame, by function symbol. Recursion is counted once.
+    pub fn static_depths(&self) -> HashMap<&'a str, u32> {
+        let mut callers = vec![Vec::new(); self.nodes.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            for callee in node.callees.iter() {
+                callers[*callee].push(index);
+            }
+        }
+
+        let mut depths = HashMap::new();
+        let mut in_progress = vec![false; self.nodes.len()];
+        let functions = self.asm_file.functions();
+        (0..self.nodes.len())
+            .map(|index| {
+                let depth = self.reaching_depth(index, &callers, &mut depths, &mut in_progress);
+                (functions[index].symbol.as_str(), depth)
+            })
+            .collect()
+    }
+
+    fn reaching_depth(
+        &self,
+        index: usize,
+        callers: &[Vec<usize>],
+        depths: &mut HashMap<usize, u32>,
+        in_progress: &mut [bool],
+    ) -> u32 {
+        if let Some(depth) = depths.get(&index) {
+            return *depth;
+        }
+        if in_progress[index] {
+            return 0;
+        }
+        in_progress[index] = true;
+
+        let deepest_caller = callers[index]
+            .iter()
+            .map(|caller| self.reaching_depth(*caller, callers, depths, in_progress))
+            .max()
+            .unwrap_or(0);
+        let depth = deepest_caller + self.nodes[index].frame_size;
+        in_progress[index] = false;
+        depths.insert(index, depth);
+
+        depth
+    }
+
+    fn depth(&self, index: usize, visits: &mut HashMap<usize, Visit>) -> Depth {
+        match visits.get(&index) {
+            Some(Visit::Done(depth)) => return depth.to_owned(),
+            Some(Visit::InProgress) => {
+                return Depth {
+                    bytes: 0,
+                    path: Vec::new(),
+                    recursive: true,
+                    indirect_calls: false,
+                    dynamic_frames: false,
+                }
+            }
+            None => (),
+        }
+        visits.insert(index, Visit::InProgress);
+
+        let node = &self.nodes[index];
+        let mut deepest: Option<Depth> = None;
+        let mut recursive = false;
+        let mut indirect_calls = node.indirect_calls;
+        let mut dynamic_frames = node.dynamic_frame;
+        for callee in node.callees.iter() {
+            let depth = self.depth(*callee, visits);
+            recursive |= depth.recursive;
+            indirect_calls |= depth.indirect_calls;
+            dynamic_frames |= depth.dynamic_frames;
+            if deepest
+                .as_ref()
+                .map(|d| depth.bytes > d.bytes)
+                .unwrap_or(true)
+            {
+                deepest = Some(depth);
+            }
+        }
+
+        let (bytes, mut path) = match deepest {
+            Some(deepest) => (deepest.bytes, deepest.path),
+            None => (0, Vec::new()),
+        };
+        path.insert(0, index);
+        let depth = Depth {
+            bytes: bytes + node.frame_size,
+            path,
+            recursive,
+            indirect_calls,
+            dynamic_frames,
+        };
+        visits.insert(index, Visit::Done(depth.to_owned()));
+
+        depth
+    }
+
+    fn to_stack_depth(&self, entry: usize, depth: Depth) -> StackDepth {
+        let functions = self.asm_file.functions();
+        StackDepth {
+            entry: functions[entry].name.to_owned(),
+            bytes: depth.bytes,
+            path: depth
+                .path
+                .iter()
+                .map(|i| functions[*i].name.to_owned())
+                .collect(),
+            recursive: depth.recursive,
+            indirect_calls: depth.indirect_calls,
+            dynamic_frames: depth.dynamic_frames,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Line charts of the stack and memory usage course, and sparkline thumbnails of the
+//! stack course to browse directories of recordings.
+
+use std::path::Path;
+
+use plotters::prelude::*;
+use thiserror::Error;
+
+use crate::{statistics::RamStatistics, DynError};
+
+const CHART_SIZE: (u32, u32) = (1024, 480);
+const SPARKLINE_SIZE: (u32, u32) = (160, 40);
+
+#[derive(Error, Debug)]
+pub enum ChartError {
+    #[error("unsupported chart format {0}, expected .svg or .png")]
+    UnsupportedFormat(String),
+}
+
+/// Writes the chart as svg or png, depending on the extension of `path`.
+pub fn write_chart(path: &Path, statistics: &RamStatistics) -> DynError<()> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("svg") => draw(
+            SVGBackend::new(path, CHART_SIZE).into_drawing_area(),
+            statistics,
+        ),
+        Some("png") => draw(
+            BitMapBackend::new(path, CHART_SIZE).into_drawing_area(),
+            statistics,
+        ),
+        _ => Err(ChartError::UnsupportedFormat(path.display().to_string()).into()),
+    }
+}
+
+fn draw<DB: DrawingBackend>(
+    root: DrawingArea<DB, plotters::coord::Shift>,
+    statistics: &RamStatistics,
+) -> DynError<()>
+where
+    DB::ErrorType: 'static,
+{
+    let stack_ptr_course = statistics.stack_ptr_course();
+    let mem_usage_course = statistics.mem_usage_course();
+    // plotted over the real time of the records, sampling is not perfectly uniform
+    let elapsed_course = statistics.elapsed_course();
+    let duration = elapsed_course.last().copied().unwrap_or(0.0).max(0.001);
+    let max = stack_ptr_course
+        .iter()
+        .chain(mem_usage_course.iter())
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    root.fill(&WHITE).map_err(|e| e.to_string())?;
+    let mut chart = ChartBuilder::on(&root)
+        .caption("stack usage course", ("sans-serif", 24))
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(0.0..duration, 0..max + max / 10)
+        .map_err(|e| e.to_string())?;
+    chart
+        .configure_mesh()
+        .x_desc("elapsed s")
+        .y_desc("bytes")
+        .draw()
+        .map_err(|e| e.to_string())?;
+
+    chart
+        .draw_series(LineSeries::new(
+            elapsed_course
+                .iter()
+                .copied()
+                .zip(mem_usage_course.iter().copied()),
+            &BLUE,
+        ))
+        .map_err(|e| e.to_string())?
+        .label("mem usage")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLUE));
+    chart
+        .draw_series(LineSeries::new(
+            elapsed_course
+                .iter()
+                .copied()
+                .zip(stack_ptr_course.iter().copied()),
+            &RED,
+        ))
+        .map_err(|e| e.to_string())?
+        .label("stack ptr offset")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+    chart
+        .configure_series_labels()
+        .background_style(WHITE)
+        .border_style(BLACK)
+        .draw()
+        .map_err(|e| e.to_string())?;
+
+    root.present().map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Writes the stack ptr offset course as png sparkline without axes, scaled to its
+/// maximum which is marked by a dot.
+pub fn write_sparkline(path: &Path, statistics: &RamStatistics) -> DynError<()> {
+    let root = BitMapBackend::new(path, SPARKLINE_SIZE).into_drawing_area();
+    let course = statistics.stack_ptr_course();
+    let elapsed_course = statistics.elapsed_course();
+    let duration = elapsed_course.last().copied().unwrap_or(0.0).max(0.001);
+    let max = course.iter().copied().max().unwrap_or(0).max(1);
+
+    root.fill(&WHITE).map_err(|e| e.to_string())?;
+    let mut chart = ChartBuilder::on(&root)
+        .margin(3)
+        .build_cartesian_2d(0.0..duration, 0..max)
+        .map_err(|e| e.to_string())?;
+    let points = elapsed_course
+        .iter()
+        .copied()
+        .zip(course.iter().copied())
+        .collect::<Vec<_>>();
+    chart
+        .draw_series(AreaSeries::new(points.iter().copied(), 0, RED.mix(0.2)).border_style(RED))
+        .map_err(|e| e.to_string())?;
+    if let Some(peak) = points.iter().max_by_key(|(_, bytes)| *bytes) {
+        chart
+            .draw_series(std::iter::once(Circle::new(*peak, 2, RED.filled())))
+            .map_err(|e| e.to_string())?;
+    }
+
+    root.present().map_err(|e| e.to_string())?;
+
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Conditions on core registers or memory words, like `r0 == 5` or `*COUNTER >= 0x10`.
+
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+use thiserror::Error;
+
+use crate::{elf, registers};
+
+#[derive(Error, Debug)]
+pub enum ConditionError {
+    #[error("invalid condition {0}, expected `<register|*addr|*symbol> <op> <value>`")]
+    InvalidCondition(String),
+    #[error("unknown register {0}")]
+    UnknownRegister(String),
+    #[error("unknown comparison {0}, expected one of: == != < <= > >=")]
+    UnknownComparison(String),
+    #[error("failed parsing value {0}")]
+    ValueParseError(String),
+    #[error(transparent)]
+    ElfError(#[from] elf::ElfError),
+}
+
+#[derive(Debug)]
+pub enum Operand {
+    Register(CoreRegisterAddress),
+    /// Word in memory.
+    Memory(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+pub struct Condition {
+    operand: Operand,
+    comparison: Comparison,
+    value: u32,
+}
+
+impl Condition {
+    pub fn parse(text: &str, obj_file: &object::File) -> Result<Self, ConditionError> {
+        Self::parse_with(text, |address| elf::resolve_addr(obj_file, address))
+    }
+
+    /// Parses `text` with the addresses of memory operands resolved by `resolve_addr`.
+    fn parse_with(
+        text: &str,
+        resolve_addr: impl FnOnce(&str) -> Result<u32, elf::ElfError>,
+    ) -> Result<Self, ConditionError> {
+        let mut parts = text.split_whitespace();
+        let (operand, comparison, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(operand), Some(comparison), Some(value)) if parts.next().is_none() => {
+                (operand, comparison, value)
+            }
+            _ => return Err(ConditionError::InvalidCondition(String::from(text))),
+        };
+
+        let operand = match operand.strip_prefix('*') {
+            Some(address) => Operand::Memory(resolve_addr(address)?),
+            None => Operand::Register(
+                register_address(operand)
+                    .ok_or_else(|| ConditionError::UnknownRegister(String::from(operand)))?,
+            ),
+        };
+        let comparison = match comparison {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            c => return Err(ConditionError::UnknownComparison(String::from(c))),
+        };
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| ConditionError::ValueParseError(String::from(value)))?;
+
+        Ok(Self {
+            operand,
+            comparison,
+            value,
+        })
+    }
+
+    /// Evaluates the condition on the halted core.
+    pub fn evaluate(&self, core: &mut Core) -> Result<bool, probe_rs::Error> {
+        let actual = match self.operand {
+            Operand::Register(address) => core.read_core_reg(address)?,
+            Operand::Memory(address) => core.read_word_32(address)?,
+        };
+
+        Ok(match self.comparison {
+            Comparison::Eq => actual == self.value,
+            Comparison::Ne => actual != self.value,
+            Comparison::Lt => actual < self.value,
+            Comparison::Le => actual <= self.value,
+            Comparison::Gt => actual > self.value,
+            Comparison::Ge => actual >= self.value,
+        })
+    }
+}
+
+/// Resolves the cortex-m register names `r0`-`r12`, `sp`, `lr`, `pc`, `xpsr`, `msp` and `psp`.
+fn register_address(name: &str) -> Option<CoreRegisterAddress> {
+    let name = name.to_lowercase();
+    let address = match name.as_str() {
+        "sp" | "r13" => CoreRegisterAddress(13),
+        "lr" | "r14" => CoreRegisterAddress(14),
+        "pc" | "r15" => CoreRegisterAddress(15),
+        "xpsr" => registers::XPSR,
+        "msp" => registers::MSP,
+        "psp" => registers::PSP,
+        _ => {
+            let index = name.strip_prefix('r')?.parse::<u16>().ok()?;
+            if index > 12 {
+                return None;
+            }
+            CoreRegisterAddress(index)
+        }
+    };
+
+    Some(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(address: &str) -> Result<u32, elf::ElfError> {
+        match address {
+            "COUNTER" => Ok(0x2000_0010),
+            address => u32::from_str_radix(address.trim_start_matches("0x"), 16)
+                .map_err(|_| elf::ElfError::SymbolNotFound(String::from(address))),
+        }
+    }
+
+    #[test]
+    fn parses_register_and_memory_conditions() {
+        let condition = Condition::parse_with("R5 != 0x10", resolve).unwrap();
+        assert!(matches!(
+            condition.operand,
+            Operand::Register(CoreRegisterAddress(5))
+        ));
+        assert!(matches!(condition.comparison, Comparison::Ne));
+        assert_eq!(condition.value, 0x10);
+
+        let condition = Condition::parse_with("*COUNTER >= 16", resolve).unwrap();
+        assert!(matches!(condition.operand, Operand::Memory(0x2000_0010)));
+        assert!(matches!(condition.comparison, Comparison::Ge));
+        assert_eq!(condition.value, 16);
+
+        let condition = Condition::parse_with("sp < 0x20001000", resolve).unwrap();
+        assert!(matches!(
+            condition.operand,
+            Operand::Register(CoreRegisterAddress(13))
+        ));
+    }
+
+    #[test]
+    fn malformed_conditions_are_rejected() {
+        let parse = |text| Condition::parse_with(text, resolve);
+        assert!(matches!(
+            parse("r0 =="),
+            Err(ConditionError::InvalidCondition(_))
+        ));
+        assert!(matches!(
+            parse("r0 == 1 2"),
+            Err(ConditionError::InvalidCondition(_))
+        ));
+        assert!(matches!(
+            parse("r13x == 1"),
+            Err(ConditionError::UnknownRegister(_))
+        ));
+        assert!(matches!(
+            parse("r13 =< 1"),
+            Err(ConditionError::UnknownComparison(_))
+        ));
+        assert!(matches!(
+            parse("r0 == 0xg"),
+            Err(ConditionError::ValueParseError(_))
+        ));
+        assert!(matches!(
+            parse("*MISSING == 1"),
+            Err(ConditionError::ElfError(elf::ElfError::SymbolNotFound(_)))
+        ));
+    }
+}
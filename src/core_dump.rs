@@ -0,0 +1,160 @@
+//! ELF core dump of the registers and RAM of the halted core, opened with
+//! `arm-none-eabi-gdb <firmware> <core>`.
+//!
+//! The register set is the one of the linux arm `NT_PRSTATUS` note, gdb and crash
+//! analysers know it, the special registers besides xpsr are not part of it.
+
+use std::{ops::Range, path::Path};
+
+use probe_rs::{CoreRegisterAddress, MemoryInterface};
+
+use crate::{cpu, registers, DynError};
+
+const SIGTRAP: u16 = 5;
+const SIGSEGV: u16 = 11;
+
+const ELF_HEADER_SIZE: u32 = 52;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+const ET_CORE: u16 = 4;
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+/// `elf_prstatus` up to the registers, signal info, pids and times.
+const PRSTATUS_REGS_OFFSET: usize = 72;
+/// r0-r15, cpsr and orig_r0.
+const PRSTATUS_REG_COUNT: usize = 18;
+const PRSTATUS_SIZE: usize = PRSTATUS_REGS_OFFSET + PRSTATUS_REG_COUNT * 4 + 4;
+
+pub struct CoreDump {
+    /// r0-r15 and xpsr.
+    registers: [u32; 17],
+    memory: Vec<(u32, Vec<u8>)>,
+    signal: u16,
+}
+
+impl CoreDump {
+    /// Reads the registers and the memory `regions`, `fault` marks the dump as taken
+    /// on a fault instead of on request.
+    pub fn capture(cpu: &mut cpu::CPU, regions: &[Range<u32>], fault: bool) -> DynError<Self> {
+        let (registers, memory) = cpu.access_only_in_halt_mode(|core| {
+            let mut registers = [0u32; 17];
+            for (index, register) in registers.iter_mut().take(16).enumerate() {
+                *register = core.read_core_reg(CoreRegisterAddress(index as u16))?;
+            }
+            registers[16] = core.read_core_reg(registers::XPSR)?;
+
+            let mut memory = Vec::new();
+            for region in regions {
+                let mut data = vec![0u8; (region.end - region.start) as usize];
+                core.read_8(region.start, &mut data)?;
+                memory.push((region.start, data));
+            }
+
+            Ok((registers, memory))
+        })?;
+
+        Ok(Self {
+            registers,
+            memory,
+            signal: if fault { SIGSEGV } else { SIGTRAP },
+        })
+    }
+
+    pub fn write_elf(&self, path: &Path) -> DynError<()> {
+        let segment_count = 1 + self.memory.len() as u32;
+        let note = self.prstatus_note();
+
+        let mut elf = Vec::new();
+        // e_ident: 32 bit, little endian, current version
+        elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+        elf.extend_from_slice(&[0; 8]);
+        push_u16(&mut elf, ET_CORE);
+        push_u16(&mut elf, EM_ARM);
+        push_u32(&mut elf, 1);
+        push_u32(&mut elf, 0);
+        push_u32(&mut elf, ELF_HEADER_SIZE);
+        push_u32(&mut elf, 0);
+        push_u32(&mut elf, 0);
+        push_u16(&mut elf, ELF_HEADER_SIZE as u16);
+        push_u16(&mut elf, PROGRAM_HEADER_SIZE as u16);
+        push_u16(&mut elf, segment_count as u16);
+        push_u16(&mut elf, 0);
+        push_u16(&mut elf, 0);
+        push_u16(&mut elf, 0);
+
+        let mut offset = ELF_HEADER_SIZE + segment_count * PROGRAM_HEADER_SIZE;
+        push_program_header(&mut elf, PT_NOTE, offset, 0, note.len() as u32, 0);
+        offset += note.len() as u32;
+        for (start, data) in self.memory.iter() {
+            push_program_header(
+                &mut elf,
+                PT_LOAD,
+                offset,
+                *start,
+                data.len() as u32,
+                PF_R | PF_W,
+            );
+            offset += data.len() as u32;
+        }
+
+        elf.extend_from_slice(&note);
+        for (_, data) in self.memory.iter() {
+            elf.extend_from_slice(data);
+        }
+        std::fs::write(path, elf)?;
+
+        Ok(())
+    }
+
+    fn prstatus_note(&self) -> Vec<u8> {
+        let mut prstatus = vec![0u8; PRSTATUS_SIZE];
+        // si_signo and pr_cursig
+        prstatus[0..4].copy_from_slice(&(self.signal as u32).to_le_bytes());
+        prstatus[12..14].copy_from_slice(&self.signal.to_le_bytes());
+        for (index, register) in self.registers.iter().enumerate() {
+            let offset = PRSTATUS_REGS_OFFSET + index * 4;
+            prstatus[offset..offset + 4].copy_from_slice(&register.to_le_bytes());
+        }
+
+        let name = b"CORE\0";
+        let mut note = Vec::new();
+        push_u32(&mut note, name.len() as u32);
+        push_u32(&mut note, prstatus.len() as u32);
+        push_u32(&mut note, NT_PRSTATUS);
+        note.extend_from_slice(name);
+        note.resize((note.len() + 3) & !3, 0);
+        note.extend_from_slice(&prstatus);
+        note.resize((note.len() + 3) & !3, 0);
+
+        note
+    }
+}
+
+fn push_program_header(
+    elf: &mut Vec<u8>,
+    kind: u32,
+    offset: u32,
+    addr: u32,
+    size: u32,
+    flags: u32,
+) {
+    push_u32(elf, kind);
+    push_u32(elf, offset);
+    push_u32(elf, addr);
+    push_u32(elf, addr);
+    push_u32(elf, size);
+    push_u32(elf, size);
+    push_u32(elf, flags);
+    push_u32(elf, if kind == PT_NOTE { 4 } else { 1 });
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
@@ -1,74 +1,416 @@
-use std::{sync::MutexGuard, time::Duration};
+use std::{
+    sync::MutexGuard,
+    time::{Duration, Instant},
+};
 
 use probe_rs::{
-    config::{MemoryRegion, NvmRegion, RamRegion},
+    config::{MemoryRegion, RamRegion},
     flashing::DownloadOptions,
-    Core, Session,
+    Architecture, Core, MemoryInterface, Session, Target,
 };
+use thiserror::Error;
+
+use crate::registers::{DWTCtrl, DWTFunction, DEMCR, DFSR, DHCSR};
+
+/// Address of the 96 bit unique device id register per STM32 family.
+const UNIQUE_ID_ADDRESSES: &[(&str, u32)] = &[
+    ("STM32F0", 0x1FFF_F7AC),
+    ("STM32F1", 0x1FFF_F7E8),
+    ("STM32F2", 0x1FFF_7A10),
+    ("STM32F3", 0x1FFF_F7AC),
+    ("STM32F4", 0x1FFF_7A10),
+    ("STM32F7", 0x1FF0_F420),
+    ("STM32G0", 0x1FFF_7590),
+    ("STM32G4", 0x1FFF_7590),
+    ("STM32H7", 0x1FF1_E800),
+    ("STM32L0", 0x1FF8_0050),
+    ("STM32L4", 0x1FFF_7590),
+    ("STM32WB", 0x1FFF_7590),
+];
+
+/// Semihosting operations in r0 of a call.
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+/// Longer strings of `SYS_WRITE0` and buffers of `SYS_WRITE` are cut, the terminator or
+/// the length may be corrupt.
+const MAX_WRITE_LEN: usize = 1024;
+
+/// Semihosting call of a halt at the semihosting `bkpt`.
+#[derive(Debug)]
+pub enum SemihostingCall {
+    /// The firmware ended its run.
+    Exit,
+    /// Output of the firmware to the debug console.
+    Write(Vec<u8>),
+    /// Operation which isn't served.
+    Other(u32),
+}
 
-use crate::asm_parsing::AsmFile;
+#[derive(Error, Debug)]
+pub enum CpuError {
+    #[error(transparent)]
+    Probe(#[from] probe_rs::Error),
+    #[error("breakpoint at {addr:#010x} not reached within {timeout:?}, the firmware may not run through it")]
+    BreakpointTimeout { addr: u32, timeout: Duration },
+}
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU<'a> {
     session: MutexGuard<'a, Session>,
+    /// Core of a multi-core target all accesses go to.
+    core_index: usize,
+    /// Start of the current halt, none while the core runs.
+    halted_since: Option<Instant>,
+    halted_time: Duration,
+    /// Whether the DWT cycle counter was enabled.
+    cycle_counter: bool,
 }
 
 impl<'a> CPU<'a> {
     const DURATION: Duration = Duration::from_secs(5);
-    pub fn new(session: MutexGuard<'a, Session>) -> Self {
-        Self { session }
+    pub fn new(session: MutexGuard<'a, Session>, core_index: usize) -> Self {
+        Self {
+            session,
+            core_index,
+            halted_since: None,
+            halted_time: Duration::ZERO,
+            cycle_counter: false,
+        }
+    }
+
+    pub fn reset(&mut self) -> std::result::Result<(), probe_rs::Error> {
+        self.session.core(self.core_index)?.reset()?;
+        self.mark_running();
+
+        Ok(())
     }
 
     pub fn reset_and_halt(&mut self) -> std::result::Result<(), probe_rs::Error> {
-        let mut core = self.session.core(0)?;
-        core.reset_and_halt(Self::DURATION)?;
+        self.session
+            .core(self.core_index)?
+            .reset_and_halt(Self::DURATION)?;
+        self.mark_halted();
 
         Ok(())
     }
 
     pub fn halt(&mut self) -> std::result::Result<(), probe_rs::Error> {
-        let mut core = self.session.core(0)?;
-        core.halt(Self::DURATION)?;
+        self.session.core(self.core_index)?.halt(Self::DURATION)?;
+        self.mark_halted();
 
         Ok(())
     }
 
     pub fn run(&mut self) -> std::result::Result<(), probe_rs::Error> {
-        let mut core = self.session.core(0)?;
-        core.run()?;
+        self.session.core(self.core_index)?.run()?;
+        self.mark_running();
 
         Ok(())
     }
 
+    /// Total time the core was halted, by the analyser or at breakpoints and
+    /// watchpoints, including the current halt.
+    pub fn halted_time(&self) -> Duration {
+        self.halted_time
+            + self
+                .halted_since
+                .map(|since| since.elapsed())
+                .unwrap_or_default()
+    }
+
+    fn mark_halted(&mut self) {
+        self.halted_since.get_or_insert_with(Instant::now);
+    }
+
+    fn mark_running(&mut self) {
+        if let Some(since) = self.halted_since.take() {
+            self.halted_time += since.elapsed();
+        }
+    }
+
     pub fn step(&mut self) -> std::result::Result<(), probe_rs::Error> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.core_index)?;
         core.step()?;
 
         Ok(())
     }
 
-    pub fn run_to_point(&mut self, addr: u32) -> std::result::Result<(), probe_rs::Error> {
-        let mut core = self.session.core(0)?;
-        core.set_hw_breakpoint(addr)?;
-        core.run()?;
-        core.wait_for_core_halted(Self::DURATION)
-            .expect("Breakpoint not reached before timeout");
+    pub fn run_to_point(&mut self, addr: u32) -> std::result::Result<(), CpuError> {
+        self.run_until(addr, |_| Ok(true))
+    }
+
+    /// Runs until the core reaches `addr` with `condition` holding. The condition is
+    /// checked on every hit of the breakpoint, the core continues if it does not hold.
+    /// A breakpoint not hit within `DURATION` halts the core wherever it is.
+    pub fn run_until<F: FnMut(&mut Core) -> std::result::Result<bool, probe_rs::Error>>(
+        &mut self,
+        addr: u32,
+        mut condition: F,
+    ) -> std::result::Result<(), CpuError> {
+        self.mark_running();
+        let reached = {
+            let mut core = self.session.core(self.core_index)?;
+            core.set_hw_breakpoint(addr)?;
+            let reached = 'hits: loop {
+                core.run()?;
+                let start = Instant::now();
+                while !core.core_halted()? {
+                    if start.elapsed() > Self::DURATION {
+                        core.halt(Self::DURATION)?;
+                        break 'hits false;
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                if condition(&mut core)? {
+                    break true;
+                }
+                core.clear_hw_breakpoint(addr)?;
+                core.step()?;
+                core.set_hw_breakpoint(addr)?;
+            };
+            core.clear_hw_breakpoint(addr)?;
+            reached
+        };
+        self.mark_halted();
+
+        match reached {
+            true => Ok(()),
+            false => Err(CpuError::BreakpointTimeout {
+                addr,
+                timeout: Self::DURATION,
+            }),
+        }
+    }
+
+    /// Polls the core until it halted or `timeout` elapsed. Returns whether the core halted.
+    pub fn wait_for_halt(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<bool, probe_rs::Error> {
+        let start = std::time::Instant::now();
+        while std::time::Instant::now() - start < timeout {
+            if self.session.core(self.core_index)?.core_halted()? {
+                self.mark_halted();
+                return Ok(true);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        Ok(false)
+    }
+
+    /// Executes the instruction at the hw breakpoint `addr` the core is halted at.
+    pub fn step_over_breakpoint(&mut self, addr: u32) -> std::result::Result<(), probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
         core.clear_hw_breakpoint(addr)?;
+        core.step()?;
+        core.set_hw_breakpoint(addr)?;
 
         Ok(())
     }
 
-    pub fn halt_while<T, F: FnMut(&mut Core) -> std::result::Result<T, probe_rs::Error>>(
+    /// Halts the core on writes to the word at `addr` with DWT comparator 0.
+    pub fn set_write_watchpoint(&mut self, addr: u32) -> std::result::Result<(), probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        let mut demcr = DEMCR(core.read_word_32(DEMCR::ADDRESS)?);
+        demcr.set_trcena(true);
+        core.write_word_32(DEMCR::ADDRESS, demcr.0)?;
+
+        core.write_word_32(DWTFunction::COMP_ADDRESS, addr & !0b11)?;
+        core.write_word_32(DWTFunction::MASK_ADDRESS, 2)?;
+        let mut function = DWTFunction(0);
+        function.set_function(DWTFunction::WRITE_WATCHPOINT);
+        core.write_word_32(DWTFunction::ADDRESS, function.0)?;
+
+        Ok(())
+    }
+
+    /// Enables the DWT cycle counter, false if the core has none like ARMv6-M and
+    /// RISC-V cores.
+    pub fn enable_cycle_counter(&mut self) -> std::result::Result<bool, probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        if core.architecture() != Architecture::Arm {
+            return Ok(false);
+        }
+        let mut demcr = DEMCR(core.read_word_32(DEMCR::ADDRESS)?);
+        demcr.set_trcena(true);
+        core.write_word_32(DEMCR::ADDRESS, demcr.0)?;
+
+        let mut ctrl = DWTCtrl(core.read_word_32(DWTCtrl::ADDRESS)?);
+        if ctrl.nocyccnt() {
+            return Ok(false);
+        }
+        ctrl.set_cyccntena(true);
+        core.write_word_32(DWTCtrl::ADDRESS, ctrl.0)?;
+        self.cycle_counter = DWTCtrl(core.read_word_32(DWTCtrl::ADDRESS)?).cyccntena();
+
+        Ok(self.cycle_counter)
+    }
+
+    pub fn has_cycle_counter(&self) -> bool {
+        self.cycle_counter
+    }
+
+    /// Cycle counter at `hits` consecutive hits of `addr`, none if a hit takes longer
+    /// than `timeout` or the core halts elsewhere, e.g. at a fault or a `bkpt`. The core
+    /// is halted afterwards.
+    pub fn cycles_at_hits(
         &mut self,
-        mut func: F,
-    ) -> std::result::Result<T, probe_rs::Error> {
-        self.halt()?;
-        let res = {
-            let mut core = self.session.core(0)?;
-            func(&mut core)?
+        addr: u32,
+        hits: usize,
+        timeout: Duration,
+    ) -> std::result::Result<Option<Vec<u32>>, probe_rs::Error> {
+        self.mark_running();
+        let mut cycles = Vec::new();
+        {
+            let mut core = self.session.core(self.core_index)?;
+            core.set_hw_breakpoint(addr)?;
+            'hits: while cycles.len() < hits {
+                core.run()?;
+                let start = Instant::now();
+                while !core.core_halted()? {
+                    if start.elapsed() > timeout {
+                        core.halt(Self::DURATION)?;
+                        break 'hits;
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                if core.read_core_reg(core.registers().program_counter())? != addr {
+                    break 'hits;
+                }
+                cycles.push(core.read_word_32(DWTCtrl::CYCCNT_ADDRESS)?);
+                core.clear_hw_breakpoint(addr)?;
+                core.step()?;
+                core.set_hw_breakpoint(addr)?;
+            }
+            core.clear_hw_breakpoint(addr)?;
+        }
+        self.mark_halted();
+
+        Ok(Some(cycles).filter(|c| c.len() == hits))
+    }
+
+    pub fn clear_watchpoint(&mut self) -> std::result::Result<(), probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        core.write_word_32(DWTFunction::ADDRESS, DWTFunction::DISABLED)?;
+
+        Ok(())
+    }
+
+    /// Whether the core was reset since DHCSR was last read. probe-rs reads DHCSR on
+    /// most core accesses, so this has to be the first access after running the core.
+    pub fn reset_since_last_access(&mut self) -> std::result::Result<bool, probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        let dhcsr = DHCSR(core.read_word_32(DHCSR::ADDRESS)?);
+
+        Ok(dhcsr.s_reset_st())
+    }
+
+    /// Whether the last halt was caused by a DWT watchpoint. Clears the sticky flag.
+    pub fn halted_by_watchpoint(&mut self) -> std::result::Result<bool, probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        let dfsr = DFSR(core.read_word_32(DFSR::ADDRESS)?);
+        // write one to clear
+        core.write_word_32(DFSR::ADDRESS, dfsr.0)?;
+
+        Ok(dfsr.dwttrap())
+    }
+
+    /// Immediate of the `bkpt` instruction the core halted at, if the last halt was
+    /// caused by one. Clears the sticky flag.
+    pub fn halted_by_bkpt(&mut self) -> std::result::Result<Option<u8>, probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        let dfsr = DFSR(core.read_word_32(DFSR::ADDRESS)?);
+        core.write_word_32(DFSR::ADDRESS, dfsr.0)?;
+        if !dfsr.bkpt() {
+            return Ok(None);
+        }
+
+        let instr_ptr = core.read_core_reg(core.registers().program_counter())?;
+        let word = core.read_word_32(instr_ptr & !0b11)?;
+        let instruction = if instr_ptr & 0b10 == 0 {
+            word & 0xffff
+        } else {
+            word >> 16
         };
-        self.run()?;
+        // thumb encoding 0xbe<imm8>
+        if instruction & 0xff00 != 0xbe00 {
+            return Ok(None);
+        }
 
-        Ok(res)
+        Ok(Some(instruction as u8))
+    }
+
+    /// Semihosting call of the core halted at the semihosting `bkpt`, r0 holds the
+    /// operation and r1 its parameter.
+    pub fn semihosting_call(&mut self) -> std::result::Result<SemihostingCall, probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        let registers = core.registers();
+        let operation = core.read_core_reg(registers.argument_register(0))?;
+        let parameter = core.read_core_reg(registers.argument_register(1))?;
+        let call = match operation {
+            SYS_EXIT | SYS_EXIT_EXTENDED => SemihostingCall::Exit,
+            SYS_WRITEC => {
+                let mut data = [0u8];
+                core.read_8(parameter, &mut data)?;
+                SemihostingCall::Write(data.to_vec())
+            }
+            SYS_WRITE0 => {
+                let mut data = Vec::new();
+                let mut byte = [0u8];
+                while data.len() < MAX_WRITE_LEN {
+                    let addr = match parameter.checked_add(data.len() as u32) {
+                        Some(addr) => addr,
+                        None => break,
+                    };
+                    core.read_8(addr, &mut byte)?;
+                    if byte[0] == 0 {
+                        break;
+                    }
+                    data.push(byte[0]);
+                }
+                SemihostingCall::Write(data)
+            }
+            SYS_WRITE => {
+                // handle, buffer and length, every handle is taken as console
+                let mut block = [0u32; 3];
+                core.read_32(parameter, &mut block)?;
+                let mut data = vec![0u8; (block[2] as usize).min(MAX_WRITE_LEN)];
+                core.read_8(block[1], &mut data)?;
+                SemihostingCall::Write(data)
+            }
+            operation => SemihostingCall::Other(operation),
+        };
+
+        Ok(call)
+    }
+
+    /// Returns `result` in r0 from the semihosting call the core is halted at and moves
+    /// the pc behind its `bkpt`.
+    pub fn finish_semihosting_call(
+        &mut self,
+        result: u32,
+    ) -> std::result::Result<(), probe_rs::Error> {
+        {
+            let mut core = self.session.core(self.core_index)?;
+            let r0 = core.registers().result_register(0);
+            core.write_core_reg(r0.into(), result)?;
+        }
+
+        self.skip_bkpt()
+    }
+
+    /// Moves the pc behind the 16 bit `bkpt` instruction the core is halted at.
+    pub fn skip_bkpt(&mut self) -> std::result::Result<(), probe_rs::Error> {
+        let mut core = self.session.core(self.core_index)?;
+        let pc = core.registers().program_counter();
+        let instr_ptr = core.read_core_reg(pc)?;
+        core.write_core_reg(pc.into(), instr_ptr + 2)?;
+
+        Ok(())
     }
 
     pub fn access_only_in_halt_mode<
@@ -79,7 +421,7 @@ impl<'a> CPU<'a> {
         mut func: F,
     ) -> std::result::Result<T, probe_rs::Error> {
         let prev_state_halt = {
-            let mut core = self.session.core(0)?;
+            let mut core = self.session.core(self.core_index)?;
             core.core_halted()?
         };
 
@@ -87,7 +429,7 @@ impl<'a> CPU<'a> {
             self.halt()?;
         }
         let res = {
-            let mut core = self.session.core(0)?;
+            let mut core = self.session.core(self.core_index)?;
             func(&mut core)?
         };
         if !prev_state_halt {
@@ -101,48 +443,30 @@ impl<'a> CPU<'a> {
         &mut self,
         mut func: F,
     ) -> std::result::Result<T, probe_rs::Error> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.core_index)?;
         func(&mut core)
     }
 
-    pub fn flash_region(&mut self) -> std::result::Result<NvmRegion, probe_rs::Error> {
-        let flash_region = self
-            .session
-            .target()
-            .memory_map
+    /// Reads the unique device id of the chip, if its family is known.
+    pub fn unique_id(&mut self) -> std::result::Result<Option<String>, probe_rs::Error> {
+        let address = UNIQUE_ID_ADDRESSES
             .iter()
-            .filter(|m| match m {
-                MemoryRegion::Nvm(m) => m.is_boot_memory,
-                _ => false,
-            })
-            .collect::<Vec<_>>();
-
-        let region = match flash_region[0] {
-            MemoryRegion::Nvm(m) => m.clone(),
-            _ => unreachable!(),
+            .find(|(family, _)| self.session.target().name.starts_with(family))
+            .map(|(_, address)| *address);
+        let address = match address {
+            Some(address) => address,
+            None => return Ok(None),
         };
 
-        Ok(region)
-    }
+        let mut id = [0u32; 3];
+        self.access_core(|core| core.read_32(address, &mut id))?;
 
-    pub fn ram_region(&mut self) -> std::result::Result<RamRegion, probe_rs::Error> {
-        let ram_region = self
-            .session
-            .target()
-            .memory_map
-            .iter()
-            .filter(|m| match m {
-                MemoryRegion::Ram(_) => true,
-                _ => false,
-            })
-            .collect::<Vec<_>>();
-
-        let region = match ram_region[0] {
-            MemoryRegion::Ram(m) => m.clone(),
-            _ => unreachable!(),
-        };
+        Ok(Some(format!("{:08x}{:08x}{:08x}", id[2], id[1], id[0])))
+    }
 
-        Ok(region)
+    /// All ram banks of the target, e.g. SRAM1, SRAM2 and CCM.
+    pub fn ram_regions(&mut self) -> std::result::Result<Vec<RamRegion>, probe_rs::Error> {
+        Ok(ram_regions_of(self.session.target()))
     }
 
     pub fn flash(
@@ -153,34 +477,21 @@ impl<'a> CPU<'a> {
         let mut loader = target.flash_loader();
         loader.load_elf_data(&mut file)?;
         let options = DownloadOptions::default();
-        loader.commit(&mut *self.session, options)?;
+        loader.commit(&mut self.session, options)?;
         self.reset_and_halt()?;
 
         Ok(())
     }
+}
 
-    fn step_over_act_func(
-        &mut self,
-        asm_file: &AsmFile,
-    ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut core = self.session.core(0)?;
-        if !core.core_halted()? {
-            core.halt(Self::DURATION)?;
-        }
-
-        let instr_ptr = core.read_core_reg(core.registers().program_counter())?;
-        let function = asm_file.get_function_based_on_addr(&instr_ptr).unwrap();
-
-        let start = std::time::Instant::now();
-        while std::time::Instant::now() - start < Duration::from_secs(1) {
-            // core.write_core_reg(, value)
-            core.step()?;
-            let instr_ptr = core.read_core_reg(core.registers().program_counter())?;
-            if !function.range.contains(&instr_ptr) {
-                break;
-            }
-        }
-
-        Ok(())
-    }
+/// Ram banks in the memory map of `target`, known without attaching to it.
+pub fn ram_regions_of(target: &Target) -> Vec<RamRegion> {
+    target
+        .memory_map
+        .iter()
+        .filter_map(|m| match m {
+            MemoryRegion::Ram(m) => Some(m.clone()),
+            _ => None,
+        })
+        .collect()
 }
@@ -0,0 +1,77 @@
+//! Decoding of the defmt frames of an RTT channel with the defmt table of the elf file.
+
+use defmt_decoder::{DecodeError, Locations, StreamDecoder, Table};
+
+use crate::{rtt::LogLine, DynError};
+
+/// Name of the up channel defmt-rtt writes to.
+pub const CHANNEL_NAME: &str = "defmt";
+
+/// Table and source locations of the log statements of the firmware, loaded once per
+/// run and borrowed by the stream decoders.
+pub struct DefmtTable {
+    table: Table,
+    /// Empty if the elf file has no debug info.
+    locations: Locations,
+}
+
+impl DefmtTable {
+    /// None if the firmware doesn't use defmt, an error for tables of an unsupported
+    /// defmt version.
+    pub fn load(elf: &[u8]) -> DynError<Option<Self>> {
+        let table = match Table::parse(elf)? {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let locations = table.get_locations(elf).unwrap_or_default();
+
+        Ok(Some(Self { table, locations }))
+    }
+
+    pub fn decoder(&self) -> DefmtDecoder<'_> {
+        DefmtDecoder {
+            decoder: self.table.new_stream_decoder(),
+            locations: self.locations.to_owned(),
+        }
+    }
+}
+
+/// Decodes the frames of one channel, frames may be split over several reads.
+pub struct DefmtDecoder<'t> {
+    decoder: Box<dyn StreamDecoder + 't>,
+    locations: Locations,
+}
+
+impl DefmtDecoder<'_> {
+    pub fn decode(&mut self, channel: &str, data: &[u8]) -> Vec<LogLine> {
+        self.decoder.received(data);
+        let mut lines = Vec::new();
+        loop {
+            let line = match self.decoder.decode() {
+                Ok(frame) => LogLine {
+                    channel: String::from(channel),
+                    line: frame.display_message().to_string(),
+                    level: frame.level().map(|l| format!("{:?}", l).to_lowercase()),
+                    location: self
+                        .locations
+                        .get(&frame.index())
+                        .map(|l| format!("{}:{}", l.file.display(), l.line)),
+                },
+                Err(DecodeError::UnexpectedEof) => break,
+                // decoding continues with the next read
+                Err(DecodeError::Malformed) => {
+                    lines.push(LogLine {
+                        channel: String::from(channel),
+                        line: String::from("malformed defmt frame"),
+                        level: None,
+                        location: None,
+                    });
+                    break;
+                }
+            };
+            lines.push(line);
+        }
+
+        lines
+    }
+}
@@ -0,0 +1,26 @@
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    Rust,
+    Cpp,
+}
+
+/// Demangles a symbol name of the given source language. Names which are not
+/// mangled are returned unchanged.
+pub fn demangle(name: &str, language: Language) -> Cow<'_, str> {
+    match language {
+        Language::Rust => match rustc_demangle::try_demangle(name) {
+            // alternate format omits the hash suffix
+            Ok(demangled) => Cow::Owned(format!("{:#}", demangled)),
+            Err(_) => Cow::Borrowed(name),
+        },
+        Language::Cpp => match cpp_demangle::Symbol::new(name) {
+            Ok(symbol) => symbol
+                .demangle(&cpp_demangle::DemangleOptions::default())
+                .map(Cow::Owned)
+                .unwrap_or(Cow::Borrowed(name)),
+            Err(_) => Cow::Borrowed(name),
+        },
+    }
+}
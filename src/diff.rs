@@ -0,0 +1,159 @@
+//! Changes in stack usage between two recordings, to review the RAM impact of a
+//! firmware change.
+
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use crate::{mem_monitoring::RamSnapshotRecorder, statistics::StatisticsEngine};
+
+/// Maximum stack pointer offset of a function in the old and the new recording,
+/// none if the core was never halted in it.
+pub struct FunctionChange {
+    pub function: String,
+    pub old_max: Option<u32>,
+    pub new_max: Option<u32>,
+}
+
+impl FunctionChange {
+    pub fn delta(&self) -> i64 {
+        self.new_max.unwrap_or(0) as i64 - self.old_max.unwrap_or(0) as i64
+    }
+}
+
+impl std::fmt::Display for FunctionChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.old_max, self.new_max) {
+            (None, Some(new)) => write!(f, "new      {} ({} bytes)", self.function, new),
+            (Some(old), None) => write!(f, "gone     {} ({} bytes)", self.function, old),
+            (Some(old), Some(new)) => write!(
+                f,
+                "{:+8} {} ({} -> {} bytes)",
+                self.delta(),
+                self.function,
+                old,
+                new
+            ),
+            (None, None) => write!(f, "         {}", self.function),
+        }
+    }
+}
+
+pub struct RecordingDiff {
+    /// Old and new value.
+    pub max_stack_ptr_off: (u32, u32),
+    pub median_stack_ptr_off: (u32, u32),
+    pub max_mem_usage: (u32, u32),
+    pub max_heap_usage: Option<(u32, u32)>,
+    /// Labels of the old and the new session.
+    pub labels: (String, String),
+    /// Notes of the old and the new session.
+    pub notes: (Vec<String>, Vec<String>),
+    /// Functions whose maximum changed, appeared or disappeared, largest change first.
+    pub functions: Vec<FunctionChange>,
+}
+
+impl RecordingDiff {
+    /// Both recordings must contain records.
+    pub fn new(old: &RamSnapshotRecorder, new: &RamSnapshotRecorder) -> Self {
+        let old_statistics = StatisticsEngine::new(old).calculate();
+        let new_statistics = StatisticsEngine::new(new).calculate();
+
+        let old_functions = function_maxima(old);
+        let mut new_functions = function_maxima(new);
+        let mut functions = old_functions
+            .into_iter()
+            .map(|(function, old_max)| FunctionChange {
+                new_max: new_functions.remove(function.as_str()),
+                function,
+                old_max: Some(old_max),
+            })
+            .collect::<Vec<_>>();
+        functions.extend(
+            new_functions
+                .into_iter()
+                .map(|(function, new_max)| FunctionChange {
+                    function,
+                    old_max: None,
+                    new_max: Some(new_max),
+                }),
+        );
+        functions.retain(|c| c.old_max != c.new_max);
+        functions.sort_by_key(|c| Reverse(c.delta().abs()));
+
+        Self {
+            max_stack_ptr_off: (
+                old_statistics.max_stack_ptr_off,
+                new_statistics.max_stack_ptr_off,
+            ),
+            median_stack_ptr_off: (
+                old_statistics.median_stack_ptr_off(),
+                new_statistics.median_stack_ptr_off(),
+            ),
+            max_mem_usage: (
+                old_statistics.max_mem_usage(),
+                new_statistics.max_mem_usage(),
+            ),
+            max_heap_usage: old_statistics
+                .max_heap_usage()
+                .zip(new_statistics.max_heap_usage()),
+            labels: (old.session().labels_text(), new.session().labels_text()),
+            notes: (
+                old.session().notes.to_owned(),
+                new.session().notes.to_owned(),
+            ),
+            functions,
+        }
+    }
+
+    pub fn print(&self) {
+        if self.labels.0 != self.labels.1 {
+            let or_none = |labels: &str| match labels {
+                "" => String::from("none"),
+                labels => String::from(labels),
+            };
+            println!(
+                "labels: {} -> {}",
+                or_none(&self.labels.0),
+                or_none(&self.labels.1)
+            );
+        } else if !self.labels.0.is_empty() {
+            println!("labels: {}", self.labels.0);
+        }
+        for (name, notes) in [("old", &self.notes.0), ("new", &self.notes.1)] {
+            for note in notes {
+                println!("{} note: {}", name, note);
+            }
+        }
+        let print_value = |name: &str, (old, new): (u32, u32)| {
+            println!(
+                "{}: {} -> {} ({:+})",
+                name,
+                old,
+                new,
+                new as i64 - old as i64
+            )
+        };
+        print_value("max stack ptr offset", self.max_stack_ptr_off);
+        print_value("median stack ptr offset", self.median_stack_ptr_off);
+        print_value("max mem usage", self.max_mem_usage);
+        if let Some(heap) = self.max_heap_usage {
+            print_value("max heap usage", heap);
+        }
+
+        if self.functions.is_empty() {
+            println!("no changes in the maximum stack per function");
+            return;
+        }
+        println!("max stack ptr offset per function:");
+        for change in self.functions.iter() {
+            println!("  {}", change);
+        }
+    }
+}
+
+fn function_maxima(recorder: &RamSnapshotRecorder) -> BTreeMap<String, u32> {
+    recorder
+        .function_statistics()
+        .into_iter()
+        .map(|f| (f.function, f.max_stack_ptr_off))
+        .collect()
+}
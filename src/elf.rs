@@ -0,0 +1,217 @@
+use std::ops::Range;
+
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use thiserror::Error;
+
+use crate::demangle::{demangle, Language};
+
+#[derive(Error, Debug)]
+pub enum ElfError {
+    #[error("symbol {0} not found in obj file")]
+    SymbolNotFound(String),
+}
+
+pub fn find_symbol(obj_file: &object::File, name: &str) -> Option<u32> {
+    find_symbol_with_size(obj_file, name).map(|(addr, _)| addr)
+}
+
+pub fn find_symbol_with_size(obj_file: &object::File, name: &str) -> Option<(u32, u32)> {
+    obj_file
+        .symbols()
+        .find(|s| s.name().map(|n| n == name).unwrap_or(false))
+        .map(|s| (s.address() as u32, s.size() as u32))
+}
+
+/// Resolves a cli value which is either a hex address or a symbol name of the obj file.
+pub fn resolve_addr(obj_file: &object::File, value: &str) -> Result<u32, ElfError> {
+    let hex = value.trim_start_matches("0x");
+    if let Ok(addr) = u32::from_str_radix(hex, 16) {
+        return Ok(addr);
+    }
+
+    find_symbol(obj_file, value).ok_or_else(|| ElfError::SymbolNotFound(String::from(value)))
+}
+
+/// Initial stack pointer symbols of cortex-m-rt, riscv-rt, the common risc-v and the
+/// CMSIS linker scripts.
+const STACK_START_SYMBOLS: &[&str] = &["_stack_start", "__stack_top", "__StackTop"];
+
+/// Initial stack pointer of targets without a vector table or with a stripped one.
+pub fn stack_start(obj_file: &object::File) -> Result<u32, ElfError> {
+    STACK_START_SYMBOLS
+        .iter()
+        .find_map(|name| find_symbol(obj_file, name))
+        .ok_or_else(|| ElfError::SymbolNotFound(STACK_START_SYMBOLS.join(" or ")))
+}
+
+/// Heap start symbols of cortex-m-rt, gcc/newlib and CMSIS linker scripts.
+const HEAP_START_SYMBOLS: &[&str] = &[
+    "__sheap",
+    "_sheap",
+    "__heap_start",
+    "_heap_start",
+    "__HeapBase",
+    "end",
+];
+const HEAP_END_SYMBOLS: &[&str] = &[
+    "__eheap",
+    "_eheap",
+    "__heap_end",
+    "_heap_end",
+    "__HeapLimit",
+];
+/// Absolute symbols holding the heap size, the heap then ends at its start plus the size.
+const HEAP_SIZE_SYMBOLS: &[&str] = &["__heap_size", "_heap_size", "_Min_Heap_Size"];
+
+/// Static byte array of Rust firmwares handed to the allocator, as in the examples of
+/// `embedded-alloc` (`static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE]`).
+const DEFAULT_HEAP_STATIC: &str = "HEAP_MEM";
+
+/// Extent of the static `name`, matched on the demangled path or its last segment.
+fn find_static(obj_file: &object::File, name: &str) -> Option<Range<u32>> {
+    let suffix = format!("::{}", name);
+    obj_file
+        .symbols()
+        .filter(|s| s.kind() == SymbolKind::Data && s.size() > 0)
+        .find(|s| {
+            s.name()
+                .map(|n| {
+                    let n = demangle(n, Language::Rust);
+                    n == name || n.ends_with(&suffix)
+                })
+                .unwrap_or(false)
+        })
+        .map(|s| s.address() as u32..(s.address() + s.size()) as u32)
+}
+
+/// Heap bounds from the heap static of Rust firmwares, the `.heap` section or the heap
+/// symbols of the common linker scripts, the given bounds take precedence. Without an
+/// end symbol the heap ends at the stack limit of STM32Cube linker scripts (`_estack -
+/// _Min_Stack_Size`), else after the heap size symbol. A heap static given by name has
+/// to exist, the default one is only looked for.
+pub fn heap_range(
+    obj_file: &object::File,
+    start: Option<u32>,
+    end: Option<u32>,
+    heap_static: Option<&str>,
+) -> Result<Option<Range<u32>>, ElfError> {
+    let heap_static = match heap_static {
+        Some(name) => Some(
+            find_static(obj_file, name)
+                .ok_or_else(|| ElfError::SymbolNotFound(String::from(name)))?,
+        ),
+        None => find_static(obj_file, DEFAULT_HEAP_STATIC),
+    };
+    let section = heap_static.or_else(|| {
+        obj_file
+            .section_by_name(".heap")
+            .map(|s| s.address() as u32..(s.address() + s.size()) as u32)
+    });
+    let first_symbol = |names: &[&str]| names.iter().find_map(|n| find_symbol(obj_file, n));
+
+    let start = match start
+        .or_else(|| section.as_ref().map(|s| s.start))
+        .or_else(|| first_symbol(HEAP_START_SYMBOLS))
+    {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+    let end = end
+        .or_else(|| section.as_ref().map(|s| s.end))
+        .or_else(|| first_symbol(HEAP_END_SYMBOLS))
+        .or_else(|| {
+            find_symbol(obj_file, "_estack")?.checked_sub(find_symbol(obj_file, "_Min_Stack_Size")?)
+        })
+        .or_else(|| start.checked_add(first_symbol(HEAP_SIZE_SYMBOLS).filter(|size| *size > 0)?));
+
+    Ok(end.filter(|end| start < *end).map(|end| start..end))
+}
+
+/// Extent of the statics initialised by the startup code, `.data` and `.bss`. Memory
+/// painted there before the reset is overwritten before main is reached.
+pub fn statics_range(obj_file: &object::File) -> Option<Range<u32>> {
+    [".data", ".bss"]
+        .iter()
+        .filter_map(|name| obj_file.section_by_name(name))
+        .filter(|s| s.size() > 0)
+        .map(|s| s.address() as u32..(s.address() + s.size()) as u32)
+        .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+}
+
+/// End of the statics of cortex-m-rt (`_stack_end`, `__euninit`) and gcc/newlib
+/// (`__ebss`) linker scripts.
+pub const STATICS_END_SYMBOLS: &[&str] = &["_stack_end", "__euninit", "__ebss"];
+
+/// Lowest address the main stack may grow down to: the end of the heap or of the
+/// statics by symbol or by the `.bss` section, whichever is higher. A heap static of a
+/// Rust firmware lies within the statics.
+pub fn stack_limit(obj_file: &object::File, heap_range: Option<&Range<u32>>) -> Option<u32> {
+    let statics_end = STATICS_END_SYMBOLS
+        .iter()
+        .find_map(|s| find_symbol(obj_file, s))
+        .or_else(|| {
+            obj_file
+                .section_by_name(".bss")
+                .map(|s| (s.address() + s.size()) as u32)
+        });
+
+    match (heap_range.map(|heap| heap.end), statics_end) {
+        (Some(heap_end), Some(statics_end)) => Some(heap_end.max(statics_end)),
+        (heap_end, statics_end) => heap_end.or(statics_end),
+    }
+}
+
+/// Which parts of the symbolication the obj file supports.
+pub struct DebugInfoQuality {
+    pub function_symbols: usize,
+    pub debug_info: bool,
+    pub line_info: bool,
+    pub frame_info: bool,
+}
+
+impl DebugInfoQuality {
+    pub fn of(obj_file: &object::File) -> Self {
+        Self {
+            function_symbols: obj_file
+                .symbols()
+                .filter(|s| s.kind() == SymbolKind::Text && s.size() > 0)
+                .count(),
+            debug_info: obj_file.section_by_name(".debug_info").is_some(),
+            line_info: obj_file.section_by_name(".debug_line").is_some(),
+            frame_info: obj_file.section_by_name(".debug_frame").is_some(),
+        }
+    }
+
+    /// Describes what is missing and how to get it into the build.
+    pub fn warnings(&self, language: Language) -> Vec<String> {
+        let (keep_symbols, enable_debug) = match language {
+            Language::Rust => (
+                "set `strip = false` in the cargo profile",
+                "set `debug = true` in `[profile.release]`",
+            ),
+            Language::Cpp => ("do not link with `-s`", "compile with `-g`"),
+        };
+
+        let mut warnings = Vec::new();
+        if self.function_symbols == 0 {
+            warnings.push(format!(
+                "obj file has no function symbols, snapshots are attributed to addresses only ({})",
+                keep_symbols
+            ));
+        }
+        if !self.debug_info || !self.line_info {
+            warnings.push(format!(
+                "obj file has no DWARF line info, reports show no source locations ({})",
+                enable_debug
+            ));
+        }
+        if !self.frame_info {
+            warnings.push(format!(
+                "obj file has no .debug_frame section, snapshots have no backtraces ({})",
+                enable_debug
+            ));
+        }
+
+        warnings
+    }
+}
@@ -0,0 +1,94 @@
+//! Decoding of the registers the core stacks on exception entry.
+
+use probe_rs::{Core, MemoryInterface};
+use serde::{Deserialize, Serialize};
+use serde_hex::{SerHex, StrictPfx};
+
+/// Words of the basic frame, an extended frame with fp registers starts the same.
+const FRAME_WORDS: usize = 8;
+/// EXC_RETURN values have all bits above the return flags set.
+const EXC_RETURN_PREFIX: u32 = 0xFFFF_FF00;
+/// EXC_RETURN bit selecting the stack the frame was pushed to.
+const EXC_RETURN_PSP: u32 = 1 << 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExceptionFrame {
+    pub exception_number: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub r0: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub r1: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub r2: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub r3: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub r12: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub lr: u32,
+    /// Instruction the exception interrupted.
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub pc: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub xpsr: u32,
+}
+
+/// Entry of the active exception handler, as found by unwinding its frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionEntry {
+    /// Stack pointer right after the core stacked the exception frame.
+    pub stack_ptr: u32,
+    /// Value of lr on entry, selects the stack the frame was pushed to.
+    pub exc_return: u32,
+}
+
+impl ExceptionFrame {
+    /// HardFault, MemManage, BusFault or UsageFault.
+    pub fn is_fault(&self) -> bool {
+        (3..=6).contains(&self.exception_number)
+    }
+}
+
+/// Reads the stacked frame of the active exception. A frame on the main stack lies at
+/// the stack pointer of the handler entry, below which the handler pushed its own
+/// registers, so it is only located with the `entry` found by the unwinder. A frame
+/// on the process stack lies at the psp, which handlers don't move; without unwinding
+/// it is found while lr still holds the EXC_RETURN value.
+pub fn read_exception_frame(
+    core: &mut Core,
+    exception_number: u32,
+    entry: Option<ExceptionEntry>,
+    psp: u32,
+) -> Result<Option<ExceptionFrame>, probe_rs::Error> {
+    if exception_number == 0 {
+        return Ok(None);
+    }
+
+    let frame_ptr = match entry {
+        Some(entry) if entry.exc_return & EXC_RETURN_PSP != 0 => psp,
+        Some(entry) => entry.stack_ptr,
+        None => {
+            let exc_return = core.read_core_reg(core.registers().return_address())?;
+            if exc_return & EXC_RETURN_PREFIX != EXC_RETURN_PREFIX
+                || exc_return & EXC_RETURN_PSP == 0
+            {
+                return Ok(None);
+            }
+            psp
+        }
+    };
+    let mut frame = [0u32; FRAME_WORDS];
+    core.read_32(frame_ptr, &mut frame)?;
+
+    Ok(Some(ExceptionFrame {
+        exception_number,
+        r0: frame[0],
+        r1: frame[1],
+        r2: frame[2],
+        r3: frame[3],
+        r12: frame[4],
+        lr: frame[5],
+        pc: frame[6],
+        xpsr: frame[7],
+    }))
+}
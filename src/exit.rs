@@ -0,0 +1,210 @@
+//! Exit codes and machine readable error reports for automation.
+//!
+//! | code | meaning |
+//! |------|---------|
+//! | 0 | success |
+//! | 1 | other error |
+//! | 2 | budget exceeded |
+//! | 3 | stack overflow detected |
+//! | 4 | probe error |
+//! | 5 | config error |
+//! | 6 | target fault |
+
+use std::{error::Error, path::Path, time::Duration};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    asm_parsing::AsmError,
+    bisect::BisectError,
+    budget::BudgetError,
+    chart::ChartError,
+    condition::ConditionError,
+    cpu::CpuError,
+    elf::ElfError,
+    import::ImportError,
+    paint::PaintError,
+    preflight::{CheckKind, PreflightError},
+    profile::ProfileError,
+    record_format::RecordFormatError,
+    regions::RegionError,
+    schedule::ScheduleError,
+    selection::SelectionError,
+    source::SourceError,
+    stack::StackError,
+    stack_usage::StackUsageError,
+    trigger::TriggerError,
+    unwind::UnwindError,
+    DynError,
+};
+
+/// Measured maximum above its `--max-*-bytes` threshold.
+#[derive(Debug, Serialize)]
+pub struct BudgetViolation {
+    pub budget: String,
+    pub limit: u32,
+    pub measured: u32,
+}
+
+impl std::fmt::Display for BudgetViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} bytes exceeds {} bytes",
+            self.budget, self.measured, self.limit
+        )
+    }
+}
+
+/// Outcomes of a run which are reported as failure.
+#[derive(Error, Debug)]
+pub enum Failure {
+    #[error("budget exceeded: {}", join(.0))]
+    BudgetExceeded(Vec<BudgetViolation>),
+    #[error("stack overflow detected at 0x{0:08x}")]
+    OverflowDetected(u32),
+    #[error("invalid config: {0}")]
+    Config(String),
+    #[error("target fault: {0}")]
+    TargetFault(String),
+    #[error("start trigger `{trigger}` did not fire within {timeout:?}")]
+    StartTimeout { trigger: String, timeout: Duration },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+    Other = 1,
+    BudgetExceeded = 2,
+    OverflowDetected = 3,
+    ProbeError = 4,
+    ConfigError = 5,
+    TargetFault = 6,
+}
+
+impl ExitCode {
+    pub fn of(error: &(dyn Error + Send + Sync + 'static)) -> Self {
+        if let Some(failure) = error.downcast_ref::<Failure>() {
+            return match failure {
+                Failure::BudgetExceeded(_) => ExitCode::BudgetExceeded,
+                Failure::OverflowDetected(_) => ExitCode::OverflowDetected,
+                Failure::Config(_) => ExitCode::ConfigError,
+                Failure::TargetFault(_) => ExitCode::TargetFault,
+                Failure::StartTimeout { .. } => ExitCode::Other,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<SelectionError>() {
+            return match error {
+                SelectionError::NoProbe => ExitCode::ProbeError,
+                _ => ExitCode::ConfigError,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<PreflightError>() {
+            return match error.problems().iter().any(|p| p.kind == CheckKind::Probe) {
+                true => ExitCode::ProbeError,
+                false => ExitCode::ConfigError,
+            };
+        }
+        if let Some(ScheduleError::InvalidEntry(_)) = error.downcast_ref::<ScheduleError>() {
+            return ExitCode::ConfigError;
+        }
+        if let Some(RecordFormatError::UnknownFormat(_)) = error.downcast_ref::<RecordFormatError>()
+        {
+            return ExitCode::ConfigError;
+        }
+        if let Some(error) = error.downcast_ref::<BisectError>() {
+            return match error {
+                BisectError::CommandFailed(..) | BisectError::NoRecords(_) => ExitCode::Other,
+                _ => ExitCode::ConfigError,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<CpuError>() {
+            return match error {
+                CpuError::Probe(_) => ExitCode::ProbeError,
+                CpuError::BreakpointTimeout { .. } => ExitCode::ConfigError,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<UnwindError>() {
+            return match error {
+                UnwindError::Probe(_) => ExitCode::ProbeError,
+                UnwindError::UnsupportedRegister { .. } => ExitCode::ConfigError,
+            };
+        }
+        if let Some(TriggerError::InvalidTrigger(_)) = error.downcast_ref::<TriggerError>() {
+            return ExitCode::ConfigError;
+        }
+        if error.is::<probe_rs::Error>() || error.is::<probe_rs::DebugProbeError>() {
+            return ExitCode::ProbeError;
+        }
+        if error.is::<AsmError>()
+            || error.is::<BudgetError>()
+            || error.is::<ChartError>()
+            || error.is::<ElfError>()
+            || error.is::<ImportError>()
+            || error.is::<ConditionError>()
+            || error.is::<PaintError>()
+            || error.is::<ProfileError>()
+            || error.is::<RegionError>()
+            || error.is::<SourceError>()
+            || error.is::<StackError>()
+            || error.is::<StackUsageError>()
+            || error.is::<probe_rs::config::RegistryError>()
+            || error.is::<object::Error>()
+            || error.is::<std::num::ParseIntError>()
+            || error.is::<std::num::ParseFloatError>()
+            || error.is::<std::net::AddrParseError>()
+        {
+            return ExitCode::ConfigError;
+        }
+
+        ExitCode::Other
+    }
+
+    fn class(&self) -> &'static str {
+        match self {
+            ExitCode::Other => "other",
+            ExitCode::BudgetExceeded => "budget_exceeded",
+            ExitCode::OverflowDetected => "overflow_detected",
+            ExitCode::ProbeError => "probe_error",
+            ExitCode::ConfigError => "config_error",
+            ExitCode::TargetFault => "target_fault",
+        }
+    }
+}
+
+fn join(violations: &[BudgetViolation]) -> String {
+    violations
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    exit_code: i32,
+    class: &'a str,
+    message: String,
+    /// Exceeded budgets if the run failed on them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budgets: Option<&'a [BudgetViolation]>,
+}
+
+pub fn write_error_report(
+    path: &Path,
+    code: ExitCode,
+    error: &(dyn Error + Send + Sync + 'static),
+) -> DynError<()> {
+    let report = ErrorReport {
+        exit_code: code as i32,
+        class: code.class(),
+        message: error.to_string(),
+        budgets: match error.downcast_ref::<Failure>() {
+            Some(Failure::BudgetExceeded(violations)) => Some(violations),
+            _ => None,
+        },
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+
+    Ok(())
+}
@@ -0,0 +1,273 @@
+use std::{collections::HashMap, fs::File, io::BufWriter, path::Path};
+
+use serde::Serialize;
+
+use crate::{mem_monitoring::RamSnapshotRecorder, timestamp, DynError};
+
+#[derive(Serialize)]
+struct CsvRecord<'a> {
+    /// Time of the record in UTC, see `RamSnapshotRecorder::record_time`.
+    timestamp: String,
+    /// Seconds since the first record.
+    elapsed_s: f64,
+    used_bytes: u32,
+    stack_ptr_offset: u32,
+    pc: String,
+    function: &'a str,
+    /// DWT cycle counter at the halt, empty without a cycle counter.
+    cycles: Option<u64>,
+}
+
+/// Writes one row per record of the timeline.
+pub fn write_csv(path: &Path, recorder: &RamSnapshotRecorder) -> DynError<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for (index, snapshot) in recorder.timeline().enumerate() {
+        writer.serialize(CsvRecord {
+            timestamp: timestamp::to_iso8601(&recorder.record_time(index)),
+            elapsed_s: recorder.elapsed(index).as_secs_f64(),
+            used_bytes: snapshot.used_bytes,
+            stack_ptr_offset: snapshot.stack_ptr_offset,
+            pc: format!("0x{:08x}", snapshot.instr_ptr),
+            function: &snapshot.function,
+            cycles: recorder.sample_time(index).and_then(|t| t.cycles),
+        })?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// What the width of a flamegraph frame stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlameWeight {
+    /// Stack pointer offset of the samples.
+    Bytes,
+    Samples,
+}
+
+/// Aggregates the backtraces of the timeline into folded stacks, outermost frame first.
+pub fn folded_stacks(recorder: &RamSnapshotRecorder, weight: FlameWeight) -> Vec<String> {
+    let mut stacks: HashMap<String, u64> = HashMap::new();
+    for snapshot in recorder.timeline() {
+        let stack = if snapshot.backtrace.is_empty() {
+            snapshot.function.to_owned()
+        } else {
+            snapshot
+                .backtrace
+                .iter()
+                .rev()
+                .map(|f| f.function.as_deref().unwrap_or("<unknown>"))
+                .collect::<Vec<_>>()
+                .join(";")
+        };
+        *stacks.entry(stack).or_default() += match weight {
+            FlameWeight::Bytes => snapshot.stack_ptr_offset as u64,
+            FlameWeight::Samples => 1,
+        };
+    }
+
+    let mut lines = stacks
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(stack, count)| format!("{} {}", stack, count))
+        .collect::<Vec<_>>();
+    lines.sort();
+
+    lines
+}
+
+/// Writes the folded stacks for `.folded` paths, else a flamegraph svg.
+pub fn write_flamegraph(
+    path: &Path,
+    recorder: &RamSnapshotRecorder,
+    weight: FlameWeight,
+) -> DynError<()> {
+    let lines = folded_stacks(recorder, weight);
+    if path.extension().and_then(|e| e.to_str()) == Some("folded") {
+        std::fs::write(path, lines.join("\n"))?;
+        return Ok(());
+    }
+
+    let mut options = inferno::flamegraph::Options::default();
+    options.title = String::from("stack usage by call path");
+    options.count_name = String::from(match weight {
+        FlameWeight::Bytes => "bytes",
+        FlameWeight::Samples => "samples",
+    });
+    inferno::flamegraph::from_lines(
+        &mut options,
+        lines.iter().map(|l| l.as_str()),
+        BufWriter::new(File::create(path)?),
+    )?;
+
+    Ok(())
+}
+
+/// Event of the Chrome Trace Event format, read by ui.perfetto.dev and chrome://tracing.
+#[derive(Serialize)]
+struct TraceEvent<'a> {
+    name: &'a str,
+    ph: &'static str,
+    /// Microseconds since the start of the measurement.
+    ts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<u64>,
+    pid: u32,
+    tid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<HashMap<&'static str, u32>>,
+}
+
+/// Writes the timeline as trace events: the function at pc as slices, stack and heap
+/// usage as counter tracks.
+pub fn write_trace(path: &Path, recorder: &RamSnapshotRecorder) -> DynError<()> {
+    let records = recorder.timeline().collect::<Vec<_>>();
+    let interval_us = recorder.analyse_interval().as_micros() as u64;
+    let timings = recorder.sample_timings();
+    let ts = |index: usize| {
+        timings
+            .get(index)
+            .map(|t| t.actual_us)
+            .unwrap_or(index as u64 * interval_us)
+    };
+    // heap samples are only aligned with the timeline if taken for every record
+    let heap_records = recorder.heap_records();
+    let heap_records = if heap_records.len() == records.len() {
+        heap_records
+    } else {
+        &[]
+    };
+
+    let mut events = Vec::new();
+    let mut slice_start = 0;
+    for (index, snapshot) in records.iter().enumerate() {
+        events.push(TraceEvent {
+            name: "stack",
+            ph: "C",
+            ts: ts(index),
+            dur: None,
+            pid: 1,
+            tid: 1,
+            args: Some(HashMap::from([
+                ("stack_ptr_offset", snapshot.stack_ptr_offset),
+                ("used_bytes", snapshot.used_bytes),
+            ])),
+        });
+        if let Some(heap) = heap_records.get(index) {
+            events.push(TraceEvent {
+                name: "heap",
+                ph: "C",
+                ts: ts(index),
+                dur: None,
+                pid: 1,
+                tid: 1,
+                args: Some(HashMap::from([
+                    ("used_bytes", heap.used_bytes),
+                    ("high_watermark", heap.high_watermark),
+                ])),
+            });
+        }
+
+        // consecutive samples in the same function form one slice
+        let next = records.get(index + 1);
+        if next
+            .map(|n| n.function != snapshot.function)
+            .unwrap_or(true)
+        {
+            let end = match next {
+                Some(_) => ts(index + 1),
+                None => ts(index) + interval_us,
+            };
+            events.push(TraceEvent {
+                name: &records[slice_start].function,
+                ph: "X",
+                ts: ts(slice_start),
+                dur: Some(end.saturating_sub(ts(slice_start))),
+                pid: 1,
+                tid: 1,
+                args: None,
+            });
+            slice_start = index + 1;
+        }
+    }
+
+    serde_json::to_writer(BufWriter::new(File::create(path)?), &events)?;
+
+    Ok(())
+}
+
+/// Tags of every InfluxDB point, identifying the measurement in a fleet.
+pub struct InfluxTags<'a> {
+    pub chip: &'a str,
+    pub firmware_hash: &'a str,
+    pub run_id: &'a str,
+}
+
+/// One line protocol point per record of the timeline, measurement `mem_analyser`,
+/// nanosecond timestamps.
+pub fn influx_lines(recorder: &RamSnapshotRecorder, tags: &InfluxTags) -> Vec<String> {
+    let records = recorder.timeline().collect::<Vec<_>>();
+    // heap samples are only aligned with the timeline if taken for every record
+    let heap_records = recorder.heap_records();
+    let heap_records = if heap_records.len() == records.len() {
+        heap_records
+    } else {
+        &[]
+    };
+    let tags = format!(
+        "chip={},firmware_hash={},run_id={}",
+        escape_influx_tag(tags.chip),
+        escape_influx_tag(tags.firmware_hash),
+        escape_influx_tag(tags.run_id)
+    );
+
+    let mut lines = Vec::new();
+    for (index, snapshot) in records.iter().enumerate() {
+        let mut fields = format!(
+            "used_bytes={}i,stack_ptr_offset={}i,function=\"{}\"",
+            snapshot.used_bytes,
+            snapshot.stack_ptr_offset,
+            snapshot.function.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        if let Some(heap) = heap_records.get(index) {
+            fields.push_str(&format!(",heap_used_bytes={}i", heap.used_bytes));
+        }
+        let time = recorder.record_time(index);
+        lines.push(format!(
+            "mem_analyser,{} {} {}",
+            tags,
+            fields,
+            time.timestamp() * 1_000_000_000 + time.timestamp_subsec_nanos() as i64
+        ));
+    }
+
+    lines
+}
+
+/// Posts the points to `target` if it is an http(s) url of the InfluxDB write api, else
+/// writes them to the file `target`. `INFLUX_TOKEN` is sent as api token if set.
+pub fn write_influx(
+    target: &str,
+    recorder: &RamSnapshotRecorder,
+    tags: &InfluxTags,
+) -> DynError<()> {
+    let body = influx_lines(recorder, tags).join("\n");
+    if target.starts_with("http://") || target.starts_with("https://") {
+        let mut request = ureq::post(target).set("Content-Type", "text/plain; charset=utf-8");
+        if let Ok(token) = std::env::var("INFLUX_TOKEN") {
+            request = request.set("Authorization", &format!("Token {}", token));
+        }
+        request.send_string(&body)?;
+    } else {
+        std::fs::write(target, body)?;
+    }
+
+    Ok(())
+}
+
+fn escape_influx_tag(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
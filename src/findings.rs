@@ -0,0 +1,163 @@
+//! Diagnostics of a run collected in one place, stored in the recording and rendered on
+//! the console, in the html report and in the run end stream message.
+
+use std::{cmp::Reverse, collections::HashMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{call_graph::CallGraph, mem_monitoring::RamSnapshotRecorder};
+
+/// Observed stack depth in a function beyond its static estimate which is reported.
+const STATIC_EXCESS_MIN_BYTES: u32 = 64;
+const STATIC_EXCESS_FACTOR: f32 = 1.5;
+/// Static frames from this size on are reported.
+const LARGE_FRAME_BYTES: u32 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    StackOverflow,
+    TargetFault,
+    DebugInfo,
+    /// Observed usage the static analysis does not explain.
+    StaticEstimate,
+    LargeFrame,
+    Leak,
+    Reset,
+    Sampling,
+    Budget,
+    /// Usage grown beyond the tolerance against the baseline recording.
+    Regression,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub category: Category,
+    pub message: String,
+    /// Record the finding was made at.
+    #[serde(default)]
+    pub snapshot: Option<usize>,
+    /// Code or memory addresses the finding refers to.
+    #[serde(default)]
+    pub addresses: Vec<u32>,
+}
+
+impl Finding {
+    pub fn new(severity: Severity, category: Category, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            category,
+            message: message.into(),
+            snapshot: None,
+            addresses: Vec::new(),
+        }
+    }
+
+    pub fn at_snapshot(mut self, id: usize) -> Self {
+        self.snapshot = Some(id);
+        self
+    }
+
+    pub fn with_addresses(mut self, addresses: Vec<u32>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)?;
+        if let Some(id) = self.snapshot {
+            write!(f, " (snapshot {})", id)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Functions observed much deeper than any static call chain reaches them, a sign of
+/// recursion, alloca or calls through function pointers the call graph misses.
+pub fn static_estimate_findings(
+    recorder: &RamSnapshotRecorder,
+    call_graph: &CallGraph,
+) -> Vec<Finding> {
+    let static_depths = call_graph.static_depths();
+    // by symbol, the names of monomorphizations collide
+    let mut observed: HashMap<&str, (&str, u32)> = HashMap::new();
+    // exception handlers stack on top of the interrupted function
+    for snapshot in recorder.timeline().filter(|s| s.exception.is_none()) {
+        let symbol = match call_graph.symbol_at(snapshot.instr_ptr) {
+            Some(symbol) => symbol,
+            None => continue,
+        };
+        let (_, max) = observed
+            .entry(symbol)
+            .or_insert((snapshot.function.as_str(), 0));
+        *max = (*max).max(snapshot.stack_ptr_offset);
+    }
+
+    let mut findings = observed
+        .into_iter()
+        .filter_map(|(symbol, (function, observed))| {
+            let estimate = *static_depths.get(symbol)?;
+            (observed >= estimate + STATIC_EXCESS_MIN_BYTES
+                && observed as f32 > estimate as f32 * STATIC_EXCESS_FACTOR)
+                .then_some((function, observed, estimate))
+        })
+        .collect::<Vec<_>>();
+    findings.sort_by(|a, b| (b.1 - b.2).cmp(&(a.1 - a.2)).then(a.0.cmp(b.0)));
+
+    findings
+        .into_iter()
+        .map(|(function, observed, estimate)| {
+            Finding::new(
+                Severity::Warning,
+                Category::StaticEstimate,
+                format!(
+                    "{} observed at {} bytes of stack, static estimate {} bytes, check it for recursion, alloca or calls through function pointers",
+                    function, observed, estimate
+                ),
+            )
+        })
+        .collect()
+}
+
+/// Functions with a static frame of at least `LARGE_FRAME_BYTES`.
+pub fn large_frame_findings(call_graph: &CallGraph) -> Vec<Finding> {
+    let mut frames = call_graph
+        .frames()
+        .filter(|(_, _, bytes)| *bytes >= LARGE_FRAME_BYTES)
+        .collect::<Vec<_>>();
+    frames.sort_by_key(|frame| Reverse(frame.2));
+
+    frames
+        .into_iter()
+        .map(|(function, addr, bytes)| {
+            Finding::new(
+                Severity::Info,
+                Category::LargeFrame,
+                format!("{} has a stack frame of {} bytes", function, bytes),
+            )
+            .with_addresses(vec![addr])
+        })
+        .collect()
+}
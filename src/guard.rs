@@ -0,0 +1,92 @@
+//! Stack overflow guard with a DWT data watchpoint.
+//!
+//! On ARMv7-M the watchpoint halts the core imprecisely, the reported pc may be
+//! one or two instructions behind the writing instruction.
+
+use std::time::{Duration, Instant};
+
+use probe_rs::CoreRegisterAddress;
+
+use crate::{asm_parsing::AsmFile, cpu, interrupt, registers, DynError};
+
+pub struct GuardHit {
+    pub guard_addr: u32,
+    pub instr_ptr: u32,
+    pub function: Option<String>,
+    pub registers: Vec<(String, u32)>,
+}
+
+impl GuardHit {
+    pub fn print(&self) {
+        println!(
+            "stack guard at 0x{:08x} written at 0x{:08x} in {}",
+            self.guard_addr,
+            self.instr_ptr,
+            self.function.as_deref().unwrap_or("<unknown>")
+        );
+        for (name, value) in self.registers.iter() {
+            println!("  {:>4}: 0x{:08x}", name, value);
+        }
+    }
+}
+
+/// Runs the core until the word at `guard_addr` is written or `timeout` elapsed.
+pub fn run_until_overflow(
+    cpu: &mut cpu::CPU,
+    guard_addr: u32,
+    asm_file: Option<&AsmFile>,
+    timeout: Duration,
+) -> DynError<Option<GuardHit>> {
+    cpu.set_write_watchpoint(guard_addr)?;
+    cpu.run()?;
+
+    let start = Instant::now();
+    let mut hit = None;
+    while Instant::now() - start < timeout && !interrupt::interrupted() {
+        if !cpu.wait_for_halt(Duration::from_millis(100))? {
+            continue;
+        }
+        if cpu.halted_by_watchpoint()? {
+            hit = Some(read_hit(cpu, guard_addr, asm_file)?);
+            break;
+        }
+        cpu.run()?;
+    }
+
+    if hit.is_none() {
+        cpu.halt()?;
+    }
+    cpu.clear_watchpoint()?;
+
+    Ok(hit)
+}
+
+fn read_hit(cpu: &mut cpu::CPU, guard_addr: u32, asm_file: Option<&AsmFile>) -> DynError<GuardHit> {
+    let (instr_ptr, registers) = cpu.access_core(|core| {
+        let instr_ptr = core.read_core_reg(core.registers().program_counter())?;
+
+        let mut registers = Vec::new();
+        for register in core.registers().platform_registers() {
+            let value = core.read_core_reg(CoreRegisterAddress::from(register))?;
+            registers.push((String::from(register.name()), value));
+        }
+        for (name, address) in [
+            ("xpsr", registers::XPSR),
+            ("msp", registers::MSP),
+            ("psp", registers::PSP),
+        ] {
+            registers.push((String::from(name), core.read_core_reg(address)?));
+        }
+
+        Ok((instr_ptr, registers))
+    })?;
+
+    Ok(GuardHit {
+        guard_addr,
+        instr_ptr,
+        function: asm_file
+            .and_then(|a| a.get_function_based_on_addr(&instr_ptr))
+            .map(|f| f.name),
+        registers,
+    })
+}
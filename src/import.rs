@@ -0,0 +1,317 @@
+//! Stack reports of other tools converted into recordings, so `diff`, `aggregate`,
+//! `report` and `--baseline` compare them with measured runs.
+//!
+//! Reports are tables with a function column first and a stack column selected by its
+//! header, either csv like the exports of the static stack analyser of STM32CubeIDE
+//! (`Max cost`) or html like the function pages saved from puncover. The static ram
+//! comes from the output of `arm-none-eabi-size` in berkeley format.
+
+use std::{path::Path, time::Duration};
+
+use thiserror::Error;
+
+use crate::{
+    mem_monitoring::{RamSnapshot, RamSnapshotRecorder},
+    session::SessionInfo,
+};
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("could not read report")]
+    FailedReadingFile(#[from] std::io::Error),
+    #[error("malformed csv report")]
+    MalformedCsv(#[from] csv::Error),
+    #[error("no column `{0}` in the header of the csv report")]
+    MissingColumn(String),
+    #[error("invalid stack bytes `{value}` of function {function}")]
+    InvalidBytes { function: String, value: String },
+    #[error("no `text data bss` line in the size output")]
+    MalformedSizeOutput,
+}
+
+/// Worst case stack of a function reported by another tool.
+#[derive(Debug, Clone)]
+pub struct FunctionStack {
+    pub function: String,
+    pub bytes: u32,
+}
+
+/// Column named `stack_column` in `header`, matched case insensitively.
+fn stack_column_in<'a>(
+    mut header: impl Iterator<Item = &'a str>,
+    stack_column: &str,
+) -> Option<usize> {
+    header.position(|h| h.trim().eq_ignore_ascii_case(stack_column))
+}
+
+/// Stack of the row with the cells `function` and `value`, none for rows without a
+/// function.
+fn function_stack(function: &str, value: &str) -> Result<Option<FunctionStack>, ImportError> {
+    let function = function.trim();
+    let value = value.trim();
+    if function.is_empty() {
+        return Ok(None);
+    }
+    // some tools append units or mark unbounded frames, e.g. `128 B` or `>64`
+    let bytes = value
+        .trim_start_matches('>')
+        .split_whitespace()
+        .next()
+        .and_then(|b| b.parse().ok())
+        .ok_or_else(|| ImportError::InvalidBytes {
+            function: String::from(function),
+            value: String::from(value),
+        })?;
+
+    Ok(Some(FunctionStack {
+        function: String::from(function),
+        bytes,
+    }))
+}
+
+/// Rows of the csv report at `path`, `stack_column` is matched case insensitively.
+pub fn read_csv_report(path: &Path, stack_column: &str) -> Result<Vec<FunctionStack>, ImportError> {
+    parse_csv_report(
+        csv::ReaderBuilder::new().flexible(true).from_path(path)?,
+        stack_column,
+    )
+}
+
+fn parse_csv_report<R: std::io::Read>(
+    mut reader: csv::Reader<R>,
+    stack_column: &str,
+) -> Result<Vec<FunctionStack>, ImportError> {
+    let column = stack_column_in(reader.headers()?.iter(), stack_column)
+        .ok_or_else(|| ImportError::MissingColumn(String::from(stack_column)))?;
+
+    let mut functions = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let function = record.get(0).unwrap_or("");
+        let value = record.get(column).unwrap_or("");
+        functions.extend(function_stack(function, value)?);
+    }
+
+    Ok(functions)
+}
+
+/// Rows of the first table with a `stack_column` header in the html report at `path`.
+pub fn read_html_report(
+    path: &Path,
+    stack_column: &str,
+) -> Result<Vec<FunctionStack>, ImportError> {
+    parse_html_report(&std::fs::read_to_string(path)?, stack_column)
+}
+
+fn parse_html_report(html: &str, stack_column: &str) -> Result<Vec<FunctionStack>, ImportError> {
+    let lower = html.to_ascii_lowercase();
+    for (table, table_lower) in after_tags(html, &lower, &["table"]) {
+        let mut rows = after_tags(table, table_lower, &["tr"])
+            .into_iter()
+            .map(|(row, row_lower)| {
+                after_tags(row, row_lower, &["th", "td"])
+                    .into_iter()
+                    .map(|(cell, _)| cell_text(cell))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|cells| !cells.is_empty());
+        let column = match rows
+            .next()
+            .and_then(|header| stack_column_in(header.iter().map(String::as_str), stack_column))
+        {
+            Some(column) => column,
+            None => continue,
+        };
+
+        let mut functions = Vec::new();
+        for cells in rows {
+            let function = cells.first().map_or("", String::as_str);
+            let value = cells.get(column).map_or("", String::as_str);
+            functions.extend(function_stack(function, value)?);
+        }
+        return Ok(functions);
+    }
+
+    Err(ImportError::MissingColumn(String::from(stack_column)))
+}
+
+/// Content after each opening tag named in `names` up to the next one of them, as slices
+/// of `html` and its lowercase copy `lower`. Nested elements of the same name aren't
+/// told apart, report tables don't nest.
+fn after_tags<'a>(html: &'a str, lower: &'a str, names: &[&str]) -> Vec<(&'a str, &'a str)> {
+    let starts = lower
+        .match_indices('<')
+        .filter_map(|(i, _)| {
+            let tag = &lower[i + 1..];
+            let named = names.iter().any(|name| {
+                tag.starts_with(name)
+                    && tag[name.len()..].starts_with(|c: char| c == '>' || c.is_ascii_whitespace())
+            });
+            let content = i + 1 + tag.find('>')? + 1;
+            named.then_some((i, content))
+        })
+        .collect::<Vec<_>>();
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(k, &(_, content))| {
+            let end = starts
+                .get(k + 1)
+                .map_or(lower.len(), |&(next, _)| next)
+                .max(content);
+            (&html[content..end], &lower[content..end])
+        })
+        .collect()
+}
+
+/// Text of a table cell without markup, entities decoded and whitespace collapsed.
+fn cell_text(cell: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in cell.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// `data` plus `bss` of the first file in the berkeley output of `arm-none-eabi-size`.
+pub fn read_size_output(path: &Path) -> Result<u32, ImportError> {
+    let output = std::fs::read_to_string(path)?;
+    output
+        .lines()
+        .skip_while(|l| !l.trim_start().starts_with("text"))
+        .nth(1)
+        .and_then(|line| {
+            let mut columns = line.split_whitespace().skip(1);
+            let data: u32 = columns.next()?.parse().ok()?;
+            let bss: u32 = columns.next()?.parse().ok()?;
+            Some(data + bss)
+        })
+        .ok_or(ImportError::MalformedSizeOutput)
+}
+
+/// One record per function with its reported stack as stack ptr offset, the interval
+/// is zero as nothing was sampled.
+pub fn to_recording(
+    session: SessionInfo,
+    functions: &[FunctionStack],
+    static_ram_size: u32,
+) -> RamSnapshotRecorder {
+    let mut recorder = RamSnapshotRecorder::new(session, static_ram_size as usize, Duration::ZERO);
+    for function in functions {
+        recorder.record(
+            RamSnapshot {
+                used_bytes: function.bytes,
+                stack_ptr_offset: function.bytes,
+                ranges: Vec::new(),
+                instr_ptr: 0,
+                function: function.function.to_owned(),
+                psp_active: false,
+                process_stack: None,
+                exception: None,
+                interrupted_function: None,
+                backtrace: Vec::new(),
+                source: Vec::new(),
+                marker: None,
+                sp_anomaly: None,
+                halted_at: None,
+            },
+            None,
+        );
+    }
+
+    recorder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stacks(functions: &[FunctionStack]) -> Vec<(&str, u32)> {
+        functions
+            .iter()
+            .map(|f| (f.function.as_str(), f.bytes))
+            .collect()
+    }
+
+    #[test]
+    fn html_report_reads_the_table_with_the_stack_column() {
+        let html = r#"<html><body>
+            <table><tr><td>Symbols</td><td>12</td></tr></table>
+            <TABLE class="table">
+              <thead><tr><th>Name</th><th>Code</th><th>Stack</th></tr></thead>
+              <tbody>
+                <tr><td><a href="main.html">main</a></td><td>120</td><td>64</td></tr>
+                <tr><td><a href="x.html">Vec&lt;u8&gt;::push</a></td><td>40</td><td>&gt;16 B</td></tr>
+                <tr><td></td><td>8</td><td></td></tr>
+              </tbody>
+            </TABLE>
+        </body></html>"#;
+
+        let functions = parse_html_report(html, "stack").unwrap();
+        assert_eq!(stacks(&functions), [("main", 64), ("Vec<u8>::push", 16)]);
+    }
+
+    #[test]
+    fn html_report_without_the_stack_column_is_rejected() {
+        let html =
+            "<table><tr><th>Name</th><th>Code</th></tr><tr><td>main</td><td>4</td></tr></table>";
+
+        assert!(matches!(
+            parse_html_report(html, "stack"),
+            Err(ImportError::MissingColumn(_))
+        ));
+    }
+
+    fn csv_reader(csv: &str) -> csv::Reader<&[u8]> {
+        csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(csv.as_bytes())
+    }
+
+    #[test]
+    fn csv_report_reads_the_stack_column_case_insensitively() {
+        let csv = "Function,Local cost,MAX COST,Type\n\
+                   main,16,128 B,static\n\
+                   \"Vec<u8>::push\",8,>64,dynamic\n\
+                   ,0,0\n\
+                   idle, 4 , 4\n";
+
+        let functions = parse_csv_report(csv_reader(csv), "Max cost").unwrap();
+        assert_eq!(
+            stacks(&functions),
+            [("main", 128), ("Vec<u8>::push", 64), ("idle", 4)]
+        );
+    }
+
+    #[test]
+    fn csv_report_rejects_a_missing_column_and_invalid_bytes() {
+        assert!(matches!(
+            parse_csv_report(csv_reader("Function,Local cost\nmain,16\n"), "Max cost"),
+            Err(ImportError::MissingColumn(_))
+        ));
+        match parse_csv_report(
+            csv_reader("Function,Max cost\nmain,unbounded\n"),
+            "Max cost",
+        ) {
+            Err(ImportError::InvalidBytes { function, value }) => {
+                assert_eq!((function.as_str(), value.as_str()), ("main", "unbounded"))
+            }
+            functions => panic!("unexpected {:?}", functions),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! Ctrl+C handling. The first Ctrl+C ends the measurement like its timeout does, so the
+//! record is still written and the target released, a second one exits immediately.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::DynError;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code of processes killed by SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+pub fn install_handler() -> DynError<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        println!("interrupted, finishing the run (press Ctrl+C again to exit immediately)");
+    })?;
+
+    Ok(())
+}
+
+/// Whether the measurement should stop.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
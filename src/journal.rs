@@ -0,0 +1,194 @@
+//! Append-only journal of a running measurement in JSON Lines, next to the record file.
+//! The record file is only written at the end of a run, the journal keeps the records
+//! of a run which dies with the tool or the probe.
+//!
+//! The first line holds the session, then the records and events are appended every few
+//! records. The journal is removed once the record file is written, `recover` rebuilds
+//! the record file from the journal of a run which didn't get there.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mem_monitoring::{HeapSnapshot, RamSnapshot, RamSnapshotRecorder, RecordedEvent, SampleTime},
+    session::SessionInfo,
+    DynError,
+};
+
+/// Extension of the journal, replacing the one of the record file.
+pub const EXTENSION: &str = "jsonl";
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JournalLine<'a> {
+    Start {
+        session: &'a SessionInfo,
+        static_ram_size: usize,
+        #[serde(with = "crate::timestamp::iso8601_duration")]
+        analyse_interval: Duration,
+    },
+    Record {
+        snapshot: &'a RamSnapshot,
+        time: Option<&'a SampleTime>,
+        heap: Option<&'a HeapSnapshot>,
+    },
+    Event(&'a RecordedEvent),
+}
+
+/// Owned counterpart of `JournalLine` to read the journal back.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JournalEntry {
+    Start {
+        session: SessionInfo,
+        static_ram_size: usize,
+        #[serde(with = "crate::timestamp::iso8601_duration")]
+        analyse_interval: Duration,
+    },
+    Record {
+        snapshot: RamSnapshot,
+        time: Option<SampleTime>,
+        heap: Option<HeapSnapshot>,
+    },
+    Event(RecordedEvent),
+}
+
+pub struct Journal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Records are appended once this many are pending.
+    flush_every: usize,
+    /// Counted over the whole run, a bounded recording evicts its oldest records.
+    written_records: usize,
+    written_events: usize,
+}
+
+impl Journal {
+    /// Starts the journal of `recorder` at `path`, replacing a journal left there.
+    pub fn create(
+        path: &Path,
+        recorder: &RamSnapshotRecorder,
+        flush_every: usize,
+    ) -> DynError<Self> {
+        let mut journal = Self {
+            path: path.to_owned(),
+            writer: BufWriter::new(File::create(path)?),
+            flush_every,
+            written_records: 0,
+            written_events: 0,
+        };
+        journal.append(&JournalLine::Start {
+            session: recorder.session(),
+            static_ram_size: recorder.static_ram_size(),
+            analyse_interval: recorder.analyse_interval(),
+        })?;
+        journal.writer.flush()?;
+
+        Ok(journal)
+    }
+
+    fn append(&mut self, line: &JournalLine) -> DynError<()> {
+        serde_json::to_writer(&mut self.writer, line)?;
+        self.writer.write_all(b"\n")?;
+
+        Ok(())
+    }
+
+    /// Appends the new records if `flush_every` of them are pending.
+    pub fn update(&mut self, recorder: &RamSnapshotRecorder) -> DynError<()> {
+        let records = recorder.evicted_records() + recorder.timeline().count();
+        if records - self.written_records >= self.flush_every {
+            self.flush(recorder)?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends the records and events recorded since the last flush. Records evicted
+    /// before are missing, which takes a `--flush-every` of at least `--max-records`.
+    pub fn flush(&mut self, recorder: &RamSnapshotRecorder) -> DynError<()> {
+        let evicted = recorder.evicted_records();
+        let records = evicted + recorder.timeline().count();
+        for record in self.written_records.max(evicted)..records {
+            let id = record - evicted;
+            self.append(&JournalLine::Record {
+                snapshot: recorder.get_snapshot(id).unwrap(),
+                time: recorder.sample_time(id),
+                heap: recorder.heap_records().get(id),
+            })?;
+        }
+        self.written_records = records;
+
+        let evicted_events = recorder.aggregates().map(|a| a.evicted_events).unwrap_or(0);
+        let events = recorder.recorded_events();
+        let pending = self.written_events.max(evicted_events) - evicted_events;
+        for event in events[pending.min(events.len())..].iter() {
+            // ids of the whole run, as the records are replayed by `recover`
+            self.append(&JournalLine::Event(&event.before_evicted(evicted)))?;
+        }
+        self.written_events = evicted_events + events.len();
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Removes the journal, the record file holds everything it did.
+    pub fn finish(self) -> DynError<()> {
+        drop(self.writer);
+        std::fs::remove_file(&self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Recording of the journal at `path`. A last line cut off by the end of the run is
+/// skipped.
+pub fn recover(path: &Path) -> DynError<RamSnapshotRecorder> {
+    let mut recorder: Option<RamSnapshotRecorder> = None;
+    let mut lines = BufReader::new(File::open(path)?).lines().peekable();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let entry = match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) => entry,
+            Err(_) if lines.peek().is_none() => break,
+            Err(e) => return Err(e.into()),
+        };
+        match (entry, recorder.as_mut()) {
+            (
+                JournalEntry::Start {
+                    session,
+                    static_ram_size,
+                    analyse_interval,
+                },
+                None,
+            ) => {
+                recorder = Some(RamSnapshotRecorder::new(
+                    session,
+                    static_ram_size,
+                    analyse_interval,
+                ))
+            }
+            (
+                JournalEntry::Record {
+                    mut snapshot,
+                    time,
+                    heap,
+                },
+                Some(recorder),
+            ) => {
+                snapshot.halted_at = time;
+                recorder.record(snapshot, heap);
+            }
+            (JournalEntry::Event(event), Some(recorder)) => recorder.restore_event(event),
+            _ => return Err(format!("malformed journal {}", path.display()).into()),
+        }
+    }
+
+    recorder.ok_or_else(|| format!("empty journal {}", path.display()).into())
+}
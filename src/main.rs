@@ -1,27 +1,77 @@
-#![feature(drain_filter)]
-
 use std::{
+    ffi::OsString,
     fs::File,
-    io::{ErrorKind, Write},
-    net::{TcpListener, TcpStream},
-    path::Path,
+    io::Write,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
-    thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use object::{Object, ObjectSection};
-use probe_rs::{MemoryInterface, Probe};
+use probe_rs::MemoryInterface;
 
 use text_io::read;
 
-use crate::mem_monitoring::{calculate_used_ram, cpu_monitor, RamSnapshot, RamSnapshotRecorder};
+use crate::{
+    mem_monitoring::{calculate_used_ram, cpu_monitor, monitor_heap, RamSnapshotRecorder},
+    record_format::RecordFormat,
+    statistics::StatisticsEngine,
+};
 
+mod aggregate;
+mod aliasing;
+mod alloc_tracking;
 mod asm_parsing;
+mod bisect;
+mod budget;
+mod call_graph;
+mod chart;
+mod condition;
+mod core_dump;
 mod cpu;
+mod defmt_log;
+mod demangle;
+mod diff;
+mod elf;
+mod exception;
+mod exit;
+mod export;
+mod findings;
+mod guard;
+mod import;
+mod interrupt;
+mod journal;
 mod mem_monitoring;
+mod metrics;
+mod mqtt;
+mod paint;
+mod plugins;
+mod preflight;
+mod probe_log;
+mod profile;
+mod range_encoding;
+mod record_format;
+mod regions;
 mod registers;
+mod repl;
+mod report;
+mod rtos;
+mod rtt;
+mod schedule;
+mod selection;
+mod session;
+mod source;
+mod stack;
+mod stack_usage;
+mod statistics;
+mod streaming;
+mod timestamp;
+mod trigger;
+mod tui;
+mod unwind;
 
 type DynError<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
@@ -32,89 +82,186 @@ where
     Ok(std::fs::read(path)?)
 }
 
-struct ConnectionHandler {
-    streams: Arc<Mutex<Vec<TcpStream>>>,
-    server: JoinHandle<()>,
+enum AnalyseMode {
+    Looping,
+    SingleShot,
+    Stepping,
+    LoopMeasure,
+    AllocTracking,
+    OverflowGuard,
+    Markers,
 }
 
-impl ConnectionHandler {
-    fn new() -> Self {
-        let streams = Arc::new(Mutex::new(Vec::new()));
-        let streams_tmp = streams.to_owned();
-        let server = std::thread::spawn(move || {
-            let streams = streams_tmp;
-            let tcp = TcpListener::bind("127.0.0.10:80").expect("could'nt bind to address");
-            while let Ok((stream, _)) = tcp.accept() {
-                streams.lock().unwrap().push(stream);
-            }
-        });
+const RECORD_FILE: &str = "record.json";
+/// Replaced with the start time of the run in the record file path.
+const TIMESTAMP_PLACEHOLDER: &str = "{timestamp}";
+const REPORT_FILE: &str = "report.html";
+
+/// Cli options which influence the measurement and are part of the session config.
+const CONFIG_ARGS: &[&str] = &[
+    "language",
+    "core",
+    "mode",
+    "start_addr",
+    "start_condition",
+    "start_trigger",
+    "start_timeout",
+    "marker_bkpt",
+    "scan_top",
+    "scan_bottom",
+    "stack_start",
+    "fill_pattern",
+    "psp_top",
+    "psp_bottom",
+    "heap_start",
+    "heap_end",
+    "heap_static",
+    "asm_file",
+    "rtos",
+    "interval",
+];
+
+/// Default objdump file of arduino (cpp) builds in the cache directory, used instead of
+/// the internal disassembly if present.
+const ARDUINO_ASM_FILE: &str = "asm_arduino";
+/// Directory in the cache directory keeping a copy of every recording.
+const HISTORY_DIR: &str = "history";
+/// Extension of the sparkline written besides every recording, distinct from a chart
+/// next to the record file.
+const THUMBNAIL_EXTENSION: &str = "thumb.png";
+
+/// Immediate of the `bkpt` instruction used for semihosting calls.
+const SEMIHOSTING_BKPT: u8 = 0xab;
 
-        Self { streams, server }
+fn parse_imm(value: &str) -> Result<u8, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
     }
+}
 
-    fn distribute(&mut self, json_str: &str) -> std::io::Result<()> {
-        let mut streams = self.streams.lock().unwrap();
-        let mut to_close_connections = Vec::new();
-        for stream in streams.as_mut_slice() {
-            match (*stream).write_all(json_str.as_bytes()) {
-                Err(e) => match e.kind() {
-                    ErrorKind::ConnectionAborted => {
-                        to_close_connections.push(stream.peer_addr().unwrap());
-                        Ok(())
-                    }
-                    _ => Err(e),
-                },
-                _ => Ok(()),
-            }?;
-        }
+/// Runs to the start address, until the start condition holds if given.
+fn run_to_start(
+    cpu: &mut cpu::CPU,
+    start_addr: u32,
+    condition: Option<&condition::Condition>,
+) -> Result<(), cpu::CpuError> {
+    cpu.run_until(start_addr, |core| match condition {
+        Some(condition) => condition.evaluate(core),
+        None => Ok(true),
+    })
+}
 
-        for to_close in to_close_connections {
-            let mut streams = self.streams.lock().unwrap();
-            streams.drain_filter(|t| t.peer_addr().unwrap().eq(&to_close));
+/// Like `run_to_start`, but a main loop found in the disassembly which is not reached
+/// only warns, the measurement then starts wherever the core was halted.
+fn run_to_auto_start(
+    cpu: &mut cpu::CPU,
+    start_addr: u32,
+    condition: Option<&condition::Condition>,
+    auto_start: bool,
+) -> Result<(), cpu::CpuError> {
+    match run_to_start(cpu, start_addr, condition) {
+        Err(e @ cpu::CpuError::BreakpointTimeout { .. }) if auto_start => {
+            println!(
+                "warning: {}, measuring from here on (--start-addr or --no-auto-start to choose)",
+                e
+            );
+            Ok(())
         }
-
-        Ok(())
+        result => result,
     }
 }
 
-enum AnalyseMode {
-    Looping,
-    SingleShot,
-    Stepping,
-    LoopMeasure,
+fn firmware_path_arg() -> Arg<'static, 'static> {
+    Arg::with_name("firmware_path")
+        .short("f")
+        .long("firmware-path")
+        .value_name("FIRMWARE_PATH")
+        .takes_value(true)
+        .required(true)
 }
 
-fn main() -> DynError<()> {
-    let asm_file = asm_parsing::AsmFile::from_file(Path::new("./tmp/.asm_arduino"))?;
+fn language_arg() -> Arg<'static, 'static> {
+    Arg::with_name("language")
+        .possible_values(&["rust", "cpp"])
+        .value_name("LANGUAGE")
+        .short("l")
+        .default_value("rust")
+        .takes_value(true)
+}
 
-    // println!(
-    //     "{:?}",
-    //     asm_file
-    //         .get_subfunctions_of_function(&"loop")
-    //         .unwrap()
-    //         .iter()
-    //         .map(|f| f.name.to_owned())
-    //         .collect::<Vec<_>>()
-    // );
-    // return Ok(());
+fn asm_file_arg() -> Arg<'static, 'static> {
+    Arg::with_name("asm_file")
+        .value_name("ASM_FILE")
+        .long("asm-file")
+        .takes_value(true)
+        .help("Uses an objdump file instead of disassembling the firmware.")
+}
 
-    let matches = App::new("Stack Analyser")
-        .version("0.1.0")
-        .author("Alexander H. <alex.teamplayer@gmail.com>")
+fn probe_arg() -> Arg<'static, 'static> {
+    Arg::with_name("probe")
+        .value_name("VID:PID[:SERIAL]")
+        .long("probe")
+        .takes_value(true)
+        .help(
+            "Selects the debug probe, asks which one to use if omitted and several are connected.",
+        )
+}
+
+fn chip_arg() -> Arg<'static, 'static> {
+    Arg::with_name("chip")
+        .value_name("CHIP")
+        .long("chip")
+        .takes_value(true)
+        .default_value("STM32G431RBTx")
+        .help("Sets the target chip, asks which one to use if the name matches several chips.")
+}
+
+fn core_arg() -> Arg<'static, 'static> {
+    Arg::with_name("core")
+        .value_name("INDEX")
+        .long("core")
+        .takes_value(true)
+        .default_value("0")
+        .help("Sets the core of a multi-core target to measure, e.g. 1 for the second core of a STM32H7 or RP2040.")
+}
+
+fn non_interactive_arg() -> Arg<'static, 'static> {
+    Arg::with_name("non_interactive")
+        .long("non-interactive")
+        .takes_value(false)
+        .help("Fails instead of asking when the probe or chip is ambiguous.")
+}
+
+fn su_file_arg() -> Arg<'static, 'static> {
+    Arg::with_name("su_file")
+        .value_name("PATH")
+        .long("su-file")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Takes frame sizes for the static stack analysis from a gcc -fstack-usage file.")
+}
+
+/// Arguments of a measurement on the target, shared by `measure` and `serve`.
+fn measure_command(name: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        // may be set by the profile, checked once it is applied
+        .arg(firmware_path_arg().required_unless("profile"))
+        .arg(language_arg())
+        .arg(asm_file_arg())
         .arg(
-            Arg::with_name("firmware_path")
-                .short("f")
-                .value_name("FIRMWARE_PATH")
+            Arg::with_name("history_dir")
+                .value_name("DIR")
+                .long("history-dir")
                 .takes_value(true)
-                .required(true),
+                .help("Sets the directory keeping every recording, the end of run summary is compared against the latest recording of the same firmware in it. (default: history in the cache directory)"),
         )
         .arg(
-            Arg::with_name("language")
-                .possible_values(&["rust", "cpp"])
-                .value_name("LANGUAGE")
-                .short("l")
-                .default_value("rust")
-                .takes_value(true),
+            Arg::with_name("no_history")
+                .long("no-history")
+                .takes_value(false)
+                .help("Neither stores the recording in the history directory nor compares against it."),
         )
         .arg(
             Arg::with_name("no_flash")
@@ -122,15 +269,42 @@ fn main() -> DynError<()> {
                 .short("n")
                 .value_name("NO_FLASH"),
         )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .takes_value(false)
+                .help("Resolves the configuration against the firmware and chip, prints the measurement plan and exits without touching the target."),
+        )
+        .arg(probe_arg())
+        .arg(chip_arg())
+        .arg(core_arg())
+        .arg(non_interactive_arg())
         .arg(
             Arg::with_name("mode")
                 .value_name("MODE")
                 .short("m")
                 .long("mode")
                 .takes_value(true)
-                .possible_values(&["stepping", "looping", "single-shot", "loop-measure"])
+                .possible_values(&[
+                    "stepping",
+                    "looping",
+                    "single-shot",
+                    "loop-measure",
+                    "alloc-tracking",
+                    "overflow-guard",
+                    "markers",
+                ])
                 .default_value("looping"),
         )
+        .arg(
+            Arg::with_name("marker_bkpt")
+                .value_name("IMM")
+                .long("marker-bkpt")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Sets the bkpt immediates which request a sample in markers mode. (default: all but 0xab)"),
+        )
         .arg(
             Arg::with_name("start_addr")
                 .value_name("START_ADDR")
@@ -139,77 +313,2076 @@ fn main() -> DynError<()> {
                 .takes_value(true)
                 .help("Sets start address of measuring if in stepping mode."),
         )
-        .get_matches();
-    let elf_path = matches.value_of("firmware_path").unwrap();
-    let is_cpp = match matches.value_of("language").unwrap() {
-        "cpp" => true,
-        _ => false,
+        .arg(
+            Arg::with_name("no_auto_start")
+                .long("no-auto-start")
+                .takes_value(false)
+                .conflicts_with("start_addr")
+                .help("Starts the looping modes right away instead of at the main loop found in the disassembly."),
+        )
+        .arg(
+            Arg::with_name("start_condition")
+                .value_name("CONDITION")
+                .long("start-condition")
+                .takes_value(true)
+                .requires("start_addr")
+                .help("Starts measuring at the start address only once the condition holds, e.g. `r0 == 5` or `*COUNTER >= 0x10`."),
+        )
+        .arg(
+            Arg::with_name("scan_top")
+                .value_name("ADDR|SYMBOL")
+                .long("scan-top")
+                .takes_value(true)
+                .help("Sets the upper bound of the stack scan. (default: initial stack pointer)"),
+        )
+        .arg(
+            Arg::with_name("guard_addr")
+                .value_name("ADDR|SYMBOL")
+                .long("guard-addr")
+                .takes_value(true)
+                .help("Sets the watched address in overflow-guard mode. (default: below the worst case of the previous record)"),
+        )
+        .arg(
+            Arg::with_name("scan_bottom")
+                .value_name("ADDR|SYMBOL")
+                .long("scan-bottom")
+                .takes_value(true)
+                .help("Sets the lower bound of the stack scan. (default: end of the heap or statics, start of ram with --paint-all)"),
+        )
+        .arg(
+            Arg::with_name("no_paint")
+                .long("no-paint")
+                .takes_value(false)
+                .conflicts_with_all(&["paint_all", "on_reset"])
+                .help("Attaches to the running target without painting, flashing or resetting it, only the stack pointer offset is measured."),
+        )
+        .arg(
+            Arg::with_name("scan_existing")
+                .long("scan-existing")
+                .takes_value(false)
+                .requires("no_paint")
+                .help("Scans the stack and heap for the fill pattern the firmware painted itself, e.g. 0xCCCCCCCC of the paint-stack feature of cortex-m-rt."),
+        )
+        .arg(
+            Arg::with_name("paint_all")
+                .long("paint-all")
+                .takes_value(false)
+                .help("Paints all ram banks instead of only the stack and heap, to monitor the other banks as regions."),
+        )
+        .arg(
+            Arg::with_name("stack_start")
+                .value_name("ADDR|SYMBOL")
+                .long("stack-start")
+                .takes_value(true)
+                .help("Sets the initial stack pointer. (default: first word of the vector table, else the _stack_start, __stack_top or __StackTop symbol)"),
+        )
+        .arg(
+            Arg::with_name("fill_pattern")
+                .value_name("HEX")
+                .long("fill-pattern")
+                .takes_value(true)
+                .default_value("0x55")
+                .help("Sets the byte (0x55) or 32 bit word (0xCCCCCCCC) unused memory is painted with."),
+        )
+        .arg(
+            Arg::with_name("psp_top")
+                .value_name("ADDR|SYMBOL")
+                .long("psp-top")
+                .takes_value(true)
+                .requires("psp_bottom")
+                .help("Sets the upper bound of the process stack, scanned while the psp points into it."),
+        )
+        .arg(
+            Arg::with_name("psp_bottom")
+                .value_name("ADDR|SYMBOL")
+                .long("psp-bottom")
+                .takes_value(true)
+                .requires("psp_top")
+                .help("Sets the lower bound of the process stack."),
+        )
+        .arg(
+            Arg::with_name("heap_start")
+                .value_name("ADDR|SYMBOL")
+                .long("heap-start")
+                .takes_value(true)
+                .help("Sets the start of the monitored heap. (default: .heap section or heap symbols)"),
+        )
+        .arg(
+            Arg::with_name("heap_end")
+                .value_name("ADDR|SYMBOL")
+                .long("heap-end")
+                .takes_value(true)
+                .help("Sets the end of the monitored heap. (default: .heap section or heap symbols)"),
+        )
+        .arg(
+            Arg::with_name("heap_static")
+                .value_name("SYMBOL")
+                .long("heap-static")
+                .takes_value(true)
+                .help("Sets the static byte array handed to the allocator, monitored as heap. (default: HEAP_MEM)"),
+        )
+        .arg(
+            Arg::with_name("region")
+                .value_name("NAME=ADDR+LEN|NAME=ADDR..END|NAME=SYMBOL[:stack]")
+                .long("region")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Paints and monitors a custom region like the pool of an arena allocator, e.g. --region pool=20001000+0x800 or --region pool=ARENA. With :stack the region is measured like a stack growing down from its end."),
+        )
+        .arg(
+            Arg::with_name("rtos")
+                .value_name("RTOS")
+                .long("rtos")
+                .takes_value(true)
+                .possible_values(&["freertos"])
+                .help("Measures the stack of every rtos task."),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .value_name("MS|auto")
+                .long("interval")
+                .takes_value(true)
+                .default_value("100")
+                .help("Sets the sampling interval, auto derives it from the measured sample cost."),
+        )
+        .arg(
+            Arg::with_name("max_perturbation")
+                .value_name("PERCENT")
+                .long("max-perturbation")
+                .takes_value(true)
+                .default_value("1")
+                .help("Sets the share of run time the core may be halted for sampling."),
+        )
+        .arg(
+            Arg::with_name("output_format")
+                .value_name("FORMAT")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .default_value("json")
+                .help("Sets the record format, csv is written besides the json record file with the extension csv."),
+        )
+        .arg(
+            Arg::with_name("record_format")
+                .value_name("FORMAT")
+                .long("record-format")
+                .takes_value(true)
+                .possible_values(&["json", "bin"])
+                .default_value("json")
+                .help("Sets the format of the record file, bin is compressed binary for long runs and replaces the extension json with bin. `convert` turns it back into json."),
+        )
+        .arg(
+            Arg::with_name("output")
+                .value_name("PATH")
+                .long("output")
+                .takes_value(true)
+                .default_value(RECORD_FILE)
+                .help("Sets the record file, {timestamp} in the path is replaced with the start time of the run, e.g. records/run-{timestamp}.json."),
+        )
+        .arg(
+            Arg::with_name("label")
+                .value_name("KEY=VALUE")
+                .long("label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Labels the recording, e.g. --label board=revB. The history comparison prefers runs with the same labels."),
+        )
+        .arg(
+            Arg::with_name("note")
+                .value_name("TEXT")
+                .long("note")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Adds a free-form note to the recording, e.g. --note \"heatsink removed\"."),
+        )
+        .arg(
+            Arg::with_name("collapse_records")
+                .long("collapse-records")
+                .takes_value(false)
+                .help("Writes runs of identical consecutive records as spans to shrink the record file."),
+        )
+        .arg(
+            Arg::with_name("no_thumbnail")
+                .long("no-thumbnail")
+                .takes_value(false)
+                .help("Writes no sparkline png of the stack course besides the record file and its copy in the history directory."),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .value_name("NAME")
+                .long("profile")
+                .takes_value(true)
+                .help("Takes the options of the profile [profile.NAME] of the config file, options given on the command line take precedence."),
+        )
+        .arg(
+            Arg::with_name("config")
+                .value_name("PATH")
+                .long("config")
+                .takes_value(true)
+                .env("MEM_ANALYSER_CONFIG")
+                .default_value(profile::CONFIG_FILE)
+                .help("Sets the config file with the profiles."),
+        )
+        .arg(
+            Arg::with_name("flush_every")
+                .value_name("RECORDS")
+                .long("flush-every")
+                .takes_value(true)
+                .default_value("50")
+                .help("Appends the records to a journal besides the record file every RECORDS records, a run which dies keeps them for `recover`. 0 disables the journal."),
+        )
+        .arg(
+            Arg::with_name("max_records")
+                .value_name("RECORDS")
+                .long("max-records")
+                .takes_value(true)
+                .help("Keeps only the last RECORDS records in memory and in the record file, the maxima, median and per function statistics cover the whole run. For runs over days."),
+        )
+        .arg(
+            Arg::with_name("stream")
+                .value_name("TRANSPORT")
+                .long("stream")
+                .takes_value(true)
+                .possible_values(&["tcp", "websocket"])
+                .help("Streams run start, snapshots, statistics and run end as json to connected clients. (default: no server)"),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .value_name("ADDR:PORT")
+                .long("listen")
+                .takes_value(true)
+                .default_value("127.0.0.1:9000")
+                .help("Sets the address the streaming server listens on."),
+        )
+        .arg(
+            Arg::with_name("stream_max_rate")
+                .value_name("BATCHES")
+                .long("stream-max-rate")
+                .takes_value(true)
+                .default_value("20")
+                .help("Sets how many batches of messages per second each streaming client is sent at most."),
+        )
+        .arg(
+            Arg::with_name("influx")
+                .value_name("PATH|URL")
+                .long("influx")
+                .takes_value(true)
+                .help("Writes the records as InfluxDB line protocol to a file or posts them to the write api url (token from INFLUX_TOKEN)."),
+        )
+        .arg(
+            Arg::with_name("run_id")
+                .value_name("ID")
+                .long("run-id")
+                .takes_value(true)
+                .help("Tags the InfluxDB points of this run. (default: start time of the run)"),
+        )
+        .arg(
+            Arg::with_name("mqtt")
+                .value_name("HOST[:PORT]")
+                .long("mqtt")
+                .takes_value(true)
+                .help("Publishes run start, snapshots, statistics and run end to the MQTT broker."),
+        )
+        .arg(
+            Arg::with_name("mqtt_topic")
+                .value_name("PREFIX")
+                .long("mqtt-topic")
+                .takes_value(true)
+                .help("Sets the topic prefix of the published messages. (default: mem-analyser/<device id or chip>)"),
+        )
+        .arg(
+            Arg::with_name("metrics_listen")
+                .value_name("ADDR:PORT")
+                .long("metrics-listen")
+                .takes_value(true)
+                .help("Serves the current stack and heap usage as Prometheus gauges on http://ADDR:PORT/metrics."),
+        )
+        .arg(
+            Arg::with_name("on_reset")
+                .value_name("POLICY")
+                .long("on-reset")
+                .takes_value(true)
+                .possible_values(&["full", "keep-high-water"])
+                .help("Paints the stack again when the target resets during looping mode, everything below the stack pointer or only below the deepest stack of the run. (default: no reset detection)"),
+        )
+        .arg(
+            Arg::with_name("on_exit")
+                .value_name("ACTION")
+                .long("on-exit")
+                .takes_value(true)
+                .possible_values(&["resume", "reset", "halt"])
+                .default_value("resume")
+                .help("Sets what happens to the core when the run ends or is interrupted with Ctrl+C."),
+        )
+        .arg(
+            Arg::with_name("core_dump")
+                .value_name("PATH")
+                .long("core-dump")
+                .takes_value(true)
+                .help("Writes the registers and RAM as ELF core dump, open it with gdb and the firmware."),
+        )
+        .arg(
+            Arg::with_name("core_dump_on")
+                .value_name("WHEN")
+                .long("core-dump-on")
+                .takes_value(true)
+                .possible_values(&["fault", "end"])
+                .requires("core_dump")
+                .help("Sets whether the core dump is written only on a target fault or stack overflow (default), or at the end of every run."),
+        )
+        .arg(
+            Arg::with_name("start_trigger")
+                .value_name("TRIGGER")
+                .long("start-trigger")
+                .takes_value(true)
+                .help("Discards the samples of looping mode until the trigger fires: `stack-bytes=<BYTES>` once the stack ptr offset reaches BYTES, `left-startup` on the first sample outside the startup code."),
+        )
+        .arg(
+            Arg::with_name("start_timeout")
+                .value_name("SECONDS")
+                .long("start-timeout")
+                .takes_value(true)
+                .requires("start_trigger")
+                .help("Ends the run with an error if the start trigger did not fire within SECONDS. (default: waits until stopped)"),
+        )
+        .arg(
+            Arg::with_name("at")
+                .value_name("SAMPLE=COMMAND|SECONDSs=COMMAND")
+                .long("at")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Runs a host command after the given sample or time of the looping measurement, e.g. --at \"100=./power-off.sh\" or --at \"2.5s=./power-on.sh\"."),
+        )
+        .arg(
+            Arg::with_name("tui")
+                .long("tui")
+                .takes_value(false)
+                .help("Shows a live dashboard in looping mode, runs until q is pressed."),
+        )
+        .arg(
+            Arg::with_name("rtt")
+                .long("rtt")
+                .takes_value(false)
+                .help("Records the lines of the RTT up channels between the samples."),
+        )
+        .arg(
+            Arg::with_name("rtt_block")
+                .value_name("ADDR|SYMBOL")
+                .long("rtt-block")
+                .takes_value(true)
+                .requires("rtt")
+                .help("Sets the RTT control block. (default: _SEGGER_RTT)"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .value_name("PATH")
+                .long("trace")
+                .takes_value(true)
+                .help("Writes the timeline as Trace Event json for ui.perfetto.dev."),
+        )
+        .arg(
+            Arg::with_name("flamegraph")
+                .value_name("PATH")
+                .long("flamegraph")
+                .takes_value(true)
+                .help("Writes a flamegraph svg of the sampled call paths, folded stacks for .folded paths."),
+        )
+        .arg(
+            Arg::with_name("flamegraph_weight")
+                .value_name("WEIGHT")
+                .long("flamegraph-weight")
+                .takes_value(true)
+                .possible_values(&["bytes", "samples"])
+                .default_value("bytes")
+                .help("Weights the flamegraph by stack bytes or sample counts."),
+        )
+        .arg(
+            Arg::with_name("safety_factor")
+                .value_name("FACTOR")
+                .long("safety-factor")
+                .takes_value(true)
+                .default_value("1.25")
+                .help("Sets the factor applied to the observed maximum for the recommended stack size."),
+        )
+        .arg(
+            Arg::with_name("max_stack_bytes")
+                .value_name("BYTES")
+                .long("max-stack-bytes")
+                .takes_value(true)
+                .help("Fails the run with exit code 2 when the maximum stack ptr offset exceeds BYTES."),
+        )
+        .arg(
+            Arg::with_name("max_heap_bytes")
+                .value_name("BYTES")
+                .long("max-heap-bytes")
+                .takes_value(true)
+                .help("Fails the run with exit code 2 when the maximum heap usage exceeds BYTES."),
+        )
+        .arg(
+            Arg::with_name("budgets")
+                .value_name("PATH")
+                .long("budgets")
+                .takes_value(true)
+                .help("Fails the run with exit code 2 when a function exceeds its stack budget, lines of the file are `pattern bytes` with `*` as wildcard."),
+        )
+        .arg(
+            Arg::with_name("baseline")
+                .value_name("RECORD_FILE")
+                .long("baseline")
+                .takes_value(true)
+                .help("Fails the run with exit code 2 when the max stack or heap usage grew beyond the tolerance against this recording."),
+        )
+        .arg(
+            Arg::with_name("tolerance")
+                .value_name("PERCENT")
+                .long("tolerance")
+                .takes_value(true)
+                .default_value("5%")
+                .help("Sets the allowed growth against the baseline."),
+        )
+        .arg(
+            Arg::with_name("baseline_warn_only")
+                .long("baseline-warn-only")
+                .takes_value(false)
+                .requires("baseline")
+                .help("Only warns about regressions against the baseline instead of failing."),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .takes_value(false)
+                .help("Overwrites an existing record file and its thumbnail."),
+        )
+        .arg(
+            Arg::with_name("plugin")
+                .value_name("PLUGIN")
+                .long("plugin")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Runs a wasm analysis plugin on the recording."),
+        )
+        .arg(
+            Arg::with_name("html_report")
+                .value_name("HTML_REPORT")
+                .long("html-report")
+                .takes_value(true)
+                .help("Writes an html report with the stack hotspots and their source."),
+        )
+        .arg(
+            Arg::with_name("chart")
+                .value_name("PATH")
+                .long("chart")
+                .takes_value(true)
+                .help("Writes a chart of the stack and memory usage course as .svg or .png."),
+        )
+        .arg(
+            Arg::with_name("remap_path")
+                .value_name("FROM=TO")
+                .long("remap-path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Remaps source paths of the debug info for the html report."),
+        )
+        .arg(su_file_arg())
+}
+
+fn main() {
+    let app = App::new("Stack Analyser")
+        .version("0.1.0")
+        .author("Alexander H. <alex.teamplayer@gmail.com>")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("cache_dir")
+                .value_name("DIR")
+                .long("cache-dir")
+                .env("MEM_ANALYSER_CACHE_DIR")
+                .global(true)
+                .takes_value(true)
+                .help("Sets the directory searched for generated files like the arduino objdump file. (default: the mem-analyser directory in the user cache directory)"),
+        )
+        .arg(
+            Arg::with_name("local_time")
+                .long("local-time")
+                .takes_value(false)
+                .global(true)
+                .help("Shows timestamps in reports in local time instead of UTC."),
+        )
+        .arg(
+            Arg::with_name("errors_json")
+                .value_name("PATH")
+                .long("errors-json")
+                .global(true)
+                .takes_value(true)
+                .help("Writes a json report of the failure class and message on error."),
+        )
+        .subcommand(
+            measure_command("measure")
+                .about("Flashes the firmware and measures the stack and heap usage on the target."),
+        )
+        .subcommand(
+            SubCommand::with_name("flash")
+                .about("Flashes the firmware and lets it run.")
+                .arg(firmware_path_arg())
+                .arg(probe_arg())
+                .arg(chip_arg())
+                .arg(core_arg())
+                .arg(non_interactive_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Recomputes the statistics of a recording without a target, prints them and writes a self-contained html report.")
+                .arg(
+                    Arg::with_name("record_file")
+                        .value_name("RECORD_FILE")
+                        .default_value(RECORD_FILE),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("PATH")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value(REPORT_FILE),
+                )
+                .arg(
+                    Arg::with_name("top")
+                        .value_name("N")
+                        .long("top")
+                        .takes_value(true)
+                        .help("Sets the number of stack hotspots in the report."),
+                )
+                .arg(
+                    Arg::with_name("statistics")
+                        .value_name("PATH")
+                        .long("statistics")
+                        .takes_value(true)
+                        .help("Writes the recomputed statistics as json."),
+                )
+                .arg(
+                    Arg::with_name("chart")
+                        .value_name("PATH")
+                        .long("chart")
+                        .takes_value(true)
+                        .help("Writes a chart of the stack and memory usage course as .svg or .png."),
+                )
+                .arg(
+                    Arg::with_name("no_html")
+                        .long("no-html")
+                        .takes_value(false)
+                        .help("Only prints and exports the statistics, without the html report."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compares the stack usage of two recordings, e.g. before and after a firmware change.")
+                .arg(
+                    Arg::with_name("old_record_file")
+                        .value_name("OLD_RECORD_FILE")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new_record_file")
+                        .value_name("NEW_RECORD_FILE")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("aggregate")
+                .about("Reports the spread of the max stack and heap usage over repeated runs of the same firmware and merges their statistics.")
+                .arg(
+                    Arg::with_name("record_files")
+                        .value_name("RECORD_FILE")
+                        .multiple(true)
+                        .min_values(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("tolerance")
+                        .value_name("PERCENT")
+                        .long("tolerance")
+                        .takes_value(true)
+                        .default_value("5%")
+                        .help("Sets the spread between runs above which a maximum is flagged as non-deterministic."),
+                )
+                .arg(
+                    Arg::with_name("merge")
+                        .value_name("PATH")
+                        .long("merge")
+                        .takes_value(true)
+                        .help("Writes the statistics of all runs merged as json, with the record file, start time, device and labels of every run and the run each maximum was measured in."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bisect")
+                .about("Measures a sequence of firmware builds in binary search for the first one whose max stack exceeds a threshold.")
+                .arg(
+                    Arg::with_name("builds_dir")
+                        .value_name("BUILDS_DIR")
+                        .required_unless("revs")
+                        .conflicts_with("revs")
+                        .help("Directory of firmware builds, ordered by file name."),
+                )
+                .arg(
+                    Arg::with_name("revs")
+                        .value_name("GOOD..BAD")
+                        .long("revs")
+                        .takes_value(true)
+                        .requires_all(&["build_command", "artifact"])
+                        .help("Sets the git revisions to bisect, each one built with the build command."),
+                )
+                .arg(
+                    Arg::with_name("build_command")
+                        .value_name("COMMAND")
+                        .long("build-command")
+                        .takes_value(true)
+                        .help("Sets the shell command building a revision, {rev} is replaced with it, e.g. `git checkout {rev} && cargo build --release`."),
+                )
+                .arg(
+                    Arg::with_name("artifact")
+                        .value_name("PATH")
+                        .long("artifact")
+                        .takes_value(true)
+                        .help("Sets the firmware the build command produces."),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .value_name("BYTES")
+                        .long("threshold")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Sets the max stack ptr offset above which a build is bad."),
+                )
+                .arg(
+                    Arg::with_name("record_dir")
+                        .value_name("DIR")
+                        .long("record-dir")
+                        .takes_value(true)
+                        .default_value("bisect")
+                        .help("Sets the directory the recordings of the measured builds are written to."),
+                )
+                .arg(
+                    Arg::with_name("measure_args")
+                        .value_name("MEASURE_ARGS")
+                        .multiple(true)
+                        .last(true)
+                        .help("Options of `measure` for every build, e.g. `-- --chip nRF52840_xxAA -m looping`."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Converts a stack report of another tool into a recording, to compare it with measured runs.")
+                .arg(
+                    Arg::with_name("report_file")
+                        .value_name("REPORT_FILE")
+                        .required(true)
+                        .help("Csv or html report with the function in the first column."),
+                )
+                .arg(
+                    Arg::with_name("stack_column")
+                        .value_name("NAME")
+                        .long("stack-column")
+                        .takes_value(true)
+                        .default_value("stack")
+                        .help("Sets the header of the column with the worst case stack bytes, e.g. `Max cost` for STM32CubeIDE exports."),
+                )
+                .arg(
+                    Arg::with_name("size_output")
+                        .value_name("PATH")
+                        .long("size")
+                        .takes_value(true)
+                        .help("Sets the output of arm-none-eabi-size to take the static ram from."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("PATH")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value(RECORD_FILE),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .takes_value(false)
+                        .help("Overwrites an existing record file."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("recover")
+                .about("Rebuilds the record file of a run which died from the journal it left behind.")
+                .arg(
+                    Arg::with_name("journal")
+                        .value_name("JOURNAL")
+                        .required(true)
+                        .help("Journal of the run, the record file path with the extension jsonl."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("PATH")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Sets the record file, by default the journal path with the extension json."),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .takes_value(false)
+                        .help("Overwrites an existing record file."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts a record file between the json and the binary record format.")
+                .arg(
+                    Arg::with_name("record_file")
+                        .value_name("RECORD_FILE")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .value_name("FORMAT")
+                        .long("to")
+                        .takes_value(true)
+                        .possible_values(&["json", "bin"])
+                        .default_value("json")
+                        .help("Sets the format to convert to."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("PATH")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Sets the converted record file, by default the record file with the extension of the format."),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .takes_value(false)
+                        .help("Overwrites an existing record file."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("static")
+                .about("Prints the static worst case stack per entry point of the firmware, without a target.")
+                .arg(firmware_path_arg())
+                .arg(language_arg())
+                .arg(asm_file_arg())
+                .arg(su_file_arg()),
+        )
+        .subcommand(
+            measure_command("serve")
+                .about("Measures in looping mode until Ctrl+C and streams to connected clients, over WebSocket unless --stream is given."),
+        )
+        .subcommand(
+            SubCommand::with_name("list-probes").about("Lists the connected debug probes."),
+        );
+
+    let matches = app.clone().get_matches();
+    // the options of a profile are appended to the command line, they are validated
+    // like given ones
+    let profile_args = match matches.subcommand() {
+        (_, Some(matches)) => profile::selected_args(matches),
+        _ => Ok(Vec::new()),
+    };
+    let matches = match profile_args.as_ref() {
+        Ok(args) if !args.is_empty() => {
+            app.get_matches_from(std::env::args_os().chain(args.iter().map(OsString::from)))
+        }
+        _ => matches,
+    };
+
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(matches)) => (command, matches),
+        _ => unreachable!(),
+    };
+    let result = match profile_args {
+        Err(e) => Err(e.into()),
+        Ok(_) => match command {
+            "measure" => run(matches, false),
+            "serve" => run(matches, true),
+            "flash" => run_flash(matches),
+            "report" => run_report(matches),
+            "diff" => run_diff(matches),
+            "aggregate" => run_aggregate(matches),
+            "bisect" => run_bisect(matches),
+            "import" => run_import(matches),
+            "recover" => run_recover(matches),
+            "convert" => run_convert(matches),
+            "static" => run_static(matches),
+            "list-probes" => run_list_probes(),
+            _ => unreachable!(),
+        },
+    };
+    if let Err(e) = result {
+        let code = exit::ExitCode::of(e.as_ref());
+        eprintln!("error: {}", e);
+        if let Some(path) = matches.value_of("errors_json") {
+            if let Err(e) = exit::write_error_report(Path::new(path), code, e.as_ref()) {
+                eprintln!("could not write error report: {}", e);
+            }
+        }
+        std::process::exit(code as i32);
+    }
+}
+
+/// Platform cache directory of the user, the temp directory if there is none.
+fn cache_dir(matches: &ArgMatches) -> PathBuf {
+    match matches.value_of("cache_dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("mem-analyser"),
+    }
+}
+
+fn time_zone(matches: &ArgMatches) -> timestamp::TimeZone {
+    if matches.is_present("local_time") {
+        timestamp::TimeZone::Local
+    } else {
+        timestamp::TimeZone::Utc
+    }
+}
+
+/// `--tolerance` as a fraction, given in percent with or without a `%` sign.
+fn tolerance(matches: &ArgMatches) -> DynError<f32> {
+    Ok(matches
+        .value_of("tolerance")
+        .unwrap()
+        .trim_end_matches('%')
+        .parse::<f32>()?
+        / 100.0)
+}
+
+fn print_statistics(statistics: &statistics::RamStatistics) {
+    println!("{:?}", statistics);
+    if let Some(sampling) = statistics.sampling.as_ref() {
+        println!("sampling: {}", sampling);
+    }
+    if let Some(overhead) = statistics.overhead.as_ref() {
+        println!("overhead: {}", overhead);
+    }
+    for priority in statistics.priorities.iter() {
+        println!("{}", priority);
+    }
+}
+
+/// The statistics are recomputed from the records, so a recording of an older version
+/// gets the metrics added since.
+fn run_report(matches: &ArgMatches) -> DynError<()> {
+    let record_path = matches.value_of("record_file").unwrap();
+    let recorder = session::load_recording(Path::new(record_path))?;
+    if recorder.is_empty() {
+        return Err(exit::Failure::Config(format!("{} contains no records", record_path)).into());
+    }
+    let statistics = StatisticsEngine::new(&recorder).calculate();
+    print_statistics(&statistics);
+    for finding in recorder.findings() {
+        println!("{}", finding);
+    }
+    if let Some(path) = matches.value_of("statistics") {
+        std::fs::write(path, serde_json::to_string_pretty(&statistics)?)?;
+        println!("statistics written to {}", path);
+    }
+    if let Some(path) = matches.value_of("chart") {
+        chart::write_chart(Path::new(path), &statistics)?;
+        println!("chart written to {}", path);
+    }
+    if matches.is_present("no_html") {
+        return Ok(());
+    }
+
+    let options = report::ReportOptions {
+        hotspot_count: matches
+            .value_of("top")
+            .map(|n| n.parse())
+            .transpose()?
+            .unwrap_or(report::HOTSPOT_COUNT),
+        time_zone: time_zone(matches),
+    };
+    let path = Path::new(matches.value_of("output").unwrap());
+    report::write_html_report(path, &recorder, None, None, &options)?;
+    println!("report written to {}", path.display());
+
+    Ok(())
+}
+
+fn run_diff(matches: &ArgMatches) -> DynError<()> {
+    let load = |name| -> DynError<_> {
+        let path = matches.value_of(name).unwrap();
+        let recorder = session::load_recording(Path::new(path))?;
+        if recorder.is_empty() {
+            return Err(exit::Failure::Config(format!("{} contains no records", path)).into());
+        }
+        Ok(recorder)
+    };
+    let old = load("old_record_file")?;
+    let new = load("new_record_file")?;
+    diff::RecordingDiff::new(&old, &new).print();
+
+    Ok(())
+}
+
+fn run_aggregate(matches: &ArgMatches) -> DynError<()> {
+    let paths = matches
+        .values_of("record_files")
+        .unwrap()
+        .collect::<Vec<_>>();
+    let mut recordings: Vec<mem_monitoring::RamSnapshotRecorder> = Vec::new();
+    for path in paths.iter() {
+        let recorder = session::load_recording(Path::new(path))?;
+        if recorder.is_empty() {
+            return Err(exit::Failure::Config(format!("{} contains no records", path)).into());
+        }
+        if let Some(first) = recordings.first() {
+            if !recorder.session().is_same_firmware(first.session()) {
+                return Err(exit::Failure::Config(format!(
+                    "{} is a recording of another firmware or core",
+                    path
+                ))
+                .into());
+            }
+        }
+        recordings.push(recorder);
+    }
+    aggregate::Aggregate::new(&recordings).print(tolerance(matches)?);
+    let merged = aggregate::MergedStatistics::new(&paths, &recordings);
+    merged.print();
+    if let Some(path) = matches.value_of("merge") {
+        std::fs::write(path, serde_json::to_string_pretty(&merged)?)?;
+        println!("merged statistics written to {}", path);
+    }
+
+    Ok(())
+}
+
+fn run_bisect(matches: &ArgMatches) -> DynError<()> {
+    let builds = match matches.value_of("revs") {
+        Some(range) => bisect::revisions(range)?,
+        None => bisect::builds_in(Path::new(matches.value_of("builds_dir").unwrap()))?,
+    };
+    let bisector = bisect::Bisector {
+        build_command: matches.value_of("build_command").map(String::from),
+        artifact: PathBuf::from(matches.value_of("artifact").unwrap_or_default()),
+        measure_args: matches
+            .values_of("measure_args")
+            .map(|args| args.map(String::from).collect())
+            .unwrap_or_default(),
+        record_dir: PathBuf::from(matches.value_of("record_dir").unwrap()),
+    };
+    let threshold = matches.value_of("threshold").unwrap().parse()?;
+    let first_bad = bisector.run(&builds, threshold)?;
+    println!(
+        "first build above {} bytes: {}{}",
+        threshold,
+        builds[first_bad],
+        match first_bad {
+            0 => String::new(),
+            _ => format!(" (last good: {})", builds[first_bad - 1]),
+        }
+    );
+
+    Ok(())
+}
+
+fn run_import(matches: &ArgMatches) -> DynError<()> {
+    let report_path = Path::new(matches.value_of("report_file").unwrap());
+    let record_path = Path::new(matches.value_of("output").unwrap());
+    if record_path.exists() && !matches.is_present("force") {
+        return Err(exit::Failure::Config(format!(
+            "{} already exists, use --force to overwrite",
+            record_path.display()
+        ))
+        .into());
+    }
+
+    let stack_column = matches.value_of("stack_column").unwrap();
+    let is_html = report_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm"));
+    let functions = match is_html {
+        true => import::read_html_report(report_path, stack_column)?,
+        false => import::read_csv_report(report_path, stack_column)?,
+    };
+    if functions.is_empty() {
+        return Err(exit::Failure::Config(format!(
+            "{} contains no functions",
+            report_path.display()
+        ))
+        .into());
+    }
+    let static_ram_size = matches
+        .value_of("size_output")
+        .map(|p| import::read_size_output(Path::new(p)))
+        .transpose()?
+        .unwrap_or(0);
+
+    let mut session_info = session::SessionInfo::new(
+        &std::fs::read(report_path)?,
+        format!("imported from {}", report_path.display()),
+    );
+    session_info.firmware_name = report_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(String::from);
+    let recorder = import::to_recording(session_info, &functions, static_ram_size);
+    std::fs::write(record_path, serde_json::to_string(&recorder)?)?;
+    println!(
+        "imported {} functions into {}",
+        functions.len(),
+        record_path.display()
+    );
+
+    Ok(())
+}
+
+fn run_convert(matches: &ArgMatches) -> DynError<()> {
+    let input_path = Path::new(matches.value_of("record_file").unwrap());
+    let format = RecordFormat::from_name(matches.value_of("to").unwrap())?;
+    let output_path = match matches.value_of("output") {
+        Some(path) => PathBuf::from(path),
+        None => input_path.with_extension(format.extension()),
+    };
+    if output_path.exists() && !matches.is_present("force") {
+        return Err(exit::Failure::Config(format!(
+            "{} already exists, use --force to overwrite",
+            output_path.display()
+        ))
+        .into());
+    }
+
+    let recorder = session::load_recording(input_path)?;
+    record_format::write(&output_path, &recorder, format)?;
+    println!(
+        "converted {} records into {} ({} bytes)",
+        recorder.timeline().count(),
+        output_path.display(),
+        std::fs::metadata(&output_path)?.len()
+    );
+
+    Ok(())
+}
+
+fn run_recover(matches: &ArgMatches) -> DynError<()> {
+    let journal_path = Path::new(matches.value_of("journal").unwrap());
+    let record_path = match matches.value_of("output") {
+        Some(path) => PathBuf::from(path),
+        None => journal_path.with_extension("json"),
+    };
+    if record_path.exists() && !matches.is_present("force") {
+        return Err(exit::Failure::Config(format!(
+            "{} already exists, use --force to overwrite",
+            record_path.display()
+        ))
+        .into());
+    }
+
+    let recorder = journal::recover(journal_path)?;
+    std::fs::write(&record_path, serde_json::to_string(&recorder)?)?;
+    println!(
+        "recovered {} records into {}",
+        recorder.timeline().count(),
+        record_path.display()
+    );
+
+    Ok(())
+}
+
+fn run_flash(matches: &ArgMatches) -> DynError<()> {
+    let interactive = !matches.is_present("non_interactive");
+    let probe = selection::open_probe(matches.value_of("probe"), interactive)?;
+    let chip = selection::resolve_chip(matches.value_of("chip").unwrap(), interactive)?;
+    let session = Mutex::new(probe.attach(chip)?);
+    let core_index: usize = matches.value_of("core").unwrap().parse()?;
+    let mut cpu = cpu::CPU::new(session.lock().unwrap(), core_index);
+
+    println!("start flashing");
+    cpu.flash(File::open(matches.value_of("firmware_path").unwrap())?)?;
+    cpu.run()?;
+    println!("flashed");
+
+    Ok(())
+}
+
+fn run_static(matches: &ArgMatches) -> DynError<()> {
+    let file = read_bin_file(matches.value_of("firmware_path").unwrap())?;
+    let obj_file = object::File::parse(file.as_slice())?;
+    let language = match matches.value_of("language").unwrap() {
+        "cpp" => demangle::Language::Cpp,
+        _ => demangle::Language::Rust,
+    };
+    for warning in elf::DebugInfoQuality::of(&obj_file).warnings(language) {
+        println!(
+            "{}",
+            findings::Finding::new(
+                findings::Severity::Warning,
+                findings::Category::DebugInfo,
+                warning
+            )
+        );
+    }
+
+    let su_paths = matches
+        .values_of("su_file")
+        .map(|v| v.map(Path::new).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let frame_sizes = stack_usage::FrameSizes::from_files(&su_paths)?;
+    let asm_file_path = matches.value_of("asm_file").map(PathBuf::from);
+    let asm_file = asm_parsing::AsmFile::load(&obj_file, asm_file_path.as_deref(), language)?;
+
+    for depth in call_graph::CallGraph::new(&asm_file, &frame_sizes).worst_case_stack() {
+        println!(
+            "{}: {} bytes{}",
+            depth.entry,
+            depth.bytes,
+            if depth.is_lower_bound() {
+                " (lower bound)"
+            } else {
+                ""
+            }
+        );
+        println!("  {}", depth.path.join(" -> "));
+    }
+
+    Ok(())
+}
+
+fn run_list_probes() -> DynError<()> {
+    let probes = selection::list_probes();
+    if probes.is_empty() {
+        println!("no debug probe found");
+    }
+    for probe in probes {
+        println!("{}", probe);
+    }
+
+    Ok(())
+}
+
+/// Prints `message`, into the log pane of the dashboard while it owns the terminal.
+fn print_message(dashboard: Option<&mut tui::Dashboard>, message: String) {
+    match dashboard {
+        Some(dashboard) => dashboard.log(message),
+        None => println!("{}", message),
+    }
+}
+
+/// Everything besides the snapshot recorded per sample of a run, and where the sample
+/// goes once recorded.
+struct SampleOutputs<'a> {
+    heap_range: Option<&'a Range<u32>>,
+    fill_pattern: paint::FillPattern,
+    rtos: Option<&'a rtos::FreeRtos>,
+    named_regions: &'a [regions::NamedRegion],
+    journal: Option<&'a mut journal::Journal>,
+    metrics_server: Option<&'a metrics::MetricsServer>,
+    sinks: &'a mut [Box<dyn streaming::Sink>],
+}
+
+impl SampleOutputs<'_> {
+    /// Records `ram` with the heap, tasks and regions of the halted core and passes it
+    /// on to the journal, the metrics server and the sinks. Returns the id of the record.
+    fn record(
+        &mut self,
+        cpu: &mut cpu::CPU,
+        recorder: &mut RamSnapshotRecorder,
+        ram: mem_monitoring::RamSnapshot,
+        timing: Option<mem_monitoring::SampleTiming>,
+    ) -> DynError<usize> {
+        let heap = self
+            .heap_range
+            .map(|r| monitor_heap(cpu, r, self.fill_pattern))
+            .transpose()?;
+        let id = recorder.record(ram, heap);
+        if let Some(timing) = timing {
+            recorder.record_timing(timing);
+        }
+        if let Some(rtos) = self.rtos {
+            recorder.record_tasks(rtos.task_snapshots(cpu)?);
+        }
+        if !self.named_regions.is_empty() {
+            let usage = regions::measure_regions(cpu, self.named_regions, self.fill_pattern)?;
+            recorder.record_regions(self.named_regions, usage);
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.update(recorder)?;
+        }
+        if let Some(server) = self.metrics_server {
+            server.update(recorder, id);
+        }
+        let cycles = recorder.sample_time(id).and_then(|t| t.cycles);
+        for sink in self.sinks.iter_mut() {
+            sink.distribute(&streaming::StreamMessage::Snapshot {
+                id: recorder.evicted_records() + id,
+                timestamp: timestamp::to_iso8601(&recorder.record_time(id)),
+                cycles,
+                snapshot: recorder.get_snapshot(id).unwrap(),
+            })?;
+        }
+
+        Ok(id)
+    }
+}
+
+/// Runs a measurement, `serve` measures in looping mode until interrupted and streams
+/// over WebSocket by default.
+fn run(matches: &ArgMatches, serve: bool) -> DynError<()> {
+    interrupt::install_handler()?;
+    let elf_path = matches.value_of("firmware_path").ok_or_else(|| {
+        exit::Failure::Config(String::from(
+            "no firmware, give it with -f or as firmware-path in the profile",
+        ))
+    })?;
+    let is_cpp = matches.value_of("language").unwrap() == "cpp";
+    let should_flash = !matches.is_present("no_flash");
+    let analyse_mode = match matches.value_of("mode").unwrap() {
+        "stepping" => AnalyseMode::Stepping,
+        "looping" => AnalyseMode::Looping,
+        "single-shot" => AnalyseMode::SingleShot,
+        "loop-measure" => AnalyseMode::LoopMeasure,
+        "alloc-tracking" => AnalyseMode::AllocTracking,
+        "overflow-guard" => AnalyseMode::OverflowGuard,
+        "markers" => AnalyseMode::Markers,
+        _ => unreachable!(),
+    };
+    if serve && !matches!(analyse_mode, AnalyseMode::Looping) {
+        return Err(
+            exit::Failure::Config(String::from("serve only measures in looping mode")).into(),
+        );
+    }
+
+    let marker_imms = matches
+        .values_of("marker_bkpt")
+        .map(|v| v.map(parse_imm).collect::<Result<Vec<_>, _>>())
+        .transpose()?
+        .unwrap_or_default();
+    // without designated values every bkpt besides semihosting is a marker
+    let is_marker = |imm: u8| {
+        if marker_imms.is_empty() {
+            imm != SEMIHOSTING_BKPT
+        } else {
+            marker_imms.contains(&imm)
+        }
+    };
+
+    let start_instr_addr: Option<u32> = matches
+        .value_of("start_addr")
+        .map(|s| u32::from_str_radix(s, 16).unwrap());
+
+    let interval = match matches.value_of("interval").unwrap() {
+        "auto" => None,
+        ms => match ms.parse()? {
+            0 => {
+                return Err(exit::Failure::Config(String::from(
+                    "the sampling interval has to be at least 1 ms",
+                ))
+                .into())
+            }
+            ms => Some(Duration::from_millis(ms)),
+        },
+    };
+    let time_zone = time_zone(matches);
+    let safety_factor: f32 = matches.value_of("safety_factor").unwrap().parse()?;
+    let max_stack_bytes: Option<u32> = matches
+        .value_of("max_stack_bytes")
+        .map(|b| b.parse())
+        .transpose()?;
+    let max_heap_bytes: Option<u32> = matches
+        .value_of("max_heap_bytes")
+        .map(|b| b.parse())
+        .transpose()?;
+    let baseline_statistics = match matches.value_of("baseline") {
+        Some(path) => {
+            let baseline = session::load_recording(Path::new(path))?;
+            if baseline.is_empty() {
+                return Err(exit::Failure::Config(format!("{} contains no records", path)).into());
+            }
+            Some(StatisticsEngine::new(&baseline).calculate())
+        }
+        None => None,
+    };
+    let tolerance = tolerance(matches)?;
+    let function_budgets = matches
+        .value_of("budgets")
+        .map(|p| budget::FunctionBudgets::from_file(Path::new(p)))
+        .transpose()?;
+    let max_perturbation: f32 = matches.value_of("max_perturbation").unwrap().parse()?;
+    if !(max_perturbation > 0.0 && max_perturbation < 100.0) {
+        return Err(exit::Failure::Config(format!(
+            "the max perturbation has to be above 0 and below 100 percent, not {}",
+            max_perturbation
+        ))
+        .into());
+    }
+
+    let html_report_path = matches.value_of("html_report").map(Path::new);
+    let chart_path = matches.value_of("chart").map(Path::new);
+    let remaps = matches
+        .values_of("remap_path")
+        .map(|v| v.map(source::parse_remap).collect::<Result<Vec<_>, _>>())
+        .transpose()?
+        .unwrap_or_default();
+
+    let force = matches.is_present("force");
+    let plugin_paths = matches
+        .values_of("plugin")
+        .map(|v| v.map(Path::new).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let file = read_bin_file(elf_path)?;
+    let obj_file = object::File::parse(file.as_slice())?;
+
+    let config = CONFIG_ARGS
+        .iter()
+        .filter_map(|a| matches.value_of(a).map(|v| format!("{}={}", a, v)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut session_info = session::SessionInfo::new(file.as_slice(), config);
+    session_info.build_id = obj_file.build_id()?.map(hex::encode);
+    session_info.firmware_name = Path::new(elf_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(String::from);
+    session_info.core = matches.value_of("core").unwrap().parse()?;
+    session_info.fill_pattern =
+        paint::FillPattern::parse(matches.value_of("fill_pattern").unwrap())?;
+    let fill_pattern = session_info.fill_pattern;
+    for label in matches.values_of("label").into_iter().flatten() {
+        let (key, value) = label
+            .split_once('=')
+            .filter(|(key, _)| !key.trim().is_empty())
+            .ok_or_else(|| {
+                exit::Failure::Config(format!("invalid label {}, expected KEY=VALUE", label))
+            })?;
+        session_info
+            .labels
+            .insert(String::from(key.trim()), String::from(value.trim()));
+    }
+    session_info.notes = matches
+        .values_of("note")
+        .map(|notes| notes.map(String::from).collect())
+        .unwrap_or_default();
+    if !session_info.labels.is_empty() {
+        println!("labels: {}", session_info.labels_text());
+    }
+
+    let mut record_path = PathBuf::from(matches.value_of("output").unwrap().replace(
+        TIMESTAMP_PLACEHOLDER,
+        &session_info.timestamp.format("%Y%m%dT%H%M%S").to_string(),
+    ));
+    let record_format = RecordFormat::from_name(matches.value_of("record_format").unwrap())?;
+    if record_format == RecordFormat::Bin
+        && record_path.extension().and_then(|e| e.to_str()) == Some("json")
+    {
+        record_path.set_extension(record_format.extension());
+    }
+
+    // a run of the same firmware and config is only compared against if confirmed, the
+    // non-interactive run compares against it without asking
+    let interactive = !matches.is_present("non_interactive");
+    let confirm_duplicate = |previous: &mem_monitoring::RamSnapshotRecorder, path: &Path| {
+        if !interactive {
+            return true;
+        }
+        println!(
+            "same firmware and config already measured at {} ({}), compare against it? [y/N]",
+            time_zone.format(&previous.session().timestamp),
+            path.display()
+        );
+        let answer: String = read!("{}\n");
+        answer.trim().eq_ignore_ascii_case("y")
+    };
+    let mut previous_statistics = None;
+    let mut previous_max_used_bytes = None;
+    if record_path.exists() {
+        if !force {
+            return Err(exit::Failure::Config(format!(
+                "{} already exists, use --force to overwrite",
+                record_path.display()
+            ))
+            .into());
+        }
+        if let Ok(previous) = session::load_recording(&record_path) {
+            previous_max_used_bytes = StatisticsEngine::new(&previous).max_used_bytes();
+            if session_info.is_duplicate_of(previous.session())
+                && confirm_duplicate(&previous, &record_path)
+            {
+                previous_statistics = Some(StatisticsEngine::new(&previous).calculate());
+            }
+        }
+    }
+
+    let history_dir = match matches.value_of("history_dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => cache_dir(matches).join(HISTORY_DIR),
+    };
+    let use_history = !matches.is_present("no_history");
+    let flush_every: usize = matches.value_of("flush_every").unwrap().parse()?;
+    let max_records = matches
+        .value_of("max_records")
+        .map(str::parse::<usize>)
+        .transpose()?;
+    let thumbnail = !matches.is_present("no_thumbnail");
+    if thumbnail {
+        let thumbnail_path = record_path.with_extension(THUMBNAIL_EXTENSION);
+        if chart_path == Some(thumbnail_path.as_path()) {
+            return Err(exit::Failure::Config(format!(
+                "--chart {} is the thumbnail of the record file, pick another path or use --no-thumbnail",
+                thumbnail_path.display()
+            ))
+            .into());
+        }
+        if thumbnail_path.exists() && !force {
+            return Err(exit::Failure::Config(format!(
+                "{} already exists, use --force to overwrite",
+                thumbnail_path.display()
+            ))
+            .into());
+        }
+    }
+    let journal_path =
+        Some(record_path.with_extension(journal::EXTENSION)).filter(|_| flush_every > 0);
+    if use_history && previous_statistics.is_none() {
+        if let Some(path) = session::find_previous(&history_dir, &session_info) {
+            if let Ok(previous) = session::load_recording(&path) {
+                let compare = !previous.is_empty()
+                    && (!session_info.is_duplicate_of(previous.session())
+                        || confirm_duplicate(&previous, &path));
+                if compare {
+                    println!(
+                        "comparing against the run of {} ({})",
+                        time_zone.format(&previous.session().timestamp),
+                        path.display()
+                    );
+                    previous_statistics = Some(StatisticsEngine::new(&previous).calculate());
+                }
+            }
+        }
+    }
+
+    let arduino_asm_file = cache_dir(matches).join(ARDUINO_ASM_FILE);
+    let asm_file_path = match matches.value_of("asm_file") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if is_cpp && arduino_asm_file.exists() => Some(arduino_asm_file),
+        None => None,
+    };
+    let language = if is_cpp {
+        demangle::Language::Cpp
+    } else {
+        demangle::Language::Rust
+    };
+    let mut debug_info_findings = Vec::new();
+    for warning in elf::DebugInfoQuality::of(&obj_file).warnings(language) {
+        let finding = findings::Finding::new(
+            findings::Severity::Warning,
+            findings::Category::DebugInfo,
+            warning,
+        );
+        println!("{}", finding);
+        debug_info_findings.push(finding);
+    }
+
+    let source_resolver = match source::SourceResolver::new(&obj_file, remaps) {
+        Ok(r) => Some(r),
+        Err(e) => {
+            println!("no source info: {}", e);
+            None
+        }
+    };
+
+    let is_riscv = obj_file.architecture() == object::Architecture::Riscv32;
+    // the unwind rules address the arm dwarf registers
+    let unwinder = if is_riscv {
+        None
+    } else {
+        unwind::Unwinder::new(&obj_file)
+    };
+
+    let su_paths = matches
+        .values_of("su_file")
+        .map(|v| v.map(Path::new).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let frame_sizes = stack_usage::FrameSizes::from_files(&su_paths)?;
+
+    // a broken disassembly must not block the measurement, snapshots are then
+    // attributed to their address only
+    let asm_file = match asm_parsing::AsmFile::load(&obj_file, asm_file_path.as_deref(), language) {
+        Ok(asm_file) => Some(asm_file),
+        Err(e) => {
+            let finding = findings::Finding::new(
+                findings::Severity::Warning,
+                findings::Category::DebugInfo,
+                format!(
+                    "no disassembly available ({}), functions are shown as addresses",
+                    e
+                ),
+            );
+            println!("{}", finding);
+            debug_info_findings.push(finding);
+            None
+        }
+    };
+
+    // the looping modes are anchored at the main loop unless told otherwise, attaching
+    // without painting keeps the target running as it is
+    let auto_start = start_instr_addr.is_none();
+    let start_instr_addr = match start_instr_addr {
+        None if matches!(
+            analyse_mode,
+            AnalyseMode::Looping | AnalyseMode::LoopMeasure
+        ) && !matches.is_present("no_paint")
+            && !matches.is_present("no_auto_start") =>
+        {
+            match asm_file.as_ref().and_then(|asm_file| asm_file.main_loop()) {
+                Some((function, addr)) => {
+                    println!(
+                        "starting at the main loop in {} at {:#010x} (--start-addr to override)",
+                        function, addr
+                    );
+                    Some(addr)
+                }
+                None => None,
+            }
+        }
+        start_instr_addr => start_instr_addr,
+    };
+
+    // println!(
+    //     "{:?}",
+    //     asm_file
+    //         .get_subfunctions_of_function(&"loop")
+    //         .unwrap()
+    //         .iter()
+    //         .map(|f| f.name.to_owned())
+    //         .collect::<Vec<_>>()
+    // );
+    // return Ok(());
+
+    // risc-v has no vector table, the runtime sets the sp from a linker symbol and traps
+    // enter through the single mtvec handler
+    let vector_table = if is_riscv {
+        None
+    } else {
+        obj_file
+            .section_by_name(if !is_cpp {
+                ".vector_table"
+            } else {
+                ".isr_vector"
+            })
+            .map(|s| s.data())
+            .transpose()?
+            .map(|data| {
+                data.chunks_exact(4)
+                    .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+                    .collect::<Vec<_>>()
+            })
     };
-    let should_flash = match matches.value_of("no_flash") {
-        Some(_) => false,
-        None => true,
+    // handlers after the initial stack pointer and the reset handler
+    let mut exception_handlers = vector_table
+        .iter()
+        .flat_map(|t| t.iter().skip(2))
+        .filter(|h| **h != 0)
+        .map(|h| h & !1)
+        .collect::<Vec<_>>();
+    let stack_start = matches
+        .value_of("stack_start")
+        .map(|v| elf::resolve_addr(&obj_file, v))
+        .transpose()?;
+    let initial_stack_ptr = match (stack_start, vector_table.as_ref().and_then(|t| t.first())) {
+        (Some(stack_start), _) => stack_start,
+        (None, Some(initial_stack_ptr)) => *initial_stack_ptr,
+        // stripped vector table or non-standard linker script
+        (None, None) => elf::stack_start(&obj_file)?,
     };
-    let analyse_mode = match matches.value_of("mode").unwrap() {
-        "stepping" => AnalyseMode::Stepping,
-        "looping" => AnalyseMode::Looping,
-        "single-shot" => AnalyseMode::SingleShot,
-        "loop-measure" => AnalyseMode::LoopMeasure,
-        _ => unreachable!(),
+    exception_handlers.sort_unstable();
+    exception_handlers.dedup();
+
+    let scan_top = matches
+        .value_of("scan_top")
+        .map(|v| elf::resolve_addr(&obj_file, v))
+        .transpose()?;
+    let scan_bottom = matches
+        .value_of("scan_bottom")
+        .map(|v| elf::resolve_addr(&obj_file, v))
+        .transpose()?;
+
+    let mut start_trigger = matches
+        .value_of("start_trigger")
+        .map(trigger::parse)
+        .transpose()?;
+    let start_timeout = matches
+        .value_of("start_timeout")
+        .map(|secs| {
+            secs.parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .filter(|timeout| !timeout.is_zero())
+                .ok_or_else(|| {
+                    exit::Failure::Config(format!(
+                        "invalid --start-timeout {}, expected positive seconds",
+                        secs
+                    ))
+                })
+        })
+        .transpose()?;
+    let start_condition = matches
+        .value_of("start_condition")
+        .map(|c| condition::Condition::parse(c, &obj_file))
+        .transpose()?;
+
+    let guard_addr = matches
+        .value_of("guard_addr")
+        .map(|v| elf::resolve_addr(&obj_file, v))
+        .transpose()?;
+
+    let process_stack = match (matches.value_of("psp_bottom"), matches.value_of("psp_top")) {
+        (Some(bottom), Some(top)) => {
+            Some(elf::resolve_addr(&obj_file, bottom)?..elf::resolve_addr(&obj_file, top)?)
+        }
+        _ => None,
     };
 
-    let start_instr_addr: Option<u32> = matches
-        .value_of("start_addr")
-        .and_then(|s| Some(u32::from_str_radix(s, 16).unwrap()));
+    let rtos = match matches.value_of("rtos") {
+        Some(_) => Some(
+            rtos::FreeRtos::from_elf(&obj_file)
+                .ok_or("no FreeRTOS task lists found in obj file")?,
+        ),
+        None => None,
+    };
 
-    let file = read_bin_file(elf_path)?;
-    let obj_file = object::File::parse(file.as_slice())?;
+    let custom_regions = matches
+        .values_of("region")
+        .into_iter()
+        .flatten()
+        .map(|spec| regions::NamedRegion::parse(spec, &obj_file))
+        .collect::<DynError<Vec<_>>>()?;
+    let mut named_regions = regions::discover_regions(&obj_file);
+    named_regions.extend(custom_regions.iter().cloned());
+    for region in named_regions.iter() {
+        println!(
+            "monitoring {:?} region {} [{:#010x}..{:#010x}]",
+            region.kind, region.name, region.range.start, region.range.end
+        );
+    }
 
-    let stack_start_ptr = if let Some(vec_section) = obj_file.section_by_name(if !is_cpp {
-        ".vector_table"
-    } else {
-        ".isr_vector"
-    }) {
-        let data = vec_section.data()?;
-        u32::from_le_bytes([data[0], data[1], data[2], data[3]])
-    } else {
-        panic!(".vector_table section required in obj file");
+    let heap_start = matches
+        .value_of("heap_start")
+        .map(|v| elf::resolve_addr(&obj_file, v))
+        .transpose()?;
+    let heap_end = matches
+        .value_of("heap_end")
+        .map(|v| elf::resolve_addr(&obj_file, v))
+        .transpose()?;
+    let heap_range = elf::heap_range(
+        &obj_file,
+        heap_start,
+        heap_end,
+        matches.value_of("heap_static"),
+    )?;
+    match heap_range.as_ref() {
+        Some(range) => println!(
+            "monitoring heap [{:#010x}..{:#010x}]",
+            range.start, range.end
+        ),
+        None => println!(
+            "no heap static, .heap section or heap symbols in obj file, heap not monitored (use --heap-static or --heap-start/--heap-end)"
+        ),
+    }
+
+    let rtt_control_block = matches
+        .is_present("rtt")
+        .then(|| {
+            elf::resolve_addr(
+                &obj_file,
+                matches
+                    .value_of("rtt_block")
+                    .unwrap_or(rtt::CONTROL_BLOCK_SYMBOL),
+            )
+        })
+        .transpose()?;
+
+    let paint_all = matches.is_present("paint_all");
+    let no_paint = matches.is_present("no_paint");
+    // memory is only scanned for the pattern if it was painted, by us or the firmware
+    let scan_pattern = (!no_paint || matches.is_present("scan_existing")).then_some(fill_pattern);
+    let stack_limit = elf::stack_limit(&obj_file, heap_range.as_ref());
+    // the startup code initialises `.data` and zeroes `.bss`, a heap or region there is
+    // painted once main is reached, also after painting the whole bank
+    let statics = elf::statics_range(&obj_file);
+    let painted_after_startup = |range: &Range<u32>| {
+        statics
+            .as_ref()
+            .map(|statics| range.start < statics.end && statics.start < range.end)
+            .unwrap_or(false)
     };
+    let startup_note = |range: &Range<u32>| match painted_after_startup(range) {
+        true => ", once main is reached",
+        false => "",
+    };
+
+    // everything the run resolves before attaching, the ram layout comes from the chip
+    // description instead of the attached target
+    if matches.is_present("dry_run") {
+        let chip = selection::resolve_chip(
+            matches.value_of("chip").unwrap(),
+            !matches.is_present("non_interactive"),
+        )?;
+        let ram_regions = cpu::ram_regions_of(&probe_rs::config::get_target_by_name(&chip)?);
+        let ram_region = regions::stack_bank(&ram_regions, initial_stack_ptr)
+            .ok_or("no ram region in the memory map of the target")?;
+        let stack_base = stack::StackBase::new(initial_stack_ptr, &ram_region.range)?;
+        let stack_bottom = stack_base.bottom(stack_limit, &ram_region.range);
+        let scan_range = scan_bottom.unwrap_or(if paint_all {
+            ram_region.range.start
+        } else {
+            stack_bottom
+        })..scan_top.unwrap_or(stack_base.addr());
+        if scan_range.is_empty() {
+            return Err(exit::Failure::Config(String::from(
+                "scan bottom has to be below scan top",
+            ))
+            .into());
+        }
+        for entry in matches.values_of("at").into_iter().flatten() {
+            schedule::parse_entry(entry)?;
+        }
+
+        println!("measurement plan");
+        println!("  chip: {}, core {}", chip, session_info.core);
+        println!(
+            "  mode: {}{}",
+            matches.value_of("mode").unwrap(),
+            if should_flash && !no_paint {
+                ", flashing first"
+            } else {
+                ""
+            }
+        );
+        match interval {
+            Some(interval) => println!("  interval: {:?}", interval),
+            None => println!("  interval: chosen from the measured sample cost"),
+        }
+        println!(
+            "  stack: base {:#010x} in ram bank [{:#010x}..{:#010x}], scanned [{:#010x}..{:#010x}], fill pattern {}",
+            stack_base.addr(),
+            ram_region.range.start,
+            ram_region.range.end,
+            scan_range.start,
+            scan_range.end,
+            fill_pattern
+        );
+        if let Some(range) = process_stack.as_ref() {
+            println!(
+                "  process stack: [{:#010x}..{:#010x}]",
+                range.start, range.end
+            );
+        }
+        if no_paint {
+            println!(
+                "  painting: none, attached to the running target{}",
+                if scan_pattern.is_some() {
+                    ", scanning for the pattern painted by the firmware"
+                } else {
+                    ""
+                }
+            );
+        } else if paint_all {
+            for bank in ram_regions.iter() {
+                println!(
+                    "  painted ram bank: [{:#010x}..{:#010x}]",
+                    bank.range.start, bank.range.end
+                );
+            }
+            if let Some(heap) = heap_range.as_ref().filter(|h| painted_after_startup(h)) {
+                println!(
+                    "  painted heap: [{:#010x}..{:#010x}]{}",
+                    heap.start,
+                    heap.end,
+                    startup_note(heap)
+                );
+            }
+            for region in custom_regions
+                .iter()
+                .filter(|r| painted_after_startup(&r.range))
+            {
+                println!(
+                    "  painted region {}: [{:#010x}..{:#010x}]{}",
+                    region.name,
+                    region.range.start,
+                    region.range.end,
+                    startup_note(&region.range)
+                );
+            }
+        } else {
+            println!(
+                "  painted stack: [{:#010x}..{:#010x}]",
+                stack_bottom,
+                stack_base.addr()
+            );
+            if let Some(heap) = heap_range.as_ref() {
+                println!(
+                    "  painted heap: [{:#010x}..{:#010x}]{}",
+                    heap.start,
+                    heap.end,
+                    startup_note(heap)
+                );
+            }
+            for region in custom_regions.iter() {
+                println!(
+                    "  painted region {}: [{:#010x}..{:#010x}]{}",
+                    region.name,
+                    region.range.start,
+                    region.range.end,
+                    startup_note(&region.range)
+                );
+            }
+        }
+        if let Some(addr) = start_instr_addr {
+            println!("  start breakpoint: {:#010x}", addr);
+        }
+        if let Some(condition) = start_condition.as_ref() {
+            println!("  start condition: {:?}", condition);
+        }
+        if let Some(trigger) = start_trigger.as_ref() {
+            println!("  recording starts once the {}", trigger);
+            if let Some(timeout) = start_timeout {
+                println!("  the run fails if it did not start within {:?}", timeout);
+            }
+        }
+        if let Some(addr) = guard_addr {
+            println!("  stack guard: {:#010x}", addr);
+        }
+        if let Some(addr) = rtt_control_block {
+            println!("  rtt control block: {:#010x}", addr);
+        }
+        for entry in matches.values_of("at").into_iter().flatten() {
+            println!("  scheduled: {}", entry);
+        }
+        if let Some(limit) = max_stack_bytes {
+            println!("  stack budget: {} bytes", limit);
+        }
+        if let Some(limit) = max_heap_bytes {
+            println!("  heap budget: {} bytes", limit);
+        }
+        if function_budgets.is_some() {
+            println!(
+                "  function budgets: {}",
+                matches.value_of("budgets").unwrap()
+            );
+        }
+        if baseline_statistics.is_some() {
+            println!(
+                "  baseline: {}, tolerance {}%",
+                matches.value_of("baseline").unwrap(),
+                tolerance * 100.0
+            );
+        }
+        println!("  record: {}", record_path.display());
+        if let Some(path) = journal_path.as_ref() {
+            println!(
+                "  journal: {} (every {} records)",
+                path.display(),
+                flush_every
+            );
+        }
+        if let Some(max_records) = max_records {
+            println!("  keeps the last {} records", max_records);
+        }
+        if matches.value_of("output_format") == Some("csv") {
+            println!("  csv: {}", record_path.with_extension("csv").display());
+        }
+        if thumbnail {
+            println!(
+                "  thumbnail: {}",
+                record_path.with_extension(THUMBNAIL_EXTENSION).display()
+            );
+        }
+        for (name, arg) in [
+            ("html report", "html_report"),
+            ("chart", "chart"),
+            ("trace", "trace"),
+            ("flamegraph", "flamegraph"),
+            ("influx", "influx"),
+            ("core dump", "core_dump"),
+        ] {
+            if let Some(path) = matches.value_of(arg) {
+                println!("  {}: {}", name, path);
+            }
+        }
+
+        return Ok(());
+    }
 
-    // let mut connection_handler = ConnectionHandler::new();
+    let transport = match matches.value_of("stream") {
+        None if serve => Some("websocket"),
+        transport => transport,
+    };
+    let listen_addr: Option<SocketAddr> = transport
+        .map(|_| matches.value_of("listen").unwrap().parse())
+        .transpose()?;
+    let metrics_addr: Option<SocketAddr> = matches
+        .value_of("metrics_listen")
+        .map(str::parse)
+        .transpose()?;
 
-    // let heap_section = obj_file
-    //     .section_by_name(".heap")
-    //     .expect("no .heap section in obj file");
+    // everything the run writes or binds is checked before the probe is opened
+    let mut preflight = preflight::Preflight::default();
+    preflight.check_probe_access();
+    if let Some(addr) = listen_addr {
+        preflight.check_listen("--listen", addr);
+    }
+    if let Some(addr) = metrics_addr {
+        preflight.check_listen("--metrics-listen", addr);
+    }
+    preflight.check_output("--output", &record_path);
+    if use_history {
+        preflight.check_output("--history-dir", &history_dir.join(RECORD_FILE));
+    }
+    for (option, arg) in [
+        ("--html-report", "html_report"),
+        ("--chart", "chart"),
+        ("--trace", "trace"),
+        ("--flamegraph", "flamegraph"),
+        ("--core-dump", "core_dump"),
+    ] {
+        if let Some(path) = matches.value_of(arg) {
+            preflight.check_output(option, Path::new(path));
+        }
+    }
+    if let Some(path) = matches
+        .value_of("influx")
+        .filter(|t| !t.starts_with("http://") && !t.starts_with("https://"))
+    {
+        preflight.check_output("--influx", Path::new(path));
+    }
+    preflight.finish()?;
 
-    // let defmt_table = defmt_decoder::Table::parse(file.as_slice())?;
-    // let locations = defmt_table.unwrap().get_locations(file.as_slice())?;
-    // println!("defmt_locations = {:?}", locations);
+    let mut sinks: Vec<Box<dyn streaming::Sink>> = Vec::new();
+    if let (Some(transport), Some(addr)) = (transport, listen_addr) {
+        sinks.push(Box::new(streaming::ConnectionHandler::new(
+            match transport {
+                "tcp" => streaming::Transport::Tcp,
+                _ => streaming::Transport::WebSocket,
+            },
+            addr,
+            matches.value_of("stream_max_rate").unwrap().parse()?,
+        )?));
+    }
 
-    let probes = Probe::list_all();
-    let probe = probes[0].open()?;
-    let session = Arc::new(Mutex::new(probe.attach("STM32G431RBTx")?));
+    let metrics_server = metrics_addr.map(metrics::MetricsServer::new).transpose()?;
 
-    // let mut rtt = Rtt::attach(session.to_owned())?;
-    // println!("{:?}", rtt.up_channels());
+    let probe_log = probe_log::ProbeLog::install();
+    let probe = selection::open_probe(matches.value_of("probe"), interactive)?;
+    let chip = selection::resolve_chip(matches.value_of("chip").unwrap(), interactive)?;
+    let session = Arc::new(Mutex::new(probe.attach(chip.to_owned())?));
 
-    let mut session = session.lock().unwrap();
-    let mut cpu = cpu::CPU::new(session);
+    let session = session.lock().unwrap();
+    let mut cpu = cpu::CPU::new(session, session_info.core);
     cpu.halt()?;
+    // a table of another defmt version only costs the decoding, not the run
+    let defmt_table = match rtt_control_block.map(|_| defmt_log::DefmtTable::load(file.as_slice()))
+    {
+        Some(Ok(table)) => table,
+        Some(Err(e)) => {
+            println!(
+                "warning: could not load the defmt table ({}), the defmt channel is logged undecoded",
+                e
+            );
+            None
+        }
+        None => None,
+    };
+    let mut rtt_log = rtt_control_block
+        .map(|control_block| rtt::RttLog::new(control_block, defmt_table.as_ref()));
 
     // let mem_map = session.target().memory_map;
 
-    let ram_region = cpu.ram_region()?;
-    let flash_region = cpu.flash_region()?;
+    session_info.device_id = cpu.unique_id()?;
+    if let Some(device_id) = session_info.device_id.as_ref() {
+        println!("device id: {}", device_id);
+    }
+    if let Some(broker) = matches.value_of("mqtt") {
+        let topic_prefix = match matches.value_of("mqtt_topic") {
+            Some(topic_prefix) => String::from(topic_prefix),
+            None => format!(
+                "mem-analyser/{}",
+                session_info.device_id.as_deref().unwrap_or(&chip)
+            ),
+        };
+        sinks.push(Box::new(mqtt::MqttPublisher::new(broker, topic_prefix)?));
+    }
 
-    cpu.access_core(|core| {
-        for reg in ram_region.clone().range {
-            core.write_word_8(reg, 0x55)?;
-        }
+    let ram_regions = cpu.ram_regions()?;
+    // the main stack lives in the bank of the initial sp, with all banks painted the
+    // other banks (CCM, DTCM, SRAM2) are monitored as regions
+    let ram_region = regions::stack_bank(&ram_regions, initial_stack_ptr)
+        .cloned()
+        .ok_or("no ram region in the memory map of the target")?;
+    let other_banks = ram_regions
+        .iter()
+        .filter(|r| paint_all && r.range != ram_region.range);
+    for bank in other_banks {
+        let region = regions::NamedRegion::ram_bank(bank);
+        println!(
+            "monitoring ram bank {} [{:#010x}..{:#010x}]",
+            region.name, region.range.start, region.range.end
+        );
+        named_regions.push(region);
+    }
+    let stack_base = stack::StackBase::new(initial_stack_ptr, &ram_region.range)?;
+    let stack_bottom = stack_base.bottom(stack_limit, &ram_region.range);
 
-        Ok(())
-    })?;
+    let scan_range = scan_bottom.unwrap_or(if paint_all {
+        ram_region.range.start
+    } else {
+        stack_bottom
+    })..scan_top.unwrap_or(stack_base.addr());
+    if scan_range.is_empty() {
+        return Err(
+            exit::Failure::Config(String::from("scan bottom has to be below scan top")).into(),
+        );
+    }
+
+    // only the stack and heap by default, statics keep their state in no-flash runs and
+    // painting small ranges is faster
+    let monitored_ranges = heap_range
+        .iter()
+        .cloned()
+        .chain(custom_regions.iter().map(|r| r.range.clone()));
+    let (startup_paint_ranges, paint_ranges): (Vec<_>, Vec<_>) = if paint_all {
+        (
+            monitored_ranges.filter(painted_after_startup).collect(),
+            ram_regions.iter().map(|r| r.range.clone()).collect(),
+        )
+    } else {
+        std::iter::once(stack_bottom..stack_base.addr())
+            .chain(monitored_ranges)
+            .partition(painted_after_startup)
+    };
+    if !no_paint {
+        cpu.access_core(|core| {
+            for range in paint_ranges.iter() {
+                core.write_8(range.start, &fill_pattern.fill(range))?;
+            }
+
+            Ok(())
+        })?;
+    }
+    // without painting only what needs no pattern is measured
+    let heap_range = heap_range.filter(|_| scan_pattern.is_some());
+    named_regions.retain(|r| scan_pattern.is_some() || r.kind == regions::RegionKind::Static);
 
-    if should_flash {
+    if no_paint {
+        println!("attached to the running target without painting");
+    } else if should_flash {
         let file = File::open(elf_path)?;
         println!("start flashing");
         cpu.flash(file)?;
@@ -217,77 +2390,407 @@ fn main() -> DynError<()> {
     } else {
         cpu.reset_and_halt()?;
     }
+    if !no_paint && !startup_paint_ranges.is_empty() {
+        let main = elf::find_symbol(&obj_file, "main")
+            .ok_or_else(|| elf::ElfError::SymbolNotFound(String::from("main")))?;
+        cpu.run_to_point(main & !1)?;
+        cpu.access_core(|core| {
+            for range in startup_paint_ranges.iter() {
+                core.write_8(range.start, &fill_pattern.fill(range))?;
+            }
+
+            Ok(())
+        })?;
+    }
+    if !cpu.enable_cycle_counter()? {
+        println!("the core has no DWT cycle counter, samples are timed by the host clock only");
+    }
+
+    let sample_cost = match analyse_mode {
+        AnalyseMode::Looping => {
+            let start = Instant::now();
+            calculate_used_ram(
+                stack_base,
+                &scan_range,
+                scan_pattern,
+                process_stack.as_ref(),
+                unwinder.as_ref(),
+                &mut cpu,
+                asm_file.as_ref(),
+            )?;
+            Some(start.elapsed())
+        }
+        AnalyseMode::LoopMeasure => {
+            let start = Instant::now();
+            cpu_monitor(stack_base, &mut cpu)?;
+            Some(start.elapsed())
+        }
+        _ => None,
+    };
+    if let Some(sample_cost) = sample_cost {
+        mem_monitoring::print_interval_trade_off(sample_cost, max_perturbation);
+    }
 
-    let analyse_interval = Duration::from_millis(100);
+    let analyse_interval = match (interval, sample_cost) {
+        (Some(interval), _) => interval,
+        (None, Some(sample_cost)) => {
+            mem_monitoring::suggest_interval(sample_cost, max_perturbation)
+        }
+        (None, None) => Duration::from_millis(100),
+    };
+    println!("sampling interval: {:?}", analyse_interval);
+    let loop_period = match (&analyse_mode, start_instr_addr) {
+        (AnalyseMode::Looping, Some(loop_head)) => {
+            aliasing::measure_loop_period(&mut cpu, loop_head)?
+        }
+        _ => None,
+    };
+    let aliasing = loop_period.and_then(|period| {
+        println!("main loop period: {}", period);
+        aliasing::check(analyse_interval, sample_cost.unwrap_or_default(), period)
+    });
+    if let Some(aliasing) = aliasing.as_ref() {
+        println!("warning: {}", aliasing);
+    }
     let mut recorder = RamSnapshotRecorder::new(
-        (ram_region.range.end - stack_start_ptr) as usize,
+        session_info,
+        stack_base.ram_above(&ram_region.range) as usize,
         analyse_interval.to_owned(),
     );
+    recorder.set_stack_base(stack_base.addr());
+    if let Some(max_records) = max_records {
+        recorder.set_max_records(max_records);
+    }
+    for finding in debug_info_findings {
+        recorder.add_finding(finding);
+    }
+    let mut journal = match journal_path.as_ref() {
+        Some(path) => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            Some(journal::Journal::create(path, &recorder, flush_every)?)
+        }
+        None => None,
+    };
+    if let Some(aliasing) = aliasing {
+        recorder.add_finding(findings::Finding::new(
+            findings::Severity::Warning,
+            findings::Category::Sampling,
+            aliasing.to_string(),
+        ));
+    }
+    if scan_pattern.is_none() {
+        recorder.add_finding(findings::Finding::new(
+            findings::Severity::Info,
+            findings::Category::Sampling,
+            "attached without painting, only the stack pointer offset is measured",
+        ));
+    }
 
     println!("start measuring");
+    let measurement_start = Instant::now();
+    let halted_before = cpu.halted_time();
+    for sink in sinks.iter_mut() {
+        sink.distribute(&streaming::StreamMessage::RunStart {
+            session: recorder.session(),
+            interval_ms: analyse_interval.as_millis() as u64,
+        })?;
+    }
 
-    let now = std::time::Instant::now();
+    let mut now = std::time::Instant::now();
+    let mut failure = None;
+    let mut outputs = SampleOutputs {
+        heap_range: heap_range.as_ref(),
+        fill_pattern,
+        rtos: rtos.as_ref(),
+        named_regions: &named_regions,
+        journal: journal.as_mut(),
+        metrics_server: metrics_server.as_ref(),
+        sinks: &mut sinks,
+    };
 
     match analyse_mode {
         AnalyseMode::Looping => {
-            if start_instr_addr.is_some() {
-                cpu.run_to_point(*start_instr_addr.as_ref().unwrap())?;
+            if let Some(start_addr) = start_instr_addr {
+                run_to_auto_start(&mut cpu, start_addr, start_condition.as_ref(), auto_start)?;
             }
+            let mut dashboard = if matches.is_present("tui") {
+                probe_log.set_echo(false);
+                Some(tui::Dashboard::new()?)
+            } else {
+                None
+            };
+            let repaint_policy = match matches.value_of("on_reset") {
+                Some("full") => Some(mem_monitoring::RepaintPolicy::Full),
+                Some(_) => Some(mem_monitoring::RepaintPolicy::KeepHighWater),
+                None => None,
+            };
+            if repaint_policy.is_some() {
+                // clears the reset flag of the reset before the run
+                cpu.reset_since_last_access()?;
+            }
+            let mut scheduler = schedule::Scheduler::builder();
+            for entry in matches.values_of("at").into_iter().flatten() {
+                let (trigger, command) = schedule::parse_entry(entry)?;
+                let callback = schedule::command_callback(command);
+                scheduler = match trigger {
+                    schedule::Trigger::Sample(index) => scheduler.at_sample(index, callback),
+                    schedule::Trigger::Elapsed(elapsed) => scheduler.at_elapsed(elapsed, callback),
+                };
+            }
+            let mut scheduler = scheduler.build();
+            let mut clock = mem_monitoring::SampleClock::new(analyse_interval);
             loop {
-                let ram = calculate_used_ram(stack_start_ptr, &mut cpu, &asm_file)?;
-                recorder.record(ram);
+                if let Some(policy) = repaint_policy {
+                    if cpu.reset_since_last_access()? {
+                        let keep_below = match policy {
+                            mem_monitoring::RepaintPolicy::Full => None,
+                            mem_monitoring::RepaintPolicy::KeepHighWater => {
+                                recorder.deepest_stack_addr(scan_range.start)
+                            }
+                        };
+                        let repainted = mem_monitoring::repaint_stack(
+                            &mut cpu,
+                            &scan_range,
+                            keep_below,
+                            fill_pattern,
+                        )?;
+                        let event = mem_monitoring::Event::Reset { repainted };
+                        print_message(dashboard.as_mut(), event.to_string());
+                        recorder.add_finding(
+                            findings::Finding::new(
+                                findings::Severity::Info,
+                                findings::Category::Reset,
+                                event.to_string(),
+                            )
+                            .at_snapshot(recorder.timeline().count()),
+                        );
+                        recorder.record_event(event);
+                    }
+                }
+                for event in probe_log.drain() {
+                    // printed when logged without the dashboard
+                    if let Some(dashboard) = dashboard.as_mut() {
+                        dashboard.log(event.to_string());
+                    }
+                    recorder.record_event(event);
+                }
+                if let Some(rtt_log) = rtt_log.as_mut() {
+                    for line in cpu.access_core(|core| rtt_log.poll(core))? {
+                        print_message(dashboard.as_mut(), line.to_string());
+                        recorder.record_event(mem_monitoring::Event::Log(line));
+                    }
+                }
+                let mut timing = clock.sample_timing();
+                let mut ram = calculate_used_ram(
+                    stack_base,
+                    &scan_range,
+                    scan_pattern,
+                    process_stack.as_ref(),
+                    unwinder.as_ref(),
+                    &mut cpu,
+                    asm_file.as_ref(),
+                )?;
+                if let Some(resolver) = source_resolver.as_ref() {
+                    ram.annotate(resolver);
+                }
+                let fault = ram.exception.as_ref().filter(|e| e.is_fault()).map(|e| {
+                    let message = format!(
+                        "exception {} interrupting 0x{:08x} ({})",
+                        e.exception_number,
+                        e.pc,
+                        ram.interrupted_function.as_deref().unwrap_or("<unknown>")
+                    );
+                    (message, e.pc)
+                });
+                if let Some(trigger) = start_trigger.as_mut() {
+                    if fault.is_none() && !trigger.fired(&ram) {
+                        let quit = match dashboard.as_ref() {
+                            Some(dashboard) => dashboard.quit_requested()?,
+                            None => false,
+                        };
+                        if interrupt::interrupted() || quit {
+                            break;
+                        }
+                        if let Some(timeout) = start_timeout.filter(|t| now.elapsed() >= *t) {
+                            failure = Some(exit::Failure::StartTimeout {
+                                trigger: trigger.to_string(),
+                                timeout,
+                            });
+                            break;
+                        }
+                        clock.wait_next();
+                        continue;
+                    }
+                    print_message(
+                        dashboard.as_mut(),
+                        format!("start trigger fired: {}", trigger),
+                    );
+                    start_trigger = None;
+                    now = std::time::Instant::now();
+                    // the sample grid and its missed intervals start with the recording
+                    clock.restart();
+                    timing = clock.sample_timing();
+                }
+                let id = outputs.record(&mut cpu, &mut recorder, ram, Some(timing))?;
+                if let Some((fault, pc)) = fault {
+                    recorder.add_finding(
+                        findings::Finding::new(
+                            findings::Severity::Error,
+                            findings::Category::TargetFault,
+                            fault.to_owned(),
+                        )
+                        .at_snapshot(id)
+                        .with_addresses(vec![pc]),
+                    );
+                    failure = Some(exit::Failure::TargetFault(fault));
+                    break;
+                }
+                scheduler.run_due(&schedule::SampleContext {
+                    index: id,
+                    elapsed: now.elapsed(),
+                    snapshot: recorder.get_snapshot(id).unwrap(),
+                })?;
 
-                std::thread::sleep(analyse_interval);
-                if std::time::Instant::now() - now > Duration::from_secs(60) {
+                if interrupt::interrupted() {
+                    break;
+                }
+                // the dashboard and serve run until stopped, else the measurement ends after 60s
+                if let Some(dashboard) = dashboard.as_mut() {
+                    dashboard.draw(
+                        &recorder,
+                        recorder.get_snapshot(id).unwrap(),
+                        heap_range.as_ref(),
+                    )?;
+                    if dashboard.quit_requested()? {
+                        break;
+                    }
+                } else if !serve && std::time::Instant::now() - now > Duration::from_secs(60) {
                     break;
                 }
+                recorder.record_missed_intervals(clock.wait_next());
             }
+            probe_log.set_echo(true);
         }
         AnalyseMode::Stepping => {
-            if start_instr_addr.is_some() {
-                cpu.run_to_point(*start_instr_addr.as_ref().unwrap())?;
+            if let Some(start_addr) = start_instr_addr {
+                run_to_start(&mut cpu, start_addr, start_condition.as_ref())?;
             }
 
-            loop {
+            'stepping: loop {
                 cpu.step()?;
-                let ram = calculate_used_ram(stack_start_ptr, &mut cpu, &asm_file)?;
-                recorder.record(ram);
+                let mut ram = calculate_used_ram(
+                    stack_base,
+                    &scan_range,
+                    scan_pattern,
+                    process_stack.as_ref(),
+                    unwinder.as_ref(),
+                    &mut cpu,
+                    asm_file.as_ref(),
+                )?;
+                if let Some(resolver) = source_resolver.as_ref() {
+                    ram.annotate(resolver);
+                }
+                let id = outputs.record(&mut cpu, &mut recorder, ram, None)?;
+                println!("snapshot {}: {}", id, recorder.get_snapshot(id).unwrap());
 
-                let line: String = read!("{}\n");
-                if line.starts_with("c") {
-                    break;
-                } else {
-                    continue;
+                loop {
+                    let line: String = read!("{}\n");
+                    if interrupt::interrupted() {
+                        break 'stepping;
+                    }
+                    match repl::parse_command(&line) {
+                        Ok(repl::Command::Step) => break,
+                        Ok(repl::Command::Continue) => break 'stepping,
+                        Ok(command) => {
+                            if let Err(e) = repl::execute(
+                                &command,
+                                &mut cpu,
+                                &obj_file,
+                                asm_file.as_ref(),
+                                &mut recorder,
+                            ) {
+                                println!("{}", e);
+                            }
+                        }
+                        Err(e) => println!("{}", e),
+                    }
                 }
             }
         }
         AnalyseMode::SingleShot => {
             if start_instr_addr.is_none() {
-                panic!("start_addr is needed")
+                return Err(exit::Failure::Config(String::from("start_addr is needed")).into());
             }
 
-            let ram = calculate_used_ram(stack_start_ptr, &mut cpu, &asm_file)?;
+            let mut ram = calculate_used_ram(
+                stack_base,
+                &scan_range,
+                scan_pattern,
+                process_stack.as_ref(),
+                unwinder.as_ref(),
+                &mut cpu,
+                asm_file.as_ref(),
+            )?;
+
+            if let Some(resolver) = source_resolver.as_ref() {
+                ram.annotate(resolver);
+            }
             println!("start stack usage: {}", ram);
 
-            cpu.run_to_point(start_instr_addr.unwrap())?;
+            run_to_start(
+                &mut cpu,
+                start_instr_addr.unwrap(),
+                start_condition.as_ref(),
+            )?;
 
-            let ram = calculate_used_ram(stack_start_ptr, &mut cpu, &asm_file)?;
+            let mut ram = calculate_used_ram(
+                stack_base,
+                &scan_range,
+                scan_pattern,
+                process_stack.as_ref(),
+                unwinder.as_ref(),
+                &mut cpu,
+                asm_file.as_ref(),
+            )?;
+
+            if let Some(resolver) = source_resolver.as_ref() {
+                ram.annotate(resolver);
+            }
             println!("at point stack usage: {}", ram);
         }
         AnalyseMode::LoopMeasure => {
             if start_instr_addr.is_none() {
-                panic!("start_addr is needed")
+                return Err(exit::Failure::Config(String::from(
+                    "start_addr is needed, no main loop found in the disassembly",
+                ))
+                .into());
             }
 
             let mut cpu_records = Vec::new();
 
-            cpu.run_to_point(start_instr_addr.unwrap())?;
+            run_to_auto_start(
+                &mut cpu,
+                start_instr_addr.unwrap(),
+                start_condition.as_ref(),
+                auto_start,
+            )?;
             cpu.run()?;
             loop {
-                let cpu_snapshot = cpu_monitor(stack_start_ptr, &mut cpu)?;
+                let cpu_snapshot = cpu_monitor(stack_base, &mut cpu)?;
+                for sink in sinks.iter_mut() {
+                    sink.distribute(&streaming::StreamMessage::CpuSnapshot {
+                        id: cpu_records.len(),
+                        timestamp: timestamp::to_iso8601(&chrono::Utc::now()),
+                        snapshot: &cpu_snapshot,
+                    })?;
+                }
                 cpu_records.push(cpu_snapshot);
                 std::thread::sleep(analyse_interval);
-                if std::time::Instant::now() - now > Duration::from_secs(60) {
+                if std::time::Instant::now() - now > Duration::from_secs(60)
+                    || interrupt::interrupted()
+                {
                     break;
                 }
             }
@@ -301,6 +2804,130 @@ fn main() -> DynError<()> {
                     .as_slice()
             );
         }
+        AnalyseMode::AllocTracking => {
+            if let Some(start_addr) = start_instr_addr {
+                run_to_start(&mut cpu, start_addr, start_condition.as_ref())?;
+            }
+
+            let tracker =
+                alloc_tracking::track_allocations(&mut cpu, &obj_file, Duration::from_secs(60))?;
+            tracker.print_summary();
+            tracker.print_call_site_report(asm_file.as_ref(), source_resolver.as_ref());
+            for finding in tracker.leak_findings() {
+                recorder.add_finding(finding);
+            }
+            recorder.set_allocations(tracker);
+        }
+        AnalyseMode::OverflowGuard => {
+            // first word below the deepest stack usage seen so far
+            let guard_addr = match (guard_addr, previous_max_used_bytes) {
+                (Some(guard_addr), _) => guard_addr,
+                (None, Some(used_bytes)) => {
+                    stack::StackBase::new(scan_range.end, &ram_region.range)?
+                        .word_below(stack::StackDepth::new(used_bytes), scan_range.start)?
+                }
+                (None, None) => scan_range.start,
+            };
+            println!("guarding 0x{:08x}", guard_addr);
+
+            if let Some(start_addr) = start_instr_addr {
+                run_to_start(&mut cpu, start_addr, start_condition.as_ref())?;
+            }
+
+            match guard::run_until_overflow(
+                &mut cpu,
+                guard_addr,
+                asm_file.as_ref(),
+                Duration::from_secs(60),
+            )? {
+                Some(hit) => {
+                    hit.print();
+                    recorder.add_finding(
+                        findings::Finding::new(
+                            findings::Severity::Error,
+                            findings::Category::StackOverflow,
+                            format!(
+                                "stack guard at 0x{:08x} written in {}",
+                                hit.guard_addr,
+                                hit.function.as_deref().unwrap_or("<unknown>")
+                            ),
+                        )
+                        .with_addresses(vec![hit.guard_addr, hit.instr_ptr]),
+                    );
+                    failure = Some(exit::Failure::OverflowDetected(hit.instr_ptr));
+                }
+                None => println!("stack guard not reached"),
+            }
+        }
+        AnalyseMode::Markers => {
+            if let Some(start_addr) = start_instr_addr {
+                run_to_start(&mut cpu, start_addr, start_condition.as_ref())?;
+            }
+
+            cpu.run()?;
+            while std::time::Instant::now() - now < Duration::from_secs(60)
+                && !interrupt::interrupted()
+            {
+                if !cpu.wait_for_halt(Duration::from_millis(100))? {
+                    continue;
+                }
+                let marker = match cpu.halted_by_bkpt()? {
+                    Some(imm) if is_marker(imm) => imm,
+                    // semihosting calls are answered and the run goes on, only an exit ends it
+                    Some(SEMIHOSTING_BKPT) => {
+                        match cpu.semihosting_call()? {
+                            cpu::SemihostingCall::Exit => {
+                                println!("firmware exited by semihosting");
+                                break;
+                            }
+                            cpu::SemihostingCall::Write(data) => {
+                                print!("{}", String::from_utf8_lossy(&data));
+                                cpu.finish_semihosting_call(0)?;
+                            }
+                            cpu::SemihostingCall::Other(operation) => {
+                                println!(
+                                    "semihosting operation {:#04x} is not served, answered with an error",
+                                    operation
+                                );
+                                cpu.finish_semihosting_call(u32::MAX)?;
+                            }
+                        }
+                        cpu.run()?;
+                        continue;
+                    }
+                    Some(imm) => {
+                        println!("stopped at bkpt #{} which is no marker", imm);
+                        break;
+                    }
+                    None => {
+                        println!("core halted without marker");
+                        break;
+                    }
+                };
+
+                let mut ram = calculate_used_ram(
+                    stack_base,
+                    &scan_range,
+                    scan_pattern,
+                    process_stack.as_ref(),
+                    unwinder.as_ref(),
+                    &mut cpu,
+                    asm_file.as_ref(),
+                )?;
+                ram.marker = Some(marker);
+                if let Some(resolver) = source_resolver.as_ref() {
+                    ram.annotate(resolver);
+                }
+                let id = outputs.record(&mut cpu, &mut recorder, ram, None)?;
+                println!("snapshot {}: {}", id, recorder.get_snapshot(id).unwrap());
+
+                cpu.skip_bkpt()?;
+                cpu.run()?;
+            }
+            if !cpu.wait_for_halt(Duration::ZERO)? {
+                cpu.halt()?;
+            }
+        }
     }
 
     // {
@@ -317,12 +2944,356 @@ fn main() -> DynError<()> {
     //     }
     // }
 
-    let statistics = recorder.calculate_statistics();
-    println!("{:?}", statistics);
+    recorder.set_overhead(
+        cpu.halted_time() - halted_before,
+        measurement_start.elapsed(),
+    );
+    for event in probe_log.drain() {
+        recorder.record_event(event);
+    }
+    if let Some(rtt_log) = rtt_log.as_mut() {
+        let mut lines = cpu.access_core(|core| rtt_log.poll(core))?;
+        lines.extend(rtt_log.flush());
+        for line in lines {
+            println!("{}", line);
+            recorder.record_event(mem_monitoring::Event::Log(line));
+        }
+        if rtt_log.channel_names().is_empty() {
+            recorder.add_finding(findings::Finding::new(
+                findings::Severity::Warning,
+                findings::Category::Sampling,
+                String::from("the RTT control block was never initialized by the firmware, no log lines recorded"),
+            ));
+        }
+    }
+    let probe_warnings = recorder
+        .events()
+        .filter(|e| matches!(e, mem_monitoring::Event::ProbeLog { .. }))
+        .count();
+    if probe_warnings > 0 {
+        recorder.add_finding(findings::Finding::new(
+            findings::Severity::Warning,
+            findings::Category::Sampling,
+            format!(
+                "probe-rs logged {} warnings during the run, see the events of the recording",
+                probe_warnings
+            ),
+        ));
+    }
 
-    let record_file_content = serde_json::to_string(&recorder)?;
-    let mut record_file = File::create("record.json")?;
-    record_file.write(record_file_content.as_bytes())?;
+    // a scan of the whole bank starts at the statics, which lose the paint at startup
+    let gap_bottom = scan_range.start.max(stack_bottom);
+    let deepest = recorder
+        .deepest_stack_addr(gap_bottom)
+        .filter(|deepest| *deepest > gap_bottom);
+    if let (Some(deepest), Some(scan_pattern)) = (deepest, scan_pattern) {
+        let gap = mem_monitoring::untouched_gap(&mut cpu, gap_bottom..deepest, scan_pattern)?;
+        match gap.as_ref() {
+            Some(gap) => println!(
+                "untouched gap below the stack: {} bytes [{:#010x}..{:#010x}]",
+                gap.end - gap.start,
+                gap.start,
+                gap.end
+            ),
+            None => recorder.add_finding(
+                findings::Finding::new(
+                    findings::Severity::Error,
+                    findings::Category::StackOverflow,
+                    "no untouched memory left below the stack, it probably overflowed",
+                )
+                .with_addresses(vec![gap_bottom]),
+            ),
+        }
+        recorder.set_untouched_gap(gap);
+    }
 
-    Ok(())
+    if let Some(path) = matches.value_of("core_dump").map(Path::new) {
+        let fault = matches!(
+            failure,
+            Some(exit::Failure::TargetFault(_)) | Some(exit::Failure::OverflowDetected(_))
+        );
+        // without --core-dump-on the dump is only written on a fault, a default value
+        // would count as given and require --core-dump
+        if fault || matches.value_of("core_dump_on") == Some("end") {
+            let ranges = ram_regions
+                .iter()
+                .map(|r| r.range.clone())
+                .collect::<Vec<_>>();
+            core_dump::CoreDump::capture(&mut cpu, &ranges, fault)?.write_elf(path)?;
+            println!("core dump written to {}", path.display());
+        }
+    }
+
+    match matches.value_of("on_exit").unwrap() {
+        "reset" => cpu.reset()?,
+        "halt" => {
+            if !cpu.wait_for_halt(Duration::ZERO)? {
+                cpu.halt()?;
+            }
+        }
+        _ => {
+            if cpu.wait_for_halt(Duration::ZERO)? {
+                cpu.run()?;
+            }
+        }
+    }
+
+    if recorder.missed_intervals() > 0 {
+        recorder.add_finding(findings::Finding::new(
+            findings::Severity::Warning,
+            findings::Category::Sampling,
+            format!(
+                "{} sampling intervals were missed, the interval is too short for the probe",
+                recorder.missed_intervals()
+            ),
+        ));
+    }
+
+    let mut run_statistics = None;
+    if !recorder.is_empty() {
+        let statistics = StatisticsEngine::new(&recorder).calculate();
+        print_statistics(&statistics);
+        if statistics.sp_anomalies > 0 {
+            recorder.add_finding(findings::Finding::new(
+                findings::Severity::Warning,
+                findings::Category::Sampling,
+                format!(
+                    "{} samples with an sp outside the stack or unaligned, left out of the stack statistics",
+                    statistics.sp_anomalies
+                ),
+            ));
+        }
+        if let Some(previous) = previous_statistics {
+            statistics.print_comparison(&previous);
+        }
+        if let Some(path) = chart_path {
+            chart::write_chart(path, &statistics)?;
+        }
+
+        let entry = obj_file.entry() as u32 & !1;
+        let call_graph = asm_file
+            .as_ref()
+            .map(|a| call_graph::CallGraph::new(a, &frame_sizes));
+        if let (Some(asm_file), Some(call_graph)) = (asm_file.as_ref(), call_graph.as_ref()) {
+            let static_depth = asm_file
+                .get_function_based_on_addr(&entry)
+                .and_then(|f| call_graph.worst_case_stack_at(f.range.start));
+            if let Some(depth) = static_depth {
+                println!(
+                    "static worst case stack from {}: {} bytes{}, measured: {} bytes",
+                    depth.entry,
+                    depth.bytes,
+                    if depth.is_lower_bound() {
+                        " (lower bound)"
+                    } else {
+                        ""
+                    },
+                    statistics.max_stack_ptr_off
+                );
+            }
+        }
+        // the pc pattern of the samples only hints at aliasing the loop period did not show
+        if matches!(analyse_mode, AnalyseMode::Looping) && loop_period.is_none() {
+            if let Some(finding) = aliasing::repeated_pc_finding(&recorder) {
+                recorder.add_finding(finding);
+            }
+        }
+        if let Some(call_graph) = call_graph.as_ref() {
+            let mut static_findings = findings::static_estimate_findings(&recorder, call_graph);
+            static_findings.extend(findings::large_frame_findings(call_graph));
+            for finding in static_findings {
+                recorder.add_finding(finding);
+            }
+        }
+
+        let isr = call_graph.as_ref().and_then(|c| {
+            exception_handlers
+                .iter()
+                .filter_map(|h| c.worst_case_stack_at(*h))
+                .max_by_key(|d| d.bytes)
+        });
+        statistics::StackRecommendation::new(statistics.max_stack_ptr_off, safety_factor, isr)
+            .print(language);
+
+        let mut violations = Vec::new();
+        if let Some(limit) = max_stack_bytes.filter(|l| statistics.max_stack_ptr_off > *l) {
+            violations.push(exit::BudgetViolation {
+                budget: String::from("max stack"),
+                limit,
+                measured: statistics.max_stack_ptr_off,
+            });
+        }
+        if let Some((limit, measured)) = max_heap_bytes
+            .zip(statistics.max_heap_usage())
+            .filter(|(l, m)| m > l)
+        {
+            violations.push(exit::BudgetViolation {
+                budget: String::from("max heap"),
+                limit,
+                measured,
+            });
+        }
+        if let Some(budgets) = function_budgets.as_ref() {
+            for violation in budgets.check(&recorder.function_statistics()) {
+                println!("budget violation: {}", violation);
+                violations.push(exit::BudgetViolation {
+                    budget: format!("stack in {}", violation.function),
+                    limit: violation.limit,
+                    measured: violation.measured,
+                });
+            }
+        }
+        if let Some(baseline) = baseline_statistics.as_ref() {
+            let limit = |baseline: u32| (baseline as f32 * (1.0 + tolerance)) as u32;
+            let mut regressions = Vec::new();
+            for (name, baseline, measured) in [
+                (
+                    "max stack",
+                    Some(baseline.max_stack_ptr_off),
+                    Some(statistics.max_stack_ptr_off),
+                ),
+                (
+                    "max heap",
+                    baseline.max_heap_usage(),
+                    statistics.max_heap_usage(),
+                ),
+            ] {
+                if let (Some(baseline), Some(measured)) = (baseline, measured) {
+                    if measured > limit(baseline) {
+                        regressions.push(exit::BudgetViolation {
+                            budget: format!("{} against baseline", name),
+                            limit: limit(baseline),
+                            measured,
+                        });
+                    }
+                }
+            }
+            if matches.is_present("baseline_warn_only") {
+                for regression in regressions {
+                    recorder.add_finding(findings::Finding::new(
+                        findings::Severity::Warning,
+                        findings::Category::Regression,
+                        regression.to_string(),
+                    ));
+                }
+            } else {
+                violations.extend(regressions);
+            }
+        }
+        for violation in violations.iter() {
+            recorder.add_finding(findings::Finding::new(
+                findings::Severity::Error,
+                findings::Category::Budget,
+                violation.to_string(),
+            ));
+        }
+        if !violations.is_empty() && failure.is_none() {
+            failure = Some(exit::Failure::BudgetExceeded(violations));
+        }
+        run_statistics = Some(statistics);
+    }
+
+    recorder.sort_findings();
+    for finding in recorder.findings() {
+        println!("{}", finding);
+    }
+
+    for sink in sinks.iter_mut() {
+        sink.distribute(&streaming::StreamMessage::RunEnd {
+            records: recorder.timeline().count(),
+            failure: failure.as_ref().map(|f| f.to_string()),
+            statistics: run_statistics.as_ref(),
+            findings: recorder.findings(),
+        })?;
+        sink.close();
+        if let Some(statistics) = sink.statistics() {
+            println!("{}", statistics);
+            recorder.record_event(mem_monitoring::Event::Distribution(statistics));
+        }
+    }
+
+    recorder.collapse_records(matches.is_present("collapse_records"));
+    let record_file_content = record_format.encode(&recorder)?;
+    if let Some(dir) = record_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut record_file = File::create(&record_path)?;
+    record_file.write_all(&record_file_content)?;
+    if let Some(journal) = journal {
+        journal.finish()?;
+    }
+    let mut record_paths = vec![record_path.to_owned()];
+    if use_history {
+        record_paths.push(session::store_in_history(
+            &history_dir,
+            recorder.session(),
+            &record_file_content,
+            record_format.extension(),
+        )?);
+    }
+    if let Some(statistics) = run_statistics.as_ref().filter(|_| thumbnail) {
+        for path in record_paths {
+            chart::write_sparkline(&path.with_extension(THUMBNAIL_EXTENSION), statistics)?;
+        }
+    }
+    if matches.value_of("output_format") == Some("csv") {
+        export::write_csv(&record_path.with_extension("csv"), &recorder)?;
+    }
+    if let Some(path) = matches.value_of("trace") {
+        export::write_trace(Path::new(path), &recorder)?;
+    }
+    if let Some(path) = matches.value_of("flamegraph") {
+        let weight = match matches.value_of("flamegraph_weight").unwrap() {
+            "samples" => export::FlameWeight::Samples,
+            _ => export::FlameWeight::Bytes,
+        };
+        export::write_flamegraph(Path::new(path), &recorder, weight)?;
+    }
+    if let Some(target) = matches.value_of("influx") {
+        let run_id = match matches.value_of("run_id") {
+            Some(run_id) => String::from(run_id),
+            None => timestamp::to_iso8601(&recorder.session().timestamp),
+        };
+        export::write_influx(
+            target,
+            &recorder,
+            &export::InfluxTags {
+                chip: &chip,
+                firmware_hash: &recorder.session().firmware_hash,
+                run_id: &run_id,
+            },
+        )?;
+    }
+
+    if !plugin_paths.is_empty() {
+        // plugins get the recording as json in either record format
+        let recording_json = serde_json::to_string(&recorder)?;
+        for plugin_path in plugin_paths {
+            match plugins::run_plugin(plugin_path, &recording_json) {
+                Ok(output) => println!("plugin {}: {}", plugin_path.display(), output),
+                Err(e) => println!("plugin {} failed: {}", plugin_path.display(), e),
+            }
+        }
+    }
+
+    if let Some(path) = html_report_path {
+        let call_graph = asm_file
+            .as_ref()
+            .map(|a| call_graph::CallGraph::new(a, &frame_sizes));
+        report::write_html_report(
+            path,
+            &recorder,
+            source_resolver.as_ref(),
+            call_graph.as_ref(),
+            &report::ReportOptions {
+                time_zone,
+                ..Default::default()
+            },
+        )?;
+    }
+
+    match failure {
+        Some(failure) => Err(failure.into()),
+        None => Ok(()),
+    }
 }
@@ -1,10 +1,36 @@
-use std::{fmt::Display, ops::Range, sync::MutexGuard, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    ops::Range,
+    time::{Duration, Instant},
+};
 
-use probe_rs::{MemoryInterface, Session};
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use probe_rs::{Architecture, Core, MemoryInterface};
+use serde::{Deserialize, Serialize};
 use serde_hex::{SerHex, StrictPfx};
 
-use crate::{asm_parsing::AsmFile, cpu, DynError};
+use crate::{
+    alloc_tracking::AllocationTracker,
+    asm_parsing::AsmFile,
+    cpu,
+    exception::{read_exception_frame, ExceptionFrame},
+    findings::Finding,
+    paint::FillPattern,
+    range_encoding,
+    regions::NamedRegion,
+    registers,
+    rtos::TaskSnapshot,
+    rtt::LogLine,
+    session::SessionInfo,
+    source::{SourceFrame, SourceResolver},
+    stack::{StackBase, StackPointer},
+    streaming::DistributionStatistics,
+    timestamp,
+    unwind::{Backtrace, BacktraceFrame, Unwinder},
+    DynError,
+};
 
 struct UsedRange {
     start: u32,
@@ -20,310 +46,1571 @@ impl UsedRange {
     }
 }
 
-#[derive(Debug, Clone, Eq, Serialize)]
+/// Sampled main stack pointer which can't point into the stack, the sample is likely
+/// taken with a corrupted sp or in the middle of a context switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpAnomaly {
+    OutsideStack {
+        #[serde(with = "SerHex::<StrictPfx>")]
+        sp: u32,
+    },
+    Unaligned {
+        #[serde(with = "SerHex::<StrictPfx>")]
+        sp: u32,
+    },
+}
+
+impl SpAnomaly {
+    /// Checks `sp` against the stack from `stack` start up to its top, inclusive.
+    pub fn check(sp: u32, stack: &Range<u32>) -> Option<Self> {
+        if sp < stack.start || sp > stack.end {
+            Some(SpAnomaly::OutsideStack { sp })
+        } else if sp & 0b11 != 0 {
+            Some(SpAnomaly::Unaligned { sp })
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for SpAnomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpAnomaly::OutsideStack { sp } => write!(f, "sp 0x{:08x} outside the stack", sp),
+            SpAnomaly::Unaligned { sp } => write!(f, "unaligned sp 0x{:08x}", sp),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub struct RamSnapshot {
-    used_bytes: u32,
-    stack_ptr_offset: u32,
-    ranges: Vec<Range<u32>>,
+    pub used_bytes: u32,
+    /// Zero if the sampled sp is anomalous.
+    pub stack_ptr_offset: u32,
+    #[serde(with = "crate::range_encoding")]
+    pub ranges: Vec<Range<u32>>,
     #[serde(with = "SerHex::<StrictPfx>")]
-    instr_ptr: u32,
-    function: String,
+    pub instr_ptr: u32,
+    pub function: String,
+    /// Whether the core used the process stack pointer when halted.
+    pub psp_active: bool,
+    pub process_stack: Option<ProcessStackSnapshot>,
+    /// Stacked frame if the sample was taken in an exception handler.
+    pub exception: Option<ExceptionFrame>,
+    /// Function the exception interrupted.
+    pub interrupted_function: Option<String>,
+    /// Call stack at the sample, innermost frame first.
+    pub backtrace: Vec<BacktraceFrame>,
+    /// Source of the instruction, inlined functions first.
+    pub source: Vec<SourceFrame>,
+    /// Immediate of the `bkpt` marker which requested the sample.
+    pub marker: Option<u8>,
+    #[serde(default)]
+    pub sp_anomaly: Option<SpAnomaly>,
+    /// Moved to the recorder when recorded, the deduplicated snapshots are shared by
+    /// records of different times.
+    #[serde(skip)]
+    pub halted_at: Option<SampleTime>,
+}
+
+/// Host wall clock and DWT cycle counter when the core was halted for a sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SampleTime {
+    pub host: DateTime<Utc>,
+    /// CYCCNT of the sample, in a recording extended to 64 bit over the wraps of the
+    /// counter since the first record. None without a cycle counter.
+    pub cycles: Option<u64>,
+}
+
+impl SampleTime {
+    /// Reads CYCCNT of the halted core if `cycle_counter` is enabled.
+    pub fn read(core: &mut Core, cycle_counter: bool) -> Result<Self, probe_rs::Error> {
+        let cycles = match cycle_counter {
+            true => Some(core.read_word_32(registers::DWTCtrl::CYCCNT_ADDRESS)? as u64),
+            false => None,
+        };
+
+        Ok(Self {
+            host: Utc::now(),
+            cycles,
+        })
+    }
 }
 
+/// Snapshots of the same stack state in different functions stay apart, the per function
+/// statistics are taken from the deduplicated snapshots.
 impl PartialEq for RamSnapshot {
     fn eq(&self, other: &Self) -> bool {
         self.used_bytes == other.used_bytes
+            && self.function == other.function
             && self.stack_ptr_offset == other.stack_ptr_offset
             && self.ranges == other.ranges
+            && self.process_stack == other.process_stack
+            && self.marker == other.marker
+            && self.sp_anomaly == other.sp_anomaly
     }
 }
 
+/// Usage of the stack in the process stack region.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessStackSnapshot {
+    pub used_bytes: u32,
+    /// Offset of the psp from the top of the process stack region.
+    pub stack_ptr_offset: u32,
+    #[serde(with = "crate::range_encoding")]
+    pub ranges: Vec<Range<u32>>,
+}
+
 impl PartialOrd for RamSnapshot {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.used_bytes.partial_cmp(&other.used_bytes) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.stack_ptr_offset.partial_cmp(&other.stack_ptr_offset) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-
-        Some(std::cmp::Ordering::Equal)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for RamSnapshot {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.used_bytes
+            .cmp(&other.used_bytes)
+            .then(self.stack_ptr_offset.cmp(&other.stack_ptr_offset))
     }
 }
 
 impl Display for RamSnapshot {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "RamSnapshot {{ instruction: 0x{:08x}, used_bytes: {}, stack_ptr_offset: {}, ranges: {:?}, function: {} }}", &self.instr_ptr, &self.used_bytes, &self.stack_ptr_offset, &self.ranges, &self.function)
+        write!(f, "RamSnapshot {{ instruction: 0x{:08x}, used_bytes: {}, stack_ptr_offset: {}, ranges: {:?}, function: {} }}", &self.instr_ptr, &self.used_bytes, &self.stack_ptr_offset, &self.ranges, &self.function)?;
+        if let Some(location) = self.source.first().and_then(|s| s.location.as_ref()) {
+            write!(f, " at {}", location)?;
+        }
+        if let Some(marker) = self.marker {
+            write!(f, " marker #{}", marker)?;
+        }
+        if let Some(anomaly) = self.sp_anomaly {
+            write!(f, " anomalous: {}", anomaly)?;
+        }
+        if let Some(exception) = self.exception.as_ref() {
+            write!(
+                f,
+                " in exception {} interrupting 0x{:08x} ({})",
+                exception.exception_number,
+                exception.pc,
+                self.interrupted_function.as_deref().unwrap_or("<unknown>")
+            )?;
+        }
+        if !self.backtrace.is_empty() {
+            let functions = self
+                .backtrace
+                .iter()
+                .map(|f| f.function.as_deref().unwrap_or("<unknown>"))
+                .collect::<Vec<_>>();
+            write!(f, " backtrace: {}", functions.join(" <- "))?;
+        }
+
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-pub struct RamStatistics {
-    median_stack_ptr_off: u32,
-    max_stack_ptr_off: u32,
-    max_mem_usage: u32,
-    stack_ptr_course: Vec<u32>,
-    mem_usage_course: Vec<u32>,
+impl RamSnapshot {
+    /// Adds the source locations of the instruction and the backtrace.
+    pub fn annotate(&mut self, resolver: &SourceResolver) {
+        self.source = resolver.frames(self.instr_ptr);
+        for (index, frame) in self.backtrace.iter_mut().enumerate() {
+            // return addresses may already belong to the next source line
+            let addr = if index == 0 {
+                frame.instr_ptr
+            } else {
+                frame.instr_ptr - 1
+            };
+            frame.location = resolver.locate(addr);
+        }
+    }
+
+    /// Prints what changed from `self` to `newer`.
+    pub fn print_diff(&self, newer: &RamSnapshot) {
+        let delta = |now: u32, prev: u32| now as i64 - prev as i64;
+        println!(
+            "stack ptr offset: {} -> {} ({:+}), used bytes: {} -> {} ({:+})",
+            self.stack_ptr_offset,
+            newer.stack_ptr_offset,
+            delta(newer.stack_ptr_offset, self.stack_ptr_offset),
+            self.used_bytes,
+            newer.used_bytes,
+            delta(newer.used_bytes, self.used_bytes)
+        );
+        if self.function != newer.function {
+            println!("function: {} -> {}", self.function, newer.function);
+        }
+
+        let added = newer
+            .ranges
+            .iter()
+            .filter(|r| !self.ranges.contains(r))
+            .collect::<Vec<_>>();
+        let removed = self
+            .ranges
+            .iter()
+            .filter(|r| !newer.ranges.contains(r))
+            .collect::<Vec<_>>();
+        if !added.is_empty() {
+            println!("new ranges: {:x?}", added);
+        }
+        if !removed.is_empty() {
+            println!("removed ranges: {:x?}", removed);
+        }
+
+        if let (Some(prev), Some(now)) = (self.process_stack.as_ref(), newer.process_stack.as_ref())
+        {
+            println!(
+                "process stack ptr offset: {:+}, process stack used bytes: {:+}",
+                delta(now.stack_ptr_offset, prev.stack_ptr_offset),
+                delta(now.used_bytes, prev.used_bytes)
+            );
+        }
+
+        // registers are only captured in the stacked exception frame
+        if let (Some(prev), Some(now)) = (self.exception.as_ref(), newer.exception.as_ref()) {
+            let registers = [
+                ("r0", prev.r0, now.r0),
+                ("r1", prev.r1, now.r1),
+                ("r2", prev.r2, now.r2),
+                ("r3", prev.r3, now.r3),
+                ("r12", prev.r12, now.r12),
+                ("lr", prev.lr, now.lr),
+                ("pc", prev.pc, now.pc),
+                ("xpsr", prev.xpsr, now.xpsr),
+            ];
+            for (name, prev, now) in registers.iter().filter(|(_, p, n)| p != n) {
+                println!("{}: 0x{:08x} -> 0x{:08x}", name, prev, now);
+            }
+        }
+    }
+}
+
+/// Manual intervention on the target, logged for traceability of a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    RegisterWrite {
+        register: String,
+        #[serde(with = "SerHex::<StrictPfx>")]
+        value: u32,
+    },
+    MemoryWrite {
+        #[serde(with = "SerHex::<StrictPfx>")]
+        address: u32,
+        #[serde(with = "SerHex::<StrictPfx>")]
+        value: u32,
+    },
+    /// The target reset during the run, `repainted` was painted again.
+    Reset { repainted: Range<u32> },
+    /// Warning or error probe-rs logged during the run.
+    ProbeLog { level: String, message: String },
+    /// Clients and messages of a streaming sink at the end of the run.
+    Distribution(DistributionStatistics),
+    /// Line the firmware logged over RTT.
+    Log(LogLine),
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::RegisterWrite { register, value } => {
+                write!(f, "wrote 0x{:08x} to register {}", value, register)
+            }
+            Event::MemoryWrite { address, value } => {
+                write!(f, "wrote 0x{:08x} to address 0x{:08x}", value, address)
+            }
+            Event::Reset { repainted } => write!(
+                f,
+                "target reset, repainted 0x{:08x}..0x{:08x}",
+                repainted.start, repainted.end
+            ),
+            Event::ProbeLog { level, message } => write!(f, "probe-rs {}: {}", level, message),
+            Event::Distribution(statistics) => write!(f, "{}", statistics),
+            Event::Log(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// Index of the record following the event.
+    record: usize,
+    timestamp: DateTime<Utc>,
+    event: Event,
+}
+
+impl RecordedEvent {
+    /// The event with its record counted from `evicted` records earlier.
+    pub fn before_evicted(&self, evicted: usize) -> Self {
+        Self {
+            record: self.record + evicted,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskStatistics {
+    pub name: String,
+    pub priority: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub tcb: u32,
+    pub min_free_bytes: u32,
+    pub free_bytes_course: Vec<u32>,
+    /// Samples taken while the task was running.
+    pub running_samples: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionStatistics {
+    pub name: String,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub start: u32,
+    pub size: u32,
+    pub max_used_bytes: u32,
+    pub used_bytes_course: Vec<u32>,
+}
+
+/// Records and maxima of the snapshots taken while the core was halted in a function.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionStatistics {
+    pub function: String,
+    pub records: usize,
+    pub max_stack_ptr_off: u32,
+    pub max_used_bytes: u32,
+}
+
+/// Time the target was halted during the measurement and the wall-clock time of the
+/// measurement, in microseconds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct HaltOverhead {
+    pub halted_us: u64,
+    pub wall_clock_us: u64,
+}
+
+/// Intended and actual start of a sample in microseconds since the start of the measurement.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleTiming {
+    pub intended_us: u64,
+    pub actual_us: u64,
+}
+
+impl SampleTiming {
+    pub fn jitter_us(&self) -> u64 {
+        self.actual_us.saturating_sub(self.intended_us)
+    }
+}
+
+/// Schedules samples on a fixed grid of the analyse interval. A sample which overruns
+/// its interval skips the missed grid points instead of shifting all following samples.
+pub struct SampleClock {
+    start: Instant,
+    interval: Duration,
+    index: u32,
 }
 
-#[derive(Serialize)]
+impl SampleClock {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            interval,
+            index: 0,
+        }
+    }
+
+    /// Starts the grid again at the current time, for a recording starting late.
+    pub fn restart(&mut self) {
+        self.start = Instant::now();
+        self.index = 0;
+    }
+
+    /// Timing of the sample starting now.
+    pub fn sample_timing(&self) -> SampleTiming {
+        SampleTiming {
+            intended_us: (self.interval * self.index).as_micros() as u64,
+            actual_us: self.start.elapsed().as_micros() as u64,
+        }
+    }
+
+    /// Sleeps until the next grid point and returns the number of skipped grid points.
+    pub fn wait_next(&mut self) -> u32 {
+        let elapsed = self.start.elapsed();
+        let mut missed = 0;
+        self.index += 1;
+        while self.interval * self.index < elapsed {
+            self.index += 1;
+            missed += 1;
+        }
+        std::thread::sleep(self.interval * self.index - elapsed);
+
+        missed
+    }
+}
+
+/// Run of identical consecutive records, `start` and `end` are the indices of its first
+/// and last record.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordSpan {
+    pub variant: usize,
+    pub count: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Snapshot variant of every record in recording order. Serialized as plain indices, or
+/// as spans of identical consecutive records if collapsed. Binary formats get the
+/// indices with the collapse flag, they are compressed anyway.
+#[derive(Default)]
+struct Records {
+    indices: Vec<usize>,
+    collapse: bool,
+}
+
+impl Records {
+    fn spans(&self) -> Vec<RecordSpan> {
+        let mut spans: Vec<RecordSpan> = Vec::new();
+        for (index, variant) in self.indices.iter().enumerate() {
+            match spans.last_mut() {
+                Some(span) if span.variant == *variant => {
+                    span.count += 1;
+                    span.end = index;
+                }
+                _ => spans.push(RecordSpan {
+                    variant: *variant,
+                    count: 1,
+                    start: index,
+                    end: index,
+                }),
+            }
+        }
+
+        spans
+    }
+}
+
+impl Serialize for Records {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return (self.collapse, &self.indices).serialize(serializer);
+        }
+        if self.collapse {
+            self.spans().serialize(serializer)
+        } else {
+            self.indices.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Records {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Serialized {
+            Indices(Vec<usize>),
+            Spans(Vec<RecordSpan>),
+        }
+
+        if !deserializer.is_human_readable() {
+            let (collapse, indices) = <(bool, Vec<usize>)>::deserialize(deserializer)?;
+            return Ok(Records { indices, collapse });
+        }
+        Ok(match Serialized::deserialize(deserializer)? {
+            Serialized::Indices(indices) => Records {
+                indices,
+                collapse: false,
+            },
+            Serialized::Spans(spans) => Records {
+                indices: spans
+                    .iter()
+                    .flat_map(|s| std::iter::repeat_n(s.variant, s.count))
+                    .collect(),
+                collapse: true,
+            },
+        })
+    }
+}
+
+/// Distinct snapshots of a recording. With a known stack base the used ranges are
+/// serialized as offsets below it, `{"stack_base": .., "variants": [..]}`, recordings with
+/// a plain list of snapshots are still read.
+#[derive(Default)]
+struct SnapshotVariants {
+    stack_base: Option<u32>,
+    variants: Vec<RamSnapshot>,
+}
+
+/// Variants serialized one at a time with their ranges mirrored at `base`.
+struct MirroredVariants<'a> {
+    base: Option<u32>,
+    variants: &'a [RamSnapshot],
+}
+
+impl Serialize for MirroredVariants<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let base = match self.base {
+            Some(base) => base,
+            None => return self.variants.serialize(serializer),
+        };
+        serializer.collect_seq(self.variants.iter().map(|snapshot| RamSnapshot {
+            ranges: range_encoding::mirror(&snapshot.ranges, base),
+            ..snapshot.clone()
+        }))
+    }
+}
+
+impl Serialize for SnapshotVariants {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Relative<'a> {
+            #[serde(with = "SerHex::<StrictPfx>")]
+            stack_base: u32,
+            variants: MirroredVariants<'a>,
+        }
+
+        let variants = MirroredVariants {
+            base: self.stack_base,
+            variants: &self.variants,
+        };
+        if !serializer.is_human_readable() {
+            return (self.stack_base, variants).serialize(serializer);
+        }
+        match self.stack_base {
+            Some(stack_base) => Relative {
+                stack_base,
+                variants,
+            }
+            .serialize(serializer),
+            None => variants.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SnapshotVariants {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Relative {
+            #[serde(with = "SerHex::<StrictPfx>")]
+            stack_base: u32,
+            variants: Vec<RamSnapshot>,
+        }
+
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = (Option<u32>, Vec<RamSnapshot>);
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of snapshots or snapshots relative to a stack base")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let variants = Vec::deserialize(serde::de::value::SeqAccessDeserializer::new(seq))?;
+                Ok((None, variants))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let relative =
+                    Relative::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok((Some(relative.stack_base), relative.variants))
+            }
+        }
+
+        let (stack_base, mut variants) = match deserializer.is_human_readable() {
+            true => deserializer.deserialize_any(Visitor)?,
+            false => <(Option<u32>, Vec<RamSnapshot>)>::deserialize(deserializer)?,
+        };
+        if let Some(base) = stack_base {
+            for snapshot in variants.iter_mut() {
+                snapshot.ranges = range_encoding::mirror(&snapshot.ranges, base);
+            }
+        }
+
+        Ok(Self {
+            stack_base,
+            variants,
+        })
+    }
+}
+
+/// Storage of a recording. Snapshots are deduplicated, the timeline refers to them by
+/// index. Analysis happens in [`crate::statistics::StatisticsEngine`].
+#[derive(Serialize, Deserialize)]
 pub struct RamSnapshotRecorder {
+    session: SessionInfo,
+    #[serde(with = "crate::timestamp::iso8601_duration")]
     analyse_interval: Duration,
     static_ram_size: usize,
-    snapshot_variants: Vec<RamSnapshot>,
-    records: Vec<usize>,
+    snapshot_variants: SnapshotVariants,
+    records: Records,
+    heap_records: Vec<HeapSnapshot>,
+    events: Vec<RecordedEvent>,
+    allocations: Option<AllocationTracker>,
+    tasks: Vec<TaskStatistics>,
+    /// Tcb of the task running at each record.
+    running_tasks: Vec<Option<u32>>,
+    regions: Vec<RegionStatistics>,
+    sample_timings: Vec<SampleTiming>,
+    missed_intervals: u32,
+    /// Painted range directly below the deepest stack excursion at the end of the run.
+    untouched_gap: Option<Range<u32>>,
+    #[serde(default)]
+    findings: Vec<Finding>,
+    #[serde(default)]
+    overhead: Option<HaltOverhead>,
+    /// Time of each record, empty in recordings of older versions.
+    #[serde(default)]
+    sample_times: Vec<SampleTime>,
+    /// Records kept in a bounded recording, older records are evicted.
+    #[serde(default)]
+    max_records: Option<usize>,
+    /// Aggregates over all records of a bounded recording.
+    #[serde(default)]
+    aggregates: Option<RunningAggregates>,
+}
+
+/// Aggregates over every record of a bounded recording, the evicted ones included.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunningAggregates {
+    /// Records evicted from the front of the recording.
+    pub evicted_records: usize,
+    pub evicted_events: usize,
+    pub max_stack_ptr_off: u32,
+    pub max_used_bytes: u32,
+    pub sp_anomalies: usize,
+    /// Samples per stack ptr offset for the exact median, the stack size bounds the
+    /// number of distinct offsets.
+    stack_ptr_offsets: BTreeMap<u32, usize>,
+    functions: BTreeMap<String, FunctionStatistics>,
+    deepest_stack_addr: Option<u32>,
+    #[serde(default)]
+    max_heap_usage: Option<u32>,
+    /// Highest used bytes per region name.
+    #[serde(default)]
+    region_peaks: BTreeMap<String, u32>,
+    /// Least free stack bytes per task tcb.
+    #[serde(default)]
+    task_minima: BTreeMap<u32, u32>,
+}
+
+impl RunningAggregates {
+    pub fn add(&mut self, snapshot: &RamSnapshot) {
+        self.max_used_bytes = self.max_used_bytes.max(snapshot.used_bytes);
+        match snapshot.sp_anomaly {
+            Some(_) => self.sp_anomalies += 1,
+            None => {
+                self.max_stack_ptr_off = self.max_stack_ptr_off.max(snapshot.stack_ptr_offset);
+                *self
+                    .stack_ptr_offsets
+                    .entry(snapshot.stack_ptr_offset)
+                    .or_default() += 1;
+            }
+        }
+        let function = self
+            .functions
+            .entry(snapshot.function.to_owned())
+            .or_insert_with(|| FunctionStatistics {
+                function: snapshot.function.to_owned(),
+                records: 0,
+                max_stack_ptr_off: 0,
+                max_used_bytes: 0,
+            });
+        function.records += 1;
+        function.max_stack_ptr_off = function.max_stack_ptr_off.max(snapshot.stack_ptr_offset);
+        function.max_used_bytes = function.max_used_bytes.max(snapshot.used_bytes);
+        if let Some(start) = snapshot.ranges.iter().map(|r| r.start).min() {
+            self.deepest_stack_addr = Some(self.deepest_stack_addr.map_or(start, |d| d.min(start)));
+        }
+    }
+
+    fn add_heap(&mut self, heap: &HeapSnapshot) {
+        self.max_heap_usage = Some(self.max_heap_usage.unwrap_or(0).max(heap.high_watermark));
+    }
+
+    fn add_region(&mut self, name: &str, used_bytes: u32) {
+        let peak = self.region_peaks.entry(String::from(name)).or_default();
+        *peak = (*peak).max(used_bytes);
+    }
+
+    fn add_task(&mut self, tcb: u32, min_free_bytes: u32) {
+        let minimum = self.task_minima.entry(tcb).or_insert(min_free_bytes);
+        *minimum = (*minimum).min(min_free_bytes);
+    }
+
+    /// Highest heap high watermark of all records, none without heap records.
+    pub fn max_heap_usage(&self) -> Option<u32> {
+        self.max_heap_usage
+    }
+
+    pub fn region_peak(&self, name: &str) -> Option<u32> {
+        self.region_peaks.get(name).copied()
+    }
+
+    pub fn task_min_free_bytes(&self, tcb: u32) -> Option<u32> {
+        self.task_minima.get(&tcb).copied()
+    }
+
+    /// Median of the stack ptr offsets without anomalies, zero without samples.
+    pub fn median_stack_ptr_off(&self) -> u32 {
+        let samples = self.stack_ptr_offsets.values().sum::<usize>();
+        let mut seen = 0;
+        for (offset, count) in self.stack_ptr_offsets.iter() {
+            seen += count;
+            if seen * 2 >= samples {
+                return *offset;
+            }
+        }
+
+        0
+    }
+}
+
+/// Drops the entries of the `count` oldest records of a per record vector.
+fn drain_front<T>(values: &mut Vec<T>, count: usize) {
+    values.drain(..count.min(values.len()));
 }
 
 impl RamSnapshotRecorder {
-    pub fn new(static_ram_size: usize, analyse_interval: Duration) -> Self {
+    pub fn new(session: SessionInfo, static_ram_size: usize, analyse_interval: Duration) -> Self {
         Self {
+            session,
             analyse_interval,
             static_ram_size,
-            snapshot_variants: Vec::new(),
-            records: Vec::new(),
+            snapshot_variants: SnapshotVariants::default(),
+            records: Records::default(),
+            heap_records: Vec::new(),
+            events: Vec::new(),
+            allocations: None,
+            tasks: Vec::new(),
+            running_tasks: Vec::new(),
+            regions: Vec::new(),
+            sample_timings: Vec::new(),
+            missed_intervals: 0,
+            untouched_gap: None,
+            findings: Vec::new(),
+            overhead: None,
+            sample_times: Vec::new(),
+            max_records: None,
+            aggregates: None,
         }
     }
 
-    pub fn record(&mut self, snapshot: RamSnapshot) {
-        let sp = self.snapshot_variants.iter().position(|r| r.eq(&snapshot));
+    /// Stack base the used ranges are stored relative to, set before recording.
+    pub fn set_stack_base(&mut self, stack_base: u32) {
+        self.snapshot_variants.stack_base = Some(stack_base);
+    }
+
+    /// Keeps only the last `max_records` records, the maxima and median of the run are
+    /// aggregated over all records. Set before recording.
+    pub fn set_max_records(&mut self, max_records: usize) {
+        self.max_records = Some(max_records.max(1));
+        self.aggregates = Some(RunningAggregates::default());
+    }
+
+    pub fn aggregates(&self) -> Option<&RunningAggregates> {
+        self.aggregates.as_ref()
+    }
+
+    /// Records evicted from a bounded recording, the id of a record is its index in
+    /// the kept records.
+    pub fn evicted_records(&self) -> usize {
+        self.aggregates
+            .as_ref()
+            .map(|a| a.evicted_records)
+            .unwrap_or(0)
+    }
+
+    /// Drops the `count` oldest records with everything recorded per record and the
+    /// snapshot variants no kept record refers to.
+    fn evict(&mut self, count: usize) {
+        drain_front(&mut self.records.indices, count);
+        drain_front(&mut self.heap_records, count);
+        drain_front(&mut self.running_tasks, count);
+        drain_front(&mut self.sample_timings, count);
+        drain_front(&mut self.sample_times, count);
+        for task in self.tasks.iter_mut() {
+            drain_front(&mut task.free_bytes_course, count);
+        }
+        for region in self.regions.iter_mut() {
+            drain_front(&mut region.used_bytes_course, count);
+        }
+        let events = self.events.len();
+        self.events.retain(|e| e.record >= count);
+        let evicted_events = events - self.events.len();
+        for event in self.events.iter_mut() {
+            event.record -= count;
+        }
+        for finding in self.findings.iter_mut() {
+            finding.snapshot = finding.snapshot.and_then(|s| s.checked_sub(count));
+        }
+
+        let mut remap = vec![None; self.snapshot_variants.variants.len()];
+        let mut kept = Vec::new();
+        for index in self.records.indices.iter_mut() {
+            let old = *index;
+            *index = *remap[old].get_or_insert_with(|| {
+                kept.push(old);
+                kept.len() - 1
+            });
+        }
+        let mut variants = std::mem::take(&mut self.snapshot_variants.variants)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.snapshot_variants.variants = kept.iter().filter_map(|i| variants[*i].take()).collect();
+
+        if let Some(aggregates) = self.aggregates.as_mut() {
+            aggregates.evicted_records += count;
+            aggregates.evicted_events += evicted_events;
+        }
+    }
+
+    /// Records the snapshot and returns its id.
+    pub fn record(&mut self, mut snapshot: RamSnapshot, heap: Option<HeapSnapshot>) -> usize {
+        // evicted in batches of an eighth, rebuilding the variants is linear
+        if let Some(max_records) = self.max_records {
+            let records = self.records.indices.len();
+            if records >= max_records + (max_records / 8).max(1) {
+                self.evict(records + 1 - max_records);
+            }
+        }
+        if let Some(heap) = heap {
+            if let Some(aggregates) = self.aggregates.as_mut() {
+                aggregates.add_heap(&heap);
+            }
+            self.heap_records.push(heap);
+        }
+        // snapshots not taken from a halted core are timed when recorded
+        let mut time = snapshot.halted_at.take().unwrap_or_else(|| SampleTime {
+            host: Utc::now(),
+            cycles: None,
+        });
+        // the 32 bit counter wraps within seconds on fast cores, a wrap is detected as
+        // long as the samples are less than one wrap apart
+        let prev_cycles = self.sample_times.iter().rev().find_map(|t| t.cycles);
+        if let (Some(prev), Some(cycles)) = (prev_cycles, time.cycles) {
+            time.cycles = Some(prev + (cycles as u32).wrapping_sub(prev as u32) as u64);
+        }
+        self.sample_times.push(time);
+
+        if let Some(aggregates) = self.aggregates.as_mut() {
+            aggregates.add(&snapshot);
+        }
+
+        let sp = self
+            .snapshot_variants
+            .variants
+            .iter()
+            .position(|r| r.eq(&snapshot));
         match sp {
-            Some(index) => self.records.push(index),
+            Some(index) => self.records.indices.push(index),
             None => {
-                self.snapshot_variants.push(snapshot);
-                self.records.push(self.snapshot_variants.len() - 1);
+                self.snapshot_variants.variants.push(snapshot);
+                self.records
+                    .indices
+                    .push(self.snapshot_variants.variants.len() - 1);
             }
         }
+
+        self.records.indices.len() - 1
     }
 
-    pub fn calculate_statistics(&self) -> RamStatistics {
-        let mut stack_ptrs_off = self
-            .records
-            .iter()
-            .map(|r| self.snapshot_variants[*r].stack_ptr_offset)
-            .collect::<Vec<_>>();
+    pub fn get_snapshot(&self, id: usize) -> Option<&RamSnapshot> {
+        self.records
+            .indices
+            .get(id)
+            .map(|index| &self.snapshot_variants.variants[*index])
+    }
+
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
 
-        let stack_ptr_course = stack_ptrs_off.to_owned();
+    pub fn analyse_interval(&self) -> Duration {
+        self.analyse_interval
+    }
 
-        stack_ptrs_off.sort_unstable_by(|x: &u32, y: &u32| x.partial_cmp(y).unwrap());
-        let median_stack_ptr_off = percentile_of_sorted(stack_ptrs_off.as_slice(), 50.0);
+    pub fn static_ram_size(&self) -> usize {
+        self.static_ram_size
+    }
 
-        let max_stack_ptr_off = *stack_ptrs_off.last().unwrap();
+    /// Host time the record `id` was taken. Recordings of older versions have no times,
+    /// it is derived from the session start and the analyse interval for them.
+    pub fn record_time(&self, id: usize) -> DateTime<Utc> {
+        match self.sample_time(id) {
+            Some(time) => time.host,
+            None => timestamp::nominal(&self.session.timestamp, self.analyse_interval, id),
+        }
+    }
 
-        let mut max_mem_usage = self
-            .records
-            .iter()
-            .map(|r| self.snapshot_variants[*r].used_bytes)
-            .collect::<Vec<_>>();
-        let mem_usage_course = max_mem_usage.to_owned();
-        max_mem_usage.sort_unstable_by(|x: &u32, y: &u32| x.partial_cmp(y).unwrap());
-        let max_mem_usage = *max_mem_usage.last().unwrap();
+    /// Time of the record `id` since the first record, the time axis of the courses.
+    pub fn elapsed(&self, id: usize) -> Duration {
+        (self.record_time(id) - self.record_time(0))
+            .to_std()
+            .unwrap_or_default()
+    }
 
-        RamStatistics {
-            median_stack_ptr_off,
-            max_stack_ptr_off,
-            max_mem_usage,
-            stack_ptr_course,
-            mem_usage_course,
+    /// Expected once per record, the running task is tracked per record.
+    pub fn record_tasks(&mut self, snapshots: Vec<TaskSnapshot>) {
+        self.running_tasks
+            .push(snapshots.iter().find(|t| t.running).map(|t| t.tcb));
+        for snapshot in snapshots {
+            let position = self.tasks.iter().position(|t| t.tcb == snapshot.tcb);
+            let task = match position {
+                Some(index) => &mut self.tasks[index],
+                None => {
+                    self.tasks.push(TaskStatistics {
+                        name: snapshot.name.to_owned(),
+                        priority: snapshot.priority,
+                        tcb: snapshot.tcb,
+                        min_free_bytes: snapshot.min_free_bytes,
+                        free_bytes_course: Vec::new(),
+                        running_samples: 0,
+                    });
+                    self.tasks.last_mut().unwrap()
+                }
+            };
+            task.min_free_bytes = task.min_free_bytes.min(snapshot.min_free_bytes);
+            task.free_bytes_course.push(snapshot.free_bytes);
+            if let Some(aggregates) = self.aggregates.as_mut() {
+                aggregates.add_task(snapshot.tcb, snapshot.min_free_bytes);
+            }
+            if snapshot.running {
+                task.running_samples += 1;
+            }
         }
     }
 
-    pub fn get_records(&mut self) -> RamSnapshotRecords {
-        RamSnapshotRecords {
-            pos: 0,
-            records: self,
+    pub fn record_regions(&mut self, regions: &[NamedRegion], usage: Vec<u32>) {
+        for (region, used_bytes) in regions.iter().zip(usage) {
+            let position = self.regions.iter().position(|r| r.name == region.name);
+            let statistics = match position {
+                Some(index) => &mut self.regions[index],
+                None => {
+                    self.regions.push(RegionStatistics {
+                        name: region.name.to_owned(),
+                        start: region.range.start,
+                        size: region.range.end - region.range.start,
+                        max_used_bytes: 0,
+                        used_bytes_course: Vec::new(),
+                    });
+                    self.regions.last_mut().unwrap()
+                }
+            };
+            statistics.max_used_bytes = statistics.max_used_bytes.max(used_bytes);
+            statistics.used_bytes_course.push(used_bytes);
+            if let Some(aggregates) = self.aggregates.as_mut() {
+                aggregates.add_region(&region.name, used_bytes);
+            }
         }
     }
-}
 
-// Helper function: extract a value representing the `pct` percentile of a sorted sample-set, using
-// linear interpolation. If samples are not sorted, return nonsensical value.
-fn percentile_of_sorted(sorted_samples: &[u32], pct: f32) -> u32 {
-    assert!(!sorted_samples.is_empty());
-    if sorted_samples.len() == 1 {
-        return sorted_samples[0];
+    pub fn is_empty(&self) -> bool {
+        self.records.indices.is_empty()
     }
-    assert!(0.0 <= pct);
-    assert!(pct <= 100.0);
-    if pct == 100.0 {
-        return sorted_samples[sorted_samples.len() - 1];
+
+    pub fn set_allocations(&mut self, allocations: AllocationTracker) {
+        self.allocations = Some(allocations);
     }
-    let length = (sorted_samples.len() - 1) as f32;
-    let rank = (pct / 100.0) * length;
-    let lrank = rank.floor();
-    let d = rank - lrank;
-    let n = lrank as usize;
-    let lo = sorted_samples[n];
-    let hi = sorted_samples[n + 1];
-    lo + (hi - lo) * d as u32
-}
 
-pub struct RamSnapshotRecords<'a> {
-    pos: usize,
-    records: &'a RamSnapshotRecorder,
-}
+    pub fn set_untouched_gap(&mut self, gap: Option<Range<u32>>) {
+        self.untouched_gap = gap;
+    }
+
+    pub fn untouched_gap(&self) -> Option<&Range<u32>> {
+        self.untouched_gap.as_ref()
+    }
+
+    pub fn add_finding(&mut self, finding: Finding) {
+        self.findings.push(finding);
+    }
+
+    /// Findings of the run, most severe first.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    pub fn sort_findings(&mut self) {
+        self.findings
+            .sort_by_key(|finding| Reverse(finding.severity));
+    }
+
+    /// Per function aggregation of the records, deepest stack first. Bounded recordings
+    /// aggregate the evicted records too.
+    pub fn function_statistics(&self) -> Vec<FunctionStatistics> {
+        let mut functions = match self.aggregates.as_ref() {
+            Some(aggregates) => aggregates.functions.values().cloned().collect::<Vec<_>>(),
+            None => {
+                let mut functions: HashMap<&str, FunctionStatistics> = HashMap::new();
+                for snapshot in self.timeline() {
+                    let statistics =
+                        functions
+                            .entry(&snapshot.function)
+                            .or_insert_with(|| FunctionStatistics {
+                                function: snapshot.function.to_owned(),
+                                records: 0,
+                                max_stack_ptr_off: 0,
+                                max_used_bytes: 0,
+                            });
+                    statistics.records += 1;
+                    statistics.max_stack_ptr_off =
+                        statistics.max_stack_ptr_off.max(snapshot.stack_ptr_offset);
+                    statistics.max_used_bytes = statistics.max_used_bytes.max(snapshot.used_bytes);
+                }
+                functions.into_values().collect()
+            }
+        };
+        functions.sort_by(|a, b| {
+            b.max_stack_ptr_off
+                .cmp(&a.max_stack_ptr_off)
+                .then(a.function.cmp(&b.function))
+        });
+
+        functions
+    }
+
+    /// Lowest address at or above `bottom` any recorded range reached. Ranges below
+    /// `bottom`, like the statics in a scan of the whole bank, are left out.
+    pub fn deepest_stack_addr(&self, bottom: u32) -> Option<u32> {
+        self.snapshot_variants
+            .variants
+            .iter()
+            .flat_map(|s| s.ranges.iter())
+            .filter(|r| r.end > bottom)
+            .map(|r| r.start.max(bottom))
+            .chain(
+                self.aggregates
+                    .as_ref()
+                    .and_then(|a| a.deepest_stack_addr)
+                    .filter(|d| *d >= bottom),
+            )
+            .min()
+    }
+
+    /// Serializes runs of identical consecutive records as spans, loading the recording
+    /// restores every record.
+    pub fn collapse_records(&mut self, collapse: bool) {
+        self.records.collapse = collapse;
+    }
+
+    /// Timing of the last recorded sample.
+    pub fn record_timing(&mut self, timing: SampleTiming) {
+        self.sample_timings.push(timing);
+    }
+
+    pub fn set_overhead(&mut self, halted: Duration, wall_clock: Duration) {
+        self.overhead = Some(HaltOverhead {
+            halted_us: halted.as_micros() as u64,
+            wall_clock_us: wall_clock.as_micros() as u64,
+        });
+    }
+
+    pub fn overhead(&self) -> Option<HaltOverhead> {
+        self.overhead
+    }
+
+    /// Intervals without a sample because the previous sample overran.
+    pub fn record_missed_intervals(&mut self, count: u32) {
+        self.missed_intervals += count;
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter().map(|e| &e.event)
+    }
+
+    pub fn record_event(&mut self, event: Event) {
+        self.events.push(RecordedEvent {
+            record: self.records.indices.len(),
+            timestamp: Utc::now(),
+            event,
+        });
+    }
 
-impl Iterator for RamSnapshotRecords<'_> {
-    type Item = RamSnapshot;
+    pub fn recorded_events(&self) -> &[RecordedEvent] {
+        &self.events
+    }
+
+    /// Adds an event read back from a journal, keeping its record and time.
+    pub fn restore_event(&mut self, event: RecordedEvent) {
+        self.events.push(event);
+    }
+
+    /// Recorded snapshots in recording order.
+    pub fn timeline(&self) -> impl Iterator<Item = &RamSnapshot> {
+        self.records
+            .indices
+            .iter()
+            .map(|r| &self.snapshot_variants.variants[*r])
+    }
+
+    /// Distinct snapshots of the recording.
+    pub fn snapshot_variants(&self) -> &[RamSnapshot] {
+        &self.snapshot_variants.variants
+    }
+
+    pub fn heap_records(&self) -> &[HeapSnapshot] {
+        &self.heap_records
+    }
+
+    pub fn tasks(&self) -> &[TaskStatistics] {
+        &self.tasks
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.pos == self.records.records.len() {
-            return None;
+    pub fn running_tasks(&self) -> &[Option<u32>] {
+        &self.running_tasks
+    }
+
+    pub fn regions(&self) -> &[RegionStatistics] {
+        &self.regions
+    }
+
+    pub fn sample_timings(&self) -> &[SampleTiming] {
+        &self.sample_timings
+    }
+
+    pub fn sample_time(&self, id: usize) -> Option<&SampleTime> {
+        match self.sample_times.len() == self.records.indices.len() {
+            true => self.sample_times.get(id),
+            false => None,
         }
-        let snap_index = self.records.records[self.pos];
-        Some(self.records.snapshot_variants[snap_index].clone())
     }
-}
 
-fn print_ranges(ranges: Vec<Range<u32>>) {
-    for r in ranges {
-        println!(
-            "[{:#08x}..{:#08x}] len: {}",
-            r.start,
-            r.end,
-            r.end - r.start
-        );
+    pub fn missed_intervals(&self) -> u32 {
+        self.missed_intervals
     }
 }
 
-struct HeapSnapshot {
-    used_bytes: u32,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeapSnapshot {
+    pub used_bytes: u32,
+    /// Offset of the highest overridden byte from heap start.
+    pub high_watermark: u32,
 }
 
-fn monitor_heap(
-    session: &mut MutexGuard<Session>,
-    heap_start: u32,
-    heap_size: u32,
+pub fn monitor_heap(
+    cpu: &mut cpu::CPU,
+    heap_range: &Range<u32>,
+    fill_pattern: FillPattern,
 ) -> DynError<HeapSnapshot> {
-    let mut core = session.core(0)?;
+    let res = cpu.access_only_in_halt_mode(|core| {
+        let mut heap = vec![0u8; (heap_range.end - heap_range.start) as usize];
+        core.read(heap_range.start, heap.as_mut_slice())?;
 
-    const BYTE_PATTERN: u8 = 0x55;
+        let untouched = fill_pattern.untouched(heap_range.start, &heap);
+        let used_bytes = untouched.iter().filter(|u| !**u).count() as u32;
+        let high_watermark = untouched
+            .iter()
+            .rposition(|u| !*u)
+            .map(|p| p as u32 + 1)
+            .unwrap_or(0);
+
+        Ok(HeapSnapshot {
+            used_bytes,
+            high_watermark,
+        })
+    })?;
+
+    Ok(res)
+}
+
+const READ_BLOCK_SIZE: u32 = 2048;
 
-    Ok(HeapSnapshot { used_bytes: 0 })
+/// Run of still painted bytes at the end of the memory at `start`, none if its last
+/// byte was touched.
+fn untouched_run_at_end(start: u32, untouched: &[bool]) -> Option<Range<u32>> {
+    let run = untouched.iter().rev().take_while(|u| **u).count();
+    let end = start + untouched.len() as u32;
+
+    (run > 0).then(|| end - run as u32..end)
 }
 
-pub fn calculate_used_ram(
-    stack_ptr: u32,
+/// Still painted bytes directly below the deepest stack excursion at the end of
+/// `range`, down to the highest touched byte below it. The margin the stack really has
+/// left.
+pub fn untouched_gap(
     cpu: &mut cpu::CPU,
-    asm_file: &AsmFile,
-) -> DynError<RamSnapshot> {
-    const BYTE_PATTERN: u8 = 0x55;
+    range: Range<u32>,
+    fill_pattern: FillPattern,
+) -> DynError<Option<Range<u32>>> {
+    let memory = cpu.access_only_in_halt_mode(|core| {
+        let mut memory = vec![0u8; (range.end - range.start) as usize];
+        core.read(range.start, memory.as_mut_slice())?;
+        Ok(memory)
+    })?;
+
+    Ok(untouched_run_at_end(
+        range.start,
+        &fill_pattern.untouched(range.start, &memory),
+    ))
+}
+
+/// What is painted again after the target reset during a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepaintPolicy {
+    /// Everything below the current stack pointer, the maximum starts over with the new
+    /// boot. The records of earlier boots are kept.
+    Full,
+    /// Only below the deepest stack address of the run so far, bytes touched before the
+    /// reset stay touched and the maximum carries over the reboot.
+    KeepHighWater,
+}
+
+/// Paints the scan range below the current stack pointer again, limited to below
+/// `keep_below`. Returns the painted range.
+pub fn repaint_stack(
+    cpu: &mut cpu::CPU,
+    scan_range: &Range<u32>,
+    keep_below: Option<u32>,
+    fill_pattern: FillPattern,
+) -> DynError<Range<u32>> {
+    let res = cpu.access_only_in_halt_mode(|core| {
+        let msp = core.read_core_reg(registers::MSP)?;
+        let end = msp
+            .min(keep_below.unwrap_or(u32::MAX))
+            .clamp(scan_range.start, scan_range.end);
+        core.write_8(
+            scan_range.start,
+            &fill_pattern.fill(&(scan_range.start..end)),
+        )?;
+
+        Ok(scan_range.start..end)
+    })?;
+
+    Ok(res)
+}
+
+/// Host side scanner for painted stack memory. Bytes are fed from the
+/// initial stack pointer downwards.
+struct StackScanner {
+    used_bytes: u32,
+    ranges: Vec<Range<u32>>,
+    offset_mem: Vec<bool>,
+    in_offset_flag: bool,
+    test_offset: usize,
+    act_range: Option<UsedRange>,
+}
 
-    let mut used_bytes = 0;
-    let mut address = stack_ptr - 1;
+impl StackScanner {
     const TEST_OFFSET: usize = 128;
     const OFFSET_BETWEEN_RANGES: usize = 4 * 5;
-    let mut offset_mem = Vec::<u8>::with_capacity(OFFSET_BETWEEN_RANGES);
-    let mut in_offset_flag = false;
-    let mut test_offset = TEST_OFFSET;
-    let mut act_range: Option<UsedRange> = None;
 
-    let res = cpu.access_only_in_halt_mode(move |core| {
-        let mut ranges = Vec::<Range<u32>>::new();
-        while let Ok(m) = core.read_word_8(address) {
-            let byte_not_overridden = m == BYTE_PATTERN;
-
-            if in_offset_flag {
-                offset_mem.push(m);
-                if TEST_OFFSET - test_offset > OFFSET_BETWEEN_RANGES {
-                    match act_range {
-                        Some(_) => {
-                            let mut not_used_in_mem = 0;
-                            for mb in offset_mem.iter().rev() {
-                                if *mb == 0x55 {
-                                    not_used_in_mem += 1;
-                                }
-                            }
-                            let end_range = address + not_used_in_mem;
-                            let range = act_range.take().unwrap().complete(end_range);
-                            ranges.push(range);
-                            offset_mem.clear();
-                        }
-                        None => (),
-                    }
-                }
-                if !byte_not_overridden {
-                    in_offset_flag = false;
-                    used_bytes += 1;
-                    match act_range {
-                        None => {
-                            let _ = act_range.insert(UsedRange::new(address));
-                        }
-                        _ => (),
-                    };
-                    test_offset = TEST_OFFSET;
-                } else if test_offset == 0 {
-                    break;
-                } else {
-                    test_offset -= 1;
-                }
+    fn new() -> Self {
+        Self {
+            used_bytes: 0,
+            ranges: Vec::new(),
+            offset_mem: Vec::with_capacity(Self::OFFSET_BETWEEN_RANGES),
+            in_offset_flag: false,
+            test_offset: Self::TEST_OFFSET,
+            act_range: None,
+        }
+    }
+
+    /// Scans a block read from `block_start` from its end to its start.
+    /// Returns false if the end of the used stack is reached.
+    fn scan_block(&mut self, block_start: u32, block: &[u8], fill_pattern: FillPattern) -> bool {
+        let untouched = fill_pattern.untouched(block_start, block);
+        for (offset, untouched) in untouched.into_iter().enumerate().rev() {
+            if !self.feed(block_start + offset as u32, untouched) {
+                return false;
             }
+        }
+
+        true
+    }
 
-            if byte_not_overridden {
-                in_offset_flag = true;
+    fn feed(&mut self, address: u32, byte_not_overridden: bool) -> bool {
+        if self.in_offset_flag {
+            self.offset_mem.push(byte_not_overridden);
+            if Self::TEST_OFFSET - self.test_offset > Self::OFFSET_BETWEEN_RANGES {
+                if let Some(act_range) = self.act_range.take() {
+                    let not_used_in_mem = self
+                        .offset_mem
+                        .iter()
+                        .filter(|not_overridden| **not_overridden)
+                        .count() as u32;
+                    let end_range = address + not_used_in_mem;
+                    self.ranges.push(act_range.complete(end_range));
+                    self.offset_mem.clear();
+                }
+            }
+            if !byte_not_overridden {
+                self.in_offset_flag = false;
+                self.used_bytes += 1;
+                self.act_range
+                    .get_or_insert_with(|| UsedRange::new(address));
+                self.test_offset = Self::TEST_OFFSET;
+            } else if self.test_offset == 0 {
+                return false;
             } else {
-                used_bytes += 1;
-                match act_range {
-                    None => {
-                        let _ = act_range.insert(UsedRange::new(address));
-                    }
-                    _ => (),
-                };
+                self.test_offset -= 1;
             }
-            address -= 1;
         }
 
-        // core.halt(Duration::from_millis(10))?;
-        let act_stack_ptr = core.read_core_reg(core.registers().stack_pointer())?;
+        if byte_not_overridden {
+            self.in_offset_flag = true;
+        } else {
+            self.used_bytes += 1;
+            self.act_range
+                .get_or_insert_with(|| UsedRange::new(address));
+        }
+
+        true
+    }
+}
+
+fn scan_stack(
+    core: &mut Core,
+    scan_range: &Range<u32>,
+    fill_pattern: FillPattern,
+) -> Result<StackScanner, probe_rs::Error> {
+    let mut scanner = StackScanner::new();
+    let mut buffer = vec![0u8; READ_BLOCK_SIZE as usize];
+    let mut block_end = scan_range.end;
+    while block_end > scan_range.start {
+        let block_start = block_end
+            .saturating_sub(READ_BLOCK_SIZE)
+            .max(scan_range.start);
+        let block = &mut buffer[..(block_end - block_start) as usize];
+        core.read(block_start, block)?;
+        if !scanner.scan_block(block_start, block, fill_pattern) {
+            break;
+        }
+        block_end = block_start;
+    }
+
+    Ok(scanner)
+}
+
+pub struct StackRegisters {
+    pub msp: u32,
+    pub psp: u32,
+    pub psp_active: bool,
+    /// Active exception, 0 in thread mode.
+    pub exception_number: u32,
+}
+
+fn read_stack_registers(core: &mut Core) -> Result<StackRegisters, probe_rs::Error> {
+    // risc-v has a single sp, traps run on the interrupted stack without a frame
+    if core.architecture() == Architecture::Riscv {
+        return Ok(StackRegisters {
+            msp: core.read_core_reg(core.registers().stack_pointer())?,
+            psp: 0,
+            psp_active: false,
+            exception_number: 0,
+        });
+    }
+
+    let msp = core.read_core_reg(registers::MSP)?;
+    let psp = core.read_core_reg(registers::PSP)?;
+    let control = core.read_core_reg(registers::CONTROL)? >> 24;
+    let exception_number = core.read_core_reg(registers::XPSR)? & 0x1ff;
+
+    // handler mode always uses the msp, thread mode selects by CONTROL.SPSEL
+    let psp_active = exception_number == 0 && control & 0b10 != 0;
+
+    Ok(StackRegisters {
+        msp,
+        psp,
+        psp_active,
+        exception_number,
+    })
+}
+
+/// Scans `scan_range` from its end downwards for used stack memory. If `process_stack`
+/// is given and the psp points into it, the process stack is scanned as well. Without a
+/// `fill_pattern` the memory is not painted and only the stack pointer is sampled.
+pub fn calculate_used_ram(
+    stack_base: StackBase,
+    scan_range: &Range<u32>,
+    fill_pattern: Option<FillPattern>,
+    process_stack: Option<&Range<u32>>,
+    unwinder: Option<&Unwinder>,
+    cpu: &mut cpu::CPU,
+    asm_file: Option<&AsmFile>,
+) -> DynError<RamSnapshot> {
+    let cycle_counter = cpu.has_cycle_counter();
+    let res = cpu.access_only_in_halt_mode(move |core| {
+        // taken first, the scan takes most of the halt
+        let halted_at = SampleTime::read(core, cycle_counter)?;
+        let scanner = match fill_pattern {
+            Some(fill_pattern) => scan_stack(core, scan_range, fill_pattern)?,
+            None => StackScanner::new(),
+        };
+
+        let stack_registers = read_stack_registers(core)?;
         let instr_ptr = core.read_core_reg(core.registers().program_counter())?;
 
-        // core.run()?;
-        // println!(
-        //     "act_stack_ptr: {:#08x}, stack_start_ptr: {:#08x}, used_bytes: {}",
-        //     act_stack_ptr, stack_ptr, used_bytes
-        // );
-        // println!("act {}", act_stack_ptr);
-        let stack_ptr_offset = stack_ptr - act_stack_ptr;
+        let sp_anomaly =
+            SpAnomaly::check(stack_registers.msp, &(scan_range.start..stack_base.addr()));
+        let stack_ptr_offset = match sp_anomaly {
+            Some(_) => 0,
+            None => stack_base
+                .depth_of(StackPointer(stack_registers.msp))
+                .map(|d| d.bytes())
+                .unwrap_or(0),
+        };
+
+        // an unwind error fails the sample once the core runs again
+        let unwound = match unwinder {
+            Some(unwinder) => match unwinder.backtrace(core, asm_file) {
+                Ok(backtrace) => backtrace,
+                Err(e) => return Ok(Err(e)),
+            },
+            None => Backtrace::default(),
+        };
 
-        Ok(RamSnapshot {
-            ranges,
+        let exception = read_exception_frame(
+            core,
+            stack_registers.exception_number,
+            unwound.exception_entry,
+            stack_registers.psp,
+        )?;
+        let interrupted_function = exception
+            .as_ref()
+            .and_then(|e| asm_file?.get_function_based_on_addr(&e.pc))
+            .map(|f| f.name);
+
+        let process_stack = match process_stack {
+            Some(range) if range.contains(&stack_registers.psp) => {
+                let scanner = match fill_pattern {
+                    Some(fill_pattern) => scan_stack(core, range, fill_pattern)?,
+                    None => StackScanner::new(),
+                };
+                Some(ProcessStackSnapshot {
+                    used_bytes: scanner.used_bytes,
+                    stack_ptr_offset: range.end - stack_registers.psp,
+                    ranges: scanner.ranges,
+                })
+            }
+            _ => None,
+        };
+
+        Ok(Ok(RamSnapshot {
+            ranges: scanner.ranges,
             stack_ptr_offset,
-            used_bytes,
+            used_bytes: scanner.used_bytes,
             function: asm_file
-                .get_function_based_on_addr(&instr_ptr)
-                .unwrap()
-                .name,
+                .and_then(|a| a.get_function_based_on_addr(&instr_ptr))
+                .map(|f| f.name)
+                .unwrap_or_else(|| format!("0x{:08x}", instr_ptr)),
             instr_ptr,
-        })
+            psp_active: stack_registers.psp_active,
+            process_stack,
+            exception,
+            interrupted_function,
+            backtrace: unwound.frames,
+            source: Vec::new(),
+            marker: None,
+            sp_anomaly,
+            halted_at: Some(halted_at),
+        }))
     })?;
 
-    Ok(res)
+    Ok(res?)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct CPUSnapshot {
+    #[serde(with = "SerHex::<StrictPfx>")]
     pub instr_ptr: u32,
+    /// Offset of the msp from the initial stack pointer.
     pub stack_ptr_off: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub psp: u32,
+    pub psp_active: bool,
+    /// Raw CYCCNT, not extended over wraps.
+    pub halted_at: SampleTime,
 }
 
-pub fn cpu_monitor(stack_ptr: u32, cpu: &mut cpu::CPU) -> DynError<CPUSnapshot> {
-    let res = cpu.access_only_in_halt_mode(|core| {
-        let act_stack_ptr = core.read_core_reg(core.registers().stack_pointer())?;
+pub fn cpu_monitor(stack_base: StackBase, cpu: &mut cpu::CPU) -> DynError<CPUSnapshot> {
+    let cycle_counter = cpu.has_cycle_counter();
+    let (instr_ptr, stack_registers, halted_at) = cpu.access_only_in_halt_mode(|core| {
+        let halted_at = SampleTime::read(core, cycle_counter)?;
+        let stack_registers = read_stack_registers(core)?;
         let instr_ptr = core.read_core_reg(core.registers().program_counter())?;
-        let stack_ptr_off = stack_ptr - act_stack_ptr;
 
-        Ok(CPUSnapshot {
-            instr_ptr,
-            stack_ptr_off,
-        })
+        Ok((instr_ptr, stack_registers, halted_at))
     })?;
 
-    Ok(res)
+    Ok(CPUSnapshot {
+        instr_ptr,
+        stack_ptr_off: stack_base
+            .depth_of(StackPointer(stack_registers.msp))?
+            .bytes(),
+        psp: stack_registers.psp,
+        psp_active: stack_registers.psp_active,
+        halted_at,
+    })
+}
+
+/// Sampling intervals shown to the user to compare the perturbation of the firmware.
+const TRADE_OFF_INTERVALS_MS: &[u64] = &[10, 50, 100, 500, 1000];
+/// Shortest suggested interval, the sample clock needs a grid to advance on.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Percentage of the run time the core is halted when sampling every `interval`.
+fn perturbation(sample_cost: Duration, interval: Duration) -> f32 {
+    sample_cost.as_secs_f32() / (sample_cost + interval).as_secs_f32() * 100.0
+}
+
+/// Returns the interval which keeps the halt time of the samples below
+/// `max_perturbation` percent of the run time.
+pub fn suggest_interval(sample_cost: Duration, max_perturbation: f32) -> Duration {
+    sample_cost
+        .mul_f32((100.0 / max_perturbation - 1.0).max(0.0))
+        .max(MIN_INTERVAL)
+}
+
+pub fn print_interval_trade_off(sample_cost: Duration, max_perturbation: f32) {
+    println!("one sample halts the core for {:?}", sample_cost);
+    for interval in TRADE_OFF_INTERVALS_MS
+        .iter()
+        .map(|i| Duration::from_millis(*i))
+    {
+        println!(
+            "  interval {:?}: {:.2}% halted",
+            interval,
+            perturbation(sample_cost, interval)
+        );
+    }
+    println!(
+        "suggested interval for at most {}% halted: {:?}",
+        max_perturbation,
+        suggest_interval(sample_cost, max_perturbation)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untouched_run_reaches_up_to_the_end() {
+        let untouched = [true, false, true, true, false, true, true, true];
+        assert_eq!(untouched_run_at_end(0x100, &untouched), Some(0x105..0x108));
+        assert_eq!(untouched_run_at_end(0x100, &[true; 4]), Some(0x100..0x104));
+        assert_eq!(untouched_run_at_end(0x100, &[true, false]), None);
+        assert_eq!(untouched_run_at_end(0x100, &[]), None);
+    }
+
+    #[test]
+    fn suggested_interval_keeps_the_perturbation() {
+        let sample_cost = Duration::from_millis(2);
+        for max_perturbation in [1.0, 10.0, 50.0] {
+            let interval = suggest_interval(sample_cost, max_perturbation);
+            assert!(
+                (perturbation(sample_cost, interval) - max_perturbation).abs() < 0.01,
+                "{:?} for {}%",
+                interval,
+                max_perturbation
+            );
+        }
+        assert_eq!(suggest_interval(sample_cost, 100.0), MIN_INTERVAL);
+        assert_eq!(suggest_interval(Duration::ZERO, 10.0), MIN_INTERVAL);
+    }
 }
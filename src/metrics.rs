@@ -0,0 +1,117 @@
+//! Prometheus metrics endpoint for long running measurements.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use crate::{mem_monitoring::RamSnapshotRecorder, DynError};
+
+#[derive(Default)]
+struct Metrics {
+    stack_used_bytes: u32,
+    stack_ptr_offset: u32,
+    heap_used_bytes: Option<u32>,
+    snapshots: usize,
+}
+
+impl Metrics {
+    /// Text exposition format.
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut gauge = |name: &str, help: &str, value: u64| {
+            text.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n",
+                name = name,
+                help = help,
+                value = value
+            ));
+        };
+        gauge(
+            "stack_used_bytes",
+            "Touched bytes of the stack at the last snapshot.",
+            self.stack_used_bytes as u64,
+        );
+        gauge(
+            "stack_ptr_offset",
+            "Offset of the stack pointer from the initial stack pointer at the last snapshot.",
+            self.stack_ptr_offset as u64,
+        );
+        if let Some(heap_used_bytes) = self.heap_used_bytes {
+            gauge(
+                "heap_used_bytes",
+                "Used bytes of the heap at the last snapshot.",
+                heap_used_bytes as u64,
+            );
+        }
+        gauge(
+            "snapshots",
+            "Snapshots recorded in this run.",
+            self.snapshots as u64,
+        );
+
+        text
+    }
+}
+
+/// Serves `/metrics` from a background thread.
+pub struct MetricsServer {
+    metrics: Arc<Mutex<Metrics>>,
+    _server: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub fn new(addr: SocketAddr) -> DynError<Self> {
+        let listener = TcpListener::bind(addr)?;
+        println!(
+            "serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let metrics_tmp = metrics.to_owned();
+        let server = std::thread::spawn(move || {
+            let metrics = metrics_tmp;
+            while let Ok((stream, _)) = listener.accept() {
+                // a failed request only affects its own client
+                let _ = respond(stream, &metrics);
+            }
+        });
+
+        Ok(Self {
+            metrics,
+            _server: server,
+        })
+    }
+
+    /// Publishes the record `id` as the current values.
+    pub fn update(&self, recorder: &RamSnapshotRecorder, id: usize) {
+        let snapshot = match recorder.get_snapshot(id) {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.stack_used_bytes = snapshot.used_bytes;
+        metrics.stack_ptr_offset = snapshot.stack_ptr_offset;
+        metrics.heap_used_bytes = recorder.heap_records().last().map(|h| h.used_bytes);
+        metrics.snapshots = recorder.evicted_records() + id + 1;
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", metrics.lock().unwrap().render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
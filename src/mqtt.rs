@@ -0,0 +1,103 @@
+//! Publishes the stream messages of a run to an MQTT broker, for benches reporting to a
+//! shared broker.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use rumqttc::{Client, Event, MqttOptions, Outgoing, QoS};
+
+use crate::{
+    streaming::{Sink, StreamMessage},
+    DynError,
+};
+
+const DEFAULT_PORT: u16 = 1883;
+/// Messages queued while the broker is not reachable, newer messages are dropped.
+const QUEUE_CAPACITY: usize = 1000;
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub struct MqttPublisher {
+    client: Client,
+    topic_prefix: String,
+    connected: Arc<AtomicBool>,
+    connection: Option<JoinHandle<()>>,
+}
+
+impl MqttPublisher {
+    /// Connects to `broker` (`HOST[:PORT]`) in the background, lost connections are
+    /// reestablished and the queued messages are sent then.
+    pub fn new(broker: &str, topic_prefix: String) -> DynError<Self> {
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (broker, DEFAULT_PORT),
+        };
+        let mut options =
+            MqttOptions::new(format!("mem-analyser-{}", std::process::id()), host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+        println!(
+            "publishing to mqtt broker {} under {}",
+            broker, topic_prefix
+        );
+
+        let connected = Arc::new(AtomicBool::new(true));
+        let connected_tmp = connected.to_owned();
+        let connection = std::thread::spawn(move || {
+            let connected = connected_tmp;
+            // polling again after an error reconnects
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => {
+                        if !connected.swap(true, Ordering::SeqCst) {
+                            println!("mqtt broker reconnected");
+                        }
+                    }
+                    Err(e) => {
+                        if connected.swap(false, Ordering::SeqCst) {
+                            println!("mqtt connection failed: {}, reconnecting", e);
+                        }
+                        std::thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            topic_prefix,
+            connected,
+            connection: Some(connection),
+        })
+    }
+}
+
+impl Sink for MqttPublisher {
+    /// Publishes the message to `<topic prefix>/<type>`.
+    fn distribute(&mut self, message: &StreamMessage) -> DynError<()> {
+        let topic = format!("{}/{}", self.topic_prefix, message.kind());
+        // a full queue while the broker is away must not stall the measurement
+        let _ = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, false, message.to_json()?);
+
+        Ok(())
+    }
+
+    /// Disconnects once the queued messages are sent, messages still queued for an
+    /// unreachable broker are dropped.
+    fn close(&mut self) {
+        if self.connected.load(Ordering::SeqCst) && self.client.disconnect().is_ok() {
+            if let Some(connection) = self.connection.take() {
+                let _ = connection.join();
+            }
+        }
+    }
+}
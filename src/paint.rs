@@ -0,0 +1,109 @@
+//! Pattern unused memory is painted with before the run. Memory which still holds the
+//! pattern is treated as never touched by the firmware.
+
+use std::{fmt::Display, ops::Range};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PaintError {
+    #[error("invalid fill pattern {0}, expected a hex byte like `0x55` or word like `0xCCCCCCCC`")]
+    InvalidPattern(String),
+}
+
+/// A byte, or a little endian 32 bit word painted at word aligned addresses. With a word
+/// pattern a byte only counts as untouched if its whole word still holds the pattern,
+/// data bytes which happen to equal the pattern are no longer taken for free memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FillPattern {
+    value: u32,
+    /// Bytes, 1 or 4.
+    width: u32,
+}
+
+impl Default for FillPattern {
+    fn default() -> Self {
+        Self {
+            value: 0x55,
+            width: 1,
+        }
+    }
+}
+
+impl FillPattern {
+    pub fn parse(pattern: &str) -> Result<Self, PaintError> {
+        let invalid = || PaintError::InvalidPattern(String::from(pattern));
+        let hex = pattern
+            .strip_prefix("0x")
+            .or_else(|| pattern.strip_prefix("0X"))
+            .ok_or_else(invalid)?;
+        let width = match hex.len() {
+            1 | 2 => 1,
+            8 => 4,
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            value: u32::from_str_radix(hex, 16).map_err(|_| invalid())?,
+            width,
+        })
+    }
+
+    fn byte_at(&self, addr: u32) -> u8 {
+        self.value.to_le_bytes()[(addr % self.width) as usize]
+    }
+
+    /// Bytes to write to paint `range`.
+    pub fn fill(&self, range: &Range<u32>) -> Vec<u8> {
+        range.clone().map(|addr| self.byte_at(addr)).collect()
+    }
+
+    /// Whether each byte of `data` read from `start` still holds the pattern. Words only
+    /// partly inside `data` are judged by their bytes inside it.
+    pub fn untouched(&self, start: u32, data: &[u8]) -> Vec<bool> {
+        let mut untouched = data
+            .iter()
+            .enumerate()
+            .map(|(offset, byte)| *byte == self.byte_at(start + offset as u32))
+            .collect::<Vec<_>>();
+        if self.width > 1 {
+            let mut offset = 0;
+            while offset < untouched.len() {
+                let word_end = (offset
+                    + (self.width - (start + offset as u32) % self.width) as usize)
+                    .min(untouched.len());
+                let word = &mut untouched[offset..word_end];
+                let whole_word = word.iter().all(|u| *u);
+                word.iter_mut().for_each(|u| *u = whole_word);
+                offset = word_end;
+            }
+        }
+
+        untouched
+    }
+}
+
+impl Display for FillPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.width {
+            1 => write!(f, "0x{:02X}", self.value),
+            _ => write!(f, "0x{:08X}", self.value),
+        }
+    }
+}
+
+impl From<FillPattern> for String {
+    fn from(pattern: FillPattern) -> Self {
+        pattern.to_string()
+    }
+}
+
+impl TryFrom<String> for FillPattern {
+    type Error = PaintError;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Self::parse(&pattern)
+    }
+}
@@ -0,0 +1,61 @@
+//! Analysis plugins compiled to WebAssembly.
+//!
+//! A plugin exports its `memory`, `alloc(len: i32) -> i32` and
+//! `analyse(ptr: i32, len: i32) -> i64`. `analyse` receives the recording as json
+//! and returns the location of its utf-8 result text packed as `ptr << 32 | len`.
+
+use std::path::Path;
+
+use thiserror::Error;
+use wasmi::{Config, Engine, Linker, Module, Store};
+
+use crate::DynError;
+
+/// Upper bound of executed instructions per plugin run.
+const PLUGIN_FUEL: u64 = 10_000_000_000;
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("plugin {0} does not export memory")]
+    MissingMemory(String),
+    #[error("plugin {0} returned invalid result location")]
+    InvalidResult(String),
+}
+
+/// Runs the plugin at `path` sandboxed on the recording and returns its result text.
+pub fn run_plugin(path: &Path, recording_json: &str) -> DynError<String> {
+    let name = path.display().to_string();
+
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, std::fs::File::open(path)?)?;
+
+    let mut store = Store::new(&engine, ());
+    store.add_fuel(PLUGIN_FUEL).map_err(wasmi::Error::from)?;
+    let linker = Linker::<()>::new(&engine);
+    let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+
+    let memory = instance
+        .get_memory(&store, "memory")
+        .ok_or_else(|| PluginError::MissingMemory(name.to_owned()))?;
+    let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+    let analyse = instance.get_typed_func::<(i32, i32), i64>(&store, "analyse")?;
+
+    let input = recording_json.as_bytes();
+    let input_ptr = alloc.call(&mut store, input.len() as i32)?;
+    memory
+        .write(&mut store, input_ptr as usize, input)
+        .map_err(wasmi::Error::from)?;
+
+    let result = analyse.call(&mut store, (input_ptr, input.len() as i32))? as u64;
+    let result_ptr = (result >> 32) as usize;
+    let result_len = (result & 0xffff_ffff) as usize;
+
+    let mut output = vec![0u8; result_len];
+    memory
+        .read(&store, result_ptr, output.as_mut_slice())
+        .map_err(|_| PluginError::InvalidResult(name.to_owned()))?;
+
+    Ok(String::from_utf8(output).map_err(|_| PluginError::InvalidResult(name))?)
+}
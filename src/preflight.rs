@@ -0,0 +1,247 @@
+//! Checks of the permissions a measurement needs, run before the probe is opened. A run
+//! which can't write its record file or bind its port only fails at the end or midway,
+//! after flashing and painting; the checks report every problem at once with a hint.
+
+use std::{
+    fmt::Display,
+    fs::OpenOptions,
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener},
+    path::Path,
+};
+
+use probe_rs::Probe;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckKind {
+    Probe,
+    Network,
+    Output,
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub kind: CheckKind,
+    pub problem: String,
+    pub hint: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\n    hint: {}", self.problem, self.hint)
+    }
+}
+
+fn problems_text(problems: &[Problem]) -> String {
+    problems
+        .iter()
+        .map(|p| format!("\n  - {}", p))
+        .collect::<String>()
+}
+
+#[derive(Error, Debug)]
+pub enum PreflightError {
+    #[error("{} preflight check(s) failed:{}", .0.len(), problems_text(.0))]
+    Failed(Vec<Problem>),
+}
+
+impl PreflightError {
+    pub fn problems(&self) -> &[Problem] {
+        match self {
+            PreflightError::Failed(problems) => problems,
+        }
+    }
+}
+
+/// Device nodes of the connected usb devices as `(vendor id, product id, node)`.
+#[cfg(target_os = "linux")]
+fn usb_device_nodes() -> Vec<(u16, u16, std::path::PathBuf)> {
+    let entries = match std::fs::read_dir("/sys/bus/usb/devices") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let dir = entry.path();
+            let read = |name: &str| {
+                std::fs::read_to_string(dir.join(name))
+                    .ok()
+                    .map(|text| text.trim().to_owned())
+            };
+            let vendor_id = u16::from_str_radix(&read("idVendor")?, 16).ok()?;
+            let product_id = u16::from_str_radix(&read("idProduct")?, 16).ok()?;
+            let bus: u32 = read("busnum")?.parse().ok()?;
+            let device: u32 = read("devnum")?.parse().ok()?;
+            let node = std::path::PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", bus, device));
+            Some((vendor_id, product_id, node))
+        })
+        .collect()
+}
+
+#[derive(Default)]
+pub struct Preflight {
+    problems: Vec<Problem>,
+}
+
+impl Preflight {
+    fn push(&mut self, kind: CheckKind, problem: String, hint: String) {
+        self.problems.push(Problem {
+            kind,
+            problem,
+            hint,
+        });
+    }
+
+    /// A probe is connected and, on Linux, its usb device node can be opened. Without a
+    /// udev rule the probe is listed but can't be opened by a user.
+    pub fn check_probe_access(&mut self) {
+        let probes = Probe::list_all();
+        if probes.is_empty() {
+            self.push(
+                CheckKind::Probe,
+                String::from("no debug probe found"),
+                String::from(
+                    "connect the probe, `mem-analyser list-probes` lists the probes found",
+                ),
+            );
+            return;
+        }
+
+        #[cfg(target_os = "linux")]
+        for (vendor_id, product_id, node) in usb_device_nodes() {
+            if !probes
+                .iter()
+                .any(|p| p.vendor_id == vendor_id && p.product_id == product_id)
+            {
+                continue;
+            }
+            match OpenOptions::new().read(true).write(true).open(&node) {
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => self.push(
+                    CheckKind::Probe,
+                    format!(
+                        "no access to the probe {:04x}:{:04x} at {}",
+                        vendor_id,
+                        product_id,
+                        node.display()
+                    ),
+                    format!(
+                        "add the udev rule `SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"0660\", GROUP=\"plugdev\", TAG+=\"uaccess\"` to /etc/udev/rules.d/69-probe-rs.rules, run `sudo udevadm control --reload && sudo udevadm trigger` and replug the probe",
+                        vendor_id, product_id
+                    ),
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    /// `addr` of the option `option` can be bound, the listener is closed again.
+    pub fn check_listen(&mut self, option: &str, addr: SocketAddr) {
+        let error = match TcpListener::bind(addr) {
+            Ok(_) => return,
+            Err(e) => e,
+        };
+        let hint = match error.kind() {
+            ErrorKind::AddrInUse => format!(
+                "another process listens on port {}, stop it or pick a free port with {}",
+                addr.port(),
+                option
+            ),
+            ErrorKind::PermissionDenied => format!(
+                "ports below 1024 need extra privileges, pick a port from 1024 on with {}",
+                option
+            ),
+            ErrorKind::AddrNotAvailable => format!(
+                "{} is no address of this host, listen on 127.0.0.1 or 0.0.0.0 with {}",
+                addr.ip(),
+                option
+            ),
+            _ => format!("pick another address with {}", option),
+        };
+        self.push(
+            CheckKind::Network,
+            format!("can't listen on {} ({}): {}", addr, option, error),
+            hint,
+        );
+    }
+
+    /// The file `path` of the option `option` can be written: an existing file is
+    /// writable, otherwise a file can be created in its closest existing directory.
+    pub fn check_output(&mut self, option: &str, path: &Path) {
+        if path.is_dir() {
+            self.push(
+                CheckKind::Output,
+                format!("{} ({}) is a directory", path.display(), option),
+                format!("give a file path with {}", option),
+            );
+            return;
+        }
+        if let Ok(metadata) = std::fs::metadata(path) {
+            if metadata.permissions().readonly() {
+                self.push(
+                    CheckKind::Output,
+                    format!("{} ({}) is read-only", path.display(), option),
+                    format!(
+                        "make it writable with `chmod u+w {}` or pick another path with {}",
+                        path.display(),
+                        option
+                    ),
+                );
+            }
+            return;
+        }
+
+        let dir = path
+            .ancestors()
+            .skip(1)
+            .find(|d| !d.as_os_str().is_empty() && d.exists())
+            .unwrap_or_else(|| Path::new("."));
+        if !dir.is_dir() {
+            self.push(
+                CheckKind::Output,
+                format!(
+                    "{} ({}) is below the file {}",
+                    path.display(),
+                    option,
+                    dir.display()
+                ),
+                format!("pick another path with {}", option),
+            );
+            return;
+        }
+        let probe_file = dir.join(format!(".mem-analyser-preflight-{}", std::process::id()));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&probe_file)
+        {
+            Ok(_) => {
+                let _ = std::fs::remove_file(&probe_file);
+            }
+            Err(e) => self.push(
+                CheckKind::Output,
+                format!(
+                    "can't write {} ({}), no file can be created in {}: {}",
+                    path.display(),
+                    option,
+                    dir.display(),
+                    e
+                ),
+                format!(
+                    "make the directory writable with `chmod u+w {}` or pick another path with {}",
+                    dir.display(),
+                    option
+                ),
+            ),
+        }
+    }
+
+    /// All problems found, none if the run can start.
+    pub fn finish(self) -> Result<(), PreflightError> {
+        match self.problems.is_empty() {
+            true => Ok(()),
+            false => Err(PreflightError::Failed(self.problems)),
+        }
+    }
+}
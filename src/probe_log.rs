@@ -0,0 +1,76 @@
+//! Warnings probe-rs logs while measuring (slow memory access, reattaching the probe, ...)
+//! are collected and recorded as events, the samples around them may be less reliable.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::mem_monitoring::Event;
+
+struct ProbeLogger {
+    events: Arc<Mutex<Vec<Event>>>,
+    echo: Arc<AtomicBool>,
+}
+
+impl Log for ProbeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn && metadata.target().starts_with("probe_rs")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let event = Event::ProbeLog {
+            level: record.level().to_string(),
+            message: record.args().to_string(),
+        };
+        if self.echo.load(Ordering::SeqCst) {
+            eprintln!("{}", event);
+        }
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Handle to the warnings logged since the last [`ProbeLog::drain`].
+pub struct ProbeLog {
+    events: Arc<Mutex<Vec<Event>>>,
+    echo: Arc<AtomicBool>,
+}
+
+impl ProbeLog {
+    /// Installs the logger for the process, without effect if a logger is installed.
+    pub fn install() -> Self {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let echo = Arc::new(AtomicBool::new(true));
+        let logger = ProbeLogger {
+            events: events.to_owned(),
+            echo: echo.to_owned(),
+        };
+        if log::set_boxed_logger(Box::new(logger)).is_ok() {
+            log::set_max_level(LevelFilter::Warn);
+        }
+
+        Self { events, echo }
+    }
+
+    /// Whether warnings are printed to stderr when logged, off while the dashboard owns
+    /// the terminal and shows them from [`ProbeLog::drain`].
+    pub fn set_echo(&self, echo: bool) {
+        self.echo.store(echo, Ordering::SeqCst);
+    }
+
+    pub fn drain(&self) -> Vec<Event> {
+        self.events
+            .lock()
+            .map(|mut events| events.drain(..).collect())
+            .unwrap_or_default()
+    }
+}
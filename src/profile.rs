@@ -0,0 +1,211 @@
+//! Named profiles of measure options per target board in a toml config file, so
+//! switching between the boards of a bench is a single `--profile`:
+//!
+//! ```toml
+//! [profile.nucleo-g431]
+//! firmware-path = "target/thumbv7em-none-eabihf/release/app"
+//! chip = "STM32G431RBTx"
+//! probe = "0483:374b:0671FF3833554B3043164817"
+//! stack-start = "_stack_start"
+//! max-stack-bytes = 4096
+//! budgets = "budgets/nucleo-g431.txt"
+//! label = ["board=nucleo-g431"]
+//! ```
+//!
+//! Keys are the long options, arrays give an option repeatedly and `true` sets a flag.
+//! Options given on the command line take precedence over the profile.
+
+use std::{collections::BTreeMap, path::Path};
+
+use clap::ArgMatches;
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Config file searched in the working directory without `--config`.
+pub const CONFIG_FILE: &str = "mem-analyser.toml";
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("could not read config file {0}")]
+    FailedReadingFile(String, #[source] std::io::Error),
+    #[error("malformed config file {0}")]
+    MalformedFile(String, #[source] toml::de::Error),
+    #[error("no profile {profile} in {path} (profiles: {available})")]
+    UnknownProfile {
+        profile: String,
+        path: String,
+        available: String,
+    },
+    #[error("unsupported value of {key} in profile {profile}, expected a string, number, bool or array of them")]
+    UnsupportedValue { profile: String, key: String },
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: BTreeMap<String, BTreeMap<String, toml::Value>>,
+}
+
+fn value_text(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(text) => Some(text.to_owned()),
+        toml::Value::Integer(number) => Some(number.to_string()),
+        toml::Value::Float(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+/// Command line options of the profile selected with `--profile`, without the options
+/// given in `matches`. Empty without a profile. Values are attached with `=`, they may
+/// start with a dash.
+pub fn selected_args(matches: &ArgMatches) -> Result<Vec<String>, ProfileError> {
+    let profile = match matches.value_of("profile") {
+        Some(profile) => profile,
+        None => return Ok(Vec::new()),
+    };
+    let path = matches.value_of("config").unwrap_or(CONFIG_FILE);
+    let content = std::fs::read_to_string(Path::new(path))
+        .map_err(|e| ProfileError::FailedReadingFile(String::from(path), e))?;
+    let mut config: ConfigFile =
+        toml::from_str(&content).map_err(|e| ProfileError::MalformedFile(String::from(path), e))?;
+    let options = config
+        .profile
+        .remove(profile)
+        .ok_or_else(|| ProfileError::UnknownProfile {
+            profile: String::from(profile),
+            path: String::from(path),
+            available: config
+                .profile
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+        })?;
+
+    let mut args = Vec::new();
+    for (key, value) in options.iter() {
+        let long = key.replace('_', "-");
+        if matches.occurrences_of(long.replace('-', "_")) > 0 {
+            continue;
+        }
+        let unsupported = || ProfileError::UnsupportedValue {
+            profile: String::from(profile),
+            key: key.to_owned(),
+        };
+        match value {
+            toml::Value::Boolean(true) => args.push(format!("--{}", long)),
+            toml::Value::Boolean(false) => {}
+            toml::Value::Array(values) => {
+                for value in values {
+                    args.push(format!(
+                        "--{}={}",
+                        long,
+                        value_text(value).ok_or_else(unsupported)?
+                    ));
+                }
+            }
+            value => args.push(format!(
+                "--{}={}",
+                long,
+                value_text(value).ok_or_else(unsupported)?
+            )),
+        }
+    }
+    println!("profile {} from {}: {}", profile, path, args.join(" "));
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use clap::{App, Arg};
+
+    use super::*;
+
+    /// Config files of the tests running in parallel.
+    static CONFIG_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    fn selected_args_of(config: &str, args: &[&str]) -> Result<Vec<String>, ProfileError> {
+        let path = std::env::temp_dir().join(format!(
+            "mem-analyser-profile-{}-{}.toml",
+            std::process::id(),
+            CONFIG_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, config).unwrap();
+        let matches = App::new("test")
+            .arg(Arg::with_name("profile").long("profile").takes_value(true))
+            .arg(Arg::with_name("config").long("config").takes_value(true))
+            .arg(Arg::with_name("chip").long("chip").takes_value(true))
+            .arg(Arg::with_name("no_history").long("no-history"))
+            .get_matches_from(
+                ["test", "--config", path.to_str().unwrap()]
+                    .iter()
+                    .chain(args),
+            );
+        let args = selected_args(&matches);
+        std::fs::remove_file(&path).unwrap();
+
+        args
+    }
+
+    const CONFIG: &str = r#"
+        [profile.nucleo]
+        chip = "STM32G431RBTx"
+        max-stack-bytes = 4096
+        stack_start = "_stack_start"
+        no-history = true
+        force = false
+        label = ["board=nucleo", "rev=-b"]
+
+        [profile.disco]
+        chip = "STM32F407VGTx"
+    "#;
+
+    #[test]
+    fn profile_options_become_long_options() {
+        assert_eq!(
+            selected_args_of(CONFIG, &["--profile", "nucleo"]).unwrap(),
+            [
+                "--chip=STM32G431RBTx",
+                "--label=board=nucleo",
+                "--label=rev=-b",
+                "--max-stack-bytes=4096",
+                "--no-history",
+                "--stack-start=_stack_start",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_options_take_precedence() {
+        let args =
+            selected_args_of(CONFIG, &["--profile", "disco", "--chip", "STM32F411CEUx"]).unwrap();
+        assert!(args.is_empty(), "{:?}", args);
+    }
+
+    #[test]
+    fn unknown_profiles_list_the_available_ones() {
+        match selected_args_of(CONFIG, &["--profile", "blackpill"]) {
+            Err(ProfileError::UnknownProfile { available, .. }) => {
+                assert_eq!(available, "disco, nucleo")
+            }
+            args => panic!("unexpected {:?}", args),
+        }
+    }
+
+    #[test]
+    fn tables_are_unsupported_values() {
+        let config = "[profile.nucleo]\nchip = { name = \"STM32G431RBTx\" }\n";
+        assert!(matches!(
+            selected_args_of(config, &["--profile", "nucleo"]),
+            Err(ProfileError::UnsupportedValue { .. })
+        ));
+    }
+
+    #[test]
+    fn no_profile_selects_nothing() {
+        assert!(selected_args_of(CONFIG, &[]).unwrap().is_empty());
+    }
+}
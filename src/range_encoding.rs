@@ -0,0 +1,194 @@
+//! Compact serialized form of the used ranges of a snapshot. Fragmented stacks have
+//! hundreds of small ranges, written as `{"start":..,"end":..}` objects they made up
+//! most of a recording.
+//!
+//! The ranges are written as hex string of LEB128 varints: the start of the first range,
+//! then alternately the length of a range and the gap to the next one. Recordings with
+//! the ranges as objects are still read. Binary formats get the varints as bytes.
+//!
+//! A recording knowing the stack base stores the ranges of its snapshots [`mirror`]ed
+//! at the base, the first start is then a small offset instead of a full address.
+
+use std::ops::Range;
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn encode(ranges: &[Range<u32>]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut prev_end = None;
+    for range in ranges {
+        // ranges out of order wrap around, they are still restored exactly
+        match prev_end {
+            None => push_varint(&mut bytes, range.start),
+            Some(end) => push_varint(&mut bytes, range.start.wrapping_sub(end)),
+        }
+        push_varint(&mut bytes, range.end.wrapping_sub(range.start));
+        prev_end = Some(range.end);
+    }
+
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<Vec<Range<u32>>> {
+    let mut values = Vec::new();
+    let mut value = 0u32;
+    let mut shift = 0;
+    for byte in bytes {
+        // only the low 4 bits of a 5th byte fit into 32 bits
+        if shift > 28 || (shift == 28 && byte & 0x70 != 0) {
+            return None;
+        }
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            values.push(value);
+            value = 0;
+            shift = 0;
+        } else {
+            shift += 7;
+        }
+    }
+    if shift != 0 || values.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut ranges = Vec::with_capacity(values.len() / 2);
+    let mut prev_end = 0u32;
+    for (i, pair) in values.chunks(2).enumerate() {
+        let start = match i {
+            0 => pair[0],
+            _ => prev_end.wrapping_add(pair[0]),
+        };
+        let end = start.wrapping_add(pair[1]);
+        ranges.push(start..end);
+        prev_end = end;
+    }
+
+    Some(ranges)
+}
+
+/// Ranges below `base` as offsets below it, the range ending at the base first, and
+/// such offsets back as ranges.
+pub fn mirror(ranges: &[Range<u32>], base: u32) -> Vec<Range<u32>> {
+    ranges
+        .iter()
+        .rev()
+        .map(|r| base.wrapping_sub(r.end)..base.wrapping_sub(r.start))
+        .collect()
+}
+
+pub fn serialize<S: Serializer>(ranges: &[Range<u32>], serializer: S) -> Result<S::Ok, S::Error> {
+    if !serializer.is_human_readable() {
+        return encode(ranges).serialize(serializer);
+    }
+    serializer.serialize_str(&hex::encode(encode(ranges)))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Serialized {
+    Encoded(String),
+    Plain(Vec<Range<u32>>),
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Range<u32>>, D::Error> {
+    // binary formats can't tell the untagged variants apart
+    if !deserializer.is_human_readable() {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        return decode(&bytes).ok_or_else(|| D::Error::custom("invalid encoded ranges"));
+    }
+    match Serialized::deserialize(deserializer)? {
+        Serialized::Encoded(text) => hex::decode(&text)
+            .ok()
+            .and_then(|bytes| decode(&bytes))
+            .ok_or_else(|| D::Error::custom(format!("invalid encoded ranges {}", text))),
+        Serialized::Plain(ranges) => Ok(ranges),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let ranges = vec![0x2000_0000..0x2000_0020, 0x2000_0030..0x2000_0070];
+        let bytes = encode(&ranges);
+        assert_eq!(hex::encode(&bytes), "8080808002201040");
+        assert_eq!(decode(&bytes), Some(ranges));
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn roundtrip_edge_cases() {
+        for ranges in [
+            vec![],
+            vec![0..0],
+            vec![0..u32::MAX],
+            vec![u32::MAX..u32::MAX],
+            // out of order and overlapping ranges wrap around
+            vec![0x100..0x200, 0x10..0x20, 0x150..0x300],
+        ] {
+            assert_eq!(decode(&encode(&ranges)), Some(ranges));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn decode_rejects_malformed() {
+        // truncated varint
+        assert_eq!(decode(&[0x80]), None);
+        // start without length
+        assert_eq!(decode(&[0x10]), None);
+        // 5th byte with bits above 32
+        assert_eq!(decode(&[0xff, 0xff, 0xff, 0xff, 0x1f, 0x00]), None);
+        // 6th byte
+        assert_eq!(decode(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00, 0x00]), None);
+        assert_eq!(
+            decode(&[0xff, 0xff, 0xff, 0xff, 0x0f, 0x00]),
+            Some(vec![u32::MAX..u32::MAX])
+        );
+    }
+
+    #[test]
+    fn mirror_roundtrip() {
+        let base = 0x2002_0000;
+        let ranges = vec![0x2001_f000..0x2001_f100, 0x2001_ff00..0x2002_0000];
+        let mirrored = mirror(&ranges, base);
+        assert_eq!(mirrored, vec![0..0x100, 0xf00..0x1000]);
+        assert_eq!(mirror(&mirrored, base), ranges);
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Snapshot {
+            #[serde(with = "super")]
+            ranges: Vec<Range<u32>>,
+        }
+
+        let snapshot = Snapshot {
+            ranges: vec![0x2000_0000..0x2000_0020, 0x2000_0030..0x2000_0070],
+        };
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(json, r#"{"ranges":"8080808002201040"}"#);
+        assert_eq!(serde_json::from_str::<Snapshot>(&json).unwrap(), snapshot);
+        let bin = bincode::serialize(&snapshot).unwrap();
+        assert_eq!(bincode::deserialize::<Snapshot>(&bin).unwrap(), snapshot);
+
+        let plain = r#"{"ranges":[{"start":536870912,"end":536870944}]}"#;
+        assert_eq!(
+            serde_json::from_str::<Snapshot>(plain).unwrap().ranges,
+            vec![0x2000_0000..0x2000_0020]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Compact binary record files for long runs, where the json record file grows to
+//! hundreds of megabytes. The recording is written with bincode and compressed with
+//! zstd behind a magic, so every command reading a record file takes both formats.
+//! `convert` turns a binary record file back into json for inspection.
+
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::{mem_monitoring::RamSnapshotRecorder, DynError};
+
+/// Extension of binary record files, replacing `json` of the default record file.
+pub const BIN_EXTENSION: &str = "bin";
+
+/// Start of a binary record file, followed by the version of the format.
+const MAGIC: &[u8; 7] = b"MEMREC\0";
+const VERSION: u8 = 2;
+/// Compresses a long run about as well as the higher levels in a fraction of the time.
+const COMPRESSION_LEVEL: i32 = 9;
+
+#[derive(Error, Debug)]
+pub enum RecordFormatError {
+    #[error("unknown record format {0}, expected json or bin")]
+    UnknownFormat(String),
+    #[error("binary record file of version {0}, this version reads version {VERSION}")]
+    UnsupportedVersion(u8),
+    #[error("malformed binary record file")]
+    Malformed(#[source] bincode::Error),
+    #[error("could not compress or decompress the record file")]
+    Compression(#[source] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    Json,
+    Bin,
+}
+
+impl RecordFormat {
+    pub fn from_name(name: &str) -> Result<Self, RecordFormatError> {
+        match name {
+            "json" => Ok(RecordFormat::Json),
+            "bin" => Ok(RecordFormat::Bin),
+            _ => Err(RecordFormatError::UnknownFormat(String::from(name))),
+        }
+    }
+
+    pub fn of(content: &[u8]) -> Self {
+        match content.starts_with(MAGIC) {
+            true => RecordFormat::Bin,
+            false => RecordFormat::Json,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Json => "json",
+            RecordFormat::Bin => BIN_EXTENSION,
+        }
+    }
+
+    pub fn encode(&self, recorder: &RamSnapshotRecorder) -> DynError<Vec<u8>> {
+        match self {
+            RecordFormat::Json => Ok(serde_json::to_vec(recorder)?),
+            RecordFormat::Bin => {
+                let serialized =
+                    bincode::serialize(recorder).map_err(RecordFormatError::Malformed)?;
+                let mut content = MAGIC.to_vec();
+                content.push(VERSION);
+                content.extend(
+                    zstd::encode_all(serialized.as_slice(), COMPRESSION_LEVEL)
+                        .map_err(RecordFormatError::Compression)?,
+                );
+
+                Ok(content)
+            }
+        }
+    }
+}
+
+/// Recording of a record file in either format.
+pub fn decode(content: &[u8]) -> DynError<RamSnapshotRecorder> {
+    if RecordFormat::of(content) == RecordFormat::Json {
+        return Ok(serde_json::from_slice(content)?);
+    }
+    let version = content.get(MAGIC.len()).copied().unwrap_or(0);
+    if version != VERSION {
+        return Err(RecordFormatError::UnsupportedVersion(version).into());
+    }
+    // decompressed as a whole, the hex fields borrow from the input
+    let serialized =
+        zstd::decode_all(&content[MAGIC.len() + 1..]).map_err(RecordFormatError::Compression)?;
+
+    Ok(bincode::deserialize(&serialized).map_err(RecordFormatError::Malformed)?)
+}
+
+pub fn write(path: &Path, recorder: &RamSnapshotRecorder, format: RecordFormat) -> DynError<()> {
+    std::fs::write(path, format.encode(recorder)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{mem_monitoring::RamSnapshot, session::SessionInfo};
+
+    const STACK_BASE: u32 = 0x2000_8000;
+
+    fn recording(stack_base: Option<u32>) -> RamSnapshotRecorder {
+        let mut recorder = RamSnapshotRecorder::new(
+            SessionInfo::new(b"firmware", String::from("test")),
+            1024,
+            Duration::from_millis(10),
+        );
+        if let Some(stack_base) = stack_base {
+            recorder.set_stack_base(stack_base);
+        }
+        for (function, offset) in [("main", 64), ("handler", 256), ("main", 64)] {
+            recorder.record(
+                RamSnapshot {
+                    used_bytes: offset + 16,
+                    stack_ptr_offset: offset,
+                    ranges: vec![
+                        0x2000_0000..0x2000_0010,
+                        STACK_BASE - offset..STACK_BASE - offset / 2,
+                    ],
+                    instr_ptr: 0x0800_0100 + offset,
+                    function: String::from(function),
+                    psp_active: false,
+                    process_stack: None,
+                    exception: None,
+                    interrupted_function: None,
+                    backtrace: Vec::new(),
+                    source: Vec::new(),
+                    marker: None,
+                    sp_anomaly: None,
+                    halted_at: None,
+                },
+                None,
+            );
+        }
+
+        recorder
+    }
+
+    fn ranges(recorder: &RamSnapshotRecorder) -> Vec<Vec<std::ops::Range<u32>>> {
+        recorder.timeline().map(|s| s.ranges.to_owned()).collect()
+    }
+
+    #[test]
+    fn both_formats_roundtrip_with_and_without_stack_base() {
+        for stack_base in [None, Some(STACK_BASE)] {
+            for format in [RecordFormat::Json, RecordFormat::Bin] {
+                let recorder = recording(stack_base);
+                let content = format.encode(&recorder).unwrap();
+                assert_eq!(RecordFormat::of(&content), format);
+
+                let decoded = decode(&content).unwrap();
+                assert_eq!(ranges(&decoded), ranges(&recorder));
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(&recorder).unwrap(),
+                    "{:?} with stack base {:?}",
+                    format,
+                    stack_base
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut content = RecordFormat::Bin.encode(&recording(None)).unwrap();
+        content[MAGIC.len()] = VERSION + 1;
+
+        let error = decode(&content).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RecordFormatError::UnsupportedVersion(v)) if *v == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn formats_by_name() {
+        assert_eq!(
+            RecordFormat::from_name("bin").unwrap().extension(),
+            BIN_EXTENSION
+        );
+        assert_eq!(RecordFormat::from_name("json").unwrap().extension(), "json");
+        assert!(matches!(
+            RecordFormat::from_name("cbor"),
+            Err(RecordFormatError::UnknownFormat(_))
+        ));
+    }
+}
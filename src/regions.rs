@@ -0,0 +1,183 @@
+use std::ops::Range;
+
+use object::{Object, ObjectSymbol, SymbolKind};
+use probe_rs::{config::RamRegion, MemoryInterface};
+use thiserror::Error;
+
+use crate::{cpu, elf, paint::FillPattern, DynError};
+
+/// Symbol name fragments of framework statics which hold task state.
+const STATIC_SYMBOL_PATTERNS: &[(&str, &str)] = &[
+    ("embassy", "POOL"),
+    ("embassy", "ARENA"),
+    ("__rtic_internal", ""),
+];
+
+#[derive(Error, Debug)]
+pub enum RegionError {
+    #[error("invalid region {0}, expected NAME=ADDR+LEN, NAME=ADDR..END or NAME=SYMBOL, optionally followed by :stack")]
+    InvalidRegion(String),
+    #[error("region {0} is empty")]
+    EmptyRegion(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    /// Grows downwards from the end of the region, unused memory is painted.
+    Stack,
+    /// Zero initialized static, used from the start of the region.
+    Static,
+    /// Painted ram bank besides the one of the main stack, every byte which lost the
+    /// paint is used.
+    Ram,
+    /// Painted region given by the user, like the pool of an arena allocator, every byte
+    /// which lost the paint is used.
+    Custom,
+}
+
+#[derive(Debug, Clone)]
+pub struct NamedRegion {
+    pub name: String,
+    pub range: Range<u32>,
+    pub kind: RegionKind,
+}
+
+impl NamedRegion {
+    /// Named like the bank in the target description, else by its start address.
+    pub fn ram_bank(region: &RamRegion) -> Self {
+        Self {
+            name: region
+                .name
+                .to_owned()
+                .unwrap_or_else(|| format!("ram {:#010x}", region.range.start)),
+            range: region.range.to_owned(),
+            kind: RegionKind::Ram,
+        }
+    }
+
+    /// Parses `NAME=ADDR+LEN`, `NAME=ADDR..END` or `NAME=SYMBOL` (the extent of the
+    /// symbol). Addresses are hex or symbols, lengths decimal or hex with `0x`. A `:stack`
+    /// suffix measures the region like a stack growing down from its end.
+    pub fn parse(spec: &str, obj_file: &object::File) -> DynError<Self> {
+        let invalid = || RegionError::InvalidRegion(String::from(spec));
+        let (spec_body, kind) = match spec.strip_suffix(":stack") {
+            Some(body) => (body, RegionKind::Stack),
+            None => (spec, RegionKind::Custom),
+        };
+        let (name, extent) = spec_body
+            .split_once('=')
+            .filter(|(name, extent)| !name.is_empty() && !extent.is_empty())
+            .ok_or_else(invalid)?;
+
+        let range = if let Some((start, len)) = extent.split_once('+') {
+            let start = elf::resolve_addr(obj_file, start)?;
+            let len = match len.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => len.parse(),
+            }
+            .map_err(|_| invalid())?;
+            start..start.checked_add(len).ok_or_else(invalid)?
+        } else if let Some((start, end)) = extent.split_once("..") {
+            elf::resolve_addr(obj_file, start)?..elf::resolve_addr(obj_file, end)?
+        } else {
+            let (start, size) = elf::find_symbol_with_size(obj_file, extent)
+                .ok_or_else(|| elf::ElfError::SymbolNotFound(String::from(extent)))?;
+            start..start.checked_add(size).ok_or_else(invalid)?
+        };
+        if range.is_empty() {
+            return Err(RegionError::EmptyRegion(String::from(name)).into());
+        }
+
+        Ok(Self {
+            name: String::from(name),
+            range,
+            kind,
+        })
+    }
+
+    fn used_bytes(&self, data: &[u8], fill_pattern: FillPattern) -> u32 {
+        let untouched = || fill_pattern.untouched(self.range.start, data);
+        match self.kind {
+            RegionKind::Stack => untouched()
+                .iter()
+                .position(|u| !*u)
+                .map(|p| (data.len() - p) as u32)
+                .unwrap_or(0),
+            RegionKind::Static => data
+                .iter()
+                .rposition(|b| *b != 0)
+                .map(|p| p as u32 + 1)
+                .unwrap_or(0),
+            RegionKind::Ram | RegionKind::Custom => {
+                untouched().iter().filter(|u| !**u).count() as u32
+            }
+        }
+    }
+}
+
+/// Bank of the main stack, the one the initial stack pointer points into or at the end
+/// of, else the first bank.
+pub fn stack_bank(banks: &[RamRegion], initial_stack_ptr: u32) -> Option<&RamRegion> {
+    banks
+        .iter()
+        .find(|r| r.range.start < initial_stack_ptr && initial_stack_ptr <= r.range.end)
+        .or_else(|| banks.first())
+}
+
+/// Finds the main stack and framework task statics by their linker symbols.
+pub fn discover_regions(obj_file: &object::File) -> Vec<NamedRegion> {
+    let mut regions = Vec::new();
+
+    let stack_end = elf::STATICS_END_SYMBOLS
+        .iter()
+        .find_map(|s| elf::find_symbol(obj_file, s));
+    if let (Some(start), Some(end)) = (stack_end, elf::find_symbol(obj_file, "_stack_start")) {
+        regions.push(NamedRegion {
+            name: String::from("_stack_start"),
+            range: start..end,
+            kind: RegionKind::Stack,
+        });
+    }
+
+    for symbol in obj_file.symbols() {
+        let name = match symbol.name() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let is_task_static = symbol.kind() == SymbolKind::Data
+            && symbol.size() > 0
+            && STATIC_SYMBOL_PATTERNS
+                .iter()
+                .any(|(a, b)| name.contains(a) && name.contains(b));
+        if is_task_static {
+            let start = symbol.address() as u32;
+            regions.push(NamedRegion {
+                name: String::from(name),
+                range: start..start + symbol.size() as u32,
+                kind: RegionKind::Static,
+            });
+        }
+    }
+
+    regions
+}
+
+/// Returns the used bytes of every region.
+pub fn measure_regions(
+    cpu: &mut cpu::CPU,
+    regions: &[NamedRegion],
+    fill_pattern: FillPattern,
+) -> DynError<Vec<u32>> {
+    let res = cpu.access_only_in_halt_mode(|core| {
+        let mut usage = Vec::with_capacity(regions.len());
+        for region in regions {
+            let mut data = vec![0u8; (region.range.end - region.range.start) as usize];
+            core.read(region.range.start, data.as_mut_slice())?;
+            usage.push(region.used_bytes(&data, fill_pattern));
+        }
+
+        Ok(usage)
+    })?;
+
+    Ok(res)
+}
@@ -1,18 +1,82 @@
 use bitfield::bitfield;
+use probe_rs::CoreRegisterAddress;
+
+// DCRSR register selectors of the cortex-m special registers
+pub const XPSR: CoreRegisterAddress = CoreRegisterAddress(0b1_0000);
+pub const MSP: CoreRegisterAddress = CoreRegisterAddress(0b1_0001);
+pub const PSP: CoreRegisterAddress = CoreRegisterAddress(0b1_0010);
+/// CONTROL in bits [31:24], FAULTMASK, BASEPRI and PRIMASK below.
+pub const CONTROL: CoreRegisterAddress = CoreRegisterAddress(0b1_0100);
+
+bitfield! {
+    pub struct DEMCR(u32);
+    impl Debug;
+
+    // enables DWT and ITM
+    pub trcena, set_trcena: 24;
+}
+
+impl DEMCR {
+    pub const ADDRESS: u32 = 0xE000EDFC;
+}
+
+bitfield! {
+    pub struct DHCSR(u32);
+    impl Debug;
+
+    // core was reset since the last read of DHCSR, cleared on read
+    pub s_reset_st, _: 25;
+}
+
+impl DHCSR {
+    pub const ADDRESS: u32 = 0xE000EDF0;
+}
+
+bitfield! {
+    pub struct DFSR(u32);
+    impl Debug;
+
+    pub halted, _: 0;
+    pub bkpt, _: 1;
+    // halted by a DWT comparator match
+    pub dwttrap, _: 2;
+}
+
+impl DFSR {
+    pub const ADDRESS: u32 = 0xE000ED30;
+}
+
+bitfield! {
+    pub struct DWTFunction(u32);
+    impl Debug;
+
+    pub matched, _: 24;
+    pub datavsize, set_datavsize: 11, 10;
+    pub function, set_function: 3, 0;
+}
+
+impl DWTFunction {
+    // DWT comparator 0, the mask register sits between comparator and function
+    pub const COMP_ADDRESS: u32 = 0xE0001020;
+    pub const MASK_ADDRESS: u32 = 0xE0001024;
+    pub const ADDRESS: u32 = 0xE0001028;
+
+    pub const DISABLED: u32 = 0b0000;
+    // halt on data write access (ARMv7-M encoding)
+    pub const WRITE_WATCHPOINT: u32 = 0b0110;
+}
 
 bitfield! {
-    pub struct ICSR(u32);
+    pub struct DWTCtrl(u32);
     impl Debug;
 
-    // interrupt pending generated by NVIC
-    pub isr_pending, _: 22;
-    // wether interrupt serviced after debug halt or not
-    pub isr_preemt, _: 23;
-    pub st_pending, set_st_pending: 26;
-    // if no interrupt pending -> 0 else interrupt number
-    pub vect_pending, _: 20, 12;
+    // the DWT has no cycle counter, e.g. on ARMv6-M
+    pub nocyccnt, _: 25;
+    pub cyccntena, set_cyccntena: 0;
 }
 
-impl ICSR {
-    pub const ADDRESS: u32 = 0xE000E004;
+impl DWTCtrl {
+    pub const ADDRESS: u32 = 0xE0001000;
+    // free running with the core clock, stops while the core is halted
+    pub const CYCCNT_ADDRESS: u32 = 0xE0001004;
 }
@@ -0,0 +1,149 @@
+use probe_rs::{CoreRegisterAddress, MemoryInterface};
+use thiserror::Error;
+
+use crate::{
+    asm_parsing::AsmFile,
+    cpu, elf,
+    mem_monitoring::{Event, RamSnapshotRecorder},
+    DynError,
+};
+
+#[derive(Error, Debug)]
+pub enum ReplError {
+    #[error("unknown command {0}, expected one of: s, c, reg <name> <value>, mem <addr|symbol> <value>, jump <function>, diff <snapshot-id> <snapshot-id>")]
+    UnknownCommand(String),
+    #[error("missing argument for command {0}")]
+    MissingArgument(&'static str),
+    #[error("failed parsing value {0}")]
+    ValueParseError(String),
+    #[error("unknown register {0}")]
+    UnknownRegister(String),
+    #[error("unknown function {0}")]
+    UnknownFunction(String),
+    #[error("no snapshot with id {0}")]
+    UnknownSnapshot(usize),
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Step,
+    Continue,
+    WriteRegister { register: String, value: u32 },
+    WriteMemory { address: String, value: u32 },
+    Jump { function: String },
+    Diff { from: usize, to: usize },
+}
+
+pub fn parse_command(line: &str) -> Result<Command, ReplError> {
+    let mut args = line.split_whitespace();
+    let command = match args.next() {
+        None | Some("s") => Command::Step,
+        Some("c") => Command::Continue,
+        Some("reg") => Command::WriteRegister {
+            register: String::from(args.next().ok_or(ReplError::MissingArgument("reg"))?),
+            value: parse_value(args.next().ok_or(ReplError::MissingArgument("reg"))?)?,
+        },
+        Some("mem") => Command::WriteMemory {
+            address: String::from(args.next().ok_or(ReplError::MissingArgument("mem"))?),
+            value: parse_value(args.next().ok_or(ReplError::MissingArgument("mem"))?)?,
+        },
+        Some("jump") => Command::Jump {
+            function: String::from(args.next().ok_or(ReplError::MissingArgument("jump"))?),
+        },
+        Some("diff") => Command::Diff {
+            from: parse_value(args.next().ok_or(ReplError::MissingArgument("diff"))?)? as usize,
+            to: parse_value(args.next().ok_or(ReplError::MissingArgument("diff"))?)? as usize,
+        },
+        Some(c) => return Err(ReplError::UnknownCommand(String::from(c))),
+    };
+
+    Ok(command)
+}
+
+fn parse_value(value: &str) -> Result<u32, ReplError> {
+    let res = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    };
+    res.map_err(|_| ReplError::ValueParseError(String::from(value)))
+}
+
+/// Executes a write command on the halted core and logs it as event in the recorder,
+/// or prints the difference of two recorded snapshots.
+pub fn execute(
+    command: &Command,
+    cpu: &mut cpu::CPU,
+    obj_file: &object::File,
+    asm_file: Option<&AsmFile>,
+    recorder: &mut RamSnapshotRecorder,
+) -> DynError<()> {
+    let event = match command {
+        Command::WriteRegister { register, value } => {
+            write_register(cpu, register, *value)?;
+            Event::RegisterWrite {
+                register: register.to_owned(),
+                value: *value,
+            }
+        }
+        Command::WriteMemory { address, value } => {
+            let address = elf::resolve_addr(obj_file, address)?;
+            cpu.access_core(|core| core.write_word_32(address, *value))?;
+            Event::MemoryWrite {
+                address,
+                value: *value,
+            }
+        }
+        Command::Jump { function } => {
+            let addr = asm_file
+                .and_then(|a| a.get_function_by_name(function))
+                .ok_or_else(|| ReplError::UnknownFunction(function.to_owned()))?
+                .range
+                .start;
+            write_register(cpu, "pc", addr)?;
+            Event::RegisterWrite {
+                register: String::from("pc"),
+                value: addr,
+            }
+        }
+        Command::Diff { from, to } => {
+            let snapshot = |id: usize| {
+                recorder
+                    .get_snapshot(id)
+                    .ok_or(ReplError::UnknownSnapshot(id))
+            };
+            snapshot(*from)?.print_diff(snapshot(*to)?);
+            return Ok(());
+        }
+        Command::Step | Command::Continue => return Ok(()),
+    };
+
+    println!("{}", event);
+    recorder.record_event(event);
+
+    Ok(())
+}
+
+fn write_register(cpu: &mut cpu::CPU, register: &str, value: u32) -> DynError<()> {
+    let written = cpu.access_core(|core| {
+        let registers = core.registers();
+        let address = match register.to_lowercase().as_str() {
+            "pc" => Some(CoreRegisterAddress::from(registers.program_counter())),
+            "sp" => Some(CoreRegisterAddress::from(registers.stack_pointer())),
+            "lr" => Some(CoreRegisterAddress::from(registers.return_address())),
+            _ => registers
+                .platform_registers()
+                .find(|r| r.name().eq_ignore_ascii_case(register))
+                .map(CoreRegisterAddress::from),
+        };
+        match address {
+            Some(address) => core.write_core_reg(address, value).map(|_| true),
+            None => Ok(false),
+        }
+    })?;
+
+    if !written {
+        return Err(ReplError::UnknownRegister(String::from(register)).into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,331 @@
+use std::{fmt::Write as _, path::Path};
+
+use crate::{
+    call_graph::CallGraph,
+    mem_monitoring::RamSnapshotRecorder,
+    source::SourceResolver,
+    statistics::StatisticsEngine,
+    timestamp::{self, TimeZone},
+    DynError,
+};
+
+pub const HOTSPOT_COUNT: usize = 10;
+const ENTRY_POINT_COUNT: usize = 10;
+const SNIPPET_CONTEXT_LINES: u32 = 3;
+const CHART_WIDTH: usize = 800;
+const CHART_HEIGHT: usize = 200;
+
+pub struct ReportOptions {
+    pub hotspot_count: usize,
+    pub time_zone: TimeZone,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            hotspot_count: HOTSPOT_COUNT,
+            time_zone: TimeZone::Utc,
+        }
+    }
+}
+
+/// Writes a single html file without external resources, the charts are inline svg.
+pub fn write_html_report(
+    path: &Path,
+    recorder: &RamSnapshotRecorder,
+    resolver: Option<&SourceResolver>,
+    call_graph: Option<&CallGraph>,
+    options: &ReportOptions,
+) -> DynError<()> {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>mem-analyser report</title>\n");
+    html.push_str("<style>body{font-family:sans-serif} pre{background:#f4f4f4;padding:4px} .hit{background:#ffd6d6}</style>\n");
+    html.push_str("</head>\n<body>\n<h1>mem-analyser report</h1>\n");
+    write_metadata(&mut html, recorder, options.time_zone)?;
+    write_chart(&mut html, recorder)?;
+
+    html.push_str("<h2>Stack hotspots</h2>\n");
+    for snapshot in StatisticsEngine::new(recorder).hotspots(options.hotspot_count) {
+        writeln!(
+            html,
+            "<h3>{} &mdash; {} bytes (0x{:08x})</h3>",
+            escape(&snapshot.function),
+            snapshot.stack_ptr_offset,
+            snapshot.instr_ptr
+        )?;
+        if let Some(exception) = snapshot.exception.as_ref() {
+            writeln!(
+                html,
+                "<p>in exception {}, interrupted {} (0x{:08x})</p>",
+                exception.exception_number,
+                escape(
+                    snapshot
+                        .interrupted_function
+                        .as_deref()
+                        .unwrap_or("<unknown>")
+                ),
+                exception.pc
+            )?;
+        }
+
+        if !snapshot.backtrace.is_empty() {
+            let functions = snapshot
+                .backtrace
+                .iter()
+                .map(|f| escape(f.function.as_deref().unwrap_or("<unknown>")))
+                .collect::<Vec<_>>();
+            writeln!(html, "<p>call chain: {}</p>", functions.join(" &larr; "))?;
+        }
+
+        match resolver.and_then(|r| r.snippet(snapshot.instr_ptr, SNIPPET_CONTEXT_LINES)) {
+            Some(snippet) => {
+                writeln!(
+                    html,
+                    "<p>{}:{}</p>\n<pre>",
+                    escape(&snippet.location.file),
+                    snippet.location.line
+                )?;
+                for (index, line) in snippet.lines.iter().enumerate() {
+                    let line_nr = snippet.first_line + index as u32;
+                    if line_nr == snippet.location.line {
+                        writeln!(
+                            html,
+                            "<span class=\"hit\">{:>5} {}</span>",
+                            line_nr,
+                            escape(line)
+                        )?;
+                    } else {
+                        writeln!(html, "{:>5} {}", line_nr, escape(line))?;
+                    }
+                }
+                html.push_str("</pre>\n");
+            }
+            None => html.push_str("<p>no source available</p>\n"),
+        }
+    }
+
+    write_functions(&mut html, recorder)?;
+    write_findings(&mut html, recorder)?;
+
+    if let Some(call_graph) = call_graph {
+        write_static_analysis(&mut html, recorder, call_graph)?;
+    }
+
+    html.push_str("</body>\n</html>\n");
+    std::fs::write(path, html)?;
+
+    Ok(())
+}
+
+fn write_metadata(
+    html: &mut String,
+    recorder: &RamSnapshotRecorder,
+    time_zone: TimeZone,
+) -> DynError<()> {
+    let session = recorder.session();
+    html.push_str("<h2>Run</h2>\n<table>\n");
+    for (name, value) in [
+        ("recorded", time_zone.format(&session.timestamp)),
+        ("firmware sha256", session.firmware_hash.to_owned()),
+        ("config", session.config.to_owned()),
+        ("labels", session.labels_text()),
+        ("notes", session.notes.join("; ")),
+        (
+            "device",
+            session
+                .device_id
+                .to_owned()
+                .unwrap_or_else(|| String::from("unknown")),
+        ),
+        (
+            "interval",
+            format!("{} ms", recorder.analyse_interval().as_millis()),
+        ),
+        ("records", recorder.timeline().count().to_string()),
+        (
+            "target halted",
+            StatisticsEngine::new(recorder)
+                .overhead()
+                .map(|o| o.to_string())
+                .unwrap_or_else(|| String::from("unknown")),
+        ),
+    ]
+    .into_iter()
+    // labels and notes are only shown if given
+    .filter(|(_, value)| !value.is_empty())
+    {
+        writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            name,
+            escape(&value)
+        )?;
+    }
+    html.push_str("</table>\n");
+
+    Ok(())
+}
+
+/// Stack pointer offset and used bytes of every record as svg polylines.
+fn write_chart(html: &mut String, recorder: &RamSnapshotRecorder) -> DynError<()> {
+    let records = recorder.timeline().collect::<Vec<_>>();
+    if records.is_empty() {
+        return Ok(());
+    }
+    let max = records
+        .iter()
+        .map(|r| r.used_bytes.max(r.stack_ptr_offset))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let x_step = CHART_WIDTH as f32 / (records.len().max(2) - 1) as f32;
+    let points = |value: &dyn Fn(usize) -> u32| {
+        (0..records.len())
+            .map(|i| {
+                format!(
+                    "{:.1},{:.1}",
+                    i as f32 * x_step,
+                    CHART_HEIGHT as f32 * (1.0 - value(i) as f32 / max as f32)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    html.push_str("<h2>Stack usage over time</h2>\n");
+    writeln!(
+        html,
+        "<svg width=\"{}\" height=\"{}\" style=\"border:1px solid #ccc\">",
+        CHART_WIDTH, CHART_HEIGHT
+    )?;
+    writeln!(
+        html,
+        "<polyline fill=\"none\" stroke=\"#1f77b4\" points=\"{}\"/>",
+        points(&|i| records[i].used_bytes)
+    )?;
+    writeln!(
+        html,
+        "<polyline fill=\"none\" stroke=\"#d62728\" points=\"{}\"/>",
+        points(&|i| records[i].stack_ptr_offset)
+    )?;
+    html.push_str("</svg>\n");
+    writeln!(
+        html,
+        "<p><span style=\"color:#1f77b4\">used bytes</span>, <span style=\"color:#d62728\">stack pointer offset</span>, 0 to {} bytes, {} to {}</p>",
+        max,
+        escape(&timestamp::to_iso8601(&recorder.record_time(0))),
+        escape(&timestamp::to_iso8601(
+            &recorder.record_time(records.len() - 1)
+        ))
+    )?;
+
+    Ok(())
+}
+
+/// Records and maximum stack per function the core was halted in.
+fn write_functions(html: &mut String, recorder: &RamSnapshotRecorder) -> DynError<()> {
+    html.push_str("<h2>Functions</h2>\n<table>\n<tr><th>function</th><th>records</th><th>max stack bytes</th><th>max used bytes</th></tr>\n");
+    for function in recorder.function_statistics() {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&function.function),
+            function.records,
+            function.max_stack_ptr_off,
+            function.max_used_bytes
+        )?;
+    }
+    html.push_str("</table>\n");
+
+    Ok(())
+}
+
+fn write_findings(html: &mut String, recorder: &RamSnapshotRecorder) -> DynError<()> {
+    if recorder.findings().is_empty() {
+        return Ok(());
+    }
+
+    html.push_str("<h2>Findings</h2>\n<table>\n<tr><th>severity</th><th>category</th><th>finding</th><th>snapshot</th><th>addresses</th></tr>\n");
+    for finding in recorder.findings() {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            finding.severity,
+            finding.category,
+            escape(&finding.message),
+            finding
+                .snapshot
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            finding
+                .addresses
+                .iter()
+                .map(|a| format!("0x{:08x}", a))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )?;
+    }
+    html.push_str("</table>\n");
+
+    Ok(())
+}
+
+fn write_static_analysis(
+    html: &mut String,
+    recorder: &RamSnapshotRecorder,
+    call_graph: &CallGraph,
+) -> DynError<()> {
+    html.push_str("<h2>Static worst case stack</h2>\n");
+    if let Some(measured) = StatisticsEngine::new(recorder).hotspots(1).first() {
+        writeln!(
+            html,
+            "<p>measured maximum: {} bytes in {}</p>",
+            measured.stack_ptr_offset,
+            escape(&measured.function)
+        )?;
+    }
+
+    html.push_str(
+        "<table>\n<tr><th>entry point</th><th>bytes</th><th>deepest call chain</th></tr>\n",
+    );
+    for depth in call_graph.worst_case_stack().iter().take(ENTRY_POINT_COUNT) {
+        let mut notes = Vec::new();
+        if depth.recursive {
+            notes.push("recursive");
+        }
+        if depth.indirect_calls {
+            notes.push("indirect calls");
+        }
+        if depth.dynamic_frames {
+            notes.push("dynamic frames");
+        }
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}{}</td><td>{}</td></tr>",
+            escape(&depth.entry),
+            depth.bytes,
+            if notes.is_empty() {
+                String::new()
+            } else {
+                format!(" ({}, lower bound)", notes.join(", "))
+            },
+            depth
+                .path
+                .iter()
+                .map(|f| escape(f))
+                .collect::<Vec<_>>()
+                .join(" &rarr; ")
+        )?;
+    }
+    html.push_str("</table>\n");
+
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
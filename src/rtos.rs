@@ -0,0 +1,143 @@
+//! FreeRTOS task awareness.
+//!
+//! Assumes a 32 bit port with the default list layout (no list integrity check bytes)
+//! and `configMAX_TASK_NAME_LEN` of 16.
+
+use probe_rs::{Core, MemoryInterface};
+use serde::{Deserialize, Serialize};
+use serde_hex::{SerHex, StrictPfx};
+
+use crate::{cpu, elf, DynError};
+
+/// Byte FreeRTOS fills new task stacks with.
+const STACK_FILL_BYTE: u8 = 0xa5;
+const LIST_SIZE: u32 = 20;
+const TASK_NAME_LEN: usize = 16;
+
+const TCB_PRIORITY_OFFSET: u32 = 44;
+const TCB_STACK_OFFSET: u32 = 48;
+const TCB_NAME_OFFSET: u32 = 52;
+
+/// Task lists besides the ready lists which hold TCBs.
+const TASK_LISTS: &[&str] = &[
+    "xDelayedTaskList1",
+    "xDelayedTaskList2",
+    "xPendingReadyList",
+    "xSuspendedTaskList",
+    "xTasksWaitingTermination",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskSnapshot {
+    pub name: String,
+    pub priority: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub tcb: u32,
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub stack_start: u32,
+    /// Bytes between the stack start and the saved stack pointer.
+    pub free_bytes: u32,
+    /// Bytes at the stack start which were never written.
+    pub min_free_bytes: u32,
+    /// The task was running when the core was halted.
+    pub running: bool,
+}
+
+pub struct FreeRtos {
+    ready_lists: u32,
+    ready_list_count: u32,
+    task_lists: Vec<u32>,
+    task_count: u32,
+    current_tcb: Option<u32>,
+}
+
+impl FreeRtos {
+    pub fn from_elf(obj_file: &object::File) -> Option<Self> {
+        let (ready_lists, ready_lists_size) =
+            elf::find_symbol_with_size(obj_file, "pxReadyTasksLists")?;
+
+        Some(Self {
+            ready_lists,
+            ready_list_count: ready_lists_size / LIST_SIZE,
+            task_lists: TASK_LISTS
+                .iter()
+                .filter_map(|l| elf::find_symbol(obj_file, l))
+                .collect(),
+            task_count: elf::find_symbol(obj_file, "uxCurrentNumberOfTasks")?,
+            current_tcb: elf::find_symbol(obj_file, "pxCurrentTCB"),
+        })
+    }
+
+    pub fn task_snapshots(&self, cpu: &mut cpu::CPU) -> DynError<Vec<TaskSnapshot>> {
+        let res = cpu.access_only_in_halt_mode(|core| {
+            let task_count = core.read_word_32(self.task_count)?;
+            let current_tcb = self
+                .current_tcb
+                .map(|addr| core.read_word_32(addr))
+                .transpose()?;
+
+            let lists = (0..self.ready_list_count)
+                .map(|i| self.ready_lists + i * LIST_SIZE)
+                .chain(self.task_lists.iter().copied())
+                .collect::<Vec<_>>();
+
+            let mut tasks = Vec::new();
+            for list in lists {
+                for tcb in list_owners(core, list)? {
+                    if tasks.len() as u32 == task_count {
+                        break;
+                    }
+                    let mut task = read_task(core, tcb)?;
+                    task.running = Some(tcb) == current_tcb;
+                    tasks.push(task);
+                }
+            }
+
+            Ok(tasks)
+        })?;
+
+        Ok(res)
+    }
+}
+
+fn list_owners(core: &mut Core, list: u32) -> Result<Vec<u32>, probe_rs::Error> {
+    let item_count = core.read_word_32(list)?;
+    // pxNext of xListEnd
+    let mut item = core.read_word_32(list + 12)?;
+
+    let mut owners = Vec::new();
+    for _ in 0..item_count {
+        owners.push(core.read_word_32(item + 12)?);
+        item = core.read_word_32(item + 4)?;
+    }
+
+    Ok(owners)
+}
+
+fn read_task(core: &mut Core, tcb: u32) -> Result<TaskSnapshot, probe_rs::Error> {
+    let top_of_stack = core.read_word_32(tcb)?;
+    let priority = core.read_word_32(tcb + TCB_PRIORITY_OFFSET)?;
+    let stack_start = core.read_word_32(tcb + TCB_STACK_OFFSET)?;
+
+    let mut name = [0u8; TASK_NAME_LEN];
+    core.read(tcb + TCB_NAME_OFFSET, &mut name)?;
+    let name_len = name.iter().position(|c| *c == 0).unwrap_or(TASK_NAME_LEN);
+
+    let free_bytes = top_of_stack.saturating_sub(stack_start);
+    let mut stack = vec![0u8; free_bytes as usize];
+    core.read(stack_start, stack.as_mut_slice())?;
+    let min_free_bytes = stack
+        .iter()
+        .position(|b| *b != STACK_FILL_BYTE)
+        .unwrap_or(stack.len()) as u32;
+
+    Ok(TaskSnapshot {
+        name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+        priority,
+        tcb,
+        stack_start,
+        free_bytes,
+        min_free_bytes,
+        running: false,
+    })
+}
@@ -0,0 +1,209 @@
+//! Log lines the firmware writes to its RTT up channels, recorded as events between the
+//! snapshots so stack spikes can be lined up with the log.
+//!
+//! The control block is read through the core like the samples, `probe-rs-rtt` locks
+//! the session which the measurement holds for the whole run. The `defmt` channel is
+//! decoded with the defmt table of the elf file.
+
+use std::fmt::Display;
+
+use probe_rs::{Core, MemoryInterface};
+use serde::{Deserialize, Serialize};
+
+use crate::defmt_log::{self, DefmtDecoder, DefmtTable};
+
+/// Symbol of the control block in SEGGER RTT, rtt-target and defmt-rtt.
+pub const CONTROL_BLOCK_SYMBOL: &str = "_SEGGER_RTT";
+
+/// Written by the firmware once the control block is initialized.
+const CONTROL_BLOCK_ID: &[u8; 16] = b"SEGGER RTT\0\0\0\0\0\0";
+/// Offset of the first up channel descriptor, after the id and the channel counts.
+const DESCRIPTORS_OFFSET: u32 = 24;
+/// Name, buffer, size, write offset, read offset and flags.
+const DESCRIPTOR_SIZE: u32 = 24;
+const WRITE_OFFSET: u32 = 12;
+const READ_OFFSET: u32 = 16;
+/// More channels hint at a corrupted control block.
+const MAX_UP_CHANNELS: u32 = 16;
+const MAX_NAME_LEN: usize = 32;
+/// Lines longer than this are split, binary channels have no line breaks.
+const MAX_LINE_LEN: usize = 256;
+
+/// Line of an up channel, `channel` is its name or number.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine {
+    pub channel: String,
+    pub line: String,
+    /// Level of a defmt log statement.
+    #[serde(default)]
+    pub level: Option<String>,
+    /// `file:line` of a defmt log statement.
+    #[serde(default)]
+    pub location: Option<String>,
+}
+
+impl Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}]", self.channel)?;
+        if let Some(level) = self.level.as_ref() {
+            write!(f, " {}", level.to_uppercase())?;
+        }
+        write!(f, " {}", self.line)?;
+        if let Some(location) = self.location.as_ref() {
+            write!(f, " ({})", location)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct UpChannel<'t> {
+    name: String,
+    descriptor: u32,
+    buffer: u32,
+    size: u32,
+    /// Bytes of the line not yet terminated.
+    pending: Vec<u8>,
+    /// Decoder of the defmt channel.
+    decoder: Option<DefmtDecoder<'t>>,
+}
+
+pub struct RttLog<'t> {
+    control_block: u32,
+    /// None until the firmware initialized the control block.
+    channels: Option<Vec<UpChannel<'t>>>,
+    defmt: Option<&'t DefmtTable>,
+}
+
+impl<'t> RttLog<'t> {
+    pub fn new(control_block: u32, defmt: Option<&'t DefmtTable>) -> Self {
+        Self {
+            control_block,
+            channels: None,
+            defmt,
+        }
+    }
+
+    /// Names of the up channels, empty until the control block is initialized.
+    pub fn channel_names(&self) -> Vec<&str> {
+        self.channels
+            .iter()
+            .flatten()
+            .map(|c| c.name.as_str())
+            .collect()
+    }
+
+    fn read_channels(
+        &self,
+        core: &mut Core,
+    ) -> Result<Option<Vec<UpChannel<'t>>>, probe_rs::Error> {
+        let mut id = [0u8; 16];
+        core.read_8(self.control_block, &mut id)?;
+        if &id != CONTROL_BLOCK_ID {
+            return Ok(None);
+        }
+        let up_channels = core.read_word_32(self.control_block + 16)?;
+        if up_channels > MAX_UP_CHANNELS {
+            return Ok(None);
+        }
+
+        let mut channels = Vec::new();
+        for number in 0..up_channels {
+            let descriptor = self.control_block + DESCRIPTORS_OFFSET + number * DESCRIPTOR_SIZE;
+            let mut fields = [0u32; 3];
+            core.read_32(descriptor, &mut fields)?;
+            let [name_ptr, buffer, size] = fields;
+            if buffer == 0 || size == 0 {
+                continue;
+            }
+            let name = match name_ptr {
+                0 => None,
+                _ => {
+                    let mut name = [0u8; MAX_NAME_LEN];
+                    core.read_8(name_ptr, &mut name)?;
+                    let len = name.iter().position(|b| *b == 0).unwrap_or(MAX_NAME_LEN);
+                    Some(String::from_utf8_lossy(&name[..len]).into_owned())
+                }
+            };
+            let name = name
+                .filter(|n| !n.is_empty())
+                .unwrap_or_else(|| number.to_string());
+            let decoder = self
+                .defmt
+                .filter(|_| name == defmt_log::CHANNEL_NAME)
+                .map(|table| table.decoder());
+            channels.push(UpChannel {
+                name,
+                descriptor,
+                buffer,
+                size,
+                pending: Vec::new(),
+                decoder,
+            });
+        }
+
+        Ok(Some(channels))
+    }
+
+    /// Takes the bytes written since the last poll and returns the completed lines.
+    /// Works on the running core, the read offset is advanced like a host tool does.
+    pub fn poll(&mut self, core: &mut Core) -> Result<Vec<LogLine>, probe_rs::Error> {
+        if self.channels.is_none() {
+            self.channels = self.read_channels(core)?;
+        }
+        let mut lines = Vec::new();
+        for channel in self.channels.iter_mut().flatten() {
+            let write = core.read_word_32(channel.descriptor + WRITE_OFFSET)?;
+            let read = core.read_word_32(channel.descriptor + READ_OFFSET)?;
+            if write == read || write >= channel.size || read >= channel.size {
+                continue;
+            }
+            let mut data = vec![0u8; ((write + channel.size - read) % channel.size) as usize];
+            let first_part = (channel.size - read).min(data.len() as u32) as usize;
+            core.read_8(channel.buffer + read, &mut data[..first_part])?;
+            if first_part < data.len() {
+                core.read_8(channel.buffer, &mut data[first_part..])?;
+            }
+            core.write_word_32(channel.descriptor + READ_OFFSET, write)?;
+
+            if let Some(decoder) = channel.decoder.as_mut() {
+                lines.extend(decoder.decode(&channel.name, &data));
+                continue;
+            }
+            for byte in data {
+                if byte != b'\n' {
+                    channel.pending.push(byte);
+                }
+                if byte == b'\n' || channel.pending.len() >= MAX_LINE_LEN {
+                    let line = String::from_utf8_lossy(&channel.pending)
+                        .trim_end_matches('\r')
+                        .to_owned();
+                    channel.pending.clear();
+                    lines.push(LogLine {
+                        channel: channel.name.to_owned(),
+                        line,
+                        level: None,
+                        location: None,
+                    });
+                }
+            }
+        }
+
+        Ok(lines)
+    }
+
+    /// Lines not yet terminated at the end of the run.
+    pub fn flush(&mut self) -> Vec<LogLine> {
+        self.channels
+            .iter_mut()
+            .flatten()
+            .filter(|c| !c.pending.is_empty())
+            .map(|c| LogLine {
+                channel: c.name.to_owned(),
+                line: String::from_utf8_lossy(&std::mem::take(&mut c.pending)).into_owned(),
+                level: None,
+                location: None,
+            })
+            .collect()
+    }
+}
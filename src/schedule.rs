@@ -0,0 +1,199 @@
+//! Host side callbacks at given samples or times of a looping measurement, e.g. to switch
+//! lab equipment around the measurement. Callbacks run right after the sample they are
+//! due at, while the core runs.
+
+use std::{process::Command, time::Duration};
+
+use thiserror::Error;
+
+use crate::{mem_monitoring::RamSnapshot, DynError};
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+    #[error("invalid schedule entry {0}, expected SAMPLE=COMMAND or SECONDSs=COMMAND")]
+    InvalidEntry(String),
+    #[error("command `{0}` failed with {1}")]
+    CommandFailed(String, std::process::ExitStatus),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Index of the sample in the run.
+    Sample(usize),
+    /// Time since the start of the measurement.
+    Elapsed(Duration),
+}
+
+/// State of the measurement passed to the callbacks.
+pub struct SampleContext<'a> {
+    pub index: usize,
+    pub elapsed: Duration,
+    pub snapshot: &'a RamSnapshot,
+}
+
+type Callback = Box<dyn FnMut(&SampleContext) -> DynError<()>>;
+
+struct Entry {
+    trigger: Trigger,
+    callback: Callback,
+    done: bool,
+}
+
+#[derive(Default)]
+pub struct SchedulerBuilder {
+    entries: Vec<Entry>,
+}
+
+impl SchedulerBuilder {
+    pub fn at_sample(
+        self,
+        index: usize,
+        callback: impl FnMut(&SampleContext) -> DynError<()> + 'static,
+    ) -> Self {
+        self.at(Trigger::Sample(index), callback)
+    }
+
+    pub fn at_elapsed(
+        self,
+        elapsed: Duration,
+        callback: impl FnMut(&SampleContext) -> DynError<()> + 'static,
+    ) -> Self {
+        self.at(Trigger::Elapsed(elapsed), callback)
+    }
+
+    fn at(
+        mut self,
+        trigger: Trigger,
+        callback: impl FnMut(&SampleContext) -> DynError<()> + 'static,
+    ) -> Self {
+        self.entries.push(Entry {
+            trigger,
+            callback: Box::new(callback),
+            done: false,
+        });
+        self
+    }
+
+    pub fn build(self) -> Scheduler {
+        Scheduler {
+            entries: self.entries,
+        }
+    }
+}
+
+pub struct Scheduler {
+    entries: Vec<Entry>,
+}
+
+impl Scheduler {
+    pub fn builder() -> SchedulerBuilder {
+        SchedulerBuilder::default()
+    }
+
+    /// Runs every callback whose trigger is reached and which did not run yet, in
+    /// registration order. A trigger between two samples is due at the later one.
+    pub fn run_due(&mut self, context: &SampleContext) -> DynError<()> {
+        for entry in self.entries.iter_mut().filter(|e| !e.done) {
+            let due = match entry.trigger {
+                Trigger::Sample(index) => context.index >= index,
+                Trigger::Elapsed(elapsed) => context.elapsed >= elapsed,
+            };
+            if due {
+                entry.done = true;
+                (entry.callback)(context)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses `SAMPLE=COMMAND` or `SECONDSs=COMMAND`.
+pub fn parse_entry(entry: &str) -> Result<(Trigger, String), ScheduleError> {
+    let invalid = || ScheduleError::InvalidEntry(String::from(entry));
+    let (trigger, command) = entry.split_once('=').ok_or_else(invalid)?;
+    let trigger = match trigger.strip_suffix('s') {
+        Some(seconds) => Trigger::Elapsed(
+            Duration::try_from_secs_f64(seconds.parse().map_err(|_| invalid())?)
+                .map_err(|_| invalid())?,
+        ),
+        None => Trigger::Sample(trigger.parse().map_err(|_| invalid())?),
+    };
+
+    Ok((trigger, String::from(command)))
+}
+
+/// Callback running `command` in the shell of the host, the sample is passed in the
+/// environment as `MEM_ANALYSER_SAMPLE`, `MEM_ANALYSER_ELAPSED_MS`,
+/// `MEM_ANALYSER_STACK_PTR_OFFSET` and `MEM_ANALYSER_USED_BYTES`.
+pub fn command_callback(command: String) -> impl FnMut(&SampleContext) -> DynError<()> {
+    move |context| {
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        let status = shell
+            .arg(&command)
+            .env("MEM_ANALYSER_SAMPLE", context.index.to_string())
+            .env(
+                "MEM_ANALYSER_ELAPSED_MS",
+                context.elapsed.as_millis().to_string(),
+            )
+            .env(
+                "MEM_ANALYSER_STACK_PTR_OFFSET",
+                context.snapshot.stack_ptr_offset.to_string(),
+            )
+            .env(
+                "MEM_ANALYSER_USED_BYTES",
+                context.snapshot.used_bytes.to_string(),
+            )
+            .status()?;
+        if !status.success() {
+            return Err(ScheduleError::CommandFailed(command.to_owned(), status).into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_trigger_at_a_sample_or_after_seconds() {
+        assert_eq!(
+            parse_entry("120=relay off").unwrap(),
+            (Trigger::Sample(120), String::from("relay off"))
+        );
+        assert_eq!(
+            parse_entry("1.5s=set-load --level=3").unwrap(),
+            (
+                Trigger::Elapsed(Duration::from_millis(1500)),
+                String::from("set-load --level=3")
+            )
+        );
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        for entry in [
+            "relay off",
+            "x=relay off",
+            "-1=relay off",
+            "-1s=relay off",
+            "s=relay off",
+        ] {
+            assert!(
+                matches!(parse_entry(entry), Err(ScheduleError::InvalidEntry(_))),
+                "{}",
+                entry
+            );
+        }
+    }
+}
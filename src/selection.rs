@@ -0,0 +1,99 @@
+//! Selection of the debug probe and the target chip when the cli leaves them ambiguous.
+
+use std::convert::TryFrom;
+
+use probe_rs::{DebugProbeSelector, Probe};
+use text_io::read;
+use thiserror::Error;
+
+use crate::DynError;
+
+#[derive(Error, Debug)]
+pub enum SelectionError {
+    #[error("no debug probe found")]
+    NoProbe,
+    #[error("no chip matches {0}")]
+    NoChip(String),
+    #[error("{kind} is ambiguous, candidates: {candidates}")]
+    Ambiguous {
+        kind: &'static str,
+        candidates: String,
+    },
+    #[error("invalid selection {0}")]
+    InvalidSelection(String),
+}
+
+/// Connected probes as `identifier (VID:PID[:SERIAL])`.
+pub fn list_probes() -> Vec<String> {
+    Probe::list_all()
+        .iter()
+        .map(|p| {
+            format!(
+                "{} ({:04x}:{:04x}{})",
+                p.identifier,
+                p.vendor_id,
+                p.product_id,
+                p.serial_number
+                    .as_ref()
+                    .map(|s| format!(":{}", s))
+                    .unwrap_or_default()
+            )
+        })
+        .collect()
+}
+
+/// Opens the probe matching `selector` (VID:PID[:SERIAL]), else the only connected probe.
+pub fn open_probe(selector: Option<&str>, interactive: bool) -> DynError<Probe> {
+    if let Some(selector) = selector {
+        return Ok(Probe::open(DebugProbeSelector::try_from(selector)?)?);
+    }
+
+    let probes = Probe::list_all();
+    let names = list_probes();
+    let index = match probes.len() {
+        0 => return Err(SelectionError::NoProbe.into()),
+        1 => 0,
+        _ => select("probe", &names, interactive)?,
+    };
+
+    Ok(probes[index].open()?)
+}
+
+/// Resolves `name` to a chip of the probe-rs registry, an exact match wins over partial matches.
+pub fn resolve_chip(name: &str, interactive: bool) -> DynError<String> {
+    let mut chips = probe_rs::config::search_chips(name)?;
+    if let Some(chip) = chips.iter().find(|c| c.eq_ignore_ascii_case(name)) {
+        return Ok(chip.to_owned());
+    }
+
+    let index = match chips.len() {
+        0 => return Err(SelectionError::NoChip(String::from(name)).into()),
+        1 => 0,
+        _ => select("chip", &chips, interactive)?,
+    };
+
+    Ok(chips.swap_remove(index))
+}
+
+fn select(kind: &'static str, candidates: &[String], interactive: bool) -> DynError<usize> {
+    if !interactive {
+        return Err(SelectionError::Ambiguous {
+            kind,
+            candidates: candidates.join(", "),
+        }
+        .into());
+    }
+
+    println!("multiple {}s found:", kind);
+    for (index, candidate) in candidates.iter().enumerate() {
+        println!("  [{}] {}", index, candidate);
+    }
+    print!("select {}: ", kind);
+    std::io::Write::flush(&mut std::io::stdout())?;
+
+    let answer: String = read!("{}\n");
+    match answer.trim().parse::<usize>() {
+        Ok(index) if index < candidates.len() => Ok(index),
+        _ => Err(SelectionError::InvalidSelection(String::from(answer.trim())).into()),
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    mem_monitoring::RamSnapshotRecorder,
+    paint::FillPattern,
+    record_format::{self, RecordFormat},
+    DynError,
+};
+
+/// Runs with the same firmware and config within this window are reported as duplicates.
+const DUPLICATE_RUN_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Identifies what was measured in a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub firmware_hash: String,
+    pub config: String,
+    /// Start of the run.
+    pub timestamp: DateTime<Utc>,
+    /// Unique id of the measured chip.
+    pub device_id: Option<String>,
+    /// GNU build id of the elf file.
+    #[serde(default)]
+    pub build_id: Option<String>,
+    /// File name of the elf file.
+    #[serde(default)]
+    pub firmware_name: Option<String>,
+    /// Index of the measured core on multi-core targets.
+    #[serde(default)]
+    pub core: usize,
+    /// Pattern unused memory was painted with, 0x55 in older recordings.
+    #[serde(default)]
+    pub fill_pattern: FillPattern,
+    /// `key=value` labels of the operator, like the board revision or the lab setup.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Free-form notes of the operator.
+    #[serde(default)]
+    pub notes: Vec<String>,
+}
+
+impl SessionInfo {
+    pub fn new(firmware: &[u8], config: String) -> Self {
+        Self {
+            firmware_hash: hex::encode(Sha256::digest(firmware)),
+            config,
+            timestamp: Utc::now(),
+            device_id: None,
+            build_id: None,
+            firmware_name: None,
+            core: 0,
+            fill_pattern: FillPattern::default(),
+            labels: BTreeMap::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    /// Labels as `key=value` list, empty without labels.
+    pub fn labels_text(&self) -> String {
+        self.labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Whether both sessions measured the same build or a build of the same firmware.
+    pub fn is_same_firmware(&self, other: &SessionInfo) -> bool {
+        let same = |a: &Option<String>, b: &Option<String>| a.is_some() && a == b;
+        self.core == other.core
+            && (same(&self.build_id, &other.build_id)
+                || same(&self.firmware_name, &other.firmware_name))
+    }
+
+    pub fn is_duplicate_of(&self, other: &SessionInfo) -> bool {
+        self.firmware_hash == other.firmware_hash
+            && self.config == other.config
+            && (self.timestamp - other.timestamp)
+                .to_std()
+                .map(|elapsed| elapsed < DUPLICATE_RUN_WINDOW)
+                .unwrap_or(true)
+    }
+}
+
+/// Reads a record file in either record format.
+pub fn load_recording(path: &Path) -> DynError<RamSnapshotRecorder> {
+    record_format::decode(&std::fs::read(path)?)
+}
+
+/// Only the session of a recording, to search the history without keeping the records.
+#[derive(Deserialize)]
+struct RecordingSession {
+    session: SessionInfo,
+}
+
+/// Latest recording in the history directory of the same firmware as `session`, runs
+/// with the same labels are preferred.
+pub fn find_previous(history_dir: &Path, session: &SessionInfo) -> Option<PathBuf> {
+    let entries = std::fs::read_dir(history_dir).ok()?;
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            matches!(
+                p.extension().and_then(|e| e.to_str()),
+                Some("json") | Some(record_format::BIN_EXTENSION)
+            )
+        })
+        .filter_map(|path| {
+            let content = std::fs::read(&path).ok()?;
+            // a binary recording can only be read as a whole
+            let session = match RecordFormat::of(&content) {
+                RecordFormat::Json => {
+                    serde_json::from_slice::<RecordingSession>(&content)
+                        .ok()?
+                        .session
+                }
+                RecordFormat::Bin => record_format::decode(&content).ok()?.session().to_owned(),
+            };
+            Some((session, path))
+        })
+        .filter(|(previous, _)| session.is_same_firmware(previous))
+        .max_by_key(|(previous, _)| (previous.labels == session.labels, previous.timestamp))
+        .map(|(_, path)| path)
+}
+
+/// Keeps a copy of the serialized recording in the history directory, `extension` is
+/// the one of its record format.
+pub fn store_in_history(
+    history_dir: &Path,
+    session: &SessionInfo,
+    recording: &[u8],
+    extension: &str,
+) -> DynError<PathBuf> {
+    std::fs::create_dir_all(history_dir)?;
+    let path = history_dir.join(format!(
+        "{}-{}.{}",
+        session.firmware_name.as_deref().unwrap_or("firmware"),
+        session.timestamp.format("%Y%m%dT%H%M%S%.3f"),
+        extension
+    ));
+    std::fs::write(&path, recording)?;
+
+    Ok(path)
+}
@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("failed loading debug info from obj file")]
+    DebugInfoError(#[from] addr2line::gimli::Error),
+    #[error("invalid path remapping {0}, expected FROM=TO")]
+    InvalidRemap(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Function at an address, with the functions inlined into it as separate frames.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFrame {
+    pub function: Option<String>,
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceSnippet {
+    pub location: SourceLocation,
+    pub first_line: u32,
+    pub lines: Vec<String>,
+}
+
+/// Resolves addresses to source lines with the DWARF line tables of the obj file.
+pub struct SourceResolver {
+    context: addr2line::ObjectContext,
+    remaps: Vec<(PathBuf, PathBuf)>,
+}
+
+impl SourceResolver {
+    pub fn new(
+        obj_file: &object::File,
+        remaps: Vec<(PathBuf, PathBuf)>,
+    ) -> Result<Self, SourceError> {
+        let context = addr2line::Context::new(obj_file)?;
+        Ok(Self { context, remaps })
+    }
+
+    pub fn locate(&self, addr: u32) -> Option<SourceLocation> {
+        let location = self.context.find_location(addr as u64).ok()??;
+        Some(SourceLocation {
+            file: String::from(location.file?),
+            line: location.line?,
+        })
+    }
+
+    /// Returns the inlined frames at `addr`, innermost first, followed by the function
+    /// they are inlined into.
+    pub fn frames(&self, addr: u32) -> Vec<SourceFrame> {
+        let mut frames = Vec::new();
+        let mut iter = match self.context.find_frames(addr as u64) {
+            Ok(iter) => iter,
+            Err(_) => return frames,
+        };
+        while let Ok(Some(frame)) = iter.next() {
+            frames.push(SourceFrame {
+                function: frame
+                    .function
+                    .and_then(|f| f.demangle().ok().map(|n| n.into_owned())),
+                location: frame.location.and_then(|l| {
+                    Some(SourceLocation {
+                        file: String::from(l.file?),
+                        line: l.line?,
+                    })
+                }),
+            });
+        }
+
+        frames
+    }
+
+    /// Reads the lines around the location of `addr` from the (remapped) source file.
+    pub fn snippet(&self, addr: u32, context_lines: u32) -> Option<SourceSnippet> {
+        let location = self.locate(addr)?;
+        let file = File::open(self.remap(Path::new(&location.file))).ok()?;
+
+        let first_line = location.line.saturating_sub(context_lines).max(1);
+        let last_line = location.line + context_lines;
+        let lines = BufReader::new(file)
+            .lines()
+            .skip(first_line as usize - 1)
+            .take((last_line - first_line + 1) as usize)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+
+        Some(SourceSnippet {
+            location,
+            first_line,
+            lines,
+        })
+    }
+
+    fn remap(&self, path: &Path) -> PathBuf {
+        for (from, to) in self.remaps.iter() {
+            if let Ok(rest) = path.strip_prefix(from) {
+                return to.join(rest);
+            }
+        }
+        path.to_path_buf()
+    }
+}
+
+pub fn parse_remap(remap: &str) -> Result<(PathBuf, PathBuf), SourceError> {
+    match remap.split_once('=') {
+        Some((from, to)) => Ok((PathBuf::from(from), PathBuf::from(to))),
+        None => Err(SourceError::InvalidRemap(String::from(remap))),
+    }
+}
@@ -0,0 +1,93 @@
+//! Validated stack addresses. Offsets into the full descending stack are only computed
+//! between values checked against each other, broken assumptions about the memory
+//! layout surface as errors instead of wrapping or panicking subtractions.
+
+use std::ops::Range;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StackError {
+    #[error("stack base 0x{base:08x} is outside of ram 0x{:08x}..0x{:08x}", .ram.start, .ram.end)]
+    BaseOutsideRam { base: u32, ram: Range<u32> },
+    #[error("stack pointer 0x{sp:08x} is above the stack base 0x{base:08x}")]
+    PointerAboveBase { sp: u32, base: u32 },
+    #[error("stack depth of {depth} bytes reaches below the stack bottom 0x{bottom:08x}")]
+    DepthBelowBottom { depth: u32, bottom: u32 },
+}
+
+/// Top of a full descending stack, the initial stack pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBase(u32);
+
+/// Sampled stack pointer, not yet validated against a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackPointer(pub u32);
+
+/// Bytes between the stack base and a stack pointer below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StackDepth(u32);
+
+impl StackBase {
+    /// The base may be the end of `ram`, the first push decrements before storing.
+    pub fn new(addr: u32, ram: &Range<u32>) -> Result<Self, StackError> {
+        if addr < ram.start || addr > ram.end {
+            return Err(StackError::BaseOutsideRam {
+                base: addr,
+                ram: ram.to_owned(),
+            });
+        }
+
+        Ok(Self(addr))
+    }
+
+    pub fn addr(self) -> u32 {
+        self.0
+    }
+
+    /// Ram above the stack base, the ram the linker placed above the stack.
+    pub fn ram_above(self, ram: &Range<u32>) -> u32 {
+        ram.end.saturating_sub(self.0)
+    }
+
+    /// Lowest address of the stack, `limit` if it lies in `ram` below the base, else the
+    /// start of `ram`.
+    pub fn bottom(self, limit: Option<u32>, ram: &Range<u32>) -> u32 {
+        limit
+            .filter(|l| *l >= ram.start && *l < self.0)
+            .unwrap_or(ram.start)
+    }
+
+    pub fn depth_of(self, sp: StackPointer) -> Result<StackDepth, StackError> {
+        self.0
+            .checked_sub(sp.0)
+            .map(StackDepth)
+            .ok_or(StackError::PointerAboveBase {
+                sp: sp.0,
+                base: self.0,
+            })
+    }
+
+    /// Address of the first word below `depth`, which has to stay above `bottom`.
+    pub fn word_below(self, depth: StackDepth, bottom: u32) -> Result<u32, StackError> {
+        self.0
+            .checked_sub(depth.0)
+            .and_then(|addr| addr.checked_sub(4))
+            .map(|addr| addr & !0b11)
+            .filter(|addr| *addr >= bottom)
+            .ok_or(StackError::DepthBelowBottom {
+                depth: depth.0,
+                bottom,
+            })
+    }
+}
+
+impl StackDepth {
+    pub fn new(bytes: u32) -> Self {
+        Self(bytes)
+    }
+
+    pub fn bytes(self) -> u32 {
+        self.0
+    }
+}
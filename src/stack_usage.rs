@@ -0,0 +1,165 @@
+//! Frame sizes from the `.su` files gcc writes with `-fstack-usage`.
+//!
+//! Every line has the form `file:line:column:function\tbytes\tqualifiers` where the
+//! qualifiers are `static`, `dynamic` or `dynamic,bounded`. C++ functions are spelled
+//! with return type and parameters, `int ns::foo(char*)`.
+
+use std::{collections::HashMap, io::BufRead, num::ParseIntError, path::Path};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StackUsageError {
+    #[error("could not read su file")]
+    FailedReadingFile(#[from] std::io::Error),
+    #[error("malformed line {line} in su file: {content}")]
+    MalformedLine { line: usize, content: String },
+    #[error("failed parsing frame size in line {line}")]
+    SizeParseError { line: usize, source: ParseIntError },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSize {
+    pub bytes: u32,
+    /// The frame grows at runtime (alloca, vla), `bytes` is only the static part.
+    pub dynamic: bool,
+}
+
+/// Name of a C++ function as demangled from its symbol, without the return type gcc
+/// writes in front of it. Names without parameter list are returned unchanged.
+fn demangled_form(function: &str) -> &str {
+    let mut depth = 0i32;
+    let mut name_start = 0;
+    let mut chars = function.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            // `operator()` is part of the name, not the parameter list
+            '(' if depth == 0 && function[..index].ends_with("operator") => {
+                chars.next_if(|(_, c)| *c == ')');
+            }
+            '(' if depth == 0 => return &function[name_start..],
+            ' ' if depth == 0 => name_start = index + 1,
+            _ => (),
+        }
+    }
+
+    function
+}
+
+/// Function of a `file:line:column:function` location. Windows paths contain a colon
+/// after the drive and C++ names contain `::`, so the function starts after the last
+/// `:line:column:`, which no function name contains.
+fn location_function(location: &str) -> Option<&str> {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let colons = location
+        .match_indices(':')
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    colons
+        .windows(3)
+        .rev()
+        .find(|c| is_number(&location[c[0] + 1..c[1]]) && is_number(&location[c[1] + 1..c[2]]))
+        .map(|c| &location[c[2] + 1..])
+}
+
+/// Compiler reported frame sizes by function name.
+#[derive(Debug, Default)]
+pub struct FrameSizes {
+    sizes: HashMap<String, FrameSize>,
+}
+
+impl FrameSizes {
+    pub fn from_files(paths: &[&Path]) -> Result<Self, StackUsageError> {
+        let mut frame_sizes = Self::default();
+        for path in paths {
+            frame_sizes.load(path)?;
+        }
+
+        Ok(frame_sizes)
+    }
+
+    fn load(&mut self, path: &Path) -> Result<(), StackUsageError> {
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let malformed = || StackUsageError::MalformedLine {
+                line: index + 1,
+                content: line.to_owned(),
+            };
+
+            let mut columns = line.split('\t');
+            let location = columns.next().ok_or_else(malformed)?;
+            let bytes = columns.next().ok_or_else(malformed)?;
+            let qualifiers = columns.next().unwrap_or("static");
+            let function = location_function(location).ok_or_else(malformed)?;
+
+            let size = FrameSize {
+                bytes: bytes
+                    .trim()
+                    .parse()
+                    .map_err(|e| StackUsageError::SizeParseError {
+                        line: index + 1,
+                        source: e,
+                    })?,
+                dynamic: qualifiers.contains("dynamic") && !qualifiers.contains("bounded"),
+            };
+            // static functions of different units may share a name, keep the larger frame
+            let entry = self
+                .sizes
+                .entry(String::from(demangled_form(function)))
+                .or_insert(size);
+            if size.bytes > entry.bytes {
+                *entry = size;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, function: &str) -> Option<&FrameSize> {
+        self.sizes.get(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangled_form_strips_return_type() {
+        assert_eq!(demangled_form("foo"), "foo");
+        assert_eq!(demangled_form("int foo(int)"), "foo(int)");
+        assert_eq!(
+            demangled_form("void ns::Foo::bar(char*) const"),
+            "ns::Foo::bar(char*) const"
+        );
+        assert_eq!(
+            demangled_form("std::pair<int, int> make(int, int)"),
+            "make(int, int)"
+        );
+        assert_eq!(
+            demangled_form("bool Less::operator()(int, int)"),
+            "Less::operator()(int, int)"
+        );
+        assert_eq!(demangled_form("Foo::Foo()"), "Foo::Foo()");
+    }
+
+    #[test]
+    fn location_function_splits_after_line_and_column() {
+        assert_eq!(location_function("src/main.c:12:5:main"), Some("main"));
+        assert_eq!(
+            location_function(r"C:\fw\src\main.c:12:5:main"),
+            Some("main")
+        );
+        assert_eq!(
+            location_function(r"C:\fw\foo.cpp:3:1:int ns::Foo::bar(char*)"),
+            Some("int ns::Foo::bar(char*)")
+        );
+        assert_eq!(location_function("main.c:main"), None);
+    }
+}
@@ -0,0 +1,489 @@
+//! Statistics over a recording, independent of how it was recorded.
+
+use std::{cmp::Reverse, ops::Range};
+
+use serde::Serialize;
+
+use crate::{
+    call_graph::StackDepth,
+    demangle::Language,
+    mem_monitoring::{RamSnapshot, RamSnapshotRecorder, RegionStatistics, TaskStatistics},
+};
+
+/// Extended exception frame with fp registers, the most an interrupt entry pushes.
+const EXCEPTION_FRAME_SIZE: u32 = 104;
+const STACK_ALIGNMENT: u32 = 8;
+
+#[derive(Debug, Serialize)]
+pub struct RamStatistics {
+    median_stack_ptr_off: u32,
+    pub max_stack_ptr_off: u32,
+    /// Samples with an anomalous sp, left out of the stack ptr offset maximum and median.
+    pub sp_anomalies: usize,
+    max_mem_usage: u32,
+    stack_ptr_course: Vec<u32>,
+    mem_usage_course: Vec<u32>,
+    /// Seconds since the first record of each record of the courses.
+    elapsed_course: Vec<f64>,
+    max_heap_usage: Option<u32>,
+    heap_usage_course: Vec<u32>,
+    tasks: Vec<TaskStatistics>,
+    pub priorities: Vec<PriorityStatistics>,
+    regions: Vec<RegionStatistics>,
+    pub sampling: Option<SamplingStatistics>,
+    pub overhead: Option<OverheadStatistics>,
+    /// Untouched range directly below the deepest stack excursion.
+    pub untouched_gap: Option<Range<u32>>,
+}
+
+/// Task statistics aggregated per RTOS priority level.
+#[derive(Debug, Serialize)]
+pub struct PriorityStatistics {
+    pub priority: u32,
+    pub tasks: Vec<String>,
+    /// Smallest stack margin of the tasks of this priority.
+    pub min_free_bytes: u32,
+    /// Share of the samples in which a task of this priority was running.
+    pub cpu_share: f32,
+    /// Share of the stack peaks (records at or above the 90th percentile of used bytes)
+    /// taken while a task of this priority was running.
+    pub peak_share: f32,
+}
+
+impl std::fmt::Display for PriorityStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "priority {}: {:.1}% cpu, {:.1}% of stack peaks, min free {} bytes ({})",
+            self.priority,
+            self.cpu_share * 100.0,
+            self.peak_share * 100.0,
+            self.min_free_bytes,
+            self.tasks.join(", ")
+        )
+    }
+}
+
+/// Temporal fidelity of the sampled courses, deviation of the samples from their
+/// intended start on the interval grid.
+#[derive(Debug, Serialize)]
+pub struct SamplingStatistics {
+    pub samples: usize,
+    pub missed_intervals: u32,
+    pub mean_jitter_us: f64,
+    pub max_jitter_us: u64,
+    pub std_dev_jitter_us: f64,
+}
+
+impl std::fmt::Display for SamplingStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} samples, {} missed intervals, jitter mean {:.0} us, max {} us, std dev {:.0} us",
+            self.samples,
+            self.missed_intervals,
+            self.mean_jitter_us,
+            self.max_jitter_us,
+            self.std_dev_jitter_us
+        )
+    }
+}
+
+/// Intrusiveness of the measurement, the time the target was halted by the analyser.
+#[derive(Debug, Serialize)]
+pub struct OverheadStatistics {
+    pub halted_ms: f64,
+    pub wall_clock_ms: f64,
+    /// Share of the wall-clock time the target was halted.
+    pub halted_share: f32,
+}
+
+impl std::fmt::Display for OverheadStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "target halted {:.1} ms of {:.1} ms ({:.2}%)",
+            self.halted_ms,
+            self.wall_clock_ms,
+            self.halted_share * 100.0
+        )
+    }
+}
+
+impl RamStatistics {
+    pub fn median_stack_ptr_off(&self) -> u32 {
+        self.median_stack_ptr_off
+    }
+
+    pub fn max_mem_usage(&self) -> u32 {
+        self.max_mem_usage
+    }
+
+    pub fn max_heap_usage(&self) -> Option<u32> {
+        self.max_heap_usage
+    }
+
+    pub fn stack_ptr_course(&self) -> &[u32] {
+        &self.stack_ptr_course
+    }
+
+    pub fn mem_usage_course(&self) -> &[u32] {
+        &self.mem_usage_course
+    }
+
+    pub fn elapsed_course(&self) -> &[f64] {
+        &self.elapsed_course
+    }
+
+    pub fn print_comparison(&self, previous: &RamStatistics) {
+        let delta = |now: u32, prev: u32| now as i64 - prev as i64;
+        println!(
+            "compared to previous run: max stack ptr offset {:+}, median stack ptr offset {:+}, max mem usage {:+}",
+            delta(self.max_stack_ptr_off, previous.max_stack_ptr_off),
+            delta(self.median_stack_ptr_off, previous.median_stack_ptr_off),
+            delta(self.max_mem_usage, previous.max_mem_usage),
+        );
+        if let (Some(now), Some(prev)) = (self.max_heap_usage, previous.max_heap_usage) {
+            println!(
+                "compared to previous run: max heap usage {:+}",
+                delta(now, prev)
+            );
+        }
+    }
+}
+
+/// Stack size suggestion from the measured maximum and the static analysis.
+#[derive(Debug)]
+pub struct StackRecommendation {
+    pub observed_max: u32,
+    pub safety_factor: f32,
+    /// Deepest interrupt handler of the static analysis, including its exception frame.
+    pub isr: Option<StackDepth>,
+    pub recommended: u32,
+}
+
+impl StackRecommendation {
+    /// Only the single deepest handler is added, nested interrupts of different
+    /// priorities are not accounted for.
+    pub fn new(observed_max: u32, safety_factor: f32, isr: Option<StackDepth>) -> Self {
+        let isr_bytes = isr
+            .as_ref()
+            .map(|d| d.bytes + EXCEPTION_FRAME_SIZE)
+            .unwrap_or(0);
+        let recommended = (observed_max as f32 * safety_factor).ceil() as u32 + isr_bytes;
+        let recommended = recommended.div_ceil(STACK_ALIGNMENT) * STACK_ALIGNMENT;
+
+        Self {
+            observed_max,
+            safety_factor,
+            isr,
+            recommended,
+        }
+    }
+
+    pub fn print(&self, language: Language) {
+        print!(
+            "recommended stack size: {} bytes (observed {} bytes x {}",
+            self.recommended, self.observed_max, self.safety_factor
+        );
+        match self.isr.as_ref() {
+            Some(isr) => println!(
+                " + {} bytes for {}{})",
+                isr.bytes + EXCEPTION_FRAME_SIZE,
+                isr.entry,
+                if isr.is_lower_bound() {
+                    ", lower bound"
+                } else {
+                    ""
+                }
+            ),
+            None => println!(")"),
+        }
+        match language {
+            Language::Rust => println!(
+                "  memory.x: _stack_end = _stack_start - 0x{:x};",
+                self.recommended
+            ),
+            Language::Cpp => println!(
+                "  linker script: _Min_Stack_Size = 0x{:x};",
+                self.recommended
+            ),
+        }
+    }
+}
+
+/// Computes statistics from the snapshots stored in a recorder.
+pub struct StatisticsEngine<'a> {
+    recorder: &'a RamSnapshotRecorder,
+}
+
+impl<'a> StatisticsEngine<'a> {
+    pub fn new(recorder: &'a RamSnapshotRecorder) -> Self {
+        Self { recorder }
+    }
+
+    /// Panics if the recording holds no snapshots.
+    pub fn calculate(&self) -> RamStatistics {
+        let stack_ptr_course = self
+            .recorder
+            .timeline()
+            .map(|s| s.stack_ptr_offset)
+            .collect::<Vec<_>>();
+
+        let mut stack_ptrs_off = self
+            .recorder
+            .timeline()
+            .filter(|s| s.sp_anomaly.is_none())
+            .map(|s| s.stack_ptr_offset)
+            .collect::<Vec<_>>();
+        let sp_anomalies = stack_ptr_course.len() - stack_ptrs_off.len();
+        if stack_ptrs_off.is_empty() {
+            stack_ptrs_off.push(0);
+        }
+
+        stack_ptrs_off.sort_unstable_by(|x: &u32, y: &u32| x.partial_cmp(y).unwrap());
+        let median_stack_ptr_off = percentile_of_sorted(stack_ptrs_off.as_slice(), 50.0);
+
+        let max_stack_ptr_off = *stack_ptrs_off.last().unwrap();
+
+        let mut max_mem_usage = self
+            .recorder
+            .timeline()
+            .map(|s| s.used_bytes)
+            .collect::<Vec<_>>();
+        let mem_usage_course = max_mem_usage.to_owned();
+        max_mem_usage.sort_unstable_by(|x: &u32, y: &u32| x.partial_cmp(y).unwrap());
+        let max_mem_usage = *max_mem_usage.last().unwrap();
+
+        // a bounded recording keeps only the last records, its maxima cover the whole run
+        let (median_stack_ptr_off, max_stack_ptr_off, sp_anomalies, max_mem_usage) =
+            match self.recorder.aggregates() {
+                Some(aggregates) => (
+                    aggregates.median_stack_ptr_off(),
+                    aggregates.max_stack_ptr_off,
+                    aggregates.sp_anomalies,
+                    aggregates.max_used_bytes,
+                ),
+                None => (
+                    median_stack_ptr_off,
+                    max_stack_ptr_off,
+                    sp_anomalies,
+                    max_mem_usage,
+                ),
+            };
+
+        let elapsed_course = (0..stack_ptr_course.len())
+            .map(|id| self.recorder.elapsed(id).as_secs_f64())
+            .collect();
+
+        let heap_usage_course = self
+            .recorder
+            .heap_records()
+            .iter()
+            .map(|h| h.high_watermark)
+            .collect::<Vec<_>>();
+        let max_heap_usage = match self.recorder.aggregates() {
+            Some(aggregates) => aggregates.max_heap_usage(),
+            None => heap_usage_course.iter().max().copied(),
+        };
+
+        RamStatistics {
+            median_stack_ptr_off,
+            max_stack_ptr_off,
+            sp_anomalies,
+            max_mem_usage,
+            stack_ptr_course,
+            mem_usage_course,
+            elapsed_course,
+            max_heap_usage,
+            heap_usage_course,
+            tasks: self.tasks(),
+            priorities: self.priorities(),
+            regions: self.regions(),
+            sampling: self.sampling(),
+            overhead: self.overhead(),
+            untouched_gap: self.recorder.untouched_gap().cloned(),
+        }
+    }
+
+    /// Task statistics with the least free bytes of the whole run, also of records
+    /// evicted from a bounded recording.
+    pub fn tasks(&self) -> Vec<TaskStatistics> {
+        let mut tasks = self.recorder.tasks().to_owned();
+        if let Some(aggregates) = self.recorder.aggregates() {
+            for task in tasks.iter_mut() {
+                if let Some(min_free_bytes) = aggregates.task_min_free_bytes(task.tcb) {
+                    task.min_free_bytes = min_free_bytes;
+                }
+            }
+        }
+
+        tasks
+    }
+
+    /// Region statistics with the peak of the whole run, also of records evicted from
+    /// a bounded recording.
+    pub fn regions(&self) -> Vec<RegionStatistics> {
+        let mut regions = self.recorder.regions().to_owned();
+        if let Some(aggregates) = self.recorder.aggregates() {
+            for region in regions.iter_mut() {
+                if let Some(peak) = aggregates.region_peak(&region.name) {
+                    region.max_used_bytes = peak;
+                }
+            }
+        }
+
+        regions
+    }
+
+    /// Highest priority first.
+    pub fn priorities(&self) -> Vec<PriorityStatistics> {
+        let tasks = self.tasks();
+        let running = self.recorder.running_tasks();
+        let priority_of = |tcb: u32| tasks.iter().find(|t| t.tcb == tcb).map(|t| t.priority);
+
+        let mut used_bytes = self
+            .recorder
+            .timeline()
+            .map(|s| s.used_bytes)
+            .collect::<Vec<_>>();
+        let peak_records = if used_bytes.is_empty() {
+            Vec::new()
+        } else {
+            let mut sorted = used_bytes.to_owned();
+            sorted.sort_unstable();
+            let threshold = percentile_of_sorted(&sorted, 90.0);
+            used_bytes
+                .drain(..)
+                .enumerate()
+                .filter(|(_, u)| *u >= threshold)
+                .map(|(i, _)| i)
+                .collect()
+        };
+        let share = |count: usize, total: usize| {
+            if total == 0 {
+                0.0
+            } else {
+                count as f32 / total as f32
+            }
+        };
+
+        let mut levels = tasks.iter().map(|t| t.priority).collect::<Vec<_>>();
+        levels.sort_unstable_by(|a, b| b.cmp(a));
+        levels.dedup();
+        levels
+            .into_iter()
+            .map(|priority| {
+                let members = tasks.iter().filter(|t| t.priority == priority);
+                let running_samples = running
+                    .iter()
+                    .filter(|r| r.and_then(priority_of) == Some(priority))
+                    .count();
+                let peak_samples = peak_records
+                    .iter()
+                    .filter(|i| {
+                        running.get(**i).copied().flatten().and_then(priority_of) == Some(priority)
+                    })
+                    .count();
+
+                PriorityStatistics {
+                    priority,
+                    tasks: members.clone().map(|t| t.name.to_owned()).collect(),
+                    min_free_bytes: members.map(|t| t.min_free_bytes).min().unwrap_or(0),
+                    cpu_share: share(running_samples, running.len()),
+                    peak_share: share(peak_samples, peak_records.len()),
+                }
+            })
+            .collect()
+    }
+
+    /// None for recordings without a sampling interval, like stepping.
+    pub fn sampling(&self) -> Option<SamplingStatistics> {
+        let timings = self.recorder.sample_timings();
+        if timings.is_empty() {
+            return None;
+        }
+
+        let jitters = timings.iter().map(|t| t.jitter_us() as f64);
+        let mean_jitter_us = jitters.clone().sum::<f64>() / timings.len() as f64;
+        let variance =
+            jitters.map(|j| (j - mean_jitter_us).powi(2)).sum::<f64>() / timings.len() as f64;
+
+        Some(SamplingStatistics {
+            samples: timings.len(),
+            missed_intervals: self.recorder.missed_intervals(),
+            mean_jitter_us,
+            max_jitter_us: timings.iter().map(|t| t.jitter_us()).max().unwrap_or(0),
+            std_dev_jitter_us: variance.sqrt(),
+        })
+    }
+
+    pub fn overhead(&self) -> Option<OverheadStatistics> {
+        let overhead = self.recorder.overhead()?;
+        Some(OverheadStatistics {
+            halted_ms: overhead.halted_us as f64 / 1000.0,
+            wall_clock_ms: overhead.wall_clock_us as f64 / 1000.0,
+            halted_share: if overhead.wall_clock_us == 0 {
+                0.0
+            } else {
+                overhead.halted_us as f32 / overhead.wall_clock_us as f32
+            },
+        })
+    }
+
+    /// Highest used stack bytes of all recorded snapshots.
+    pub fn max_used_bytes(&self) -> Option<u32> {
+        if let Some(aggregates) = self.recorder.aggregates() {
+            return Some(aggregates.max_used_bytes).filter(|_| !self.recorder.is_empty());
+        }
+        self.recorder
+            .snapshot_variants()
+            .iter()
+            .map(|s| s.used_bytes)
+            .max()
+    }
+
+    /// Returns the snapshot with the deepest stack of each function, deepest first.
+    pub fn hotspots(&self, count: usize) -> Vec<&'a RamSnapshot> {
+        let mut hotspots = Vec::<&RamSnapshot>::new();
+        for snapshot in self.recorder.snapshot_variants().iter() {
+            match hotspots
+                .iter_mut()
+                .find(|h| h.function == snapshot.function)
+            {
+                Some(hotspot) => {
+                    if snapshot.stack_ptr_offset > hotspot.stack_ptr_offset {
+                        *hotspot = snapshot;
+                    }
+                }
+                None => hotspots.push(snapshot),
+            }
+        }
+        hotspots.sort_by_key(|hotspot| Reverse(hotspot.stack_ptr_offset));
+        hotspots.truncate(count);
+
+        hotspots
+    }
+}
+
+// Helper function: extract a value representing the `pct` percentile of a sorted sample-set, using
+// linear interpolation. If samples are not sorted, return nonsensical value.
+fn percentile_of_sorted(sorted_samples: &[u32], pct: f32) -> u32 {
+    assert!(!sorted_samples.is_empty());
+    if sorted_samples.len() == 1 {
+        return sorted_samples[0];
+    }
+    assert!(0.0 <= pct);
+    assert!(pct <= 100.0);
+    if pct == 100.0 {
+        return sorted_samples[sorted_samples.len() - 1];
+    }
+    let length = (sorted_samples.len() - 1) as f32;
+    let rank = (pct / 100.0) * length;
+    let lrank = rank.floor();
+    let d = rank - lrank;
+    let n = lrank as usize;
+    let lo = sorted_samples[n];
+    let hi = sorted_samples[n + 1];
+    lo + (hi - lo) * d as u32
+}
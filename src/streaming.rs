@@ -0,0 +1,330 @@
+//! Live streaming of the measurement to connected clients, over plain tcp (newline
+//! delimited json) or WebSocket (one json text message per message).
+//!
+//! Each client is served by its own thread from a bounded queue, messages queued
+//! meanwhile are sent as one batch at most `max_rate` times per second. Snapshots which
+//! don't fit the queue of a slow client are dropped for that client, a client whose queue
+//! has no room for the run start or end is disconnected, the sampling never waits for a
+//! client.
+//!
+//! Every message carries `schema_version`, which is incremented on incompatible changes
+//! of the message layout.
+
+use std::{
+    fmt::Display,
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tungstenite::{Message, WebSocket};
+
+use crate::{
+    findings::Finding,
+    mem_monitoring::{CPUSnapshot, RamSnapshot},
+    session::SessionInfo,
+    statistics::RamStatistics,
+    DynError,
+};
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Messages queued per client before snapshots are dropped.
+const QUEUE_CAPACITY: usize = 512;
+/// Clients which don't take a batch within this time are disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// WebSocket clients which don't complete their handshake within this time are refused.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+}
+
+/// Messages sent to the clients, tagged by `type`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamMessage<'a> {
+    RunStart {
+        session: &'a SessionInfo,
+        interval_ms: u64,
+    },
+    Snapshot {
+        id: usize,
+        timestamp: String,
+        /// DWT cycle counter of the record, extended over its wraps.
+        cycles: Option<u64>,
+        snapshot: &'a RamSnapshot,
+    },
+    /// Register only sample of the loop-measure mode.
+    CpuSnapshot {
+        id: usize,
+        timestamp: String,
+        snapshot: &'a CPUSnapshot,
+    },
+    RunEnd {
+        records: usize,
+        failure: Option<String>,
+        /// Summary of the recording, none if nothing was recorded.
+        statistics: Option<&'a RamStatistics>,
+        findings: &'a [Finding],
+    },
+}
+
+impl StreamMessage<'_> {
+    /// Value of the `type` tag.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StreamMessage::RunStart { .. } => "run_start",
+            StreamMessage::Snapshot { .. } => "snapshot",
+            StreamMessage::CpuSnapshot { .. } => "cpu_snapshot",
+            StreamMessage::RunEnd { .. } => "run_end",
+        }
+    }
+
+    /// The message with its schema version as json.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&Envelope {
+            schema_version: SCHEMA_VERSION,
+            message: self,
+        })
+    }
+}
+
+/// Receiver of the stream messages of a run.
+pub trait Sink {
+    fn distribute(&mut self, message: &StreamMessage) -> DynError<()>;
+
+    /// Ends the connections after the last message of the run.
+    fn close(&mut self) {}
+
+    /// Counts of the distribution so far, none if the sink doesn't keep any.
+    fn statistics(&self) -> Option<DistributionStatistics> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DistributionStatistics {
+    /// Clients connected during the run.
+    pub clients: usize,
+    pub sent: usize,
+    /// Snapshots not sent to a client because its queue was full.
+    pub dropped: usize,
+    /// Clients disconnected because their queue had no room for the run start or end.
+    #[serde(default)]
+    pub disconnected: usize,
+}
+
+impl Display for DistributionStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} streaming clients, {} messages sent, {} dropped, {} slow clients disconnected",
+            self.clients, self.sent, self.dropped, self.disconnected
+        )
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    clients: AtomicUsize,
+    sent: AtomicUsize,
+    dropped: AtomicUsize,
+    disconnected: AtomicUsize,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    message: &'a StreamMessage<'a>,
+}
+
+enum Client {
+    Tcp(TcpStream),
+    // boxed, the WebSocket state is many times the size of a stream
+    WebSocket(Box<WebSocket<TcpStream>>),
+}
+
+impl Client {
+    fn close(&mut self) {
+        match self {
+            Client::Tcp(stream) => {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+            Client::WebSocket(socket) => {
+                let _ = socket.close(None);
+                // flushes the close frame
+                let _ = socket.write_pending();
+            }
+        }
+    }
+
+    /// Sends the batch with a single write over tcp, WebSocket clients get one message
+    /// per json.
+    fn send(&mut self, batch: &[String]) -> DynError<()> {
+        match self {
+            Client::Tcp(stream) => {
+                let mut lines = batch.join("\n");
+                lines.push('\n');
+                stream.write_all(lines.as_bytes())?;
+            }
+            Client::WebSocket(socket) => {
+                for json in batch {
+                    socket.write_message(Message::Text(json.to_owned()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the queued messages until the queue is closed or the client fails.
+    fn serve(mut self, queue: Receiver<String>, max_rate: u32, counters: Arc<Counters>) {
+        let min_interval = Duration::from_secs(1) / max_rate.max(1);
+        while let Ok(first) = queue.recv() {
+            let started = Instant::now();
+            let mut batch = vec![first];
+            batch.extend(queue.try_iter());
+            if self.send(&batch).is_err() {
+                return;
+            }
+            counters.sent.fetch_add(batch.len(), Ordering::SeqCst);
+            if let Some(wait) = min_interval.checked_sub(started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        self.close();
+    }
+}
+
+struct ClientQueue {
+    queue: SyncSender<String>,
+    /// Handle of the connection to end it while the worker waits in a write.
+    stream: TcpStream,
+    worker: JoinHandle<()>,
+}
+
+impl ClientQueue {
+    /// Ends the connection, the worker stops with its next write.
+    fn disconnect(&self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+pub struct ConnectionHandler {
+    clients: Arc<Mutex<Vec<ClientQueue>>>,
+    counters: Arc<Counters>,
+    _server: JoinHandle<()>,
+}
+
+impl ConnectionHandler {
+    /// Accepts clients in the background, WebSocket clients once their handshake succeeded.
+    /// Each client is sent at most `max_rate` batches per second.
+    pub fn new(transport: Transport, addr: SocketAddr, max_rate: u32) -> DynError<Self> {
+        let listener = TcpListener::bind(addr)?;
+        println!("streaming on {}", listener.local_addr()?);
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let counters = Arc::new(Counters::default());
+        let clients_tmp = clients.to_owned();
+        let counters_tmp = counters.to_owned();
+        let server = std::thread::spawn(move || {
+            let clients = clients_tmp;
+            let counters = counters_tmp;
+            while let Ok((stream, _)) = listener.accept() {
+                let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                let handle = match stream.try_clone() {
+                    Ok(handle) => handle,
+                    Err(_) => continue,
+                };
+                let client = match transport {
+                    Transport::Tcp => Client::Tcp(stream),
+                    Transport::WebSocket => {
+                        // a client stalling its handshake would block the accepting
+                        let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+                        match tungstenite::accept(stream) {
+                            Ok(socket) => {
+                                let _ = socket.get_ref().set_read_timeout(None);
+                                Client::WebSocket(Box::new(socket))
+                            }
+                            Err(_) => continue,
+                        }
+                    }
+                };
+                counters.clients.fetch_add(1, Ordering::SeqCst);
+                let (queue, receiver) = std::sync::mpsc::sync_channel(QUEUE_CAPACITY);
+                let counters = counters.to_owned();
+                let worker = std::thread::spawn(move || client.serve(receiver, max_rate, counters));
+                clients.lock().unwrap().push(ClientQueue {
+                    queue,
+                    stream: handle,
+                    worker,
+                });
+            }
+        });
+
+        Ok(Self {
+            clients,
+            counters,
+            _server: server,
+        })
+    }
+}
+
+impl Sink for ConnectionHandler {
+    /// Queues the message for all clients, clients which failed are dropped. Snapshots are
+    /// dropped for full queues, clients without room for the run start or end are
+    /// disconnected as they would miss the frame of the run.
+    fn distribute(&mut self, message: &StreamMessage) -> DynError<()> {
+        let json = message.to_json()?;
+        let droppable = matches!(
+            message,
+            StreamMessage::Snapshot { .. } | StreamMessage::CpuSnapshot { .. }
+        );
+        let counters = &self.counters;
+        self.clients.lock().unwrap().retain(|client| {
+            match client.queue.try_send(json.to_owned()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) if droppable => {
+                    counters.dropped.fetch_add(1, Ordering::SeqCst);
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    client.disconnect();
+                    counters.disconnected.fetch_add(1, Ordering::SeqCst);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Waits until the clients were sent their queued messages.
+    fn close(&mut self) {
+        let clients = self.clients.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for ClientQueue { queue, worker, .. } in clients {
+            drop(queue);
+            let _ = worker.join();
+        }
+    }
+
+    fn statistics(&self) -> Option<DistributionStatistics> {
+        Some(DistributionStatistics {
+            clients: self.counters.clients.load(Ordering::SeqCst),
+            sent: self.counters.sent.load(Ordering::SeqCst),
+            dropped: self.counters.dropped.load(Ordering::SeqCst),
+            disconnected: self.counters.disconnected.load(Ordering::SeqCst),
+        })
+    }
+}
@@ -0,0 +1,58 @@
+//! ISO8601 timestamps and durations for the record format and the reports.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+
+/// Zone of the timestamps in human facing output, files are always written in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeZone {
+    Utc,
+    Local,
+}
+
+impl TimeZone {
+    pub fn format(&self, timestamp: &DateTime<Utc>) -> String {
+        match self {
+            TimeZone::Utc => to_iso8601(timestamp),
+            TimeZone::Local => timestamp
+                .with_timezone(&Local)
+                .to_rfc3339_opts(SecondsFormat::Millis, false),
+        }
+    }
+}
+
+pub fn to_iso8601(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Serializes a `Duration` as ISO8601 duration like `PT0.100S`.
+pub mod iso8601_duration {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!(
+            "PT{}.{:03}S",
+            duration.as_secs(),
+            duration.subsec_millis()
+        ))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.strip_prefix("PT")
+            .and_then(|t| t.strip_suffix('S'))
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| D::Error::custom(format!("invalid ISO8601 duration {}", text)))
+    }
+}
+
+/// Nominal time of the record at `index`, assuming uniform spacing of the records.
+pub fn nominal(start: &DateTime<Utc>, interval: Duration, index: usize) -> DateTime<Utc> {
+    *start
+        + chrono::Duration::from_std(interval * index as u32)
+            .unwrap_or_else(|_| chrono::Duration::zero())
+}
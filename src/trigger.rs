@@ -0,0 +1,76 @@
+//! Triggers which hold back recording until the firmware shows activity, so boards
+//! with long idle boot delays don't start their recordings with minutes of noise.
+
+use std::fmt::Display;
+
+use thiserror::Error;
+
+use crate::mem_monitoring::RamSnapshot;
+
+/// Startup code of cortex-m-rt, CMSIS and newlib, leaving it starts the application.
+const STARTUP_FUNCTIONS: &[&str] = &[
+    "Reset",
+    "Reset_Handler",
+    "__pre_init",
+    "DefaultPreInit",
+    "SystemInit",
+    "__libc_init_array",
+    "_start",
+    "_mainCRTStartup",
+];
+
+#[derive(Error, Debug)]
+pub enum TriggerError {
+    #[error("invalid start trigger {0}, expected `stack-bytes=<BYTES>` or `left-startup`")]
+    InvalidTrigger(String),
+}
+
+/// Decides with which sample the recording starts, the samples before are discarded.
+pub trait StartTrigger: Display {
+    fn fired(&mut self, snapshot: &RamSnapshot) -> bool;
+}
+
+/// Fires once the stack ptr offset reaches a number of bytes.
+pub struct StackDepthTrigger {
+    bytes: u32,
+}
+
+impl StartTrigger for StackDepthTrigger {
+    fn fired(&mut self, snapshot: &RamSnapshot) -> bool {
+        snapshot.sp_anomaly.is_none() && snapshot.stack_ptr_offset >= self.bytes
+    }
+}
+
+impl Display for StackDepthTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stack ptr offset reached {} bytes", self.bytes)
+    }
+}
+
+/// Fires on the first sample in a known function outside the startup code.
+pub struct LeftStartupTrigger;
+
+impl StartTrigger for LeftStartupTrigger {
+    fn fired(&mut self, snapshot: &RamSnapshot) -> bool {
+        // functions of unknown addresses are named by their address
+        !snapshot.function.starts_with("0x")
+            && !STARTUP_FUNCTIONS.contains(&snapshot.function.as_str())
+    }
+}
+
+impl Display for LeftStartupTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "left the startup code")
+    }
+}
+
+pub fn parse(trigger: &str) -> Result<Box<dyn StartTrigger>, TriggerError> {
+    let invalid = || TriggerError::InvalidTrigger(String::from(trigger));
+    match trigger.split_once('=') {
+        Some(("stack-bytes", bytes)) => Ok(Box::new(StackDepthTrigger {
+            bytes: bytes.trim().parse().map_err(|_| invalid())?,
+        })),
+        None if trigger == "left-startup" => Ok(Box::new(LeftStartupTrigger)),
+        _ => Err(invalid()),
+    }
+}
@@ -0,0 +1,180 @@
+//! Live terminal dashboard of the looping mode.
+//!
+//! The dashboard keeps its own aggregates, updated with every drawn snapshot, so a
+//! frame costs the same at the end of a long run as at its start. Messages of the run
+//! go to its log pane, printing would tear the alternate screen.
+
+use std::{collections::VecDeque, io::Stdout, ops::Range, time::Duration};
+
+use crossterm::{
+    event::{self, Event, KeyCode, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Style},
+    widgets::{Block, Borders, Gauge, List, ListItem, Paragraph, Sparkline},
+    Terminal,
+};
+
+use crate::{
+    mem_monitoring::{RamSnapshot, RamSnapshotRecorder, RunningAggregates},
+    DynError,
+};
+
+/// Stack ptr offsets kept for the sparkline, wider than any terminal.
+const COURSE_LEN: usize = 1024;
+/// Lines kept for the log pane.
+const LOG_LEN: usize = 100;
+
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    aggregates: RunningAggregates,
+    records: usize,
+    course: VecDeque<u64>,
+    log: VecDeque<String>,
+}
+
+impl Dashboard {
+    /// Switches the terminal to the alternate screen until the dashboard is dropped.
+    pub fn new() -> DynError<Self> {
+        enable_raw_mode()?;
+        let mut stdout = std::io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+
+        Ok(Self {
+            terminal: Terminal::new(CrosstermBackend::new(stdout))?,
+            aggregates: RunningAggregates::default(),
+            records: 0,
+            course: VecDeque::with_capacity(COURSE_LEN),
+            log: VecDeque::with_capacity(LOG_LEN),
+        })
+    }
+
+    /// Adds a message of the run to the log pane, shown with the next frame.
+    pub fn log(&mut self, message: String) {
+        if self.log.len() == LOG_LEN {
+            self.log.pop_front();
+        }
+        self.log.push_back(message);
+    }
+
+    /// Draws a frame with `current`, the newest record, added to the aggregates.
+    pub fn draw(
+        &mut self,
+        recorder: &RamSnapshotRecorder,
+        current: &RamSnapshot,
+        heap_range: Option<&Range<u32>>,
+    ) -> DynError<()> {
+        self.aggregates.add(current);
+        self.records += 1;
+        if self.course.len() == COURSE_LEN {
+            self.course.pop_front();
+        }
+        self.course.push_back(current.stack_ptr_offset as u64);
+        let heap = recorder.heap_records().last();
+        let Self {
+            terminal,
+            aggregates,
+            records,
+            course,
+            log,
+        } = self;
+        let course = course.make_contiguous();
+
+        terminal.draw(|f| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([
+                    Constraint::Min(6),
+                    Constraint::Length(5),
+                    Constraint::Length(3),
+                    Constraint::Length(7),
+                ])
+                .split(f.size());
+
+            // the newest records which fit into the sparkline
+            let width = chunks[0].width.saturating_sub(2) as usize;
+            let sparkline = Sparkline::default()
+                .block(
+                    Block::default()
+                        .title("stack ptr offset (q to stop)")
+                        .borders(Borders::ALL),
+                )
+                .data(&course[course.len().saturating_sub(width)..])
+                .max(aggregates.max_stack_ptr_off as u64)
+                .style(Style::default().fg(Color::Red));
+            f.render_widget(sparkline, chunks[0]);
+
+            let info = Paragraph::new(format!(
+                "function: {}\nstack ptr offset: {} bytes, used: {} bytes\nmax: {} bytes, median: {} bytes, max used: {} bytes, records: {}",
+                current.function,
+                current.stack_ptr_offset,
+                current.used_bytes,
+                aggregates.max_stack_ptr_off,
+                aggregates.median_stack_ptr_off(),
+                aggregates.max_used_bytes,
+                records
+            ))
+            .block(Block::default().title("current").borders(Borders::ALL));
+            f.render_widget(info, chunks[1]);
+
+            let heap_size = heap_range.map(|r| r.end - r.start).unwrap_or(0);
+            let (ratio, label) = match heap {
+                Some(heap) if heap_size > 0 => (
+                    (heap.high_watermark as f64 / heap_size as f64).min(1.0),
+                    format!(
+                        "{} bytes used, high watermark {} of {} bytes",
+                        heap.used_bytes, heap.high_watermark, heap_size
+                    ),
+                ),
+                _ => (0.0, String::from("not monitored")),
+            };
+            let gauge = Gauge::default()
+                .block(Block::default().title("heap").borders(Borders::ALL))
+                .gauge_style(Style::default().fg(Color::Blue))
+                .ratio(ratio)
+                .label(label);
+            f.render_widget(gauge, chunks[2]);
+
+            // the newest lines which fit into the pane
+            let height = chunks[3].height.saturating_sub(2) as usize;
+            let lines = log
+                .iter()
+                .skip(log.len().saturating_sub(height))
+                .map(|line| ListItem::new(line.as_str()))
+                .collect::<Vec<_>>();
+            let log = List::new(lines).block(Block::default().title("log").borders(Borders::ALL));
+            f.render_widget(log, chunks[3]);
+        })?;
+
+        Ok(())
+    }
+
+    /// Polls the terminal for `q`, Esc or Ctrl+C without blocking. The raw mode of the
+    /// dashboard delivers Ctrl+C as key instead of a signal.
+    pub fn quit_requested(&self) -> DynError<bool> {
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL))
+                {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
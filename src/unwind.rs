@@ -0,0 +1,169 @@
+//! Call stack unwinding with the `.debug_frame` section of the obj file.
+
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EndianSlice, Register, RegisterRule, RunTimeEndian,
+    UnwindContext, UnwindSection,
+};
+use object::{Object, ObjectSection};
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+use serde::{Deserialize, Serialize};
+use serde_hex::{SerHex, StrictPfx};
+use thiserror::Error;
+
+use crate::{asm_parsing::AsmFile, exception::ExceptionEntry, source::SourceLocation};
+
+/// Unwinding stops after this many frames, in case of corrupted stacks.
+const MAX_FRAMES: usize = 32;
+const REGISTER_COUNT: usize = 16;
+const SP: usize = 13;
+const LR: usize = 14;
+const EXC_RETURN_PREFIX: u32 = 0xFFFF_FF00;
+
+#[derive(Error, Debug)]
+pub enum UnwindError {
+    #[error(transparent)]
+    Probe(#[from] probe_rs::Error),
+    #[error("unwind info at 0x{addr:08x} refers to dwarf register {register}, only the core registers r0-r15 are unwound")]
+    UnsupportedRegister { addr: u32, register: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BacktraceFrame {
+    #[serde(with = "SerHex::<StrictPfx>")]
+    pub instr_ptr: u32,
+    pub function: Option<String>,
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Default)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+    /// Set if the unwinding stopped at the entry of an exception handler.
+    pub exception_entry: Option<ExceptionEntry>,
+}
+
+pub struct Unwinder<'a> {
+    debug_frame: DebugFrame<EndianSlice<'a, RunTimeEndian>>,
+    bases: BaseAddresses,
+}
+
+impl<'a> Unwinder<'a> {
+    /// Returns `None` if the obj file has no `.debug_frame` section.
+    pub fn new(obj_file: &'a object::File) -> Option<Self> {
+        let data = obj_file.section_by_name(".debug_frame")?.data().ok()?;
+        let mut debug_frame = DebugFrame::new(data, RunTimeEndian::Little);
+        debug_frame.set_address_size(4);
+
+        Some(Self {
+            debug_frame,
+            bases: BaseAddresses::default(),
+        })
+    }
+
+    /// Unwinds the stack of the halted core, innermost frame first. Stops at the first
+    /// frame without unwind info and at exception entries.
+    pub fn backtrace(
+        &self,
+        core: &mut Core,
+        asm_file: Option<&AsmFile>,
+    ) -> Result<Backtrace, UnwindError> {
+        let mut registers = [0u32; REGISTER_COUNT];
+        for (index, register) in registers.iter_mut().enumerate() {
+            *register = core.read_core_reg(CoreRegisterAddress(index as u16))?;
+        }
+        let mut instr_ptr = core.read_core_reg(core.registers().program_counter())?;
+
+        let mut ctx = UnwindContext::new();
+        let mut frames = Vec::new();
+        let mut exception_entry = None;
+        while frames.len() < MAX_FRAMES {
+            // return addresses point behind the call, look up the calling instruction
+            let lookup_addr = if frames.is_empty() {
+                instr_ptr
+            } else {
+                instr_ptr - 1
+            };
+            frames.push(BacktraceFrame {
+                instr_ptr,
+                function: asm_file
+                    .and_then(|a| a.get_function_based_on_addr(&lookup_addr))
+                    .map(|f| f.name),
+                location: None,
+            });
+
+            let row = match self
+                .debug_frame
+                .fde_for_address(&self.bases, lookup_addr as u64, DebugFrame::cie_from_offset)
+                .and_then(|fde| {
+                    fde.unwind_info_for_address(
+                        &self.debug_frame,
+                        &self.bases,
+                        &mut ctx,
+                        lookup_addr as u64,
+                    )
+                }) {
+                Ok(row) => row,
+                Err(_) => break,
+            };
+
+            let register_value = |register: Register| {
+                registers.get(register.0 as usize).copied().ok_or(
+                    UnwindError::UnsupportedRegister {
+                        addr: lookup_addr,
+                        register: register.0,
+                    },
+                )
+            };
+            let cfa = match row.cfa() {
+                CfaRule::RegisterAndOffset { register, offset } => {
+                    (register_value(*register)? as i64 + offset) as u32
+                }
+                CfaRule::Expression(_) => break,
+            };
+
+            let mut caller_registers = registers;
+            for (register, rule) in row.registers() {
+                let Register(index) = *register;
+                if index as usize >= REGISTER_COUNT {
+                    continue;
+                }
+                caller_registers[index as usize] = match rule {
+                    RegisterRule::Offset(offset) => {
+                        core.read_word_32((cfa as i64 + offset) as u32)?
+                    }
+                    RegisterRule::ValOffset(offset) => (cfa as i64 + offset) as u32,
+                    RegisterRule::Register(other) => register_value(*other)?,
+                    _ => registers[index as usize],
+                };
+            }
+            caller_registers[SP] = cfa;
+
+            let return_addr = caller_registers[LR];
+            // the cfa of the handler is the stack pointer right after the core stacked
+            // the exception frame
+            if return_addr & EXC_RETURN_PREFIX == EXC_RETURN_PREFIX {
+                exception_entry = Some(ExceptionEntry {
+                    stack_ptr: cfa,
+                    exc_return: return_addr,
+                });
+                break;
+            }
+            if return_addr & !1 == 0 {
+                break;
+            }
+            let caller_instr_ptr = return_addr & !1;
+            // no progress, the frame did not save the return address
+            if caller_instr_ptr == instr_ptr && cfa == registers[SP] {
+                break;
+            }
+
+            registers = caller_registers;
+            instr_ptr = caller_instr_ptr;
+        }
+
+        Ok(Backtrace {
+            frames,
+            exception_entry,
+        })
+    }
+}